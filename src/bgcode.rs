@@ -0,0 +1,582 @@
+//! Prusa binary G-Code, the `.bgcode` files preferred by newer Prusa printers
+//!
+//! A file is a header followed by blocks: metadata as `key=value` lines, PNG thumbnails, then the
+//! G-Code itself split into blocks of at most 64KiB. Blocks can be compressed with Heatshrink,
+//! G-Code blocks can also be packed with [crate::meatpack], and every block can carry a CRC32.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program::Program;
+    use crate::thumbnail::Image;
+    use crate::*;
+
+    fn sample_gcode() -> String {
+        let mut p = Program::new();
+        p.push(auto_home()).push(relative_extrution()).comment("first layer");
+        for layer in 1..40 {
+            p.move_z(0.2 * layer as f32);
+            for &(x, y) in [(10.0, 10.0), (60.0, 10.0), (60.0, 60.0), (10.0, 60.0)].iter() {
+                p.move_xy(Point2d { x: x + layer as f32 * 0.1, y }, Some(1800.0), Some(1.75));
+            }
+        }
+        p.render()
+    }
+
+    fn all_options() -> Vec<Options> {
+        let mut all = Vec::new();
+        for &compression in [Compression::None, Compression::Heatshrink11, Compression::Heatshrink12].iter() {
+            for &encoding in [Encoding::None, Encoding::MeatPack, Encoding::MeatPackComments].iter() {
+                for &checksum in [false, true].iter() {
+                    all.push(Options { compression, encoding, checksum });
+                }
+            }
+        }
+        all
+    }
+
+    #[test]
+    fn test_heatshrink_round_trip() {
+        let data = sample_gcode().into_bytes();
+        for &window in [11, 12].iter() {
+            let compressed = heatshrink_compress(&data, window);
+            assert!(compressed.len() * 3 < data.len(), "{} {}", compressed.len(), data.len());
+            assert_eq!(data, heatshrink_decompress(&compressed, window));
+        }
+        assert_eq!(b"".to_vec(), heatshrink_decompress(&heatshrink_compress(b"", 11), 11));
+        assert_eq!(b"a".to_vec(), heatshrink_decompress(&heatshrink_compress(b"a", 11), 11));
+    }
+
+    #[test]
+    fn test_heatshrink_format() {
+        // a literal 'a', then a 3 byte back reference one byte back: 0 00000000000 0010
+        assert_eq!(vec![0xB0, 0x80, 0x01, 0x00], heatshrink_compress(b"aaaa", 11));
+    }
+
+    #[test]
+    fn test_file_layout() {
+        let bytes = Bgcode::new("G28\n").write(&Options { compression: Compression::None, encoding: Encoding::None, checksum: true });
+        assert_eq!(&b"GCDE\x01\x00\x00\x00\x01\x00"[..], &bytes[..10]);
+        // the last block holds the G-Code as is, then the checksum of the block
+        let block = &bytes[bytes.len() - 18..];
+        assert_eq!(&b"\x01\x00\x00\x00\x04\x00\x00\x00\x00\x00G28\n"[..], &block[..14]);
+        assert_eq!(crc32(&block[..14]).to_le_bytes(), block[14..]);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let gcode = sample_gcode();
+        let without_comments: String = gcode.lines().filter(|l| !l.starts_with(';')).map(|l| format!("{}\n", l)).collect();
+        let mut file = Bgcode::new(&gcode);
+        file.printer_metadata.push(("printer_model".to_string(), "MK4".to_string()));
+        file.print_metadata.push(("filament used [mm]".to_string(), "12.34".to_string()));
+        file.thumbnails.push(Thumbnail::from_image(&Image::new(16, 16)));
+        for options in all_options() {
+            let read = Bgcode::read(&file.write(&options)).unwrap();
+            let expected = if options.encoding == Encoding::MeatPack { &without_comments } else { &gcode };
+            assert_eq!(expected, &read.gcode, "{:?}", options);
+            assert_eq!(file.file_metadata, read.file_metadata);
+            assert_eq!(file.printer_metadata, read.printer_metadata);
+            assert_eq!(file.print_metadata, read.print_metadata);
+            assert_eq!(file.thumbnails, read.thumbnails);
+        }
+    }
+
+    #[test]
+    fn test_splits_large_gcode() {
+        let gcode = "G1 X100.125 Y100.125 E0.5\n".repeat(5000);
+        let bytes = Bgcode::new(&gcode).write(&Options::default());
+        assert_eq!(gcode, Bgcode::read(&bytes).unwrap().gcode);
+        let chunks = split_lines(&gcode, GCODE_BLOCK_SIZE);
+        assert_eq!(2, chunks.len());
+        assert!(chunks.iter().all(|c| c.len() <= GCODE_BLOCK_SIZE && c.ends_with('\n')));
+    }
+
+    #[test]
+    fn test_compresses() {
+        let gcode = sample_gcode();
+        let bytes = Bgcode::new(&gcode).write(&Options::default());
+        assert!(bytes.len() * 3 < gcode.len(), "{} {}", bytes.len(), gcode.len());
+    }
+
+    #[test]
+    fn test_read_errors() {
+        assert_eq!(Err(BgcodeError::NotBgcode), Bgcode::read(b"G28\n"));
+        let mut bytes = Bgcode::new("G28\n").write(&Options::default());
+        let last = bytes.len() - 5;
+        bytes[last] ^= 1;
+        assert_eq!(Err(BgcodeError::Checksum), Bgcode::read(&bytes));
+        let bytes = Bgcode::new("G28\n").write(&Options::default());
+        assert_eq!(Err(BgcodeError::Truncated), Bgcode::read(&bytes[..bytes.len() - 2]));
+    }
+
+    #[test]
+    fn test_from_program() {
+        let mut p = Program::new();
+        p.emit_metadata(true).emit_thumbnails(&[(16, 16)]);
+        p.move_xyz(Point3d { x: 10.0, y: 10.0, z: 0.2 }, None, None).move_xy(Point2d { x: 20.0, y: 10.0 }, None, Some(1.0));
+        let file = Bgcode::from_program(&p);
+        assert!(!file.gcode.contains("thumbnail") && !file.gcode.contains(";TIME:"));
+        assert!(file.gcode.contains(";LAYER:0\n"));
+        assert_eq!(1, file.thumbnails.len());
+        let value = |key: &str| file.print_metadata.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone());
+        assert_eq!(Some("1.00".to_string()), value("filament used [mm]"));
+        assert_eq!(Some("0.20".to_string()), value("max_layer_z"));
+    }
+}
+
+use std::error::Error;
+use std::fmt;
+
+use crate::meatpack::{pack_line, restore_spaces, Decoder, Signal};
+use crate::metadata::{format_duration, Metadata};
+use crate::program::Program;
+use crate::thumbnail::{crc32, Image};
+
+const MAGIC: &[u8; 4] = b"GCDE";
+const VERSION: u32 = 1;
+/// Largest amount of G-Code held by one block, before compression
+const GCODE_BLOCK_SIZE: usize = 65535;
+
+const FILE_METADATA: u16 = 0;
+const GCODE: u16 = 1;
+const SLICER_METADATA: u16 = 2;
+const PRINTER_METADATA: u16 = 3;
+const PRINT_METADATA: u16 = 4;
+const THUMBNAIL: u16 = 5;
+
+/// How block data is compressed
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Compression {
+    None,
+    /// Heatshrink with a 2KiB window and 16 byte matches
+    Heatshrink11,
+    /// Heatshrink with a 4KiB window and 16 byte matches
+    Heatshrink12,
+}
+
+impl Compression {
+    fn id(self) -> u16 {
+        match self {
+            Compression::None => 0,
+            Compression::Heatshrink11 => 2,
+            Compression::Heatshrink12 => 3,
+        }
+    }
+}
+
+/// How the G-Code text is stored, before compression
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Encoding {
+    None,
+    /// Packed with MeatPack, dropping comments
+    MeatPack,
+    /// Packed with MeatPack, keeping comments
+    MeatPackComments,
+}
+
+/// How a file is written, by default as PrusaSlicer does: Heatshrink 12, MeatPack and checksums
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Options {
+    pub compression: Compression,
+    pub encoding: Encoding,
+    pub checksum: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options { compression: Compression::Heatshrink12, encoding: Encoding::MeatPack, checksum: true }
+    }
+}
+
+/// A PNG preview image
+#[derive(Debug, Clone, PartialEq)]
+pub struct Thumbnail {
+    pub width: u16,
+    pub height: u16,
+    pub png: Vec<u8>,
+}
+
+impl Thumbnail {
+    pub fn from_image(image: &Image) -> Self {
+        Thumbnail { width: image.width as u16, height: image.height as u16, png: image.encode_png() }
+    }
+}
+
+/// The content of a binary G-Code file. Metadata is kept as ordered `(key, value)` pairs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bgcode {
+    /// About the file itself, such as the program that made it
+    pub file_metadata: Vec<(String, String)>,
+    /// Shown by the printer before printing, such as the printer model and filament type
+    pub printer_metadata: Vec<(String, String)>,
+    pub thumbnails: Vec<Thumbnail>,
+    /// Results of slicing, such as the estimated time and filament used
+    pub print_metadata: Vec<(String, String)>,
+    /// The settings used to make the program
+    pub slicer_metadata: Vec<(String, String)>,
+    pub gcode: String,
+}
+
+impl Bgcode {
+    /// Creates a file holding G-Code, with this crate as its producer
+    pub fn new(gcode: &str) -> Self {
+        Bgcode {
+            file_metadata: vec![("Producer".to_string(), format!("gen_gcode {}", env!("CARGO_PKG_VERSION")))],
+            printer_metadata: Vec::new(),
+            thumbnails: Vec::new(),
+            print_metadata: Vec::new(),
+            slicer_metadata: Vec::new(),
+            gcode: gcode.to_string(),
+        }
+    }
+
+    /// Creates a file from a program. The metadata and thumbnails it emits are stored in their
+    /// own blocks, rather than as comments, see [Program::emit_metadata] and
+    /// [Program::emit_thumbnails].
+    pub fn from_program(program: &Program) -> Self {
+        let mut file = Bgcode::new(&program.render_body());
+        file.thumbnails = program.render_thumbnails().iter().map(Thumbnail::from_image).collect();
+        if program.has_metadata() {
+            let meta = Metadata::from_program(&file.gcode, program.flavor());
+            let pairs = vec![
+                ("filament used [mm]".to_string(), format!("{:.2}", meta.filament_used)),
+                ("estimated printing time (normal mode)".to_string(), format_duration(meta.estimated_time)),
+                ("max_layer_z".to_string(), format!("{:.2}", meta.max.z)),
+            ];
+            file.printer_metadata = pairs.clone();
+            file.print_metadata = pairs;
+        }
+        file
+    }
+
+    /// Returns the file as bytes
+    pub fn write(&self, options: &Options) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.extend_from_slice(&(options.checksum as u16).to_le_bytes());
+        let metadata = |out: &mut Vec<u8>, kind: u16, pairs: &[(String, String)]| {
+            let ini: String = pairs.iter().map(|(k, v)| format!("{}={}\n", k, v)).collect();
+            // 0 is the only encoding, INI
+            write_block(out, kind, &0u16.to_le_bytes(), ini.as_bytes(), options.compression, options.checksum);
+        };
+        if !self.file_metadata.is_empty() {
+            metadata(&mut out, FILE_METADATA, &self.file_metadata);
+        }
+        metadata(&mut out, PRINTER_METADATA, &self.printer_metadata);
+        for thumbnail in &self.thumbnails {
+            let mut params = 0u16.to_le_bytes().to_vec();
+            params.extend_from_slice(&thumbnail.width.to_le_bytes());
+            params.extend_from_slice(&thumbnail.height.to_le_bytes());
+            // thumbnails are already compressed
+            write_block(&mut out, THUMBNAIL, &params, &thumbnail.png, Compression::None, options.checksum);
+        }
+        metadata(&mut out, PRINT_METADATA, &self.print_metadata);
+        metadata(&mut out, SLICER_METADATA, &self.slicer_metadata);
+        for chunk in split_lines(&self.gcode, GCODE_BLOCK_SIZE) {
+            let (id, data) = match options.encoding {
+                Encoding::None => (0u16, chunk.as_bytes().to_vec()),
+                Encoding::MeatPack => (1, pack(chunk, false)),
+                Encoding::MeatPackComments => (2, pack(chunk, true)),
+            };
+            write_block(&mut out, GCODE, &id.to_le_bytes(), &data, options.compression, options.checksum);
+        }
+        out
+    }
+
+    /// Reads a file, checking the checksums it holds
+    pub fn read(bytes: &[u8]) -> Result<Self, BgcodeError> {
+        let mut reader = Reader { bytes, pos: 0 };
+        if bytes.len() < 4 || &bytes[..4] != MAGIC {
+            return Err(BgcodeError::NotBgcode);
+        }
+        reader.pos = 4;
+        let version = reader.u32()?;
+        if version != VERSION {
+            return Err(BgcodeError::UnsupportedVersion(version));
+        }
+        let checksum = match reader.u16()? {
+            0 => false,
+            1 => true,
+            n => return Err(BgcodeError::Unsupported("checksum type", n)),
+        };
+        let mut file = Bgcode { file_metadata: Vec::new(), printer_metadata: Vec::new(), thumbnails: Vec::new(), print_metadata: Vec::new(), slicer_metadata: Vec::new(), gcode: String::new() };
+        while reader.pos < bytes.len() {
+            let start = reader.pos;
+            let kind = reader.u16()?;
+            let compression = reader.u16()?;
+            let size = reader.u32()? as usize;
+            let stored = if compression == 0 { size } else { reader.u32()? as usize };
+            let params = reader.take(if kind == THUMBNAIL { 6 } else { 2 })?;
+            let data = reader.take(stored)?;
+            if checksum {
+                let crc = crc32(&bytes[start..reader.pos]);
+                if reader.u32()? != crc {
+                    return Err(BgcodeError::Checksum);
+                }
+            }
+            let data = match compression {
+                0 => data.to_vec(),
+                2 => heatshrink_decompress(data, 11),
+                3 => heatshrink_decompress(data, 12),
+                n => return Err(BgcodeError::Unsupported("compression", n)),
+            };
+            if data.len() != size {
+                return Err(BgcodeError::Corrupt);
+            }
+            let param = u16::from_le_bytes([params[0], params[1]]);
+            match kind {
+                GCODE => match param {
+                    0 => file.gcode += &String::from_utf8_lossy(&data),
+                    1 | 2 => file.gcode += &unpack(&data),
+                    n => return Err(BgcodeError::Unsupported("G-Code encoding", n)),
+                },
+                THUMBNAIL => {
+                    let (width, height) = (u16::from_le_bytes([params[2], params[3]]), u16::from_le_bytes([params[4], params[5]]));
+                    file.thumbnails.push(Thumbnail { width, height, png: data });
+                }
+                _ if param != 0 => return Err(BgcodeError::Unsupported("metadata encoding", param)),
+                FILE_METADATA => file.file_metadata = parse_ini(&data),
+                PRINTER_METADATA => file.printer_metadata = parse_ini(&data),
+                PRINT_METADATA => file.print_metadata = parse_ini(&data),
+                SLICER_METADATA => file.slicer_metadata = parse_ini(&data),
+                n => return Err(BgcodeError::Unsupported("block type", n)),
+            }
+        }
+        Ok(file)
+    }
+}
+
+/// Why a binary G-Code file could not be read
+#[derive(Debug, Clone, PartialEq)]
+pub enum BgcodeError {
+    /// The file does not start with the `GCDE` magic number
+    NotBgcode,
+    UnsupportedVersion(u32),
+    /// A block uses a feature this crate does not read, such as Deflate compression
+    Unsupported(&'static str, u16),
+    /// The file ends inside a block
+    Truncated,
+    /// A block does not match its checksum
+    Checksum,
+    /// A block does not decompress to its stated size
+    Corrupt,
+}
+
+impl fmt::Display for BgcodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BgcodeError::NotBgcode => write!(f, "not a binary G-Code file"),
+            BgcodeError::UnsupportedVersion(v) => write!(f, "unsupported binary G-Code version {}", v),
+            BgcodeError::Unsupported(what, n) => write!(f, "unsupported {} {}", what, n),
+            BgcodeError::Truncated => write!(f, "file ends inside a block"),
+            BgcodeError::Checksum => write!(f, "block checksum does not match"),
+            BgcodeError::Corrupt => write!(f, "block does not decompress to its stated size"),
+        }
+    }
+}
+
+impl Error for BgcodeError {}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], BgcodeError> {
+        let end = self.pos.checked_add(len).filter(|&end| end <= self.bytes.len()).ok_or(BgcodeError::Truncated)?;
+        let out = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(out)
+    }
+
+    fn u16(&mut self) -> Result<u16, BgcodeError> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, BgcodeError> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+}
+
+fn write_block(out: &mut Vec<u8>, kind: u16, params: &[u8], data: &[u8], compression: Compression, checksum: bool) {
+    let start = out.len();
+    out.extend_from_slice(&kind.to_le_bytes());
+    out.extend_from_slice(&compression.id().to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    let compressed = match compression {
+        Compression::None => None,
+        Compression::Heatshrink11 => Some(heatshrink_compress(data, 11)),
+        Compression::Heatshrink12 => Some(heatshrink_compress(data, 12)),
+    };
+    if let Some(compressed) = &compressed {
+        out.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+    }
+    out.extend_from_slice(params);
+    out.extend_from_slice(compressed.as_deref().unwrap_or(data));
+    if checksum {
+        let crc = crc32(&out[start..]);
+        out.extend_from_slice(&crc.to_le_bytes());
+    }
+}
+
+fn parse_ini(data: &[u8]) -> Vec<(String, String)> {
+    let text = String::from_utf8_lossy(data);
+    text.lines().filter_map(|line| line.split_once('=')).map(|(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+/// Splits text into chunks of at most `size` bytes, at line ends where possible
+fn split_lines(text: &str, size: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut rest = text;
+    while rest.len() > size {
+        let mut end = rest[..size].rfind('\n').map_or(size, |i| i + 1);
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        chunks.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    if !rest.is_empty() {
+        chunks.push(rest);
+    }
+    chunks
+}
+
+/// Packs G-Code with spaces dropped, as PrusaSlicer does
+fn pack(gcode: &str, comments: bool) -> Vec<u8> {
+    let mut out = Signal::EnablePacking.bytes().to_vec();
+    out.extend_from_slice(&Signal::EnableNoSpaces.bytes());
+    for line in gcode.lines() {
+        let line = if comments { line } else { line.split(';').next().unwrap_or("").trim_end() };
+        if !line.is_empty() {
+            out.extend(pack_line(line, true));
+        }
+    }
+    out
+}
+
+/// Unpacks G-Code, putting back the spaces between words
+fn unpack(data: &[u8]) -> String {
+    let mut out = String::new();
+    for line in Decoder::new().feed(data).lines() {
+        out += &restore_spaces(line);
+        out.push('\n');
+    }
+    out
+}
+
+/// Heatshrink LZSS: each item is a 1 bit then a literal byte, or a 0 bit then a back reference as
+/// its distance minus one in `window` bits and its length minus one in 4 bits
+fn heatshrink_compress(data: &[u8], window: u32) -> Vec<u8> {
+    const MAX_MATCH: usize = 16;
+    const MAX_CHAIN: usize = 64;
+    let max_distance = 1usize << window;
+    let mut bits = BitWriter::default();
+    // most recent position of each pair of bytes, and the previous position with the same pair
+    let mut head = vec![usize::MAX; 1 << 16];
+    let mut prev = vec![usize::MAX; data.len()];
+    let key = |i: usize| (data[i] as usize) << 8 | data[i + 1] as usize;
+    let mut i = 0;
+    while i < data.len() {
+        let (mut best_len, mut best_dist) = (0, 0);
+        if i + 1 < data.len() {
+            let mut candidate = head[key(i)];
+            let mut chain = 0;
+            while candidate != usize::MAX && i - candidate <= max_distance && chain < MAX_CHAIN {
+                let len = data[candidate..].iter().zip(&data[i..]).take(MAX_MATCH).take_while(|(a, b)| a == b).count();
+                if len > best_len {
+                    best_len = len;
+                    best_dist = i - candidate;
+                }
+                candidate = prev[candidate];
+                chain += 1;
+            }
+        }
+        // a reference costs 1 + window + 4 bits, a literal 9
+        if best_len * 9 > 1 + window as usize + 4 {
+            bits.push(0, 1);
+            bits.push((best_dist - 1) as u32, window);
+            bits.push((best_len - 1) as u32, 4);
+            for k in i..i + best_len {
+                if k + 1 < data.len() {
+                    prev[k] = head[key(k)];
+                    head[key(k)] = k;
+                }
+            }
+            i += best_len;
+        } else {
+            bits.push(1, 1);
+            bits.push(data[i] as u32, 8);
+            if i + 1 < data.len() {
+                prev[i] = head[key(i)];
+                head[key(i)] = i;
+            }
+            i += 1;
+        }
+    }
+    bits.finish()
+}
+
+fn heatshrink_decompress(data: &[u8], window: u32) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::new();
+    let mut pos = 0;
+    let mut read = |count: u32| -> Option<u32> {
+        if pos + count as usize > data.len() * 8 {
+            return None;
+        }
+        let mut value = 0;
+        for _ in 0..count {
+            let bit = data[pos / 8] >> (7 - pos % 8) & 1;
+            value = value << 1 | bit as u32;
+            pos += 1;
+        }
+        Some(value)
+    };
+    // the input ends with fewer bits than a whole item
+    while let Some(tag) = read(1) {
+        if tag == 1 {
+            match read(8) {
+                Some(byte) => out.push(byte as u8),
+                None => break,
+            }
+        } else {
+            let (distance, len) = match (read(window), read(4)) {
+                (Some(d), Some(l)) => (d as usize + 1, l as usize + 1),
+                _ => break,
+            };
+            for _ in 0..len {
+                // the window starts out zeroed
+                let byte = if distance <= out.len() { out[out.len() - distance] } else { 0 };
+                out.push(byte);
+            }
+        }
+    }
+    out
+}
+
+/// Writes bits most significant first
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    bit: u32,
+}
+
+impl BitWriter {
+    fn push(&mut self, value: u32, count: u32) {
+        for k in (0..count).rev() {
+            if self.bit == 0 {
+                self.out.push(0);
+            }
+            *self.out.last_mut().unwrap() |= ((value >> k & 1) as u8) << (7 - self.bit);
+            self.bit = (self.bit + 1) % 8;
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.out
+    }
+}
@@ -0,0 +1,822 @@
+//! Calibration prints, test patterns for tuning a setting to a filament
+//!
+//! Each test adds itself to a [Program], after whatever start sequence the printer needs, and
+//! labels the values it tries with printed digits, or reports which part of the print tried which
+//! value.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::{simulate, State, Warning};
+    use crate::{relative_extrution, Flavor};
+
+    fn approx(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-3
+    }
+
+    #[test]
+    fn test_values() {
+        assert_eq!(vec![0.0, 0.02, 0.04, 0.06, 0.08, 0.1], PressureAdvanceTest::new(0.0, 0.1, 0.02).values());
+        assert_eq!(vec![0.5], PressureAdvanceTest::new(0.5, 0.5, 0.1).values());
+        assert_eq!(vec![0.3, 0.45], PressureAdvanceTest::new(0.3, 0.5, 0.15).values());
+        assert_eq!(vec![0.0], PressureAdvanceTest::new(0.0, 0.1, 0.0).values());
+        assert_eq!(vec![0.0], PressureAdvanceTest::new(0.0, 0.1, -0.02).values());
+        assert_eq!(vec![0.0], PressureAdvanceTest::new(0.0, f32::INFINITY, 0.02).values());
+    }
+
+    #[test]
+    fn test_label_paths() {
+        let paths = label_paths("0.1", 2.0);
+        assert_eq!(3, paths.len());
+        let p = |x: f32, y: f32| Point2d { x, y };
+        assert_eq!(vec![p(0.0, 0.0), p(1.0, 0.0), p(1.0, 2.0), p(0.0, 2.0), p(0.0, 0.0)], paths[0]);
+        // the dot follows the 0 and its gap, the 1 follows the dot and its gap
+        let close = |a: &[Point2d], b: &[Point2d]| a.iter().zip(b).all(|(a, b)| approx(a.x, b.x) && approx(a.y, b.y));
+        assert!(close(&[p(1.6, 0.0), p(1.8, 0.0)], &paths[1]));
+        assert!(close(&[p(3.4, 0.0), p(3.4, 2.0)], &paths[2]));
+        assert!(label_paths("x", 2.0).is_empty());
+    }
+
+    #[test]
+    fn test_pressure_advance_lines() {
+        let test = PressureAdvanceTest { hotend_temperature: Some(240), ..PressureAdvanceTest::new(0.0, 0.1, 0.05) };
+        let mut p = Program::new();
+        test.add_to(&mut p);
+        let gcode = p.render();
+        assert!(gcode.contains("M109 S240\n"));
+        let advances: Vec<&str> = gcode.lines().filter(|l| l.starts_with("M900")).collect();
+        assert_eq!(vec!["M900 K0", "M900 K0.05", "M900 K0.1"], advances);
+        let timeline = simulate(&gcode);
+        assert!(!timeline.warnings.iter().any(|w| matches!(w, Warning::UnsupportedCommand { .. })));
+        // each line ends 80mm to the right of where it starts, at the slow speed
+        let line = gcode.lines().position(|l| l == "M900 K0.05").unwrap();
+        assert!(gcode.lines().skip(line).any(|l| l.starts_with("G1 X110 Y35 E") && l.ends_with("F1200")));
+        // feed rates are only given when they change
+        assert!(gcode.lines().any(|l| l.starts_with("G1 X114.5 Y31.5 E") && !l.contains('F')));
+        let s = timeline.final_state();
+        assert_eq!(0.2, s.position.z);
+        // 3 lines plus their labels
+        let per_mm = 0.45 * 0.2 / (PI * 0.875 * 0.875);
+        assert!(s.filament > 3.0 * 80.0 * per_mm && s.filament < 3.0 * 120.0 * per_mm, "{}", s.filament);
+    }
+
+    #[test]
+    fn test_pressure_advance_corners() {
+        let test = PressureAdvanceTest { pattern: PressureAdvancePattern::Corners, label_size: 0.0, ..PressureAdvanceTest::new(0.02, 0.04, 0.01) };
+        let mut p = Program::new();
+        p.emit_flavor(Flavor::Klipper);
+        test.add_to(&mut p);
+        let gcode = p.render();
+        assert_eq!(3, gcode.lines().filter(|l| l.starts_with("SET_PRESSURE_ADVANCE")).count());
+        let s = simulate(&gcode).final_state().clone();
+        let per_mm = 0.45 * 0.2 / (PI * 0.875 * 0.875);
+        assert!(approx(3.0 * 2.0 * 80.0 * per_mm, s.filament), "{}", s.filament);
+        // the last corner ends above where it started
+        assert!(approx(30.0 + 2.0 * 5.0, s.position.x) && approx(30.0 + 160.0 / 2.0_f32.sqrt(), s.position.y));
+    }
+
+    #[test]
+    fn test_temperatures() {
+        assert_eq!(vec![240, 235, 230, 225], TemperatureTower::new(240, 225, 5).temperatures());
+        assert_eq!(vec![190, 200, 210], TemperatureTower::new(190, 215, 10).temperatures());
+        assert_eq!(vec![200], TemperatureTower::new(200, 200, 5).temperatures());
+        assert_eq!(24.0, TemperatureTower::new(240, 220, 10).height());
+    }
+
+    #[test]
+    fn test_emboss() {
+        let paths = label_paths("1", 2.0);
+        assert_eq!(vec![(0.8, 1.2)], emboss(&paths, 1.0, 0.4));
+        let paths = label_paths("7", 2.0);
+        // the top bar and the top of the upright
+        assert_eq!(vec![(0.0, 1.0), (0.8, 1.2)], emboss(&paths, 2.1, 0.4));
+        assert!(emboss(&paths, 2.3, 0.4).is_empty());
+    }
+
+    #[test]
+    fn test_temperature_tower() {
+        let tower = TemperatureTower::new(240, 230, 10);
+        let mut p = Program::new();
+        tower.add_to(&mut p);
+        let gcode = p.render();
+        let timeline = simulate(&gcode);
+        assert!(!timeline.warnings.iter().any(|w| matches!(w, Warning::UnsupportedCommand { .. })));
+        let s = timeline.final_state();
+        assert!(approx(16.0, s.position.z));
+        // the second segment starts at its temperature, on its first layer
+        let change = timeline.states.iter().position(|s| s.hotend_temp(0) == 230.0).unwrap();
+        assert!(approx(8.0, timeline.states[change].position.z));
+        assert!(timeline.states[change..].iter().find(|s| s.position.z > 8.0).is_some_and(|s| approx(8.2, s.position.z)));
+        // the first slab layer bridges from pillar to pillar
+        let bridge = timeline.states.windows(2).find(|w| approx(7.2, w[1].position.z) && w[1].filament > w[0].filament && w[1].position.x - w[0].position.x > 40.0);
+        assert!(bridge.is_some());
+        // below it, nothing is printed over the gap
+        let gap = |s: &State| s.position.x > 40.0 + 7.0 && s.position.x < 70.0 && s.position.y > 30.0;
+        assert!(!timeline.states.windows(2).any(|w| w[1].position.z < 7.1 && w[1].filament > w[0].filament && (gap(&w[0]) || gap(&w[1]))));
+        // every layer of the right pillar's front has some of the label between 2mm and 6mm up
+        let label = |z: f32| timeline.states.windows(2).any(|w| approx(z, w[1].position.z) && w[1].filament > w[0].filament && w[1].position.y < 30.0);
+        assert!(label(2.2) && label(4.0) && label(5.8) && !label(1.0) && !label(7.0));
+    }
+
+    #[test]
+    fn test_retraction_bands() {
+        let test = RetractionTest { band_height: 3.0, ..RetractionTest::new(RetractionSetting::Speed, 1200.0, 3000.0, 900.0) };
+        let bands = test.bands();
+        assert_eq!(3, bands.len());
+        assert_eq!((3.0, 6.0), (bands[1].bottom, bands[1].top));
+        assert_eq!(Retraction { speed: 2100.0, ..Retraction::default() }, bands[1].retraction);
+        assert_eq!(9.0, test.height());
+        let report = test.report();
+        assert!(report.starts_with("Retraction speed test, from 1200 to 3000 in steps of 900\n"), "{}", report);
+        assert!(report.contains("\nBand 3: 6mm to 9mm, retract 0.8mm at 3000mm/min\n"), "{}", report);
+        let hop = RetractionTest { retraction: Retraction { z_hop: 0.2, ..Retraction::default() }, ..RetractionTest::new(RetractionSetting::Length, 0.4, 0.4, 0.1) };
+        assert_eq!("Retraction length test, from 0.4 to 0.4 in steps of 0.1\nBand 1: 0mm to 5mm, retract 0.4mm at 2100mm/min, z hop 0.2mm\n", hop.report());
+    }
+
+    #[test]
+    fn test_retraction_test() {
+        let test = RetractionTest { band_height: 1.0, ..RetractionTest::new(RetractionSetting::Length, 0.5, 1.5, 0.5) };
+        let mut p = Program::new();
+        test.add_to(&mut p);
+        let gcode = p.render();
+        assert!(gcode.starts_with("G21\n; Retraction length test, from 0.5 to 1.5 in steps of 0.5\n; Band 1: 0mm to 1mm, retract 0.5mm at 2100mm/min\n"));
+        // every travel from tower to tower but the first retracts, by its band's length
+        let retractions: Vec<&str> = gcode.lines().filter(|l| l.starts_with("G1 E-")).collect();
+        assert_eq!(15 * 2 - 1, retractions.len());
+        assert_eq!(vec!["G1 E-0.5 F2100"; 9], retractions[..9]);
+        assert_eq!("G1 E-1 F2100", retractions[9]);
+        assert_eq!("G1 E-1.5 F2100", retractions[28]);
+        let timeline = simulate(&gcode);
+        assert!(!timeline.warnings.iter().any(|w| matches!(w, Warning::UnsupportedCommand { .. })));
+        assert!(approx(1.5, timeline.max_retraction()));
+        // travels go at the travel speed and walls at the print speed, not at the retraction's
+        assert!(gcode.lines().any(|l| l.starts_with("G0 X") && l.ends_with("F9000")));
+        assert!(!gcode.lines().any(|l| l.starts_with("G0 X") && l.ends_with("F2100")));
+        let s = timeline.final_state();
+        assert!(approx(3.0, s.position.z));
+        let per_mm = 0.45 * 0.2 / (PI * 0.875 * 0.875);
+        let walls = 15.0 * 2.0 * 4.0 * (9.55 + 8.65);
+        assert!(approx(walls * per_mm, s.filament), "{} {}", walls * per_mm, s.filament);
+        // the program's own retraction is back
+        assert_eq!(Retraction::default(), p.retraction());
+
+        let mut relative = Program::with_units(crate::Units::Inches);
+        relative.push(relative_extrution());
+        test.add_to(&mut relative);
+        let r = simulate(&relative.render());
+        assert!(approx(s.filament, r.final_state().filament));
+        assert!(approx(1.5, r.max_retraction()));
+    }
+
+    #[test]
+    fn test_retraction_test_without_positive_step() {
+        for &step in [0.0, -0.5].iter() {
+            let test = RetractionTest::new(RetractionSetting::Length, 0.5, 1.5, step);
+            assert_eq!(vec![0.5], test.values());
+            assert_eq!(1, test.bands().len());
+            assert!(test.report().contains("\nBand 1: 0mm to 5mm, retract 0.5mm at 2100mm/min\n"), "{}", test.report());
+            let mut p = Program::new();
+            test.add_to(&mut p);
+            assert!(approx(0.5, simulate(&p.render()).max_retraction()));
+        }
+    }
+
+    #[test]
+    fn test_retraction_test_builds_quickly() {
+        // 500 layers of 8 towers, with a retraction on each of the 4000 travels
+        let towers = (0..8).map(|n| Point2d { x: 30.0 + (n % 4) as f32 * 40.0, y: 40.0 + (n / 4) as f32 * 40.0 }).collect();
+        let test = RetractionTest { towers, layer_height: 0.1, ..RetractionTest::new(RetractionSetting::Length, 0.2, 2.0, 0.2) };
+        let start = std::time::Instant::now();
+        let mut p = Program::new();
+        test.add_to(&mut p);
+        let elapsed = start.elapsed();
+        assert!(elapsed < std::time::Duration::from_secs(2), "{:?}", elapsed);
+        assert_eq!(500 * 8 - 1, p.render().lines().filter(|l| l.starts_with("G1 E-")).count());
+    }
+
+    #[test]
+    fn test_relative_extrusion_and_inches() {
+        let test = PressureAdvanceTest::new(0.0, 0.04, 0.02);
+        let mut absolute = Program::new();
+        test.add_to(&mut absolute);
+        let mut relative = Program::with_units(crate::Units::Inches);
+        relative.push(relative_extrution());
+        test.add_to(&mut relative);
+        let (a, r) = (simulate(&absolute.render()), simulate(&relative.render()));
+        assert!(approx(a.final_state().filament, r.final_state().filament));
+        assert_eq!(a.final_state().position, r.final_state().position);
+    }
+}
+
+use crate::machine::Retraction;
+use crate::motion::set_pressure_advance;
+use crate::program::Program;
+use crate::{set_hotend_temp, wait_hotend_temp, Point2d, Units};
+use std::f32::consts::PI;
+
+/// Height the nozzle is lifted by when travelling over the pattern, in millimeters
+const TRAVEL_HOP: f32 = 0.4;
+
+/// How a [PressureAdvanceTest] shows the effect of each value
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PressureAdvancePattern {
+    /// Lines printed slow, then fast, then slow again, stacked from the front of the bed with each
+    /// value printed to their right. The right value keeps the line even across the speed changes.
+    Lines,
+    /// Corners printed fast, nested side by side with each value printed above them. The right
+    /// value keeps the corner sharp without bulging.
+    Corners,
+}
+
+/// A single layer pattern trying a range of pressure advance values, lengths are in millimeters and
+/// speeds in millimeters per minute
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::calibration::PressureAdvanceTest;
+/// use gen_gcode::program::Program;
+/// use gen_gcode::sequence::{EndSequence, StartSequence};
+///
+/// let mut program = Program::new();
+/// program.start_sequence(&StartSequence::new(80, 240));
+/// PressureAdvanceTest::new(0.0, 0.1, 0.02).add_to(&mut program);
+/// program.end_sequence(&EndSequence::default());
+/// assert!(program.render().contains("M900 K0.04\n"));
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PressureAdvanceTest {
+    pub pattern: PressureAdvancePattern,
+    /// First value tried
+    pub start: f32,
+    /// Last value tried, when it is a whole number of steps from the start
+    pub end: f32,
+    pub step: f32,
+    /// Front left corner of the pattern
+    pub origin: Point2d,
+    /// Hotend temperature waited for before printing, `None` to keep the current one
+    pub hotend_temperature: Option<u16>,
+    pub layer_height: f32,
+    pub line_width: f32,
+    pub filament_diameter: f32,
+    /// Speed of the slow parts of lines, and of the labels
+    pub slow_speed: f32,
+    /// Speed of the fast parts of lines, and of corners
+    pub fast_speed: f32,
+    pub travel_speed: f32,
+    /// Length of each line, or of each side of a corner
+    pub length: f32,
+    /// Distance between lines or corners
+    pub spacing: f32,
+    /// Height of the digits labelling each value, 0 for no labels
+    pub label_size: f32,
+}
+
+impl PressureAdvanceTest {
+    /// Creates a test printing [PressureAdvancePattern::Lines] 80mm long, 5mm apart, from (30,30),
+    /// at 1200mm/min and 6000mm/min with 0.45mm wide, 0.2mm high lines of 1.75mm filament
+    pub fn new(start: f32, end: f32, step: f32) -> Self {
+        PressureAdvanceTest {
+            pattern: PressureAdvancePattern::Lines,
+            start,
+            end,
+            step,
+            origin: Point2d { x: 30.0, y: 30.0 },
+            hotend_temperature: None,
+            layer_height: 0.2,
+            line_width: 0.45,
+            filament_diameter: 1.75,
+            slow_speed: 1200.0,
+            fast_speed: 6000.0,
+            travel_speed: 9000.0,
+            length: 80.0,
+            spacing: 5.0,
+            label_size: 3.0,
+        }
+    }
+
+    /// Returns the values tried, from start to end
+    pub fn values(&self) -> Vec<f32> {
+        steps(self.start, self.end, self.step)
+    }
+
+    /// Adds the pattern to a program, setting each value for the program's flavor with
+    /// [set_pressure_advance]. The last value tried is left set.
+    pub fn add_to(&self, program: &mut Program) {
+        let flavor = program.flavor();
+        program.comment(&format!("Pressure advance calibration, from {} to {} in steps of {}", self.start, self.end, self.step));
+        if let Some(temperature) = self.hotend_temperature {
+            program.push(wait_hotend_temp(temperature, None));
+        }
+        let mut nozzle = Nozzle::new(program, self.layer_height, self.line_width, self.filament_diameter, self.travel_speed);
+        for (n, advance) in self.values().into_iter().enumerate() {
+            let offset = n as f32 * self.spacing;
+            nozzle.program.push(set_pressure_advance(flavor, advance, None));
+            let label = advance.to_string();
+            match self.pattern {
+                PressureAdvancePattern::Lines => {
+                    let (x, y) = (self.origin.x, self.origin.y + offset);
+                    nozzle.travel(Point2d { x, y });
+                    nozzle.line(Point2d { x: x + self.length / 4.0, y }, self.slow_speed);
+                    nozzle.line(Point2d { x: x + self.length * 3.0 / 4.0, y }, self.fast_speed);
+                    nozzle.line(Point2d { x: x + self.length, y }, self.slow_speed);
+                    let at = Point2d { x: x + self.length + self.label_size, y: y - self.label_size / 2.0 };
+                    nozzle.label(&label, self.label_size, self.slow_speed, |p| Point2d { x: at.x + p.x, y: at.y + p.y });
+                }
+                PressureAdvancePattern::Corners => {
+                    let side = self.length / 2.0_f32.sqrt();
+                    let (x, y) = (self.origin.x + offset, self.origin.y);
+                    nozzle.travel(Point2d { x, y });
+                    nozzle.line(Point2d { x: x + side, y: y + side }, self.fast_speed);
+                    nozzle.line(Point2d { x, y: y + 2.0 * side }, self.fast_speed);
+                    // read from the bottom up, centered on the corner's ends
+                    let at = Point2d { x: x + self.label_size / 2.0, y: y + 2.0 * side + self.label_size };
+                    nozzle.label(&label, self.label_size, self.slow_speed, |p| Point2d { x: at.x - p.y, y: at.y + p.x });
+                }
+            }
+        }
+    }
+}
+
+/// A tower of blocks printed at decreasing (or increasing) hotend temperatures, lengths are in
+/// millimeters and speeds in millimeters per minute
+///
+/// Each segment stands on two pillars. The inner face of the left pillar leans out at 45° as an
+/// overhang, and the top of the segment is a solid slab whose first layer bridges the gap between
+/// the pillars. The segment's temperature is embossed on the front of the right pillar.
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::calibration::TemperatureTower;
+/// use gen_gcode::program::Program;
+///
+/// let mut program = Program::new();
+/// TemperatureTower::new(240, 220, 10).add_to(&mut program);
+/// let gcode = program.render();
+/// assert!(gcode.contains("M109 S240\n"));
+/// assert!(gcode.contains("M104 S230\n") && gcode.contains("M104 S220\n"));
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TemperatureTower {
+    /// Temperature of the bottom segment
+    pub start_temperature: u16,
+    /// Temperature of the top segment, when it is a whole number of steps from the start
+    pub end_temperature: u16,
+    /// Change in temperature from one segment to the next
+    pub step: u16,
+    /// Front left corner of the tower
+    pub origin: Point2d,
+    /// Size of the tower along the X axis
+    pub width: f32,
+    /// Size of the tower along the Y axis
+    pub depth: f32,
+    /// Width of each pillar at its base
+    pub pillar_width: f32,
+    pub segment_height: f32,
+    /// Thickness of the slab topping each segment
+    pub slab_thickness: f32,
+    pub layer_height: f32,
+    pub line_width: f32,
+    pub filament_diameter: f32,
+    /// Number of walls around the pillars and slabs
+    pub perimeters: usize,
+    pub speed: f32,
+    pub travel_speed: f32,
+    /// Height of the embossed digits, 0 for no labels
+    pub label_size: f32,
+}
+
+impl TemperatureTower {
+    /// Creates a 50mm by 10mm tower of 8mm segments with 10mm pillars and 1mm slabs, at (30,30),
+    /// printed at 2400mm/min with 2 walls of 0.45mm wide, 0.2mm high lines of 1.75mm filament
+    pub fn new(start_temperature: u16, end_temperature: u16, step: u16) -> Self {
+        TemperatureTower {
+            start_temperature,
+            end_temperature,
+            step,
+            origin: Point2d { x: 30.0, y: 30.0 },
+            width: 50.0,
+            depth: 10.0,
+            pillar_width: 10.0,
+            segment_height: 8.0,
+            slab_thickness: 1.0,
+            layer_height: 0.2,
+            line_width: 0.45,
+            filament_diameter: 1.75,
+            perimeters: 2,
+            speed: 2400.0,
+            travel_speed: 9000.0,
+            label_size: 4.0,
+        }
+    }
+
+    /// Returns the temperature of each segment, from the bottom up
+    pub fn temperatures(&self) -> Vec<u16> {
+        let (start, end, step) = (self.start_temperature as i32, self.end_temperature as i32, self.step.max(1) as i32);
+        let count = (end - start).abs() / step;
+        let direction = if end < start { -1 } else { 1 };
+        (0..=count).map(|n| (start + direction * n * step) as u16).collect()
+    }
+
+    /// Returns the height of the whole tower
+    pub fn height(&self) -> f32 {
+        self.layers_per_segment() as f32 * self.layer_height * self.temperatures().len() as f32
+    }
+
+    fn layers_per_segment(&self) -> usize {
+        (self.segment_height / self.layer_height).round().max(1.0) as usize
+    }
+
+    /// Adds the tower to a program, layer by layer from the bed up. The first segment waits for
+    /// its temperature, the following ones change it with [set_hotend_temp] as they start.
+    pub fn add_to(&self, program: &mut Program) {
+        let (x0, y0) = (self.origin.x, self.origin.y);
+        let (x1, y1) = (x0 + self.width, y0 + self.depth);
+        let layers = self.layers_per_segment();
+        let slab_layers = ((self.slab_thickness / self.layer_height).round() as usize).clamp(1, layers);
+        program.comment(&format!("Temperature tower, from {} to {} in steps of {}", self.start_temperature, self.end_temperature, self.step));
+        let mut nozzle = Nozzle::new(program, self.layer_height, self.line_width, self.filament_diameter, self.travel_speed);
+        for (segment, temperature) in self.temperatures().into_iter().enumerate() {
+            match segment {
+                0 => nozzle.program.push(wait_hotend_temp(temperature, None)),
+                _ => nozzle.program.push(set_hotend_temp(temperature, None)),
+            };
+            let label = label_paths(&temperature.to_string(), self.label_size);
+            let label_width = label.iter().flatten().fold(0.0_f32, |w, p| w.max(p.x));
+            let label_x = x1 - (self.pillar_width + label_width) / 2.0;
+            for layer in 0..layers {
+                let height = (layer + 1) as f32 * self.layer_height;
+                nozzle.layer((segment * layers) as f32 * self.layer_height + height);
+                if layer < layers - slab_layers {
+                    // the left pillar's inner face leans out by a layer height every layer
+                    let left = Point2d { x: x0 + self.pillar_width + height, y: y1 };
+                    nozzle.walls(Point2d { x: x0, y: y0 }, left, self.perimeters, self.speed);
+                    nozzle.walls(Point2d { x: x1 - self.pillar_width, y: y0 }, Point2d { x: x1, y: y1 }, self.perimeters, self.speed);
+                } else {
+                    nozzle.walls(Point2d { x: x0, y: y0 }, Point2d { x: x1, y: y1 }, self.perimeters, self.speed);
+                    // lines across the gap, the first slab layer bridges it
+                    let inset = self.perimeters as f32 * self.line_width;
+                    let lines = ((self.depth - 2.0 * inset) / self.line_width).floor().max(0.0) as usize;
+                    for n in 0..lines {
+                        let y = y0 + inset + (n as f32 + 0.5) * self.line_width;
+                        let (from, to) = if n % 2 == 0 { (x0 + inset, x1 - inset) } else { (x1 - inset, x0 + inset) };
+                        nozzle.path(&[Point2d { x: from, y }, Point2d { x: to, y }], self.speed);
+                    }
+                }
+                // the label is centered on the segment's height and stands proud of the front face
+                let y = y0 - self.line_width / 2.0;
+                for (from, to) in emboss(&label, height - (self.segment_height - self.label_size) / 2.0, self.line_width) {
+                    nozzle.path(&[Point2d { x: label_x + from, y }, Point2d { x: label_x + to, y }], self.speed);
+                }
+            }
+        }
+    }
+}
+
+/// The retraction setting a [RetractionTest] changes from one band of layers to the next
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RetractionSetting {
+    /// [Retraction::length], in millimeters
+    Length,
+    /// [Retraction::speed], in millimeters per minute. The prime speed is left as it is.
+    Speed,
+}
+
+/// A band of layers of a [RetractionTest], from `bottom` to `top` in millimeters, and the
+/// retraction its travels use
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RetractionBand {
+    pub bottom: f32,
+    pub top: f32,
+    pub retraction: Retraction,
+}
+
+/// Towers printed together, layer by layer, with a retraction on every travel from one to the
+/// next, lengths are in millimeters and speeds in millimeters per minute
+///
+/// The towers are split into bands of layers, each trying a value of the tested setting on top of
+/// [RetractionTest::retraction]. The band with the least stringing between the towers, without
+/// gaps where the towers start again, has the right value, see [RetractionTest::report].
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::calibration::{RetractionSetting, RetractionTest};
+/// use gen_gcode::program::Program;
+///
+/// let test = RetractionTest::new(RetractionSetting::Length, 0.5, 1.5, 0.5);
+/// println!("{}", test.report());
+/// let mut program = Program::new();
+/// test.add_to(&mut program);
+/// let gcode = program.render();
+/// assert!(gcode.contains("G1 E-0.5 F2100\n") && gcode.contains("G1 E-1.5 F2100\n"));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetractionTest {
+    pub setting: RetractionSetting,
+    /// Value of the bottom band
+    pub start: f32,
+    /// Value of the top band, when it is a whole number of steps from the start
+    pub end: f32,
+    pub step: f32,
+    /// Retraction the tested setting is changed in
+    pub retraction: Retraction,
+    /// Centers of the towers, the nozzle travels from each to the next, and back to the first
+    pub towers: Vec<Point2d>,
+    /// Width and depth of each tower
+    pub tower_size: f32,
+    pub band_height: f32,
+    /// Hotend temperature waited for before printing, `None` to keep the current one
+    pub hotend_temperature: Option<u16>,
+    pub layer_height: f32,
+    pub line_width: f32,
+    pub filament_diameter: f32,
+    /// Number of walls around each tower
+    pub perimeters: usize,
+    pub speed: f32,
+    pub travel_speed: f32,
+}
+
+impl RetractionTest {
+    /// Creates a test of two 10mm towers 50mm apart, centered on (60,40) and (110,40), in 5mm
+    /// bands, printed at 2400mm/min with 2 walls of 0.45mm wide, 0.2mm high lines of 1.75mm
+    /// filament and the default [Retraction]
+    pub fn new(setting: RetractionSetting, start: f32, end: f32, step: f32) -> Self {
+        RetractionTest {
+            setting,
+            start,
+            end,
+            step,
+            retraction: Retraction::default(),
+            towers: vec![Point2d { x: 60.0, y: 40.0 }, Point2d { x: 110.0, y: 40.0 }],
+            tower_size: 10.0,
+            band_height: 5.0,
+            hotend_temperature: None,
+            layer_height: 0.2,
+            line_width: 0.45,
+            filament_diameter: 1.75,
+            perimeters: 2,
+            speed: 2400.0,
+            travel_speed: 9000.0,
+        }
+    }
+
+    /// Returns the values tried, from the bottom band up
+    pub fn values(&self) -> Vec<f32> {
+        steps(self.start, self.end, self.step)
+    }
+
+    /// Returns the bands of layers, from the bottom up
+    pub fn bands(&self) -> Vec<RetractionBand> {
+        let height = self.layers_per_band() as f32 * self.layer_height;
+        // rounded to 4 decimals, hiding the error of multiplying the layer height
+        let at = |n: usize| (n as f32 * height * 10000.0).round() / 10000.0;
+        let band = |(n, value): (usize, f32)| {
+            let retraction = match self.setting {
+                RetractionSetting::Length => Retraction { length: value, ..self.retraction },
+                RetractionSetting::Speed => Retraction { speed: value, ..self.retraction },
+            };
+            RetractionBand { bottom: at(n), top: at(n + 1), retraction }
+        };
+        self.values().into_iter().enumerate().map(band).collect()
+    }
+
+    /// Returns the height of the towers
+    pub fn height(&self) -> f32 {
+        self.bands().last().map_or(0.0, |band| band.top)
+    }
+
+    fn layers_per_band(&self) -> usize {
+        (self.band_height / self.layer_height).round().max(1.0) as usize
+    }
+
+    /// Returns a table of the bands, from the bottom up, with the height they span and the
+    /// retraction they use, one line each after a title line
+    pub fn report(&self) -> String {
+        let setting = match self.setting {
+            RetractionSetting::Length => "length",
+            RetractionSetting::Speed => "speed",
+        };
+        let mut out = format!("Retraction {} test, from {} to {} in steps of {}\n", setting, self.start, self.end, self.step);
+        for (n, band) in self.bands().iter().enumerate() {
+            let r = band.retraction;
+            out += &format!("Band {}: {}mm to {}mm, retract {}mm at {}mm/min", n + 1, band.bottom, band.top, r.length, r.speed);
+            if r.z_hop > 0.0 {
+                out += &format!(", z hop {}mm", r.z_hop);
+            }
+            out += "\n";
+        }
+        out
+    }
+
+    /// Adds the towers to a program, starting with the [RetractionTest::report] as comments. Each
+    /// band sets its retraction with [Program::set_retraction], the program's own retraction is
+    /// set back once the towers are done.
+    pub fn add_to(&self, program: &mut Program) {
+        let saved = program.retraction();
+        for line in self.report().lines() {
+            program.comment(line);
+        }
+        if let Some(temperature) = self.hotend_temperature {
+            program.push(wait_hotend_temp(temperature, None));
+        }
+        let half = self.tower_size / 2.0;
+        let layers = self.layers_per_band();
+        let mut nozzle = Nozzle::new(program, self.layer_height, self.line_width, self.filament_diameter, self.travel_speed);
+        for band in self.bands() {
+            nozzle.program.set_retraction(band.retraction);
+            for layer in 0..layers {
+                nozzle.layer(band.bottom + (layer + 1) as f32 * self.layer_height);
+                for tower in &self.towers {
+                    let (min, max) = (Point2d { x: tower.x - half, y: tower.y - half }, Point2d { x: tower.x + half, y: tower.y + half });
+                    let inset = self.line_width / 2.0;
+                    nozzle.travel_retracted(Point2d { x: min.x + inset, y: min.y + inset });
+                    nozzle.walls(min, max, self.perimeters, self.speed);
+                }
+            }
+        }
+        nozzle.program.set_retraction(saved);
+    }
+}
+
+/// Adds extruding moves and travels to a program layer by layer, keeping track of the extruder
+/// position for absolute extrusion
+struct Nozzle<'a> {
+    program: &'a mut Program,
+    units: Units,
+    relative: bool,
+    filament_per_mm: f32,
+    line_width: f32,
+    z: f32,
+    travel_speed: f32,
+    e: f32,
+    pos: Option<Point2d>,
+    feed: Option<f32>,
+}
+
+impl<'a> Nozzle<'a> {
+    fn new(program: &'a mut Program, layer_height: f32, line_width: f32, filament_diameter: f32, travel_speed: f32) -> Self {
+        let radius = filament_diameter / 2.0;
+        let (units, relative) = (program.working_units(), program.relative_extrusion());
+        program.reset_extruder(0.0);
+        let filament_per_mm = line_width * layer_height / (PI * radius * radius);
+        Nozzle { program, units, relative, filament_per_mm, line_width, z: layer_height, travel_speed, e: 0.0, pos: None, feed: None }
+    }
+
+    /// Starts a new layer at height `z`, see [Program::begin_layer]
+    fn layer(&mut self, z: f32) {
+        self.program.begin_layer(self.units.from_mm(z));
+        self.z = z;
+    }
+
+    fn units(&self, p: Point2d) -> Point2d {
+        Point2d { x: self.units.from_mm(p.x), y: self.units.from_mm(p.y) }
+    }
+
+    /// Moves to a point without extruding, lifting the nozzle over the pattern unless the point is
+    /// close by
+    fn travel(&mut self, to: Point2d) {
+        let far = match self.pos {
+            Some(p) if p == to => return,
+            Some(p) => (to.x - p.x).hypot(to.y - p.y) > 5.0,
+            None => true,
+        };
+        let (dest, feed) = (self.units(to), self.feed(self.travel_speed));
+        if far {
+            self.program.move_z(self.units.from_mm(self.z + TRAVEL_HOP)).move_xy(dest, feed, None).move_z(self.units.from_mm(self.z));
+        } else {
+            self.program.move_xy(dest, feed, None);
+        }
+        self.pos = Some(to);
+    }
+
+    /// Moves to a point without extruding, with the program's retraction, see [Program::retract]
+    fn travel_retracted(&mut self, to: Point2d) {
+        if self.pos.is_none() || self.pos == Some(to) {
+            return self.travel(to)
+        }
+        // retracting and priming leave their own feed rate set
+        self.program.retract();
+        self.feed = None;
+        let (dest, feed) = (self.units(to), self.feed(self.travel_speed));
+        self.program.move_xy(dest, feed, None).unretract();
+        self.feed = None;
+        self.pos = Some(to);
+    }
+
+    /// Extrudes a line from the current point
+    fn line(&mut self, to: Point2d, speed: f32) {
+        let from = self.pos.unwrap_or(to);
+        let amount = (to.x - from.x).hypot(to.y - from.y) * self.filament_per_mm;
+        self.e = if self.relative { amount } else { self.e + amount };
+        let (dest, feed, e) = (self.units(to), self.feed(speed), Some(self.units.from_mm(self.e)));
+        self.program.move_xy(dest, feed, e);
+        self.pos = Some(to);
+    }
+
+    /// Extrudes along a path, travelling to its start first
+    fn path(&mut self, points: &[Point2d], speed: f32) {
+        self.travel(points[0]);
+        for &point in &points[1..] {
+            self.line(point, speed);
+        }
+    }
+
+    /// Prints the walls of a rectangle given by its corners, from the outside in
+    fn walls(&mut self, min: Point2d, max: Point2d, perimeters: usize, speed: f32) {
+        for n in 0..perimeters {
+            let inset = (n as f32 + 0.5) * self.line_width;
+            let (a, b) = (Point2d { x: min.x + inset, y: min.y + inset }, Point2d { x: max.x - inset, y: max.y - inset });
+            if a.x >= b.x || a.y >= b.y {
+                break;
+            }
+            self.path(&[a, Point2d { x: b.x, y: a.y }, b, Point2d { x: a.x, y: b.y }, a], speed);
+        }
+    }
+
+    /// Prints text with [label_paths], placed on the bed by `place`
+    fn label(&mut self, text: &str, size: f32, speed: f32, place: impl Fn(Point2d) -> Point2d) {
+        if size <= 0.0 {
+            return
+        }
+        for path in label_paths(text, size) {
+            let points: Vec<Point2d> = path.into_iter().map(&place).collect();
+            self.path(&points, speed);
+        }
+    }
+
+    /// Returns the feed rate to emit, only when it changes
+    fn feed(&mut self, speed: f32) -> Option<f32> {
+        let speed = self.units.from_mm(speed);
+        if self.feed == Some(speed) {
+            return None
+        }
+        self.feed = Some(speed);
+        self.feed
+    }
+}
+
+/// Returns the values from start to end, a step apart, rounded to 4 decimals. Only the start is
+/// returned when the step is not positive or the range is not finite.
+fn steps(start: f32, end: f32, step: f32) -> Vec<f32> {
+    let round = |v: f32| (v * 10000.0).round() / 10000.0;
+    let count = (end - start) / step + 1e-3;
+    if step <= 0.0 || !count.is_finite() {
+        return vec![round(start)];
+    }
+    (0..=count.floor().max(0.0) as usize).map(|n| round(start + n as f32 * step)).collect()
+}
+
+/// Returns the paths drawing text in seven segment digits `size` high, starting from the origin
+/// and reading along X. Only digits, `.` and `-` are drawn, other characters are skipped.
+fn label_paths(text: &str, size: f32) -> Vec<Vec<Point2d>> {
+    let gap = size * 0.3;
+    let mut x = 0.0;
+    let mut paths = Vec::new();
+    for c in text.chars() {
+        // corners of the digit, x across and y up in half heights
+        let glyph: &[&[(u8, u8)]] = match c {
+            '0' => &[&[(0, 0), (1, 0), (1, 2), (0, 2), (0, 0)]],
+            '1' => &[&[(1, 0), (1, 2)]],
+            '2' => &[&[(0, 2), (1, 2), (1, 1), (0, 1), (0, 0), (1, 0)]],
+            '3' => &[&[(0, 2), (1, 2), (1, 0), (0, 0)], &[(0, 1), (1, 1)]],
+            '4' => &[&[(0, 2), (0, 1), (1, 1)], &[(1, 2), (1, 0)]],
+            '5' => &[&[(1, 2), (0, 2), (0, 1), (1, 1), (1, 0), (0, 0)]],
+            '6' => &[&[(1, 2), (0, 2), (0, 0), (1, 0), (1, 1), (0, 1)]],
+            '7' => &[&[(0, 2), (1, 2), (1, 0)]],
+            '8' => &[&[(0, 1), (0, 2), (1, 2), (1, 0), (0, 0), (0, 1), (1, 1)]],
+            '9' => &[&[(0, 0), (1, 0), (1, 2), (0, 2), (0, 1), (1, 1)]],
+            '-' => &[&[(0, 1), (1, 1)]],
+            '.' => {
+                paths.push(vec![Point2d { x, y: 0.0 }, Point2d { x: x + size * 0.1, y: 0.0 }]);
+                x += size * 0.1 + gap;
+                continue;
+            }
+            _ => continue,
+        };
+        for path in glyph {
+            paths.push(path.iter().map(|&(px, py)| Point2d { x: x + px as f32 * size / 2.0, y: py as f32 * size / 2.0 }).collect());
+        }
+        x += size / 2.0 + gap;
+    }
+    paths
+}
+
+/// Returns the X ranges where text drawn with [label_paths] crosses height `z`, for printing it
+/// standing up layer by layer. Strokes are `thickness` wide.
+fn emboss(paths: &[Vec<Point2d>], z: f32, thickness: f32) -> Vec<(f32, f32)> {
+    let half = thickness / 2.0;
+    let mut ranges = Vec::new();
+    for pair in paths.iter().flat_map(|path| path.windows(2)) {
+        let (a, b) = (pair[0], pair[1]);
+        if a.y == b.y {
+            if (z - a.y).abs() <= half {
+                ranges.push((a.x.min(b.x), a.x.max(b.x)));
+            }
+        } else if z >= a.y.min(b.y) - half && z <= a.y.max(b.y) + half {
+            ranges.push((a.x - half, a.x + half));
+        }
+    }
+    ranges
+}
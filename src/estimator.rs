@@ -0,0 +1,116 @@
+//! Estimate how long a G-Code program takes to run
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::motion::*;
+    use crate::*;
+
+    #[test]
+    fn test_estimate_moves_only() {
+        let program = move_xy(Point2d { x: 30.0, y: 40.0 }, Some(3000), None);
+        let est = estimate_time(&program, &ThermalModel::default());
+        assert_eq!(1.0, est.total);
+        assert_eq!(1.0, est.moving);
+        assert_eq!(0.0, est.heating);
+    }
+
+    #[test]
+    fn test_estimate_with_heating() {
+        let mut program = wait_bed_temp(60);
+        program += &wait_hotend_temp(205, None);
+        program += &move_xy(Point2d { x: 30.0, y: 40.0 }, Some(3000), None);
+        let est = estimate_time(&program, &ThermalModel::default());
+        // bed: 35°C at 0.5°C/s, hotend: 180°C at 2°C/s
+        assert_eq!(70.0 + 90.0, est.heating);
+        assert_eq!(1.0, est.moving);
+        assert_eq!(161.0, est.total);
+    }
+
+    fn approx(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    fn moving(program: &str) -> f32 {
+        estimate_time(program, &ThermalModel::default()).moving
+    }
+
+    #[test]
+    fn test_estimate_with_acceleration() {
+        let travel = set_acceleration(Acceleration { travel: Some(500.0), ..Default::default() });
+        // 2.5mm speeding up and 2.5mm slowing down, each taking 0.1s
+        let program = format!("{}{}", travel, move_xy(Point2d { x: 50.0, y: 0.0 }, Some(3000), None));
+        assert!(approx(1.1, moving(&program)));
+        // extruding moves use the print acceleration, which is not set
+        let program = format!("{}{}", travel, move_xy(Point2d { x: 50.0, y: 0.0 }, Some(3000), Some(1.0)));
+        assert!(approx(1.0, moving(&program)));
+    }
+
+    #[test]
+    fn test_estimate_short_move_never_reaches_speed() {
+        let mut program = set_acceleration(Acceleration { travel: Some(1000.0), ..Default::default() });
+        // the X axis limit takes over from the travel acceleration
+        program += &set_max_acceleration(AxisLimits { x: Some(100.0), ..Default::default() });
+        program += &move_xy(Point2d { x: 20.0, y: 0.0 }, Some(6000), None);
+        assert!(approx(2.0 * 20.0_f32.sqrt() * 10.0 / 100.0, moving(&program)));
+    }
+
+    #[test]
+    fn test_estimate_with_jerk() {
+        let mut program = set_acceleration(Acceleration { travel: Some(500.0), ..Default::default() });
+        program += &set_jerk(Jerk::Classic(AxisLimits { x: Some(10.0), y: Some(10.0), ..Default::default() }));
+        // Y takes 80% of the speed, so the move starts and ends at 12.5mm/s
+        program += &move_xy(Point2d { x: 30.0, y: 40.0 }, Some(3000), None);
+        assert!(approx(0.15 + 45.3125 / 50.0, moving(&program)));
+    }
+
+    #[test]
+    fn test_estimate_with_feed_limits() {
+        let program = format!("{}{}", set_speed_factor(200), move_xy(Point2d { x: 30.0, y: 40.0 }, Some(3000), None));
+        assert_eq!(0.5, moving(&program));
+        let program = format!("{}{}", set_max_feed_rate(AxisLimits { x: Some(10.0), ..Default::default() }), move_xy(Point2d { x: 100.0, y: 0.0 }, Some(6000), None));
+        assert_eq!(10.0, moving(&program));
+    }
+
+    #[test]
+    fn test_estimate_custom_model() {
+        let model = ThermalModel { ambient: 55.0, ..ThermalModel::default() };
+        let est = estimate_time(&wait_bed_temp(60), &model);
+        assert_eq!(10.0, est.heating);
+    }
+}
+
+use crate::simulator::Simulator;
+use crate::thermal::ThermalModel;
+
+/// Breakdown of a program's estimated run time, in seconds
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Estimate {
+    pub total: f32,
+    /// Time spent in M109/M190/M191 waiting for heaters
+    pub heating: f32,
+    /// Time spent executing everything else
+    pub moving: f32,
+}
+
+/// Estimates the run time of a program, using the thermal model to work out how long temperature
+/// waits block for. Moves follow the acceleration, jerk, feed rate limits and speed factor set in
+/// the program, see [crate::motion].
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::{Point2d, move_xy, wait_hotend_temp};
+/// use gen_gcode::estimator::estimate_time;
+/// use gen_gcode::thermal::ThermalModel;
+///
+/// let program = format!("{}{}", wait_hotend_temp(205, None), move_xy(Point2d { x: 100.0, y: 0.0 }, Some(6000), None));
+/// let est = estimate_time(&program, &ThermalModel::default());
+/// assert_eq!(90.0, est.heating);
+/// assert_eq!(1.0, est.moving);
+/// ```
+pub fn estimate_time(program: &str, thermal: &ThermalModel) -> Estimate {
+    let timeline = Simulator::with_thermal_model(*thermal).run(program);
+    let end = timeline.final_state();
+    Estimate { total: end.time, heating: end.waiting_time, moving: end.time - end.waiting_time }
+}
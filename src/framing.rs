@@ -0,0 +1,230 @@
+//! Line numbers and checksums for streaming G-Code over a serial connection
+//!
+//! Firmware such as Marlin can detect corrupted or dropped lines when every command is sent as
+//! `N<line> <command>*<checksum>`, where the checksum is the XOR of every byte before the `*`.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(125, checksum("N0 M110 N0"));
+        assert_eq!(0, checksum(""));
+    }
+
+    #[test]
+    fn test_frame_line() {
+        assert_eq!("N0 M110 N0*125\n", frame_line(0, &reset_line_number(0)));
+        assert_eq!("N1 G28*18\n", frame_line(1, &auto_home()));
+    }
+
+    #[test]
+    fn test_frame_strips_comments_and_blank_lines() {
+        let framed = frame("G28 ; home\n\n; only a comment\n(paren comment)\nG0 X1 (inline)\n");
+        assert_eq!(format!("{}{}{}", frame_line(0, "M110 N0"), frame_line(1, "G28"), frame_line(2, "G0 X1")), framed);
+    }
+
+    #[test]
+    fn test_verify_ok() {
+        let program = format!("{}{}", auto_home(), move_xy(Point2d { x: 10.0, y: 5.0 }, None, None));
+        assert_eq!(Ok(3), verify(&frame(&program)));
+    }
+
+    #[test]
+    fn test_verify_bad_checksum() {
+        let framed = "N0 M110 N0*125\nN1 G28*19\n";
+        assert_eq!(Err(FrameError::BadChecksum { line: 2, expected: 18, found: 19 }), verify(framed));
+    }
+
+    #[test]
+    fn test_verify_missing_checksum() {
+        assert_eq!(Err(FrameError::MissingChecksum { line: 1 }), verify("N0 M110 N0\n"));
+    }
+
+    #[test]
+    fn test_verify_missing_line_number() {
+        let framed = format!("{}G28*77\n", frame_line(0, "M110 N0"));
+        assert_eq!(Err(FrameError::MissingLineNumber { line: 2 }), verify(&framed));
+    }
+
+    #[test]
+    fn test_verify_out_of_sequence() {
+        let framed = format!("{}{}", frame_line(0, "M110 N0"), frame_line(2, "G28"));
+        assert_eq!(Err(FrameError::OutOfSequence { line: 2, expected: 1, found: 2 }), verify(&framed));
+    }
+
+    #[test]
+    fn test_verify_follows_m110() {
+        let framed = format!("{}{}{}", frame_line(5, "G28"), frame_line(6, "M110 N41"), frame_line(42, "G28"));
+        assert_eq!(Ok(3), verify(&framed));
+    }
+
+    #[test]
+    fn test_split_frame() {
+        assert_eq!(Some((7, "G28")), split_frame("N7 G28*19"));
+        assert_eq!(None, split_frame("G28"));
+    }
+}
+
+use std::error::Error;
+use std::fmt;
+
+/// Returns the XOR checksum of a line, as used by Marlin and RepRap firmwares
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::framing::checksum;
+///
+/// assert_eq!(18, checksum("N1 G28"));
+/// ```
+pub fn checksum(line: &str) -> u8 {
+    line.bytes().fold(0, |cs, b| cs ^ b)
+}
+
+/// Returns a M110 command to set the number the firmware expects the next line to have as a String
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::framing::reset_line_number;
+///
+/// let gcode = reset_line_number(0);
+/// assert_eq!("M110 N0\n", gcode);
+/// ```
+pub fn reset_line_number(line: u32) -> String {
+    format!("M110 N{}\n", line)
+}
+
+/// Returns a command wrapped with a line number and checksum, comments are removed
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::auto_home;
+/// use gen_gcode::framing::frame_line;
+///
+/// let gcode = frame_line(1, &auto_home());
+/// assert_eq!("N1 G28*18\n", gcode);
+/// ```
+pub fn frame_line(line: u32, command: &str) -> String {
+    let body = format!("N{} {}", line, strip_comments(command));
+    format!("{}*{}\n", body, checksum(&body))
+}
+
+/// Wraps every command of a program with a line number and checksum, starting with a M110 to reset
+/// the firmware's line counter. Comments and blank lines are dropped.
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::{auto_home, reset_extruder};
+/// use gen_gcode::framing::frame;
+///
+/// let program = format!("{}{}", auto_home(), reset_extruder(0.0));
+/// assert_eq!("N0 M110 N0*125\nN1 G28*18\nN2 G92 E0*69\n", frame(&program));
+/// ```
+pub fn frame(program: &str) -> String {
+    let mut out = frame_line(0, &reset_line_number(0));
+    let commands = program.lines().map(strip_comments).filter(|c| !c.is_empty());
+    for (n, command) in commands.enumerate() {
+        out += &frame_line(n as u32 + 1, &command);
+    }
+    out
+}
+
+/// Splits a framed line into its line number and command, without checking the checksum
+pub fn split_frame(line: &str) -> Option<(u32, &str)> {
+    let rest = line.trim().strip_prefix('N')?;
+    let end = rest.find(' ')?;
+    let number = rest[..end].parse().ok()?;
+    let command = rest[end + 1..].split('*').next()?.trim();
+    Some((number, command))
+}
+
+/// Problem found in a framed program, lines are counted from 1
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameError {
+    MissingLineNumber { line: usize },
+    MissingChecksum { line: usize },
+    BadChecksum { line: usize, expected: u8, found: u8 },
+    OutOfSequence { line: usize, expected: u32, found: u32 },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::MissingLineNumber { line } => write!(f, "line {}: missing line number", line),
+            FrameError::MissingChecksum { line } => write!(f, "line {}: missing checksum", line),
+            FrameError::BadChecksum { line, expected, found } => write!(f, "line {}: checksum is {} but should be {}", line, found, expected),
+            FrameError::OutOfSequence { line, expected, found } => write!(f, "line {}: line number is {} but should be {}", line, found, expected),
+        }
+    }
+}
+
+impl Error for FrameError {}
+
+/// Checks that every line of a framed program has a valid checksum and that line numbers follow
+/// each other (taking M110 into account), returns the number of commands
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::auto_home;
+/// use gen_gcode::framing::{frame, verify};
+///
+/// let framed = frame(&auto_home());
+/// assert_eq!(Ok(2), verify(&framed));
+/// assert!(verify(&framed.replace("G28", "G29")).is_err());
+/// ```
+pub fn verify(framed: &str) -> Result<usize, FrameError> {
+    let mut expected: Option<u32> = None;
+    let mut count = 0;
+    for (n, raw) in framed.lines().enumerate() {
+        let line = n + 1;
+        let raw = raw.trim();
+        if raw.is_empty() {
+            continue;
+        }
+        let star = raw.rfind('*').ok_or(FrameError::MissingChecksum { line })?;
+        let (body, cs) = (&raw[..star], &raw[star + 1..]);
+        let found = cs.trim().parse().map_err(|_| FrameError::MissingChecksum { line })?;
+        if checksum(body) != found {
+            return Err(FrameError::BadChecksum { line, expected: checksum(body), found });
+        }
+        let (number, command) = split_frame(raw).ok_or(FrameError::MissingLineNumber { line })?;
+        if let Some(expected) = expected {
+            if number != expected {
+                return Err(FrameError::OutOfSequence { line, expected, found: number });
+            }
+        }
+        expected = Some(number + 1);
+        if let Some(cmd) = crate::parser::parse_line(command).command {
+            if cmd.name == "M110" {
+                if let Some(next) = cmd.get("N") {
+                    expected = Some(next as u32 + 1);
+                }
+            }
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Removes `;` and `( … )` comments from a command and collapses the whitespace left in it
+pub(crate) fn strip_comments(command: &str) -> String {
+    let mut out = String::new();
+    let mut in_paren = false;
+    for c in command.chars() {
+        match c {
+            ';' if !in_paren => break,
+            '(' => in_paren = true,
+            ')' if in_paren => in_paren = false,
+            _ if !in_paren => out.push(c),
+            _ => (),
+        }
+    }
+    out.split_whitespace().collect::<Vec<_>>().join(" ")
+}
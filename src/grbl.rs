@@ -0,0 +1,399 @@
+//! Stream G-Code to Grbl controllers using character counting
+//!
+//! Grbl acknowledges every line with `ok` or `error:N`, but waiting for each one leaves the planner
+//! starved on short segments. Instead, lines are sent for as long as they fit in Grbl's serial
+//! receive buffer, keeping count of the bytes of every line not yet acknowledged.
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::sender::tests::pty_pair;
+    use crate::*;
+    use std::fs::File;
+    use std::io::Read;
+    use std::thread;
+    use std::time::Duration;
+
+    /// What the mock saw, returned once the host hangs up
+    #[derive(Debug, Default)]
+    struct MockLog {
+        lines: Vec<String>,
+        realtime: Vec<u8>,
+        /// Most bytes waiting in the receive buffer at once
+        max_buffered: usize,
+    }
+
+    /// Behaves like Grbl: buffers received bytes, slowly executes one line at a time and answers
+    /// `ok`, or `error:20` for unsupported commands
+    fn spawn_mock(port: File, alarm_on: Option<&'static str>) -> thread::JoinHandle<MockLog> {
+        thread::spawn(move || {
+            let mut out = port.try_clone().unwrap();
+            let mut input = port;
+            let mut log = MockLog::default();
+            let mut rx: Vec<u8> = Vec::new();
+            let mut chunk = [0u8; 512];
+            while let Ok(n) = input.read(&mut chunk) {
+                if n == 0 {
+                    break;
+                }
+                for &b in &chunk[..n] {
+                    match b {
+                        b'?' | b'!' | b'~' | 0x18 => log.realtime.push(b),
+                        _ => rx.push(b),
+                    }
+                }
+                log.max_buffered = log.max_buffered.max(rx.len());
+                while let Some(pos) = rx.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = rx.drain(..=pos).collect();
+                    let line = String::from_utf8_lossy(&line).trim().to_string();
+                    thread::sleep(Duration::from_millis(1));
+                    let reply = if Some(line.as_str()) == alarm_on {
+                        "ALARM:2\n".to_string()
+                    } else if line.starts_with("M104") {
+                        "error:20\n".to_string()
+                    } else {
+                        "ok\n".to_string()
+                    };
+                    log.lines.push(line);
+                    out.write_all(reply.as_bytes()).unwrap();
+                }
+            }
+            log
+        })
+    }
+
+    fn sample_program() -> String {
+        let mut program = String::from("G21 ; millimeters\n(start)\n");
+        for i in 0..100 {
+            let p = Point2d { x: i as f32 * 0.5, y: (i % 7) as f32 * 1.25 };
+            program += &move_xy(p, Some(1200), None);
+        }
+        program
+    }
+
+    #[test]
+    fn test_stream_within_buffer() {
+        let (firmware, host) = pty_pair();
+        let handle = spawn_mock(firmware, None);
+        let mut sender = GrblSender::new(host);
+        let report = sender.send_program(&sample_program()).unwrap();
+        drop(sender);
+        let log = handle.join().unwrap();
+        assert_eq!(101, report.sent);
+        assert!(report.errors.is_empty());
+        assert_eq!(101, log.lines.len());
+        assert_eq!("G21", log.lines[0]);
+        assert_eq!("G0X0.5Y1.25F1200", log.lines[2]);
+        assert!(log.max_buffered <= RX_BUFFER_SIZE);
+        // more than one line was in flight at a time
+        assert!(log.max_buffered > 20);
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let (firmware, host) = pty_pair();
+        let handle = spawn_mock(firmware, None);
+        let mut sender = GrblSender::new(host);
+        let program = format!("{}{}{}", auto_home(), set_hotend_temp(210, None), move_z(1.0));
+        let report = sender.send_program(&program).unwrap();
+        drop(sender);
+        handle.join().unwrap();
+        assert_eq!(3, report.sent);
+        assert_eq!(vec![GrblError { line: 2, code: 20 }], report.errors);
+        assert_eq!("line 2: error 20: Unsupported or invalid g-code command found in block", report.errors[0].to_string());
+    }
+
+    #[test]
+    fn test_alarm() {
+        let (firmware, host) = pty_pair();
+        let handle = spawn_mock(firmware, Some("G0Z1"));
+        let mut sender = GrblSender::new(host);
+        let program = format!("{}{}{}", auto_home(), move_z(1.0), move_z(2.0));
+        assert!(matches!(sender.send_program(&program), Err(SendError::Alarm(2))));
+        drop(sender);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_line_too_long() {
+        let (firmware, host) = pty_pair();
+        let handle = spawn_mock(firmware, None);
+        let mut sender = GrblSender::new(host);
+        let long = format!("G1 X{}\n", "1".repeat(200));
+        assert!(matches!(sender.send_program(&long), Err(SendError::LineTooLong(_))));
+        drop(sender);
+        assert!(handle.join().unwrap().lines.is_empty());
+    }
+
+    #[test]
+    fn test_cancel_sends_soft_reset() {
+        let (firmware, host) = pty_pair();
+        let handle = spawn_mock(firmware, None);
+        let mut sender = GrblSender::new(host);
+        sender.control().cancel();
+        assert!(matches!(sender.send_program(&sample_program()), Err(SendError::Cancelled { sent: 0 })));
+        thread::sleep(Duration::from_millis(20));
+        drop(sender);
+        let log = handle.join().unwrap();
+        assert_eq!(vec![RESET], log.realtime);
+    }
+
+    #[test]
+    fn test_pause_sends_feed_hold_and_resume() {
+        let (firmware, host) = pty_pair();
+        let handle = spawn_mock(firmware, None);
+        let mut sender = GrblSender::new(host);
+        let control = sender.control();
+        control.pause();
+        let resumer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            control.resume();
+        });
+        let report = sender.send_program(&sample_program()).unwrap();
+        resumer.join().unwrap();
+        drop(sender);
+        let log = handle.join().unwrap();
+        assert_eq!(101, report.sent);
+        assert_eq!(vec![FEED_HOLD, CYCLE_START], log.realtime);
+    }
+
+    #[test]
+    fn test_descriptions() {
+        assert_eq!("Feed rate has not yet been set or is undefined", error_description(22));
+        assert_eq!("Unknown error", error_description(200));
+        assert_eq!("Hard limit triggered, machine position is likely lost", alarm_description(1));
+    }
+}
+
+use crate::framing::strip_comments;
+use crate::response::{parse_response, Response};
+use crate::sender::{Connection, Control, SendError};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{Read, Write};
+use std::thread;
+use std::time::Duration;
+
+/// Size of Grbl's serial receive buffer in bytes
+pub const RX_BUFFER_SIZE: usize = 128;
+
+/// Real-time command to pause motion
+pub const FEED_HOLD: u8 = b'!';
+/// Real-time command to resume motion after [FEED_HOLD]
+pub const CYCLE_START: u8 = b'~';
+/// Real-time command to stop everything and reset (ctrl-x)
+pub const RESET: u8 = 0x18;
+
+/// Returns the meaning of a Grbl 1.1 `error:N` code
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::grbl::error_description;
+///
+/// assert_eq!("Unsupported or invalid g-code command found in block", error_description(20));
+/// ```
+pub fn error_description(code: u8) -> &'static str {
+    match code {
+        1 => "G-code words consist of a letter and a value, letter was not found",
+        2 => "Numeric value format is not valid or missing an expected value",
+        3 => "Grbl '$' system command was not recognized or supported",
+        4 => "Negative value received for an expected positive value",
+        5 => "Homing cycle is not enabled via settings",
+        6 => "Minimum step pulse time must be greater than 3usec",
+        7 => "EEPROM read failed, reset and restored to default values",
+        8 => "Grbl '$' command cannot be used unless Grbl is idle",
+        9 => "G-code locked out during alarm or jog state",
+        10 => "Soft limits cannot be enabled without homing also enabled",
+        11 => "Max characters per line exceeded, line was not processed",
+        12 => "Grbl '$' setting value exceeds the maximum step rate supported",
+        13 => "Safety door detected as opened and door state initiated",
+        14 => "Build info or startup line exceeded EEPROM line length limit",
+        15 => "Jog target exceeds machine travel, command ignored",
+        16 => "Jog command with no '=' or contains prohibited g-code",
+        17 => "Laser mode requires PWM output",
+        20 => "Unsupported or invalid g-code command found in block",
+        21 => "More than one g-code command from same modal group found in block",
+        22 => "Feed rate has not yet been set or is undefined",
+        23 => "G-code command in block requires an integer value",
+        24 => "Two G-code commands that both require the use of the XYZ axis words were detected in the block",
+        25 => "A G-code word was repeated in the block",
+        26 => "A G-code command requires XYZ axis words in the block, but none were detected",
+        27 => "N line number value is not within the valid range of 1 - 9,999,999",
+        28 => "A G-code command is missing some required P or L value words",
+        29 => "Only the G54-G59 work coordinate systems are supported",
+        30 => "G53 requires either a G0 seek or G1 feed motion mode to be active",
+        31 => "There are unused axis words in the block and G80 motion mode cancel is active",
+        32 => "A G2 or G3 arc was commanded but there are no XYZ axis words in the selected plane",
+        33 => "The motion command has an invalid target",
+        34 => "A G2 or G3 arc, traced with the radius definition, had a mathematical error",
+        35 => "A G2 or G3 arc, traced with the offset definition, is missing the IJK offset word",
+        36 => "There are unused, leftover G-code words that aren't used by any command in the block",
+        37 => "G43.1 dynamic tool length offset cannot apply an offset to an axis other than its configured axis",
+        38 => "Tool number greater than max supported value",
+        _ => "Unknown error",
+    }
+}
+
+/// Returns the meaning of a Grbl 1.1 `ALARM:N` code
+pub fn alarm_description(code: u8) -> &'static str {
+    match code {
+        1 => "Hard limit triggered, machine position is likely lost",
+        2 => "Motion target exceeds machine travel, machine position safely retained",
+        3 => "Reset while in motion, machine position is likely lost",
+        4 => "Probe fail, the probe is not in the expected initial state",
+        5 => "Probe fail, the probe did not contact the workpiece",
+        6 => "Homing fail, reset during active homing cycle",
+        7 => "Homing fail, safety door was opened during active homing cycle",
+        8 => "Homing fail, cycle failed to clear limit switch when pulling off",
+        9 => "Homing fail, could not find limit switch within search distance",
+        _ => "Unknown alarm",
+    }
+}
+
+/// A line Grbl rejected, `line` is the line of the program it came from (counted from 1)
+#[derive(Debug, Clone, PartialEq)]
+pub struct GrblError {
+    pub line: usize,
+    pub code: u8,
+}
+
+impl GrblError {
+    pub fn description(&self) -> &'static str {
+        error_description(self.code)
+    }
+}
+
+impl fmt::Display for GrblError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: error {}: {}", self.line, self.code, self.description())
+    }
+}
+
+/// What happened while streaming a program to Grbl
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GrblReport {
+    /// Lines acknowledged with `ok` or `error:N`
+    pub sent: usize,
+    /// Lines Grbl rejected, streaming carries on past them
+    pub errors: Vec<GrblError>,
+    /// `[MSG:...]` feedback messages
+    pub messages: Vec<String>,
+}
+
+/// Streams commands to Grbl, keeping its receive buffer as full as possible
+///
+/// Comments and whitespace are stripped before sending to save buffer space. Pausing sends a feed
+/// hold and resuming a cycle start, cancelling sends a soft reset.
+///
+/// # Examples
+/// ```no_run
+/// extern crate gen_gcode;
+/// use gen_gcode::{Point2d, move_xy};
+/// use gen_gcode::grbl::GrblSender;
+/// use std::fs::OpenOptions;
+///
+/// let port = OpenOptions::new().read(true).write(true).open("/dev/ttyACM0").unwrap();
+/// let mut sender = GrblSender::new(port);
+/// let report = sender.send_program(&move_xy(Point2d { x: 10.0, y: 5.0 }, Some(600), None)).unwrap();
+/// for error in report.errors {
+///     println!("{}", error);
+/// }
+/// ```
+pub struct GrblSender<T: Read + Write> {
+    conn: Connection<T>,
+    control: Control,
+    /// Program line and byte length of every line sent but not yet acknowledged
+    in_flight: VecDeque<(usize, usize)>,
+    report: GrblReport,
+}
+
+impl<T: Read + Write> GrblSender<T> {
+    pub fn new(transport: T) -> Self {
+        GrblSender { conn: Connection::new(transport), control: Control::default(), in_flight: VecDeque::new(), report: GrblReport::default() }
+    }
+
+    /// Returns a handle to pause, resume or cancel streaming
+    pub fn control(&self) -> Control {
+        self.control.clone()
+    }
+
+    /// Sends every command of a program and waits for all of them to be acknowledged
+    pub fn send_program(&mut self, program: &str) -> Result<GrblReport, SendError> {
+        self.report = GrblReport::default();
+        self.in_flight.clear();
+        for (n, line) in program.lines().enumerate() {
+            let block = clean_block(line);
+            if block.is_empty() {
+                continue;
+            }
+            let len = block.len() + 1;
+            if len > RX_BUFFER_SIZE {
+                return Err(SendError::LineTooLong(block));
+            }
+            self.check_control()?;
+            while self.buffered() + len > RX_BUFFER_SIZE {
+                self.read_ack()?;
+            }
+            self.conn.write(format!("{}\n", block).as_bytes())?;
+            self.in_flight.push_back((n + 1, len));
+        }
+        while !self.in_flight.is_empty() {
+            self.read_ack()?;
+        }
+        Ok(std::mem::take(&mut self.report))
+    }
+
+    fn buffered(&self) -> usize {
+        self.in_flight.iter().map(|(_, len)| len).sum()
+    }
+
+    fn check_control(&mut self) -> Result<(), SendError> {
+        let mut held = false;
+        loop {
+            if self.control.is_cancelled() {
+                self.conn.write(&[RESET])?;
+                return Err(SendError::Cancelled { sent: self.report.sent });
+            }
+            if !self.control.is_paused() {
+                if held {
+                    self.conn.write(&[CYCLE_START])?;
+                }
+                return Ok(());
+            }
+            if !held {
+                self.conn.write(&[FEED_HOLD])?;
+                held = true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Reads responses until the oldest line in flight is acknowledged
+    fn read_ack(&mut self) -> Result<(), SendError> {
+        loop {
+            let line = self.conn.read_line(&self.control, self.report.sent)?;
+            match parse_response(&line) {
+                Response::Ok { .. } => {
+                    self.in_flight.pop_front();
+                    self.report.sent += 1;
+                    return Ok(());
+                }
+                Response::GrblError(code) => {
+                    let (line, _) = self.in_flight.pop_front().unwrap_or((0, 0));
+                    self.report.errors.push(GrblError { line, code });
+                    self.report.sent += 1;
+                    return Ok(());
+                }
+                Response::Alarm(code) => return Err(SendError::Alarm(code)),
+                Response::Message(msg) => self.report.messages.push(msg),
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Removes comments and whitespace from a line, and upper cases it
+fn clean_block(line: &str) -> String {
+    strip_comments(line).replace(' ', "").to_ascii_uppercase()
+}
@@ -0,0 +1,183 @@
+//! Homing commands for each firmware flavor, and which axes a parsed homing command homes
+//!
+//! Marlin, Klipper and RepRapFirmware home with G28, naming the axes to home or homing them all
+//! when none are named. Grbl runs its homing cycle with `$H`.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_line;
+
+    fn homes(line: &str) -> Option<Axes> {
+        homed_axes(&parse_line(line).command.unwrap())
+    }
+
+    #[test]
+    fn test_home() {
+        let xy = Axes { x: true, y: true, z: false };
+        assert_eq!("G28\n", home(Flavor::Marlin, Axes::all()));
+        assert_eq!("G28 X Y\n", home(Flavor::Marlin, xy));
+        assert_eq!("G28 Z\n", home(Flavor::Klipper, Axes { z: true, ..Axes::default() }));
+        assert_eq!("G28 X Y\n", home(Flavor::RepRapFirmware, xy));
+        assert_eq!("$H\n", home(Flavor::Grbl, Axes::all()));
+        assert_eq!("$HX\n$HY\n", home(Flavor::Grbl, xy));
+        assert_eq!("", home(Flavor::Marlin, Axes::default()));
+    }
+
+    #[test]
+    fn test_home_if_needed() {
+        assert_eq!("G28 O\n", home_if_needed(Flavor::Marlin, Axes::all()));
+        assert_eq!("G28 O X Y\n", home_if_needed(Flavor::Marlin, Axes { x: true, y: true, z: false }));
+        assert_eq!("G28\n", home_if_needed(Flavor::Klipper, Axes::all()));
+        assert_eq!("$H\n", home_if_needed(Flavor::Grbl, Axes::all()));
+    }
+
+    #[test]
+    fn test_homed_axes() {
+        assert_eq!(Some(Axes::all()), homes("G28"));
+        assert_eq!(Some(Axes::all()), homes("G28 O"));
+        assert_eq!(Some(Axes { x: true, y: false, z: true }), homes("G28 X0 Z0"));
+        assert_eq!(Some(Axes::all()), homes("$H"));
+        assert_eq!(Some(Axes { y: true, ..Axes::default() }), homes("$HY"));
+        assert_eq!(None, homes("G0 X10"));
+        assert_eq!(None, homes("$X"));
+    }
+
+    #[test]
+    fn test_axes() {
+        assert!(Axes::all().is_all());
+        assert!(Axes::default().is_empty());
+        let x = Axes { x: true, ..Axes::default() };
+        assert!(!x.is_all() && !x.is_empty());
+        assert!(Axes::all().contains(x));
+        assert!(!x.contains(Axes::all()));
+        assert_eq!(Axes { x: true, y: true, z: false }, x.union(Axes { y: true, ..Axes::default() }));
+    }
+}
+
+use crate::parser::Command;
+use crate::Flavor;
+
+/// A selection of the X, Y and Z axes, none by default
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Axes {
+    pub x: bool,
+    pub y: bool,
+    pub z: bool,
+}
+
+impl Axes {
+    /// Returns all three axes
+    pub fn all() -> Self {
+        Axes { x: true, y: true, z: true }
+    }
+
+    pub fn is_all(self) -> bool {
+        self.x && self.y && self.z
+    }
+
+    pub fn is_empty(self) -> bool {
+        !(self.x || self.y || self.z)
+    }
+
+    /// Whether every axis of `other` is also in this selection
+    pub fn contains(self, other: Axes) -> bool {
+        (self.x || !other.x) && (self.y || !other.y) && (self.z || !other.z)
+    }
+
+    /// Returns the axes in either selection
+    pub fn union(self, other: Axes) -> Axes {
+        Axes { x: self.x || other.x, y: self.y || other.y, z: self.z || other.z }
+    }
+
+    /// Returns the selected axes as (index, letter) pairs, X being 0
+    pub(crate) fn letters(self) -> impl Iterator<Item = (usize, char)> {
+        let selected = [self.x, self.y, self.z];
+        "XYZ".chars().enumerate().filter(move |(n, _)| selected[*n])
+    }
+}
+
+fn g28(only_if_needed: bool, axes: Axes) -> String {
+    let mut out = "G28".to_string();
+    if only_if_needed {
+        out += " O";
+    }
+    if !axes.is_all() {
+        for (_, letter) in axes.letters() {
+            out += &format!(" {}", letter);
+        }
+    }
+    format!("{}\n", out)
+}
+
+/// Returns the commands homing the given axes as a String, nothing when no axis is selected
+///
+/// All axes are homed with a bare G28, which also runs RepRapFirmware's `homeall.g` rather than
+/// `homex.g`, `homey.g` and `homez.g` in turn. Grbl homes single axes with `$HX`, `$HY` and `$HZ`,
+/// which needs the firmware built with `HOMING_SINGLE_AXIS_COMMANDS`.
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::Flavor;
+/// use gen_gcode::homing::{home, Axes};
+///
+/// let gcode = home(Flavor::Marlin, Axes { x: true, y: true, z: false });
+/// assert_eq!("G28 X Y\n", gcode);
+/// assert_eq!("$H\n", home(Flavor::Grbl, Axes::all()));
+/// ```
+pub fn home(flavor: Flavor, axes: Axes) -> String {
+    if axes.is_empty() {
+        return String::new()
+    }
+    match flavor {
+        Flavor::Marlin | Flavor::Klipper | Flavor::RepRapFirmware => g28(false, axes),
+        Flavor::Grbl if axes.is_all() => "$H\n".to_string(),
+        Flavor::Grbl => axes.letters().map(|(_, letter)| format!("$H{}\n", letter)).collect(),
+    }
+}
+
+/// Returns the commands homing the given axes unless their position is already known, as a String
+///
+/// Only Marlin can skip homing (G28 O), the other flavors always home, like [home].
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::Flavor;
+/// use gen_gcode::homing::{home_if_needed, Axes};
+///
+/// let gcode = home_if_needed(Flavor::Marlin, Axes::all());
+/// assert_eq!("G28 O\n", gcode);
+/// ```
+pub fn home_if_needed(flavor: Flavor, axes: Axes) -> String {
+    match flavor {
+        Flavor::Marlin if !axes.is_empty() => g28(true, axes),
+        _ => home(flavor, axes),
+    }
+}
+
+/// Returns the axes a parsed command homes, `None` if it is not a homing command
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::homing::{homed_axes, Axes};
+/// use gen_gcode::parser::parse_line;
+///
+/// let cmd = parse_line("G28 Z").command.unwrap();
+/// assert_eq!(Some(Axes { x: false, y: false, z: true }), homed_axes(&cmd));
+/// ```
+pub fn homed_axes(cmd: &Command) -> Option<Axes> {
+    match cmd.name.as_str() {
+        "G28" => {
+            let axes = Axes { x: cmd.has("X"), y: cmd.has("Y"), z: cmd.has("Z") };
+            Some(if axes.is_empty() { Axes::all() } else { axes })
+        }
+        "$H" => Some(Axes::all()),
+        "$HX" => Some(Axes { x: true, ..Axes::default() }),
+        "$HY" => Some(Axes { y: true, ..Axes::default() }),
+        "$HZ" => Some(Axes { z: true, ..Axes::default() }),
+        _ => None,
+    }
+}
@@ -0,0 +1,153 @@
+//! Helpers shared by the HTTP clients
+
+#[cfg(test)]
+#[allow(clippy::items_after_test_module)]
+pub(crate) mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    /// A request received by [HttpStub]
+    #[derive(Debug, Clone)]
+    pub(crate) struct Request {
+        pub method: String,
+        pub path: String,
+        pub headers: Vec<(String, String)>,
+        pub body: String,
+    }
+
+    impl Request {
+        pub fn header(&self, name: &str) -> Option<&str> {
+            self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+        }
+    }
+
+    /// A local HTTP server answering every request with whatever the handler returns, recording
+    /// the requests it received
+    pub(crate) struct HttpStub {
+        pub url: String,
+        pub requests: Arc<Mutex<Vec<Request>>>,
+    }
+
+    impl HttpStub {
+        pub fn start<F>(handler: F) -> HttpStub
+        where
+            F: Fn(&Request) -> (u16, String) + Send + 'static,
+        {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let log = requests.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut first = String::new();
+                    reader.read_line(&mut first).unwrap();
+                    let mut parts = first.split_whitespace();
+                    let method = parts.next().unwrap_or("").to_string();
+                    let path = parts.next().unwrap_or("").to_string();
+                    let mut headers = Vec::new();
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        let line = line.trim_end();
+                        if line.is_empty() {
+                            break;
+                        }
+                        let (k, v) = line.split_at(line.find(':').unwrap());
+                        headers.push((k.to_string(), v[1..].trim().to_string()));
+                    }
+                    let len = headers.iter().find(|(k, _)| k.eq_ignore_ascii_case("content-length")).map(|(_, v)| v.parse().unwrap()).unwrap_or(0);
+                    let mut body = vec![0; len];
+                    reader.read_exact(&mut body).unwrap();
+                    let request = Request { method, path, headers, body: String::from_utf8_lossy(&body).to_string() };
+                    let (status, body) = handler(&request);
+                    log.lock().unwrap().push(request);
+                    let response = format!("HTTP/1.1 {} Stub\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body);
+                    stream.write_all(response.as_bytes()).unwrap();
+                }
+            });
+            HttpStub { url, requests }
+        }
+
+        pub fn requests(&self) -> Vec<Request> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    #[test]
+    fn test_multipart() {
+        let (content_type, body) = super::multipart("file", "box.gcode", "G28\n", &[("path", "parts")]);
+        assert_eq!(format!("multipart/form-data; boundary={}", super::BOUNDARY), content_type);
+        assert_eq!(
+            format!(
+                "--{b}\r\nContent-Disposition: form-data; name=\"path\"\r\n\r\nparts\r\n--{b}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"box.gcode\"\r\nContent-Type: application/octet-stream\r\n\r\nG28\n\r\n--{b}--\r\n",
+                b = super::BOUNDARY
+            ),
+            body
+        );
+    }
+
+    #[test]
+    fn test_multipart_escapes_filename() {
+        let (_, body) = super::multipart("file", "a\"b\r\nc.gcode", "G28\n", &[]);
+        assert!(body.contains("filename=\"a%22b%0D%0Ac.gcode\"\r\n"));
+    }
+
+    #[test]
+    #[cfg(feature = "octoprint")]
+    fn test_encode_path() {
+        assert_eq!("parts/box.gcode", super::encode_path("parts/box.gcode"));
+        assert_eq!("my%20parts/box%20%231%3F.gcode", super::encode_path("my parts/box #1?.gcode"));
+        assert_eq!("caf%C3%A9.gcode", super::encode_path("café.gcode"));
+    }
+
+    #[test]
+    fn test_split_path() {
+        assert_eq!(("parts", "box.gcode"), super::split_path("parts/box.gcode"));
+        assert_eq!(("", "box.gcode"), super::split_path("box.gcode"));
+    }
+}
+
+const BOUNDARY: &str = "----gen-gcode-multipart-boundary";
+
+/// Splits a path into its folder and file name
+pub(crate) fn split_path(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => ("", path),
+    }
+}
+
+#[cfg(feature = "octoprint")]
+/// Percent-encodes each segment of a path so it can be put in a URL, keeping the `/` between them
+pub(crate) fn encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for b in path.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => encoded.push(b as char),
+            _ => encoded += &format!("%{:02X}", b),
+        }
+    }
+    encoded
+}
+
+/// Escapes the characters that would end a quoted header parameter or the header itself, the
+/// way browsers do for form uploads
+fn escape_quoted(value: &str) -> String {
+    value.replace('"', "%22").replace('\r', "%0D").replace('\n', "%0A")
+}
+
+/// Builds a multipart/form-data body uploading `contents` as `filename` along with text fields,
+/// returns the content type to send and the body
+pub(crate) fn multipart(file_field: &str, filename: &str, contents: &str, fields: &[(&str, &str)]) -> (String, String) {
+    let mut body = String::new();
+    for (name, value) in fields {
+        body += &format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n", BOUNDARY, name, value);
+    }
+    body += &format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n{}\r\n", BOUNDARY, file_field, escape_quoted(filename), contents);
+    body += &format!("--{}--\r\n", BOUNDARY);
+    (format!("multipart/form-data; boundary={}", BOUNDARY), body)
+}
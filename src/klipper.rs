@@ -0,0 +1,342 @@
+//! Extended commands understood by Klipper, and calls to user defined macros
+//!
+//! Klipper commands take `KEY=VALUE` parameters rather than single letters, see
+//! <https://www.klipper3d.org/G-Codes.html>.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_line;
+
+    #[test]
+    fn test_set_pressure_advance() {
+        assert_eq!("SET_PRESSURE_ADVANCE ADVANCE=0.045\n", set_pressure_advance(0.045, None, None));
+        assert_eq!("SET_PRESSURE_ADVANCE EXTRUDER=extruder1 ADVANCE=0.05 SMOOTH_TIME=0.03\n", set_pressure_advance(0.05, Some(0.03), Some("extruder1")));
+    }
+
+    #[test]
+    fn test_set_velocity_limit() {
+        assert_eq!("SET_VELOCITY_LIMIT\n", set_velocity_limit(VelocityLimits::default()));
+        let limits = VelocityLimits { velocity: Some(300.0), accel: Some(5000.0), square_corner_velocity: Some(5.0), ..Default::default() };
+        assert_eq!("SET_VELOCITY_LIMIT VELOCITY=300 ACCEL=5000 SQUARE_CORNER_VELOCITY=5\n", set_velocity_limit(limits));
+        let limits = VelocityLimits { minimum_cruise_ratio: Some(0.5), ..Default::default() };
+        assert_eq!("SET_VELOCITY_LIMIT MINIMUM_CRUISE_RATIO=0.5\n", set_velocity_limit(limits));
+    }
+
+    #[test]
+    fn test_exclude_object() {
+        let polygon = [Point2d { x: 0.0, y: 0.0 }, Point2d { x: 20.0, y: 0.0 }, Point2d { x: 20.0, y: 20.5 }];
+        assert_eq!(
+            "EXCLUDE_OBJECT_DEFINE NAME=part_1 CENTER=10,10.25 POLYGON=[[0,0],[20,0],[20,20.5]]\n",
+            exclude_object_define("part_1", Some(Point2d { x: 10.0, y: 10.25 }), &polygon)
+        );
+        assert_eq!("EXCLUDE_OBJECT_DEFINE NAME=part_1\n", exclude_object_define("part_1", None, &[]));
+        assert_eq!("EXCLUDE_OBJECT_START NAME=part_1\n", exclude_object_start("part_1"));
+        assert_eq!("EXCLUDE_OBJECT_END NAME=part_1\n", exclude_object_end(Some("part_1")));
+        assert_eq!("EXCLUDE_OBJECT_END\n", exclude_object_end(None));
+        assert_eq!("EXCLUDE_OBJECT NAME=part_1\n", exclude_object("part_1"));
+    }
+
+    #[test]
+    fn test_fans_and_heaters() {
+        assert_eq!("SET_FAN_SPEED FAN=nevermore SPEED=0.5\n", set_fan_speed("nevermore", 0.5));
+        assert_eq!("SET_HEATER_TEMPERATURE HEATER=heater_bed TARGET=60\n", set_heater_temperature("heater_bed", 60.0));
+        assert_eq!("TEMPERATURE_WAIT SENSOR=extruder MINIMUM=205 MAXIMUM=215\n", temperature_wait("extruder", Some(205.0), Some(215.0)));
+        assert_eq!("TEMPERATURE_WAIT SENSOR=\"temperature_sensor chamber\" MINIMUM=40\n", temperature_wait("temperature_sensor chamber", Some(40.0), None));
+    }
+
+    #[test]
+    fn test_bed_mesh_calibrate() {
+        assert_eq!("BED_MESH_CALIBRATE\n", bed_mesh_calibrate(None, false));
+        assert_eq!("BED_MESH_CALIBRATE PROFILE=pla ADAPTIVE=1\n", bed_mesh_calibrate(Some("pla"), true));
+    }
+
+    #[test]
+    fn test_macro() {
+        assert_eq!("PRINT_START BED=60 EXTRUDER=210\n", Macro::new("print_start").param("BED", 60).param("EXTRUDER", 210).render());
+        assert_eq!("PRINT_END\n", Macro::new("PRINT_END").render());
+        assert_eq!("M117_ALIAS MSG=\"hello world\" EMPTY=\"\"\n", Macro::new("m117_alias").param("msg", "hello world").param("EMPTY", "").render());
+    }
+
+    #[test]
+    fn test_macro_special_characters() {
+        let gcode = Macro::new("m117_alias").param("msg", "say \"hi\"; then stop").param("a;b", "x\"y").render();
+        assert_eq!("M117_ALIAS MSG=\"say 'hi', then stop\" A_B=x'y\n", gcode);
+        assert_eq!("SET_FAN_SPEED FAN=fan,1 SPEED=1\n", set_fan_speed("fan;1", 1.0));
+        assert_eq!("EXCLUDE_OBJECT_START NAME=\"a b\"\n", exclude_object_start("a\nb"));
+    }
+
+    #[test]
+    fn test_parses_back() {
+        let cmd = parse_line(&Macro::new("PRINT_START").param("BED", 60).param("EXTRUDER", 210.5).render()).command.unwrap();
+        assert_eq!("PRINT_START", cmd.name);
+        assert_eq!(Some(60.0), cmd.get("BED"));
+        assert_eq!(Some(210.5), cmd.get("EXTRUDER"));
+        let cmd = parse_line(&set_pressure_advance(0.04, None, None)).command.unwrap();
+        assert_eq!(Some(0.04), cmd.get("ADVANCE"));
+    }
+}
+
+use crate::Point2d;
+use std::fmt::Display;
+
+/// Returns a macro or parameter name in upper case, with characters Klipper does not accept in
+/// names replaced by `_`
+fn name(name: &str) -> String {
+    name.to_uppercase().chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' }).collect()
+}
+
+/// Formats a parameter value, quoting it when Klipper would otherwise split it. Klipper cuts a
+/// line at the first `;`, even inside quotes, and has no way to escape a quote, so `;` is replaced
+/// by `,`, `"` by `'` and line breaks by spaces.
+fn value<T: Display>(v: T) -> String {
+    let v = v.to_string().replace(';', ",").replace('"', "'").replace(['\r', '\n'], " ");
+    if v.is_empty() || v.contains(char::is_whitespace) {
+        format!("\"{}\"", v)
+    } else {
+        v
+    }
+}
+
+/// Returns a SET_PRESSURE_ADVANCE command as a String, for the active extruder unless one is named
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::klipper::set_pressure_advance;
+///
+/// let gcode = set_pressure_advance(0.045, None, None);
+/// assert_eq!("SET_PRESSURE_ADVANCE ADVANCE=0.045\n", gcode);
+/// ```
+pub fn set_pressure_advance(advance: f32, smooth_time: Option<f32>, extruder: Option<&str>) -> String {
+    let mut out = "SET_PRESSURE_ADVANCE".to_string();
+    if let Some(extruder) = extruder {
+        out += &format!(" EXTRUDER={}", value(extruder));
+    }
+    out += &format!(" ADVANCE={}", advance);
+    if let Some(smooth_time) = smooth_time {
+        out += &format!(" SMOOTH_TIME={}", smooth_time);
+    }
+    format!("{}\n", out)
+}
+
+/// Limits set by SET_VELOCITY_LIMIT, limits left to None keep their current value
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct VelocityLimits {
+    /// Maximum velocity in mm/s
+    pub velocity: Option<f32>,
+    /// Maximum acceleration in mm/s²
+    pub accel: Option<f32>,
+    /// Fraction of a move that should cruise at full speed, replaces `accel_to_decel` since
+    /// Klipper v0.12
+    pub minimum_cruise_ratio: Option<f32>,
+    /// Maximum speed through a 90° corner in mm/s
+    pub square_corner_velocity: Option<f32>,
+}
+
+/// Returns a SET_VELOCITY_LIMIT command as a String
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::klipper::{set_velocity_limit, VelocityLimits};
+///
+/// let gcode = set_velocity_limit(VelocityLimits { accel: Some(3000.0), ..Default::default() });
+/// assert_eq!("SET_VELOCITY_LIMIT ACCEL=3000\n", gcode);
+/// ```
+pub fn set_velocity_limit(limits: VelocityLimits) -> String {
+    let mut out = "SET_VELOCITY_LIMIT".to_string();
+    let params = [
+        ("VELOCITY", limits.velocity),
+        ("ACCEL", limits.accel),
+        ("MINIMUM_CRUISE_RATIO", limits.minimum_cruise_ratio),
+        ("SQUARE_CORNER_VELOCITY", limits.square_corner_velocity),
+    ];
+    for (key, v) in params.iter() {
+        if let Some(v) = v {
+            out += &format!(" {}={}", key, v);
+        }
+    }
+    format!("{}\n", out)
+}
+
+/// Returns an EXCLUDE_OBJECT_DEFINE command declaring an object that can be cancelled during the
+/// print, with an optional center and outline used by front-ends to draw it
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::Point2d;
+/// use gen_gcode::klipper::exclude_object_define;
+///
+/// let outline = [Point2d { x: 0.0, y: 0.0 }, Point2d { x: 20.0, y: 0.0 }, Point2d { x: 20.0, y: 20.0 }, Point2d { x: 0.0, y: 20.0 }];
+/// let gcode = exclude_object_define("cube", Some(Point2d { x: 10.0, y: 10.0 }), &outline);
+/// assert_eq!("EXCLUDE_OBJECT_DEFINE NAME=cube CENTER=10,10 POLYGON=[[0,0],[20,0],[20,20],[0,20]]\n", gcode);
+/// ```
+pub fn exclude_object_define(name: &str, center: Option<Point2d>, polygon: &[Point2d]) -> String {
+    let mut out = format!("EXCLUDE_OBJECT_DEFINE NAME={}", value(name));
+    if let Some(c) = center {
+        out += &format!(" CENTER={},{}", c.x, c.y);
+    }
+    if !polygon.is_empty() {
+        let points: Vec<String> = polygon.iter().map(|p| format!("[{},{}]", p.x, p.y)).collect();
+        out += &format!(" POLYGON=[{}]", points.join(","));
+    }
+    format!("{}\n", out)
+}
+
+/// Returns an EXCLUDE_OBJECT_START command marking the start of an object's moves as a String
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::klipper::exclude_object_start;
+///
+/// let gcode = exclude_object_start("cube");
+/// assert_eq!("EXCLUDE_OBJECT_START NAME=cube\n", gcode);
+/// ```
+pub fn exclude_object_start(name: &str) -> String {
+    format!("EXCLUDE_OBJECT_START NAME={}\n", value(name))
+}
+
+/// Returns an EXCLUDE_OBJECT_END command marking the end of an object's moves as a String, the
+/// name is optional but lets Klipper check that starts and ends match
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::klipper::exclude_object_end;
+///
+/// let gcode = exclude_object_end(Some("cube"));
+/// assert_eq!("EXCLUDE_OBJECT_END NAME=cube\n", gcode);
+/// ```
+pub fn exclude_object_end(name: Option<&str>) -> String {
+    match name {
+        Some(name) => format!("EXCLUDE_OBJECT_END NAME={}\n", value(name)),
+        None => "EXCLUDE_OBJECT_END\n".to_string(),
+    }
+}
+
+/// Returns an EXCLUDE_OBJECT command cancelling an object as a String
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::klipper::exclude_object;
+///
+/// let gcode = exclude_object("cube");
+/// assert_eq!("EXCLUDE_OBJECT NAME=cube\n", gcode);
+/// ```
+pub fn exclude_object(name: &str) -> String {
+    format!("EXCLUDE_OBJECT NAME={}\n", value(name))
+}
+
+/// Returns a SET_FAN_SPEED command for a `fan_generic` fan as a String, speed goes from 0 to 1
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::klipper::set_fan_speed;
+///
+/// let gcode = set_fan_speed("exhaust", 0.8);
+/// assert_eq!("SET_FAN_SPEED FAN=exhaust SPEED=0.8\n", gcode);
+/// ```
+pub fn set_fan_speed(fan: &str, speed: f32) -> String {
+    format!("SET_FAN_SPEED FAN={} SPEED={}\n", value(fan), speed)
+}
+
+/// Returns a SET_HEATER_TEMPERATURE command as a String, it does not wait for the heater
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::klipper::set_heater_temperature;
+///
+/// let gcode = set_heater_temperature("extruder", 210.0);
+/// assert_eq!("SET_HEATER_TEMPERATURE HEATER=extruder TARGET=210\n", gcode);
+/// ```
+pub fn set_heater_temperature(heater: &str, target: f32) -> String {
+    format!("SET_HEATER_TEMPERATURE HEATER={} TARGET={}\n", value(heater), target)
+}
+
+/// Returns a TEMPERATURE_WAIT command as a String, waiting until a sensor reads within the bounds
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::klipper::temperature_wait;
+///
+/// let gcode = temperature_wait("heater_bed", Some(58.0), None);
+/// assert_eq!("TEMPERATURE_WAIT SENSOR=heater_bed MINIMUM=58\n", gcode);
+/// ```
+pub fn temperature_wait(sensor: &str, minimum: Option<f32>, maximum: Option<f32>) -> String {
+    let mut out = format!("TEMPERATURE_WAIT SENSOR={}", value(sensor));
+    if let Some(min) = minimum {
+        out += &format!(" MINIMUM={}", min);
+    }
+    if let Some(max) = maximum {
+        out += &format!(" MAXIMUM={}", max);
+    }
+    format!("{}\n", out)
+}
+
+/// Returns a BED_MESH_CALIBRATE command as a String, optionally saving to a named profile. An
+/// adaptive mesh only probes the area covered by the objects defined with EXCLUDE_OBJECT_DEFINE.
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::klipper::bed_mesh_calibrate;
+///
+/// let gcode = bed_mesh_calibrate(None, true);
+/// assert_eq!("BED_MESH_CALIBRATE ADAPTIVE=1\n", gcode);
+/// ```
+pub fn bed_mesh_calibrate(profile: Option<&str>, adaptive: bool) -> String {
+    let mut out = "BED_MESH_CALIBRATE".to_string();
+    if let Some(profile) = profile {
+        out += &format!(" PROFILE={}", value(profile));
+    }
+    if adaptive {
+        out += " ADAPTIVE=1";
+    }
+    format!("{}\n", out)
+}
+
+/// A call to a `gcode_macro` defined in the printer's configuration
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::klipper::Macro;
+///
+/// let gcode = Macro::new("PRINT_START").param("BED", 60).param("EXTRUDER", 210).render();
+/// assert_eq!("PRINT_START BED=60 EXTRUDER=210\n", gcode);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Macro {
+    name: String,
+    params: Vec<(String, String)>,
+}
+
+impl Macro {
+    /// Creates a call to the macro `name`, names are case insensitive and emitted in upper case
+    pub fn new(macro_name: &str) -> Self {
+        Macro { name: name(macro_name), params: Vec::new() }
+    }
+
+    /// Adds a parameter, values containing spaces are quoted. Characters Klipper cannot take in a
+    /// value, `;` and `"`, are replaced by `,` and `'`.
+    pub fn param<T: Display>(&mut self, key: &str, v: T) -> &mut Self {
+        self.params.push((name(key), value(v)));
+        self
+    }
+
+    /// Returns the call as a String
+    pub fn render(&self) -> String {
+        let mut out = self.name.clone();
+        for (key, v) in &self.params {
+            out += &format!(" {}={}", key, v);
+        }
+        format!("{}\n", out)
+    }
+}
@@ -0,0 +1,206 @@
+//! Bed leveling and probing commands for each firmware flavor
+//!
+//! Grbl machines have no bed probing, the functions return an empty String for them.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_line;
+
+    fn grid() -> ProbeGrid {
+        ProbeGrid { min: Point2d { x: 10.0, y: 15.5 }, max: Point2d { x: 190.0, y: 200.0 }, points_x: 5, points_y: 4 }
+    }
+
+    #[test]
+    fn test_auto_level() {
+        assert_eq!("G29\n", auto_level(Flavor::Marlin, None));
+        assert_eq!("G29 L10 R190 F15.5 B200 X5 Y4\n", auto_level(Flavor::Marlin, Some(grid())));
+        assert_eq!("BED_MESH_CALIBRATE\n", auto_level(Flavor::Klipper, None));
+        assert_eq!("BED_MESH_CALIBRATE MESH_MIN=10,15.5 MESH_MAX=190,200 PROBE_COUNT=5,4\n", auto_level(Flavor::Klipper, Some(grid())));
+        assert_eq!("G29\n", auto_level(Flavor::RepRapFirmware, None));
+        assert_eq!("M557 X10:190 Y15.5:200 P5:4\nG29 S0\n", auto_level(Flavor::RepRapFirmware, Some(grid())));
+        assert_eq!("", auto_level(Flavor::Grbl, Some(grid())));
+    }
+
+    #[test]
+    fn test_probe_point() {
+        let p = Point2d { x: 100.0, y: 50.5 };
+        assert_eq!("G30 X100 Y50.5\n", probe_point(Flavor::Marlin, p));
+        assert_eq!("G0 X100 Y50.5\nPROBE\n", probe_point(Flavor::Klipper, p));
+        assert_eq!("G30 P0 X100 Y50.5 Z-99999 S-1\n", probe_point(Flavor::RepRapFirmware, p));
+        assert_eq!("", probe_point(Flavor::Grbl, p));
+    }
+
+    #[test]
+    fn test_load_mesh() {
+        assert_eq!("M420 S1\n", load_mesh(Flavor::Marlin, None));
+        assert_eq!("M420 S1 L2\n", load_mesh(Flavor::Marlin, Some(2)));
+        assert_eq!("BED_MESH_PROFILE LOAD=default\n", load_mesh(Flavor::Klipper, None));
+        assert_eq!("BED_MESH_PROFILE LOAD=2\n", load_mesh(Flavor::Klipper, Some(2)));
+        assert_eq!("G29 S1\n", load_mesh(Flavor::RepRapFirmware, None));
+        assert_eq!("G29 S1 P\"heightmap2.csv\"\n", load_mesh(Flavor::RepRapFirmware, Some(2)));
+        assert_eq!("", load_mesh(Flavor::Grbl, None));
+    }
+
+    #[test]
+    fn test_set_probe_z_offset() {
+        assert_eq!("M851 Z-1.25\n", set_probe_z_offset(Flavor::Marlin, -1.25));
+        assert_eq!("G31 Z1.8\n", set_probe_z_offset(Flavor::RepRapFirmware, 1.8));
+        assert_eq!("", set_probe_z_offset(Flavor::Klipper, 1.8));
+    }
+
+    #[test]
+    fn test_parses_back() {
+        let cmd = parse_line(&auto_level(Flavor::Marlin, Some(grid()))).command.unwrap();
+        assert_eq!("G29", cmd.name);
+        assert_eq!(Some(15.5), cmd.get("F"));
+        assert_eq!(Some(4.0), cmd.get("Y"));
+    }
+
+    #[test]
+    fn test_leveling_render() {
+        assert_eq!("", Leveling::None.render(Flavor::Marlin));
+        assert_eq!("G29\n", Leveling::Probe(None).render(Flavor::Marlin));
+        assert_eq!("BED_MESH_PROFILE LOAD=default\n", Leveling::LoadSaved(None).render(Flavor::Klipper));
+    }
+}
+
+use crate::klipper;
+use crate::{Flavor, Point2d};
+
+/// The area probed when leveling and how many points are probed across it, in millimeters
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ProbeGrid {
+    /// Front left corner of the probed area
+    pub min: Point2d,
+    /// Back right corner of the probed area
+    pub max: Point2d,
+    pub points_x: u8,
+    pub points_y: u8,
+}
+
+/// How the bed is leveled before printing
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Leveling {
+    None,
+    /// Probes a new bed mesh, over the grid set in the firmware unless one is given, see
+    /// [auto_level]
+    Probe(Option<ProbeGrid>),
+    /// Turns on a mesh saved in the firmware, the default one unless a slot is given, see
+    /// [load_mesh]
+    LoadSaved(Option<u8>),
+}
+
+impl Leveling {
+    /// Returns the commands for this kind of leveling as a String, empty for [Leveling::None]
+    pub fn render(self, flavor: Flavor) -> String {
+        match self {
+            Leveling::None => String::new(),
+            Leveling::Probe(grid) => auto_level(flavor, grid),
+            Leveling::LoadSaved(slot) => load_mesh(flavor, slot),
+        }
+    }
+}
+
+/// Returns the commands probing a new bed mesh as a String, over the grid set in the firmware
+/// unless one is given
+///
+/// Marlin only honors the grid's point counts with linear leveling, other kinds of leveling use the
+/// counts set when the firmware was built. RepRapFirmware defines the grid with M557 first.
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::{Flavor, Point2d};
+/// use gen_gcode::leveling::{auto_level, ProbeGrid};
+///
+/// let grid = ProbeGrid { min: Point2d { x: 10.0, y: 10.0 }, max: Point2d { x: 200.0, y: 200.0 }, points_x: 5, points_y: 5 };
+/// let gcode = auto_level(Flavor::Klipper, Some(grid));
+/// assert_eq!("BED_MESH_CALIBRATE MESH_MIN=10,10 MESH_MAX=200,200 PROBE_COUNT=5,5\n", gcode);
+/// ```
+pub fn auto_level(flavor: Flavor, grid: Option<ProbeGrid>) -> String {
+    match (flavor, grid) {
+        (Flavor::Grbl, _) => String::new(),
+        (Flavor::Klipper, None) => klipper::bed_mesh_calibrate(None, false),
+        (Flavor::Marlin, None) | (Flavor::RepRapFirmware, None) => "G29\n".to_string(),
+        (Flavor::Marlin, Some(g)) => format!("G29 L{} R{} F{} B{} X{} Y{}\n", g.min.x, g.max.x, g.min.y, g.max.y, g.points_x, g.points_y),
+        (Flavor::Klipper, Some(g)) => {
+            format!("BED_MESH_CALIBRATE MESH_MIN={},{} MESH_MAX={},{} PROBE_COUNT={},{}\n", g.min.x, g.min.y, g.max.x, g.max.y, g.points_x, g.points_y)
+        }
+        (Flavor::RepRapFirmware, Some(g)) => {
+            format!("M557 X{}:{} Y{}:{} P{}:{}\nG29 S0\n", g.min.x, g.max.x, g.min.y, g.max.y, g.points_x, g.points_y)
+        }
+    }
+}
+
+/// Returns the commands probing the bed height at a single point as a String
+///
+/// Klipper only probes below the nozzle, so it moves there first.
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::{Flavor, Point2d};
+/// use gen_gcode::leveling::probe_point;
+///
+/// let gcode = probe_point(Flavor::Marlin, Point2d { x: 100.0, y: 100.0 });
+/// assert_eq!("G30 X100 Y100\n", gcode);
+/// ```
+pub fn probe_point(flavor: Flavor, point: Point2d) -> String {
+    match flavor {
+        Flavor::Marlin => format!("G30 X{} Y{}\n", point.x, point.y),
+        Flavor::Klipper => format!("G0 X{} Y{}\nPROBE\n", point.x, point.y),
+        Flavor::RepRapFirmware => format!("G30 P0 X{} Y{} Z-99999 S-1\n", point.x, point.y),
+        Flavor::Grbl => String::new(),
+    }
+}
+
+/// Returns the command turning on a bed mesh saved in the firmware as a String, the default mesh
+/// unless a slot is given
+///
+/// Slots are Marlin's EEPROM mesh slots, Klipper's profile of that name and RepRapFirmware's
+/// `heightmap<slot>.csv`.
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::Flavor;
+/// use gen_gcode::leveling::load_mesh;
+///
+/// let gcode = load_mesh(Flavor::Marlin, None);
+/// assert_eq!("M420 S1\n", gcode);
+/// ```
+pub fn load_mesh(flavor: Flavor, slot: Option<u8>) -> String {
+    match (flavor, slot) {
+        (Flavor::Marlin, None) => "M420 S1\n".to_string(),
+        (Flavor::Marlin, Some(slot)) => format!("M420 S1 L{}\n", slot),
+        (Flavor::Klipper, None) => "BED_MESH_PROFILE LOAD=default\n".to_string(),
+        (Flavor::Klipper, Some(slot)) => format!("BED_MESH_PROFILE LOAD={}\n", slot),
+        (Flavor::RepRapFirmware, None) => "G29 S1\n".to_string(),
+        (Flavor::RepRapFirmware, Some(slot)) => format!("G29 S1 P\"heightmap{}.csv\"\n", slot),
+        (Flavor::Grbl, _) => String::new(),
+    }
+}
+
+/// Returns the command setting the Z offset between the probe's trigger point and the nozzle as a
+/// String, in millimeters
+///
+/// Klipper only reads the offset from its configuration, see `PROBE_CALIBRATE`, so nothing is
+/// returned for it.
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::Flavor;
+/// use gen_gcode::leveling::set_probe_z_offset;
+///
+/// let gcode = set_probe_z_offset(Flavor::Marlin, -1.5);
+/// assert_eq!("M851 Z-1.5\n", gcode);
+/// ```
+pub fn set_probe_z_offset(flavor: Flavor, offset: f32) -> String {
+    match flavor {
+        Flavor::Marlin => format!("M851 Z{}\n", offset),
+        Flavor::RepRapFirmware => format!("G31 Z{}\n", offset),
+        Flavor::Klipper | Flavor::Grbl => String::new(),
+    }
+}
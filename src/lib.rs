@@ -1,5 +1,33 @@
 //! Generate G-Code with funcational operation describing motion of the machine that the created gcode should produce
 
+pub mod bgcode;
+pub mod calibration;
+pub mod estimator;
+pub mod framing;
+pub mod grbl;
+pub mod homing;
+#[cfg(any(feature = "octoprint", feature = "moonraker"))]
+mod http;
+pub mod klipper;
+pub mod leveling;
+pub mod machine;
+pub mod meatpack;
+pub mod metadata;
+pub mod motion;
+#[cfg(feature = "moonraker")]
+pub mod moonraker;
+#[cfg(feature = "octoprint")]
+pub mod octoprint;
+pub mod parser;
+pub mod program;
+pub mod response;
+pub mod sender;
+pub mod sequence;
+pub mod simulator;
+pub mod thermal;
+pub mod thumbnail;
+pub mod wipe_tower;
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -251,6 +279,48 @@ mod tests {
         assert_eq!("G21\n", gcode);
     }
 
+    #[test]
+    fn test_select_tool() {
+        assert_eq!("T0\n", select_tool(0));
+        assert_eq!("T3\n", select_tool(3));
+    }
+
+    #[test]
+    fn test_set_tool_offset() {
+        let gcode = set_tool_offset(2, Point3d { x: -10.0, y: 0.0, z: 0.25 });
+        assert_eq!("M218 T2 X-10 Y0 Z0.25\n", gcode);
+    }
+
+    #[test]
+    fn test_disable_motors() {
+        assert_eq!("M84\n", disable_motors());
+    }
+
+    #[test]
+    fn test_comment() {
+        let gcode = comment("first layer");
+        assert_eq!("; first layer\n", gcode);
+    }
+
+    #[test]
+    fn test_add_comment() {
+        let gcode = add_comment(move_z(0.2), "first layer");
+        assert_eq!("G0 Z0.2 ; first layer\n", gcode);
+    }
+
+    #[test]
+    fn test_section_marker() {
+        let gcode = section_marker("LAYER", 3);
+        assert_eq!(";LAYER:3\n", gcode);
+    }
+
+    #[test]
+    fn test_comment_style() {
+        assert_eq!(CommentStyle::Parentheses, Flavor::Grbl.comment_style());
+        assert_eq!(CommentStyle::Semicolon, Flavor::Klipper.comment_style());
+        assert_eq!("; two lines", CommentStyle::Semicolon.format("two\nlines"));
+    }
+
 }
 
 
@@ -267,7 +337,7 @@ mod tests {
 /// let p4 = Point2d { x: 0.0, y: 10.0 };
 /// let square: Vec<Point2d> = vec!(p1, p2, p3, p4);
 /// ```
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Point2d {
     pub x: f32,
     pub y: f32,
@@ -290,7 +360,7 @@ pub struct Point2d {
 /// let p8 = Point3d { x: 0.0, y: 0.0, z: 10.0 };
 /// let cube: Vec<Point3d> = vec!(p1, p2, p3, p4, p5, p6, p7, p8);
 /// ```
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Point3d {
     pub x: f32,
     pub y: f32,
@@ -326,15 +396,15 @@ pub fn move_xy(dest:Point2d, feed_rate: Option<u32>, flow_rate: Option<f32>) ->
     if let Some(maybe_feed_rate) = feed_rate {
         f_str = format!(" F{}", maybe_feed_rate);
     } else {
-        f_str = format!("");
+        f_str = String::new();
     }
     
     
     if let Some(maybe_flow_rate) = flow_rate {
         e_str = format!(" E{}", maybe_flow_rate);
-        return format!("G1 X{x} Y{y}{e}{f}\n", x=dest.x, y=dest.y, e=e_str, f=f_str)
+        format!("G1 X{x} Y{y}{e}{f}\n", x=dest.x, y=dest.y, e=e_str, f=f_str)
     } else {
-        return format!("G0 X{x} Y{y}{f}\n", x=dest.x, y=dest.y, f=f_str)
+        format!("G0 X{x} Y{y}{f}\n", x=dest.x, y=dest.y, f=f_str)
     }
 
     
@@ -370,14 +440,14 @@ pub fn move_xyz(dest:Point3d, feed_rate: Option<u32>, flow_rate: Option<f32>) ->
     if let Some(maybe_feed_rate) = feed_rate {
         f_str = format!(" F{}", maybe_feed_rate);
     } else {
-        f_str = format!("");
+        f_str = String::new();
     }
     
     if let Some(maybe_flow_rate) = flow_rate {
         e_str = format!(" E{}", maybe_flow_rate);
-        return format!("G1 X{x} Y{y} Z{z}{e}{f}\n", x=dest.x, y=dest.y, z=dest.z, e=e_str, f=f_str)
+        format!("G1 X{x} Y{y} Z{z}{e}{f}\n", x=dest.x, y=dest.y, z=dest.z, e=e_str, f=f_str)
     } else {
-        return format!("G0 X{x} Y{y} Z{z}{f}\n", x=dest.x, y=dest.y, z=dest.z, f=f_str)
+        format!("G0 X{x} Y{y} Z{z}{f}\n", x=dest.x, y=dest.y, z=dest.z, f=f_str)
     }
 
 }
@@ -394,7 +464,7 @@ pub fn move_xyz(dest:Point3d, feed_rate: Option<u32>, flow_rate: Option<f32>) ->
 /// assert_eq!("G0 Z1.8\n", gcode);
 /// ```
 pub fn move_z(z: f32) -> String {
-    return format!("G0 Z{}\n", z)
+    format!("G0 Z{}\n", z)
 }
 
 /// Returns a G2 or G3 command as a String
@@ -431,28 +501,70 @@ pub fn move_xy_arc_ij(dest: Option<Point2d>, x_offset: Option<f32>, y_offset: Op
         x_str = format!(" X{}", maybe_dest.x);
         y_str = format!(" Y{}", maybe_dest.y);
     } else {
-        x_str = format!("");
-        y_str = format!("");
+        x_str = String::new();
+        y_str = String::new();
     }
     if let Some(maybe_x_offset) = x_offset {
         i_str = format!(" I{}", maybe_x_offset);
     } else {
-        i_str = format!("");
+        i_str = String::new();
     }
     if let Some(maybe_y_offset) = y_offset {
         j_str = format!(" J{}", maybe_y_offset);
     } else {
-        j_str = format!("");
+        j_str = String::new();
     }
     if let Some(maybe_flow_rate) = flow_rate {
         e_str = format!(" E{}", maybe_flow_rate);
     } else {
-        e_str = format!("");
+        e_str = String::new();
     }
     if ccw {
-        return format!("G3{x}{y}{i}{j}{e}\n", i=i_str, j=j_str, x=x_str, y=y_str, e=e_str);
+        format!("G3{x}{y}{i}{j}{e}\n", i=i_str, j=j_str, x=x_str, y=y_str, e=e_str)
     } else {
-        return format!("G2{x}{y}{i}{j}{e}\n", i=i_str, j=j_str, x=x_str, y=y_str, e=e_str);
+        format!("G2{x}{y}{i}{j}{e}\n", i=i_str, j=j_str, x=x_str, y=y_str, e=e_str)
+    }
+}
+
+/// Units of length a program is written in, set with [use_millimeters] and [use_inches]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Units {
+    Millimeters,
+    Inches,
+}
+
+impl Units {
+    /// Converts a length in these units to millimeters
+    ///
+    /// # Examples
+    /// ```
+    /// extern crate gen_gcode;
+    /// use gen_gcode::Units;
+    ///
+    /// assert_eq!(25.4, Units::Inches.to_mm(1.0));
+    /// assert_eq!(1.0, Units::Millimeters.to_mm(1.0));
+    /// ```
+    pub fn to_mm(self, value: f32) -> f32 {
+        match self {
+            Units::Millimeters => value,
+            Units::Inches => value * 25.4,
+        }
+    }
+
+    /// Converts a length in millimeters to these units
+    ///
+    /// # Examples
+    /// ```
+    /// extern crate gen_gcode;
+    /// use gen_gcode::Units;
+    ///
+    /// assert_eq!(2.0, Units::Inches.from_mm(50.8));
+    /// ```
+    pub fn from_mm(self, value: f32) -> f32 {
+        match self {
+            Units::Millimeters => value,
+            Units::Inches => value / 25.4,
+        }
     }
 }
 
@@ -469,7 +581,7 @@ pub fn move_xy_arc_ij(dest: Option<Point2d>, x_offset: Option<f32>, y_offset: Op
 /// assert_eq!("G21\n", gcode);
 /// ```
 pub fn use_millimeters() -> String {
-    return format!("G21\n")
+    "G21\n".to_string()
 }
 
 /// Returns a G20 command as a String
@@ -485,7 +597,83 @@ pub fn use_millimeters() -> String {
 /// assert_eq!("G20\n", gcode);
 /// ```
 pub fn use_inches() -> String {
-    return format!("G20\n")
+    "G20\n".to_string()
+}
+
+/// Whether axis positions are absolute (G90) or relative to the current position (G91), set with
+/// [absolute_positioning] and [relative_positioning]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Positioning {
+    Absolute,
+    Relative,
+}
+
+/// Firmware a program is written for, deciding how commands that differ between firmwares, such as
+/// object labels, are emitted
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub enum Flavor {
+    #[default]
+    Marlin,
+    Klipper,
+    RepRapFirmware,
+    Grbl,
+}
+
+impl Flavor {
+    /// Returns the comment style the firmware expects, Grbl follows the NIST standard and only
+    /// reliably understands `( )` comments
+    pub fn comment_style(self) -> CommentStyle {
+        match self {
+            Flavor::Grbl => CommentStyle::Parentheses,
+            _ => CommentStyle::Semicolon,
+        }
+    }
+}
+
+/// How comments are written, either to the end of the line after a `;` or between `( )`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CommentStyle {
+    Semicolon,
+    Parentheses,
+}
+
+impl CommentStyle {
+    /// Returns a comment in this style, without a line ending. Line breaks are replaced by spaces,
+    /// and parentheses by brackets in the `( )` style, as comments cannot be nested.
+    /// 
+    /// # Examples
+    /// ```
+    /// extern crate gen_gcode;
+    /// use gen_gcode::CommentStyle;
+    /// 
+    /// assert_eq!("; outer wall", CommentStyle::Semicolon.format("outer wall"));
+    /// assert_eq!("(outer wall [1])", CommentStyle::Parentheses.format("outer wall (1)"));
+    /// ```
+    pub fn format(self, text: &str) -> String {
+        let text = text.replace(['\r', '\n'], " ");
+        match self {
+            CommentStyle::Semicolon => format!("; {}", text),
+            CommentStyle::Parentheses => format!("({})", text.replace('(', "[").replace(')', "]")),
+        }
+    }
+
+    /// Returns a section marker such as `;LAYER:3` in this style, without a line ending
+    /// 
+    /// # Examples
+    /// ```
+    /// extern crate gen_gcode;
+    /// use gen_gcode::CommentStyle;
+    /// 
+    /// assert_eq!(";TYPE:WALL-OUTER", CommentStyle::Semicolon.marker("TYPE", "WALL-OUTER"));
+    /// assert_eq!("(TYPE:WALL-OUTER)", CommentStyle::Parentheses.marker("TYPE", "WALL-OUTER"));
+    /// ```
+    pub fn marker(self, key: &str, value: &str) -> String {
+        let marker = format!("{}:{}", key, value);
+        match self {
+            CommentStyle::Semicolon => format!(";{}", marker),
+            CommentStyle::Parentheses => self.format(&marker),
+        }
+    }
 }
 
 /// Returns a G90 command as a String
@@ -501,7 +689,7 @@ pub fn use_inches() -> String {
 /// assert_eq!("G90\n", gcode);
 /// ```
 pub fn absolute_positioning() -> String {
-    return format!("G90\n")
+    "G90\n".to_string()
 }
 
 /// Returns a G91 command as a String
@@ -517,7 +705,7 @@ pub fn absolute_positioning() -> String {
 /// assert_eq!("G91\n", gcode);
 /// ```
 pub fn relative_positioning() -> String {
-    return format!("G91\n")
+    "G91\n".to_string()
 }
 
 /// Returns a G92 command to set the current nozzle/tool possition in the XY plane as a String
@@ -536,9 +724,9 @@ pub fn set_pos_2d(pos: Point2d, extrude_pos: Option<f32>) -> String {
     if let Some(maybe_extrude_pos) = extrude_pos {
         e_str = format!(" E{}", maybe_extrude_pos);
     } else {
-        e_str = format!("");
+        e_str = String::new();
     }
-    return format!("G92 X{x} Y{y}{e}\n", x=pos.x, y=pos.y, e=e_str)
+    format!("G92 X{x} Y{y}{e}\n", x=pos.x, y=pos.y, e=e_str)
 }
 
 /// Returns a G92 command to set the current nozzle/tool possition in 3 dimentions (XYZ) as a String
@@ -557,9 +745,9 @@ pub fn set_pos_3d(pos: Point3d, extrude_pos: Option<f32>) -> String {
     if let Some(maybe_extrude_pos) = extrude_pos {
         e_str = format!(" E{}", maybe_extrude_pos);
     } else {
-        e_str = format!("");
+        e_str = String::new();
     }
-    return format!("G92 X{x} Y{y} Z{z}{e}\n", x=pos.x, y=pos.y, z=pos.z, e=e_str)
+    format!("G92 X{x} Y{y} Z{z}{e}\n", x=pos.x, y=pos.y, z=pos.z, e=e_str)
 }
 
 /// Returns a G92 command to set the extruder possition (E axis) as a string
@@ -573,7 +761,7 @@ pub fn set_pos_3d(pos: Point3d, extrude_pos: Option<f32>) -> String {
 /// assert_eq!("G92 E0\n", gcode);
 /// ```
 pub fn reset_extruder(extrude_pos: f32) -> String {
-    return format!("G92 E{}\n", extrude_pos)
+    format!("G92 E{}\n", extrude_pos)
 }
 
 /// Returns a G92.1 command to reset to machine's native possitioning offsets as a String
@@ -587,7 +775,7 @@ pub fn reset_extruder(extrude_pos: f32) -> String {
 /// assert_eq!("G92.1\n", gcode);
 /// ```
 pub fn reset_pos() -> String {
-    return format!("G92.1\n")
+    "G92.1\n".to_string()
 }
 
 
@@ -615,9 +803,9 @@ pub fn set_hotend_temp(temp: u16, hotend: Option<u8>) -> String {
     if let Some(maybe_hotend) = hotend {
         t_str = format!(" T{}", maybe_hotend);
     } else {
-        t_str = format!("");
+        t_str = String::new();
     }
-    return format!("M104 S{s}{t}\n", s=temp, t=t_str)
+    format!("M104 S{s}{t}\n", s=temp, t=t_str)
 }
 
 /// Returns a M109 command to set target hotend temp to wait to reach as a String
@@ -644,9 +832,37 @@ pub fn wait_hotend_temp(temp: u16, hotend: Option<u8>) -> String {
     if let Some(maybe_hotend) = hotend {
         t_str = format!(" T{}", maybe_hotend);
     } else {
-        t_str = format!("");
+        t_str = String::new();
     }
-    return format!("M109 S{s}{t}\n", s=temp, t=t_str)
+    format!("M109 S{s}{t}\n", s=temp, t=t_str)
+}
+
+/// Returns a T command to select the tool, or extruder, used by following moves as a String
+/// 
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::select_tool;
+/// 
+/// let gcode = select_tool(1);
+/// assert_eq!("T1\n", gcode);
+/// ```
+pub fn select_tool(tool: u8) -> String {
+    format!("T{}\n", tool)
+}
+
+/// Returns a M218 command to set the offset of a tool's nozzle from the first tool's, as a String
+/// 
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::{Point3d, set_tool_offset};
+/// 
+/// let gcode = set_tool_offset(1, Point3d { x: 25.0, y: -0.5, z: 0.1 });
+/// assert_eq!("M218 T1 X25 Y-0.5 Z0.1\n", gcode);
+/// ```
+pub fn set_tool_offset(tool: u8, offset: Point3d) -> String {
+    format!("M218 T{t} X{x} Y{y} Z{z}\n", t=tool, x=offset.x, y=offset.y, z=offset.z)
 }
 
 /// Returns a M106 command to set the fan speed, with optional fan index, as a String
@@ -674,9 +890,9 @@ pub fn set_fan_speed(speed: u8, fan: Option<u8>) -> String {
     if let Some(maybe_fan) = fan {
         p_str = format!(" P{}", maybe_fan);
     } else {
-        p_str = format!("");
+        p_str = String::new();
     }
-    return format!("M106 S{s}{p}\n", s=speed, p=p_str)
+    format!("M106 S{s}{p}\n", s=speed, p=p_str)
 }
 
 /// Returns a M107 command to disable the fan, with optional fan index, as a String
@@ -702,9 +918,9 @@ pub fn fan_off(fan: Option<u8>) -> String {
     if let Some(maybe_fan) = fan {
         p_str = format!(" P{}", maybe_fan);
     } else {
-        p_str = format!("");
+        p_str = String::new();
     }
-    return format!("M107{p}\n", p=p_str)
+    format!("M107{p}\n", p=p_str)
 }
 
 /// Returns a M140 command to set bed hotend temp as a String
@@ -718,7 +934,7 @@ pub fn fan_off(fan: Option<u8>) -> String {
 /// assert_eq!("M140 S210\n", gcode);
 /// ```
 pub fn set_bed_temp(temp: u8) -> String {
-    return format!("M140 S{}\n", temp)
+    format!("M140 S{}\n", temp)
 }
 
 /// Returns a M190 command to set target bed temp to wait to reach as a String
@@ -732,7 +948,7 @@ pub fn set_bed_temp(temp: u8) -> String {
 /// assert_eq!("M190 S210\n", gcode);
 /// ```
 pub fn wait_bed_temp(temp: u8) -> String {
-    return format!("M190 S{}\n", temp)
+    format!("M190 S{}\n", temp)
 }
 
 /// Returns a M141 command to set target chamber temp as a String
@@ -746,7 +962,7 @@ pub fn wait_bed_temp(temp: u8) -> String {
 /// assert_eq!("M141 S50\n", gcode);
 /// ```
 pub fn set_chamber_temp(temp: u8) -> String {
-    return format!("M141 S{}\n", temp)
+    format!("M141 S{}\n", temp)
 }
 
 
@@ -761,7 +977,7 @@ pub fn set_chamber_temp(temp: u8) -> String {
 /// assert_eq!("M191 S50\n", gcode);
 /// ```
 pub fn wait_chamber_temp(temp: u8) -> String {
-    return format!("M191 S{}\n", temp)
+    format!("M191 S{}\n", temp)
 }
 
 /// Returns a G28 command to trigger autohome procedure, using default parameters set in machine firmware, as a String
@@ -775,7 +991,7 @@ pub fn wait_chamber_temp(temp: u8) -> String {
 /// assert_eq!("G28\n", gcode);
 /// ```
 pub fn auto_home() -> String {
-    return format!("G28\n")
+    "G28\n".to_string()
 }
 
 /// Returns a M82 command to set the extruder axis to absolute mode, independant of other axes, as a String
@@ -788,7 +1004,7 @@ pub fn auto_home() -> String {
 /// let gcode = absolute_extrution();
 /// assert_eq!("M82\n", gcode);
 pub fn absolute_extrution() -> String {
-    return format!("M82\n")
+    "M82\n".to_string()
 }
 
 /// Returns a M83 command to set the extruder axis to relative mode, independant of other axes, as a String
@@ -801,5 +1017,64 @@ pub fn absolute_extrution() -> String {
 /// let gcode = relative_extrution();
 /// assert_eq!("M83\n", gcode);
 pub fn relative_extrution() -> String {
-    return format!("M83\n")
-}
\ No newline at end of file
+    "M83\n".to_string()
+}
+
+/// Returns a M84 command to turn off the stepper motors, letting the axes move freely, as a String
+/// 
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::disable_motors;
+/// 
+/// let gcode = disable_motors();
+/// assert_eq!("M84\n", gcode);
+/// ```
+pub fn disable_motors() -> String {
+    "M84\n".to_string()
+}
+
+/// Returns a line holding only a comment as a String
+/// 
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::comment;
+/// 
+/// let gcode = comment("brim");
+/// assert_eq!("; brim\n", gcode);
+/// ```
+pub fn comment(text: &str) -> String {
+    format!("{}\n", CommentStyle::Semicolon.format(text))
+}
+
+/// Returns a command with a trailing comment as a String
+/// 
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::{add_comment, auto_home};
+/// 
+/// let gcode = add_comment(auto_home(), "home all axes");
+/// assert_eq!("G28 ; home all axes\n", gcode);
+/// ```
+pub fn add_comment(gcode: String, text: &str) -> String {
+    format!("{} {}\n", gcode.trim_end_matches('\n'), CommentStyle::Semicolon.format(text))
+}
+
+/// Returns a section marker, as used by slicers to tell printer interfaces about layers and the
+/// type of what is being printed, as a String
+/// 
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::section_marker;
+/// 
+/// let gcode = section_marker("LAYER", 3);
+/// assert_eq!(";LAYER:3\n", gcode);
+/// let gcode = section_marker("TYPE", "WALL-OUTER");
+/// assert_eq!(";TYPE:WALL-OUTER\n", gcode);
+/// ```
+pub fn section_marker<T: std::fmt::Display>(key: &str, value: T) -> String {
+    format!("{}\n", CommentStyle::Semicolon.marker(key, &value.to_string()))
+}
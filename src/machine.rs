@@ -0,0 +1,105 @@
+//! Machine profiles, describing the tools a printer has and how to switch between them
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tool_lookup() {
+        let profile = MachineProfile { tools: vec![Tool::new(210), Tool { standby_temperature: Some(170), ..Tool::new(240) }], ..MachineProfile::default() };
+        assert_eq!(Some(240), profile.tool(1).map(|t| t.temperature));
+        assert_eq!(Some(170), profile.tool(1).and_then(|t| t.standby_temperature));
+        assert_eq!(None, profile.tool(2));
+    }
+}
+
+use crate::{Point2d, Point3d};
+
+/// Highest number of hotends a machine is expected to have, tool numbers past it are taken as
+/// mistakes by the simulator and the firmware response parser
+pub const MAX_HOTENDS: usize = 16;
+/// Highest number of fans a machine is expected to have, fan numbers past it are taken as mistakes
+/// by the simulator
+pub const MAX_FANS: usize = 16;
+
+/// A hotend and the extruder feeding it
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Tool {
+    /// Position of the nozzle relative to the first tool's nozzle, in millimeters
+    pub offset: Point3d,
+    /// Printing temperature
+    pub temperature: u16,
+    /// Temperature the hotend is kept at while other tools print, `None` to leave it at its
+    /// printing temperature
+    pub standby_temperature: Option<u16>,
+}
+
+impl Tool {
+    /// Creates a tool without an offset, kept at its printing temperature when not in use
+    pub fn new(temperature: u16) -> Self {
+        Tool { offset: Point3d { x: 0.0, y: 0.0, z: 0.0 }, temperature, standby_temperature: None }
+    }
+}
+
+/// Steps taken to switch tools in the middle of a program, lengths are in millimeters and speeds
+/// in millimeters per minute
+///
+/// The old tool retracts, the nozzle lifts and moves to the parking spot, the old tool drops to
+/// its standby temperature, then the new tool is selected, heated and primed before the nozzle
+/// goes back to where it was.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ToolChange {
+    /// Filament pulled back into the old tool
+    pub retract: f32,
+    pub retract_speed: f32,
+    /// Height the nozzle is lifted by before moving away
+    pub z_hop: f32,
+    /// Where to switch tools, `None` to switch in place
+    pub park: Option<Point2d>,
+    pub travel_speed: f32,
+    /// Filament pushed through the new tool before printing with it
+    pub prime: f32,
+    pub prime_speed: f32,
+    /// Whether to wait for the new tool to reach its printing temperature
+    pub wait_for_temperature: bool,
+}
+
+impl Default for ToolChange {
+    fn default() -> Self {
+        ToolChange { retract: 2.0, retract_speed: 2100.0, z_hop: 0.4, park: None, travel_speed: 6000.0, prime: 2.0, prime_speed: 300.0, wait_for_temperature: true }
+    }
+}
+
+/// How filament is pulled back from the nozzle over travels, so it doesn't ooze, lengths are in
+/// millimeters and speeds in millimeters per minute
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Retraction {
+    /// Filament pulled back, and pushed back once the travel is over
+    pub length: f32,
+    pub speed: f32,
+    pub prime_speed: f32,
+    /// Height the nozzle is lifted by while travelling, 0 to travel at the layer's height
+    pub z_hop: f32,
+}
+
+impl Default for Retraction {
+    fn default() -> Self {
+        Retraction { length: 0.8, speed: 2100.0, prime_speed: 2100.0, z_hop: 0.0 }
+    }
+}
+
+/// The tools of a printer, indexed by tool number, how to switch between them and how they
+/// retract over travels
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MachineProfile {
+    pub tools: Vec<Tool>,
+    pub tool_change: ToolChange,
+    pub retraction: Retraction,
+}
+
+impl MachineProfile {
+    /// Returns a tool by number, if the profile has it
+    pub fn tool(&self, tool: u8) -> Option<&Tool> {
+        self.tools.get(tool as usize)
+    }
+}
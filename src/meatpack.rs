@@ -0,0 +1,393 @@
+//! MeatPack, which packs the most common G-Code characters two to a byte
+//!
+//! Digits, `.`, space, newline, `G` and `X` are sent as 4 bit codes, so a typical move takes a
+//! little over half the bytes. Other characters are sent as they are after their packed pair.
+//! Packing is switched on and off with signals, see [Signal]. Marlin and Prusa firmware unpack it
+//! on the fly, and Prusa binary G-Code can store G-Code blocks packed, see [crate::bgcode].
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(line: &str, no_spaces: bool) -> String {
+        let mut decoder = Decoder::new();
+        let mut packed = Signal::EnablePacking.bytes().to_vec();
+        if no_spaces {
+            packed.extend_from_slice(&Signal::EnableNoSpaces.bytes());
+        }
+        packed.extend(pack_line(line, no_spaces));
+        decoder.feed(&packed)
+    }
+
+    #[test]
+    fn test_pack_line() {
+        // "G1" "\n" and a pad nibble
+        assert_eq!(vec![0x1D, 0xBC], pack_line("G1", false));
+        // 'M' is sent as is after its pair
+        assert_eq!(vec![0x1F, b'M', 0x04, 0xBC], pack_line("M140", false));
+        assert_eq!(vec![0xFF, b'M', b'Y', 0xC1], pack_line("MY1", false));
+    }
+
+    #[test]
+    fn test_packs_moves_smaller() {
+        let line = "G1 X125.25 Y30.1 E2.56";
+        let packed = pack_line(line, true);
+        assert!(packed.len() * 10 < line.len() * 7, "{:?}", packed);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        assert_eq!("G1 X125.25 Y30.1 E2.56\n", round_trip("G1 X125.25 Y30.1 E2.56", false));
+        assert_eq!("G1X125.25Y30.1E2.56\n", round_trip("G1 X125.25 Y30.1 E2.56", true));
+        assert_eq!("M117 Hello there\n", round_trip("M117 Hello there", true));
+        assert_eq!("G28\n", round_trip("G28", true));
+    }
+
+    #[test]
+    fn test_decoder_passes_through_until_enabled() {
+        let mut decoder = Decoder::new();
+        assert_eq!("G28\n", decoder.feed(b"G28\n"));
+        let mut packed = Signal::EnablePacking.bytes().to_vec();
+        packed.extend(pack_line("G1 X1", false));
+        packed.extend_from_slice(&Signal::DisablePacking.bytes());
+        packed.extend_from_slice(b"M84\n");
+        assert_eq!("G1 X1\nM84\n", decoder.feed(&packed));
+    }
+
+    #[test]
+    fn test_encode_program() {
+        let mut p = crate::program::Program::new();
+        p.push(crate::auto_home()).comment("print a square");
+        for &(x, y) in [(10.0, 10.0), (60.25, 10.0), (60.25, 60.5), (10.0, 60.5)].iter() {
+            p.move_xy(crate::Point2d { x, y }, Some(1800.0), Some(1.25));
+        }
+        let program = p.render();
+        let commands: Vec<&str> = program.lines().filter(|l| !l.starts_with(';')).collect();
+        for &no_spaces in [false, true].iter() {
+            let encoder = Encoder::new(no_spaces);
+            let packed = encoder.encode(&program);
+            assert!(packed.len() * 10 < program.len() * 7, "{} {}", packed.len(), program.len());
+            let mut decoder = Decoder::new();
+            let decoded = decoder.feed(&packed);
+            assert!(!decoder.is_packing());
+            let expected: Vec<String> = commands.iter().map(|c| encoder.command(c)).collect();
+            assert_eq!(expected, decoded.lines().collect::<Vec<_>>());
+            let restored: Vec<String> = decoded.lines().map(restore_spaces).collect();
+            assert_eq!(commands, restored);
+        }
+        // plain text sent after packing is switched off reads as it is
+        let mut decoder = Decoder::new();
+        let mut stream = Encoder::new(true).encode("G28\n");
+        stream.extend_from_slice(b"M84 X\n");
+        assert_eq!("G28\nM84 X\n", decoder.feed(&stream));
+    }
+
+    #[test]
+    fn test_strip_spaces() {
+        assert_eq!(Some("G1X10Y-5.5E.3".to_string()), strip_spaces("G1 X10 Y-5.5 E.3"));
+        assert_eq!(Some("G28XY".to_string()), strip_spaces("G28 X Y"));
+        assert_eq!(None, strip_spaces("M117 Hello"));
+        assert_eq!(None, strip_spaces("G1 X1 ; move"));
+        assert_eq!(None, strip_spaces("RESTART"));
+    }
+}
+
+use crate::framing::strip_comments;
+
+/// The byte sent twice before a signal
+const SIGNAL_BYTE: u8 = 0xFF;
+/// The code of a character sent as is after its packed pair
+const FULL_WIDTH: u8 = 0b1111;
+
+/// Switches sent between packed G-Code, as [SIGNAL_BYTE] twice then the signal
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Signal {
+    EnablePacking = 0xFB,
+    DisablePacking = 0xFA,
+    /// Turns packing and no spaces off
+    ResetAll = 0xF9,
+    /// Asks the firmware to report whether packing is on
+    QueryConfig = 0xF8,
+    /// Drops spaces between words, sending `E` in their place
+    EnableNoSpaces = 0xF7,
+    DisableNoSpaces = 0xF6,
+}
+
+impl Signal {
+    /// Returns the bytes sent for this signal
+    ///
+    /// # Examples
+    /// ```
+    /// extern crate gen_gcode;
+    /// use gen_gcode::meatpack::Signal;
+    ///
+    /// assert_eq!([0xFF, 0xFF, 0xFB], Signal::EnablePacking.bytes());
+    /// ```
+    pub fn bytes(self) -> [u8; 3] {
+        [SIGNAL_BYTE, SIGNAL_BYTE, self as u8]
+    }
+}
+
+fn code(c: u8, no_spaces: bool) -> u8 {
+    match c {
+        b'0'..=b'9' => c - b'0',
+        b'.' => 10,
+        b' ' if !no_spaces => 11,
+        b'E' if no_spaces => 11,
+        b'\n' => 12,
+        b'G' => 13,
+        b'X' => 14,
+        _ => FULL_WIDTH,
+    }
+}
+
+fn character(code: u8, no_spaces: bool) -> u8 {
+    match code {
+        0..=9 => b'0' + code,
+        10 => b'.',
+        11 if no_spaces => b'E',
+        11 => b' ',
+        12 => b'\n',
+        13 => b'G',
+        _ => b'X',
+    }
+}
+
+fn is_number(c: char) -> bool {
+    c.is_ascii_digit() || c == '.' || c == '-' || c == '+'
+}
+
+/// Whether a line without spaces is a command such as `G1X10Y5`, a letter and a digit followed
+/// by letters and numbers
+fn is_packed_command(line: &str) -> bool {
+    let mut chars = line.chars();
+    chars.next().is_some_and(|c| c.is_ascii_uppercase()) && chars.next().is_some_and(|c| c.is_ascii_digit()) && chars.all(|c| c.is_ascii_uppercase() || is_number(c))
+}
+
+/// Returns a command without the spaces between its words, or `None` if some word is not a
+/// letter followed by a number, such as the text of `M117` or a comment, whose spaces matter
+pub fn strip_spaces(line: &str) -> Option<String> {
+    let is_word = |word: &str| {
+        let mut chars = word.chars();
+        chars.next().is_some_and(|c| c.is_ascii_uppercase()) && chars.all(is_number)
+    };
+    let stripped: String = line.split_whitespace().collect();
+    if line.split_whitespace().all(is_word) && is_packed_command(&stripped) {
+        Some(stripped)
+    } else {
+        None
+    }
+}
+
+/// Puts back the spaces dropped by [strip_spaces], leaving other lines as they are
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::meatpack::restore_spaces;
+///
+/// assert_eq!("G1 X10 Y5", restore_spaces("G1X10Y5"));
+/// assert_eq!("RESTART", restore_spaces("RESTART"));
+/// ```
+pub fn restore_spaces(line: &str) -> String {
+    if !is_packed_command(line) {
+        return line.to_string();
+    }
+    let mut out = String::with_capacity(line.len() + 8);
+    for (i, c) in line.char_indices() {
+        if i > 0 && c.is_ascii_uppercase() {
+            out.push(' ');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Packs one line of G-Code, adding its newline. With `no_spaces` the spaces are dropped from
+/// commands that can do without them, see [strip_spaces].
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::meatpack::pack_line;
+///
+/// assert_eq!(vec![0x1D, 0xBC], pack_line("G1", false));
+/// ```
+pub fn pack_line(line: &str, no_spaces: bool) -> Vec<u8> {
+    let line = line.trim_end();
+    let mut text = match strip_spaces(line) {
+        Some(stripped) if no_spaces => stripped.into_bytes(),
+        _ => line.as_bytes().to_vec(),
+    };
+    text.push(b'\n');
+    // firmware drops the character after a newline sent first in its pair
+    if text.len() % 2 == 1 {
+        text.push(b' ');
+    }
+    let mut out = Vec::with_capacity(text.len());
+    for pair in text.chunks(2) {
+        let (first, second) = (code(pair[0], no_spaces), code(pair[1], no_spaces));
+        let second = if pair[0] == b'\n' && second == FULL_WIDTH { 11 } else { second };
+        out.push(first | second << 4);
+        if first == FULL_WIDTH {
+            out.push(pair[0]);
+        }
+        if second == FULL_WIDTH {
+            out.push(pair[1]);
+        }
+    }
+    out
+}
+
+/// Packs the command stream sent to firmware, between the signals that switch packing on and off
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::meatpack::{Decoder, Encoder};
+///
+/// let packed = Encoder::new(true).encode("G28 ; home\nG1 X10 Y5 E0.5\n");
+/// assert_eq!("G28\nG1X10Y5E0.5\n", Decoder::new().feed(&packed));
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Encoder {
+    /// Whether spaces are dropped from commands, see [strip_spaces]
+    pub no_spaces: bool,
+}
+
+impl Encoder {
+    pub fn new(no_spaces: bool) -> Self {
+        Encoder { no_spaces }
+    }
+
+    /// Returns the signals that switch packing on, to send before any packed line
+    pub fn enable(&self) -> Vec<u8> {
+        let spaces = if self.no_spaces { Signal::EnableNoSpaces } else { Signal::DisableNoSpaces };
+        [Signal::EnablePacking.bytes(), spaces.bytes()].concat()
+    }
+
+    /// Returns the signals that switch packing off, after which firmware reads plain text again
+    pub fn disable(&self) -> Vec<u8> {
+        [Signal::DisablePacking.bytes(), Signal::DisableNoSpaces.bytes()].concat()
+    }
+
+    /// Returns a command as it will be unpacked by the firmware, without spaces when possible.
+    /// Line numbers and checksums must be worked out on this form, see [crate::framing].
+    pub fn command(&self, command: &str) -> String {
+        match strip_spaces(command) {
+            Some(stripped) if self.no_spaces => stripped,
+            _ => command.trim().to_string(),
+        }
+    }
+
+    /// Packs one line, see [pack_line]
+    pub fn pack(&self, line: &str) -> Vec<u8> {
+        pack_line(line, self.no_spaces)
+    }
+
+    /// Packs every command of a program, skipping comments and blank lines, between the enable
+    /// and disable signals
+    pub fn encode(&self, program: &str) -> Vec<u8> {
+        let mut out = self.enable();
+        for line in program.lines() {
+            let command = strip_comments(line);
+            if !command.is_empty() {
+                out.extend(self.pack(&command));
+            }
+        }
+        out.extend(self.disable());
+        out
+    }
+}
+
+/// Unpacks a stream the way firmware does, passing bytes through untouched until packing is
+/// enabled
+#[derive(Debug, Default)]
+pub struct Decoder {
+    packing: bool,
+    no_spaces: bool,
+    /// Number of signal bytes in a row
+    signal_bytes: u8,
+    /// Characters still to come as they are, and a packed one to add after them
+    full_width: u8,
+    pending: Option<u8>,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder::default()
+    }
+
+    /// Whether packing is currently enabled
+    pub fn is_packing(&self) -> bool {
+        self.packing
+    }
+
+    /// Unpacks the next bytes of the stream, returning the text they hold
+    pub fn feed(&mut self, bytes: &[u8]) -> String {
+        let mut out = Vec::new();
+        for &b in bytes {
+            match self.signal_bytes {
+                2 => {
+                    self.signal(b);
+                    self.signal_bytes = 0;
+                }
+                _ if b == SIGNAL_BYTE && self.full_width == 0 => self.signal_bytes += 1,
+                1 => {
+                    // a lone signal byte is a packed pair of full width characters
+                    self.signal_bytes = 0;
+                    self.unpack(SIGNAL_BYTE, &mut out);
+                    self.unpack(b, &mut out);
+                }
+                _ => self.unpack(b, &mut out),
+            }
+        }
+        String::from_utf8_lossy(&out).into_owned()
+    }
+
+    fn signal(&mut self, signal: u8) {
+        match signal {
+            s if s == Signal::EnablePacking as u8 => self.packing = true,
+            s if s == Signal::DisablePacking as u8 => self.packing = false,
+            s if s == Signal::ResetAll as u8 => {
+                self.packing = false;
+                self.no_spaces = false;
+            }
+            s if s == Signal::EnableNoSpaces as u8 => self.no_spaces = true,
+            s if s == Signal::DisableNoSpaces as u8 => self.no_spaces = false,
+            _ => (),
+        }
+    }
+
+    fn unpack(&mut self, b: u8, out: &mut Vec<u8>) {
+        if !self.packing {
+            out.push(b);
+        } else if self.full_width > 0 {
+            out.push(b);
+            self.full_width -= 1;
+            if self.full_width == 0 {
+                out.extend(self.pending.take());
+            }
+        } else {
+            let (first, second) = (b & 0x0F, b >> 4);
+            if first == FULL_WIDTH {
+                self.full_width = 1;
+                if second == FULL_WIDTH {
+                    self.full_width = 2;
+                } else {
+                    self.pending = Some(character(second, self.no_spaces));
+                }
+                return;
+            }
+            let first = character(first, self.no_spaces);
+            out.push(first);
+            if first != b'\n' {
+                if second == FULL_WIDTH {
+                    self.full_width = 1;
+                } else {
+                    out.push(character(second, self.no_spaces));
+                }
+            }
+        }
+    }
+}
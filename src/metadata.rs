@@ -0,0 +1,206 @@
+//! Metadata comments read by printer interfaces, in the formats written by Cura and PrusaSlicer
+//!
+//! Printer screens, OctoPrint and Moonraker look for comments such as `;TIME:`, `;FLAVOR:` and
+//! `;LAYER:` to show print time, progress and layers without simulating the program themselves.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    fn two_layers() -> String {
+        let mut program = format!("{}{}", use_millimeters(), auto_home());
+        for (n, z) in [0.2_f32, 0.4].iter().enumerate() {
+            program += &move_z(*z);
+            program += &move_xy(Point2d { x: 10.0, y: 20.0 }, Some(6000), None);
+            program += &move_xy(Point2d { x: 110.0, y: 20.0 }, Some(1200), Some(5.0 * (n + 1) as f32));
+            program += &move_xy(Point2d { x: 110.0, y: 40.0 }, None, Some(6.0 * (n + 1) as f32));
+        }
+        program
+    }
+
+    #[test]
+    fn test_from_program() {
+        let meta = Metadata::from_program(&two_layers(), Flavor::Marlin);
+        assert_eq!(Point3d { x: 10.0, y: 20.0, z: 0.2 }, meta.min);
+        assert_eq!(Point3d { x: 110.0, y: 40.0, z: 0.4 }, meta.max);
+        assert_eq!(12.0, meta.filament_used);
+        assert_eq!(2, meta.layer_count);
+        assert!(meta.estimated_time > 10.0);
+    }
+
+    #[test]
+    fn test_bounds_include_arcs() {
+        let mut program = format!("{}{}", move_xyz(Point3d { x: 10.0, y: 0.0, z: 0.2 }, None, None), use_inches());
+        program += &move_xy_arc_ij(None, Some(-0.5), None, Some(1.0), true);
+        let meta = Metadata::from_program(&program, Flavor::Marlin);
+        assert!((meta.min.x - (10.0 - 25.4)).abs() < 0.01, "{:?}", meta.min);
+        assert!((meta.max.y - 12.7).abs() < 0.01, "{:?}", meta.max);
+    }
+
+    #[test]
+    fn test_header() {
+        let meta = Metadata { flavor: Flavor::Klipper, estimated_time: 3725.4, filament_used: 1234.5, min: Point3d { x: 0.0, y: 1.5, z: 0.2 }, max: Point3d { x: 20.0, y: 21.5, z: 10.0 }, layer_count: 50 };
+        let expected = format!(
+            "; generated by gen_gcode {}\n;FLAVOR:Klipper\n;TIME:3725\n;Filament used: 1.2345m\n;LAYER_COUNT:50\n;MINX:0\n;MINY:1.5\n;MINZ:0.2\n;MAXX:20\n;MAXY:21.5\n;MAXZ:10\n; estimated printing time (normal mode) = 1h 2m 5s\n; filament used [mm] = 1234.50\n",
+            env!("CARGO_PKG_VERSION")
+        );
+        assert_eq!(expected, meta.header());
+        let grbl = Metadata { flavor: Flavor::Grbl, ..meta }.header();
+        assert!(grbl.starts_with("(generated by gen_gcode "), "{}", grbl);
+        assert!(grbl.contains("\n(FLAVOR:Grbl)\n(TIME:3725)\n(Filament used: 1.2345m)\n"), "{}", grbl);
+        assert!(grbl.ends_with("\n(estimated printing time [normal mode] = 1h 2m 5s)\n(filament used [mm] = 1234.50)\n"), "{}", grbl);
+        assert!(!grbl.contains(';'));
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!("0s", format_duration(0.2));
+        assert_eq!("59s", format_duration(59.0));
+        assert_eq!("1m 0s", format_duration(60.0));
+        assert_eq!("1d 0h 0m 1s", format_duration(86401.0));
+    }
+
+    #[test]
+    fn test_layer_comments() {
+        let commented = add_layer_comments(&two_layers(), CommentStyle::Semicolon);
+        let expected = "G21\nG28\n;LAYER:0\nG0 Z0.2\nG0 X10 Y20 F6000\nG1 X110 Y20 E5 F1200\nG1 X110 Y40 E6\n;LAYER:1\nG0 Z0.4\n";
+        assert!(commented.starts_with(expected), "{}", commented);
+        assert_eq!(2, commented.matches(";LAYER:").count());
+        let grbl = add_layer_comments(&two_layers(), CommentStyle::Parentheses);
+        assert!(grbl.starts_with("G21\nG28\n(LAYER:0)\nG0 Z0.2\n"), "{}", grbl);
+    }
+}
+
+use crate::parser::parse_line;
+use crate::program::arc_points;
+use crate::simulator::Simulator;
+use crate::thermal::ThermalModel;
+use crate::{CommentStyle, Flavor, Point2d, Point3d};
+
+/// Summary of a program shown by printer interfaces, lengths are in millimeters
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Metadata {
+    pub flavor: Flavor,
+    /// Estimated run time in seconds, see [crate::estimator::estimate_time]
+    pub estimated_time: f32,
+    /// Length of filament fed into the hotend, not counting retractions
+    pub filament_used: f32,
+    /// Corners of the box holding every extruding move
+    pub min: Point3d,
+    pub max: Point3d,
+    pub layer_count: usize,
+}
+
+impl Metadata {
+    /// Works out the metadata of a program by simulating it with the default thermal model
+    pub fn from_program(program: &str, flavor: Flavor) -> Self {
+        let timeline = Simulator::with_thermal_model(ThermalModel::default()).run(program);
+        let lines: Vec<&str> = program.lines().collect();
+        let mut bounds: Option<(Point3d, Point3d)> = None;
+        for pair in timeline.states.windows(2) {
+            let (prev, cur) = (&pair[0], &pair[1]);
+            let arc = parse_line(lines[cur.line - 1]).command.filter(|c| c.name == "G2" || c.name == "G3");
+            // a full circle ends where it started
+            if cur.filament <= prev.filament || (arc.is_none() && cur.machine_position == prev.machine_position) {
+                continue;
+            }
+            let mut points = vec![prev.position, cur.position];
+            // arcs can bulge out past both ends
+            if let Some(cmd) = arc {
+                let offset = |key| cur.units.to_mm(cmd.get(key).unwrap_or(0.0));
+                let (from, to) = (Point2d { x: prev.position.x, y: prev.position.y }, Point2d { x: cur.position.x, y: cur.position.y });
+                let center = Point2d { x: from.x + offset("I"), y: from.y + offset("J") };
+                points.extend(arc_points(from, to, center, cmd.name == "G3").iter().map(|p| Point3d { x: p.x, y: p.y, z: cur.position.z }));
+            }
+            for p in points.iter() {
+                bounds = Some(match bounds {
+                    None => (*p, *p),
+                    Some((min, max)) => (Point3d { x: min.x.min(p.x), y: min.y.min(p.y), z: min.z.min(p.z) }, Point3d { x: max.x.max(p.x), y: max.y.max(p.y), z: max.z.max(p.z) }),
+                });
+            }
+        }
+        let origin = Point3d { x: 0.0, y: 0.0, z: 0.0 };
+        let (min, max) = bounds.unwrap_or((origin, origin));
+        // retracted filament is pushed back before printing again, only the furthest point counts
+        let filament_used = timeline.states.iter().map(|s| s.filament).fold(0.0, f32::max);
+        Metadata { flavor, estimated_time: timeline.duration(), filament_used, min, max, layer_count: timeline.layers().len() }
+    }
+
+    /// Returns the metadata as comments in the formats read by Cura and PrusaSlicer based tools
+    pub fn header(&self) -> String {
+        let style = self.flavor.comment_style();
+        let flavor = match self.flavor {
+            Flavor::Marlin => "Marlin",
+            Flavor::Klipper => "Klipper",
+            Flavor::RepRapFirmware => "RepRap (RepRap)",
+            Flavor::Grbl => "Grbl",
+        };
+        let lines = [
+            style.format(&format!("generated by gen_gcode {}", env!("CARGO_PKG_VERSION"))),
+            style.marker("FLAVOR", flavor),
+            style.marker("TIME", &self.estimated_time.round().to_string()),
+            style.marker("Filament used", &format!(" {}m", self.filament_used / 1000.0)),
+            style.marker("LAYER_COUNT", &self.layer_count.to_string()),
+            style.marker("MINX", &self.min.x.to_string()),
+            style.marker("MINY", &self.min.y.to_string()),
+            style.marker("MINZ", &self.min.z.to_string()),
+            style.marker("MAXX", &self.max.x.to_string()),
+            style.marker("MAXY", &self.max.y.to_string()),
+            style.marker("MAXZ", &self.max.z.to_string()),
+            style.format(&format!("estimated printing time (normal mode) = {}", format_duration(self.estimated_time))),
+            style.format(&format!("filament used [mm] = {:.2}", self.filament_used)),
+        ];
+        lines.iter().map(|l| format!("{}\n", l)).collect()
+    }
+}
+
+/// Formats a duration in seconds the way PrusaSlicer does, such as `1h 2m 5s`
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::metadata::format_duration;
+///
+/// assert_eq!("2m 5s", format_duration(125.0));
+/// ```
+pub fn format_duration(seconds: f32) -> String {
+    let total = seconds.round() as u64;
+    let (d, h, m, s) = (total / 86400, total % 86400 / 3600, total % 3600 / 60, total % 60);
+    if d > 0 {
+        format!("{}d {}h {}m {}s", d, h, m, s)
+    } else if h > 0 {
+        format!("{}h {}m {}s", h, m, s)
+    } else if m > 0 {
+        format!("{}m {}s", m, s)
+    } else {
+        format!("{}s", s)
+    }
+}
+
+/// Returns the program with a `;LAYER:<n>` marker in the given style, counting from 0, before each
+/// layer. A layer starts with the moves that bring the nozzle to its height.
+pub fn add_layer_comments(program: &str, style: CommentStyle) -> String {
+    let timeline = Simulator::new().run(program);
+    let mut starts = Vec::new();
+    let mut prev_end = 0;
+    for layer in timeline.layers() {
+        let mut k = layer.start;
+        while k - 1 > prev_end && timeline.states[k - 1].position.z == layer.z {
+            k -= 1;
+        }
+        starts.push(timeline.states[k].line);
+        prev_end = layer.end;
+    }
+    let mut out = String::with_capacity(program.len() + starts.len() * 12);
+    let mut layers = starts.iter().enumerate().peekable();
+    for (n, line) in program.lines().enumerate() {
+        while let Some((layer, _)) = layers.next_if(|(_, &start)| start == n + 1) {
+            out += &style.marker("LAYER", &layer.to_string());
+            out.push('\n');
+        }
+        out += line;
+        out.push('\n');
+    }
+    out
+}
@@ -0,0 +1,410 @@
+//! Client for Moonraker, the API server in front of Klipper, to upload generated programs, run them
+//! and follow their progress
+//!
+//! Enabled with the `moonraker` cargo feature, which brings in rustls so `https://` servers and
+//! their `wss://` websocket can be reached.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::tests::HttpStub;
+    use crate::*;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    fn moonraker_stub() -> HttpStub {
+        HttpStub::start(|req| {
+            if req.header("X-Api-Key") != Some("secret") {
+                return (401, r#"{"error":{"code":401,"message":"Unauthorized"}}"#.to_string());
+            }
+            match (req.method.as_str(), req.path.as_str()) {
+                ("POST", "/server/files/upload") => (201, r#"{"result":{"item":{"path":"parts/box.gcode","root":"gcodes"},"print_started":false,"action":"create_file"}}"#.to_string()),
+                ("POST", _) if req.path.starts_with("/printer/print/") => (200, r#"{"result":"ok"}"#.to_string()),
+                _ => (404, r#"{"error":{"code":404,"message":"Not Found"}}"#.to_string()),
+            }
+        })
+    }
+
+    /// Starts a websocket server answering JSON-RPC calls like Moonraker, recording the G-Code
+    /// scripts it receives
+    fn websocket_stub() -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/websocket", listener.local_addr().unwrap());
+        let scripts = Arc::new(Mutex::new(Vec::new()));
+        let log = scripts.clone();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            let mut messages: Vec<Value> = Vec::new();
+            loop {
+                for message in messages.drain(..) {
+                    socket.send(Message::Text(message.to_string())).unwrap();
+                }
+                let msg = match socket.read() {
+                    Ok(Message::Text(text)) => text,
+                    Ok(_) => continue,
+                    Err(_) => return,
+                };
+                let request: Value = serde_json::from_str(&msg).unwrap();
+                let id = request["id"].clone();
+                match request["method"].as_str().unwrap() {
+                    "printer.gcode.script" => {
+                        let script = request["params"]["script"].as_str().unwrap().to_string();
+                        let response = if script.contains("BOGUS") {
+                            json!({"jsonrpc": "2.0", "error": {"code": 400, "message": "Unknown command:\"BOGUS\""}, "id": id})
+                        } else {
+                            json!({"jsonrpc": "2.0", "result": "ok", "id": id})
+                        };
+                        log.lock().unwrap().push(script);
+                        messages.push(response);
+                    }
+                    "printer.objects.subscribe" => {
+                        assert_eq!(json!({"objects": {"print_stats": null, "virtual_sdcard": null}}), request["params"]);
+                        messages.push(json!({"jsonrpc": "2.0", "method": "notify_proc_stat_update", "params": [{"cpu_temp": 45.0}]}));
+                        messages.push(json!({"jsonrpc": "2.0", "result": {"eventtime": 1.0, "status": {"print_stats": {"state": "standby", "filename": "", "print_duration": 0.0}, "virtual_sdcard": {"progress": 0.0}}}, "id": id}));
+                        messages.push(json!({"jsonrpc": "2.0", "method": "notify_status_update", "params": [{"print_stats": {"state": "printing", "filename": "box.gcode"}, "virtual_sdcard": {"progress": 0.25}}, 2.0]}));
+                        messages.push(json!({"jsonrpc": "2.0", "method": "notify_status_update", "params": [{"print_stats": {"print_duration": 30.5}}, 3.0]}));
+                    }
+                    _ => messages.push(json!({"jsonrpc": "2.0", "error": {"code": -32601, "message": "Method not found"}, "id": id})),
+                }
+            }
+        });
+        (url, scripts)
+    }
+
+    #[test]
+    fn test_upload() {
+        let stub = moonraker_stub();
+        let client = Moonraker::new(&stub.url, Some("secret"));
+        let path = client.upload("parts/box.gcode", &auto_home(), false).unwrap();
+        assert_eq!("parts/box.gcode", path);
+        let req = &stub.requests()[0];
+        assert_eq!("/server/files/upload", req.path);
+        assert!(req.body.contains("name=\"root\"\r\n\r\ngcodes\r\n"));
+        assert!(req.body.contains("name=\"path\"\r\n\r\nparts\r\n"));
+        assert!(req.body.contains("name=\"print\"\r\n\r\nfalse\r\n"));
+        assert!(req.body.contains("filename=\"box.gcode\""));
+        assert!(req.body.contains("G28\n"));
+    }
+
+    #[test]
+    fn test_print_control() {
+        let stub = moonraker_stub();
+        let client = Moonraker::new(&stub.url, Some("secret"));
+        client.start("parts/my box.gcode").unwrap();
+        client.pause().unwrap();
+        client.resume().unwrap();
+        client.cancel().unwrap();
+        let paths: Vec<String> = stub.requests().iter().map(|r| r.path.clone()).collect();
+        assert_eq!(vec!["/printer/print/start?filename=parts%2Fmy+box.gcode", "/printer/print/pause", "/printer/print/resume", "/printer/print/cancel"], paths);
+    }
+
+    #[test]
+    fn test_http_error() {
+        let stub = moonraker_stub();
+        let client = Moonraker::new(&stub.url, None);
+        match client.pause() {
+            Err(MoonrakerError::Http { status, .. }) => assert_eq!(401, status),
+            other => panic!("expected an http error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_websocket_url() {
+        assert_eq!("ws://printer.local/websocket", Moonraker::new("http://printer.local/", None).websocket_url());
+        assert_eq!("wss://printer.local:7125/websocket", Moonraker::new("https://printer.local:7125", None).websocket_url());
+    }
+
+    #[test]
+    fn test_gcode_script() {
+        let (url, scripts) = websocket_stub();
+        let mut socket = MoonrakerSocket::connect(&url, None).unwrap();
+        socket.gcode_script(&format!("{}(home first)\n{}; heat\n", auto_home(), set_hotend_temp(210, None))).unwrap();
+        match socket.gcode_script("BOGUS") {
+            Err(MoonrakerError::Rpc { code, message }) => {
+                assert_eq!(400, code);
+                assert!(message.contains("BOGUS"));
+            }
+            other => panic!("expected a rpc error, got {:?}", other),
+        }
+        assert_eq!(vec!["G28\nM104 S210".to_string(), "BOGUS".to_string()], *scripts.lock().unwrap());
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let (url, _) = websocket_stub();
+        let mut socket = MoonrakerSocket::connect(&url, None).unwrap();
+        let mut status = PrintStatus::default();
+        status.update(&socket.subscribe(PrintStatus::OBJECTS).unwrap());
+        assert_eq!("standby", status.state);
+        status.update(&socket.next_status().unwrap());
+        assert_eq!(PrintStatus { state: "printing".to_string(), filename: "box.gcode".to_string(), print_duration: 0.0, progress: 0.25 }, status);
+        status.update(&socket.next_status().unwrap());
+        assert_eq!(30.5, status.print_duration);
+        assert!(status.is_active());
+    }
+}
+
+use crate::framing::strip_comments;
+use crate::http;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::net::TcpStream;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Message, WebSocket};
+
+/// Reasons a request to Moonraker failed
+#[derive(Debug)]
+pub enum MoonrakerError {
+    /// Moonraker answered a HTTP request with an error status
+    Http { status: u16, message: String },
+    /// Moonraker or Klipper answered a JSON-RPC call with an error, such as an unknown G-Code command
+    Rpc { code: i64, message: String },
+    /// Moonraker could not be reached or the connection was lost
+    Transport(String),
+    /// Moonraker answered with something that is not what its API documents
+    InvalidResponse(String),
+}
+
+impl fmt::Display for MoonrakerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MoonrakerError::Http { status, message } => write!(f, "Moonraker answered {}: {}", status, message),
+            MoonrakerError::Rpc { code, message } => write!(f, "error {}: {}", code, message),
+            MoonrakerError::Transport(e) => write!(f, "could not reach Moonraker: {}", e),
+            MoonrakerError::InvalidResponse(e) => write!(f, "unexpected response from Moonraker: {}", e),
+        }
+    }
+}
+
+impl Error for MoonrakerError {}
+
+impl From<ureq::Error> for MoonrakerError {
+    fn from(e: ureq::Error) -> Self {
+        match e {
+            ureq::Error::Status(status, response) => MoonrakerError::Http { status, message: response.into_string().unwrap_or_default() },
+            ureq::Error::Transport(t) => MoonrakerError::Transport(t.to_string()),
+        }
+    }
+}
+
+impl From<tungstenite::Error> for MoonrakerError {
+    fn from(e: tungstenite::Error) -> Self {
+        MoonrakerError::Transport(e.to_string())
+    }
+}
+
+/// HTTP client for a Moonraker server, used to upload files and control prints
+///
+/// # Examples
+/// ```no_run
+/// extern crate gen_gcode;
+/// use gen_gcode::{auto_home, move_z};
+/// use gen_gcode::moonraker::{Moonraker, PrintStatus};
+///
+/// let client = Moonraker::new("http://voron.local:7125", None);
+/// let program = format!("{}{}", auto_home(), move_z(10.0));
+/// let path = client.upload("gen_gcode/test.gcode", &program, true).unwrap();
+///
+/// let mut socket = client.connect().unwrap();
+/// let mut status = PrintStatus::default();
+/// status.update(&socket.subscribe(PrintStatus::OBJECTS).unwrap());
+/// while status.is_active() || status.filename != path {
+///     status.update(&socket.next_status().unwrap());
+///     println!("{:.0}% done", status.progress * 100.0);
+/// }
+/// ```
+pub struct Moonraker {
+    base_url: String,
+    api_key: Option<String>,
+    agent: ureq::Agent,
+}
+
+impl Moonraker {
+    /// Creates a client for the server at `base_url`, with an API key if the server requires one
+    pub fn new(base_url: &str, api_key: Option<&str>) -> Self {
+        Moonraker { base_url: base_url.trim_end_matches('/').to_string(), api_key: api_key.map(String::from), agent: ureq::Agent::new() }
+    }
+
+    /// Returns the URL of the server's websocket
+    pub fn websocket_url(&self) -> String {
+        let url = match self.base_url.strip_prefix("https://") {
+            Some(rest) => format!("wss://{}", rest),
+            None => format!("ws://{}", self.base_url.trim_start_matches("http://")),
+        };
+        format!("{}/websocket", url)
+    }
+
+    /// Opens the server's websocket to send G-Code and follow status updates
+    pub fn connect(&self) -> Result<MoonrakerSocket, MoonrakerError> {
+        MoonrakerSocket::connect(&self.websocket_url(), self.api_key.as_deref())
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let request = self.agent.request(method, &format!("{}{}", self.base_url, path));
+        match &self.api_key {
+            Some(key) => request.set("X-Api-Key", key),
+            None => request,
+        }
+    }
+
+    /// Uploads a program to the `gcodes` root, `path` may include folders. Klipper starts printing
+    /// it straight away if `print` is true. Returns the path Moonraker stored the file at.
+    pub fn upload(&self, path: &str, program: &str, print: bool) -> Result<String, MoonrakerError> {
+        let (folder, filename) = http::split_path(path);
+        let fields = [("root", "gcodes"), ("path", folder), ("print", if print { "true" } else { "false" })];
+        let (content_type, body) = http::multipart("file", filename, program, &fields);
+        let response = self.request("POST", "/server/files/upload").set("Content-Type", &content_type).send_string(&body)?;
+        let v: Value = response.into_json().map_err(|e| MoonrakerError::InvalidResponse(e.to_string()))?;
+        // Moonraker before v0.8 does not wrap the answer in "result"
+        let result = v.get("result").unwrap_or(&v);
+        result["item"]["path"].as_str().map(String::from).ok_or_else(|| MoonrakerError::InvalidResponse("upload did not return a path".to_string()))
+    }
+
+    /// Starts printing an uploaded file
+    pub fn start(&self, path: &str) -> Result<(), MoonrakerError> {
+        self.request("POST", "/printer/print/start").query("filename", path).call()?;
+        Ok(())
+    }
+
+    pub fn pause(&self) -> Result<(), MoonrakerError> {
+        self.request("POST", "/printer/print/pause").call()?;
+        Ok(())
+    }
+
+    pub fn resume(&self) -> Result<(), MoonrakerError> {
+        self.request("POST", "/printer/print/resume").call()?;
+        Ok(())
+    }
+
+    pub fn cancel(&self) -> Result<(), MoonrakerError> {
+        self.request("POST", "/printer/print/cancel").call()?;
+        Ok(())
+    }
+}
+
+/// JSON-RPC connection to Moonraker's websocket
+pub struct MoonrakerSocket {
+    socket: WebSocket<MaybeTlsStream<TcpStream>>,
+    next_id: u64,
+    notifications: VecDeque<Value>,
+}
+
+impl MoonrakerSocket {
+    /// Connects to a websocket URL such as `ws://voron.local:7125/websocket`
+    pub fn connect(url: &str, api_key: Option<&str>) -> Result<Self, MoonrakerError> {
+        let mut request = url.into_client_request()?;
+        if let Some(key) = api_key {
+            let value = key.parse().map_err(|_| MoonrakerError::Transport("API key is not a valid header value".to_string()))?;
+            request.headers_mut().insert("X-Api-Key", value);
+        }
+        let (socket, _) = tungstenite::connect(request)?;
+        Ok(MoonrakerSocket { socket, next_id: 1, notifications: VecDeque::new() })
+    }
+
+    fn read(&mut self) -> Result<Value, MoonrakerError> {
+        loop {
+            match self.socket.read()? {
+                Message::Text(text) => return serde_json::from_str(&text).map_err(|e| MoonrakerError::InvalidResponse(e.to_string())),
+                Message::Close(_) => return Err(MoonrakerError::Transport("connection closed".to_string())),
+                _ => (),
+            }
+        }
+    }
+
+    /// Calls a JSON-RPC method and waits for its result, notifications received meanwhile are kept
+    /// for [MoonrakerSocket::next_status]
+    pub fn call(&mut self, method: &str, params: Value) -> Result<Value, MoonrakerError> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let request = json!({"jsonrpc": "2.0", "method": method, "params": params, "id": id});
+        self.socket.send(Message::Text(request.to_string()))?;
+        loop {
+            let mut message = self.read()?;
+            if message.get("id").and_then(Value::as_u64) != Some(id) {
+                if message.get("method").is_some() {
+                    self.notifications.push_back(message);
+                }
+                continue;
+            }
+            if let Some(error) = message.get("error") {
+                let code = error["code"].as_i64().unwrap_or(0);
+                let message = error["message"].as_str().unwrap_or("").to_string();
+                return Err(MoonrakerError::Rpc { code, message });
+            }
+            return Ok(message["result"].take());
+        }
+    }
+
+    /// Runs G-Code on the printer and waits for Klipper to finish it, `gcode` may hold several
+    /// lines as returned by the functions of this crate. Comments and blank lines are dropped.
+    pub fn gcode_script(&mut self, gcode: &str) -> Result<(), MoonrakerError> {
+        let script: Vec<String> = gcode.lines().map(strip_comments).filter(|l| !l.is_empty()).collect();
+        self.call("printer.gcode.script", json!({"script": script.join("\n")}))?;
+        Ok(())
+    }
+
+    /// Subscribes to every field of the given Klipper objects, returns their current status
+    pub fn subscribe(&mut self, objects: &[&str]) -> Result<Value, MoonrakerError> {
+        let objects: serde_json::Map<String, Value> = objects.iter().map(|o| (o.to_string(), Value::Null)).collect();
+        let mut result = self.call("printer.objects.subscribe", json!({ "objects": objects }))?;
+        Ok(result["status"].take())
+    }
+
+    /// Waits for the next status update of the subscribed objects, returns only the fields that
+    /// changed
+    pub fn next_status(&mut self) -> Result<Value, MoonrakerError> {
+        loop {
+            let mut message = match self.notifications.pop_front() {
+                Some(message) => message,
+                None => self.read()?,
+            };
+            if message["method"] == "notify_status_update" {
+                return Ok(message["params"][0].take());
+            }
+        }
+    }
+}
+
+/// Progress of the current print, built from the `print_stats` and `virtual_sdcard` objects
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PrintStatus {
+    /// Klipper's print state: `standby`, `printing`, `paused`, `complete`, `cancelled` or `error`
+    pub state: String,
+    pub filename: String,
+    /// Seconds spent printing so far, pauses excluded
+    pub print_duration: f32,
+    /// Fraction of the file printed, from 0 to 1
+    pub progress: f32,
+}
+
+impl PrintStatus {
+    /// Objects to subscribe to for [PrintStatus::update]
+    pub const OBJECTS: &'static [&'static str] = &["print_stats", "virtual_sdcard"];
+
+    /// Applies a status, as returned by [MoonrakerSocket::subscribe] or [MoonrakerSocket::next_status]
+    pub fn update(&mut self, status: &Value) {
+        let stats = &status["print_stats"];
+        if let Some(state) = stats["state"].as_str() {
+            self.state = state.to_string();
+        }
+        if let Some(filename) = stats["filename"].as_str() {
+            self.filename = filename.to_string();
+        }
+        if let Some(duration) = stats["print_duration"].as_f64() {
+            self.print_duration = duration as f32;
+        }
+        if let Some(progress) = status["virtual_sdcard"]["progress"].as_f64() {
+            self.progress = progress as f32;
+        }
+    }
+
+    /// Returns true while a print is running or paused
+    pub fn is_active(&self) -> bool {
+        self.state == "printing" || self.state == "paused"
+    }
+}
@@ -0,0 +1,224 @@
+//! Acceleration, jerk and feed rate limits, the speed and flow overrides, and pressure advance
+//!
+//! Values follow Marlin: accelerations in mm/s², feed rates and jerk in mm/s and junction deviation
+//! in mm. The simulator, and so [crate::estimator::estimate_time], honors them.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_line;
+
+    #[test]
+    fn test_set_max_acceleration() {
+        let limits = AxisLimits { x: Some(3000.0), y: Some(3000.0), z: Some(100.0), e: Some(10000.0) };
+        assert_eq!("M201 X3000 Y3000 Z100 E10000\n", set_max_acceleration(limits));
+        assert_eq!("M201 Z50\n", set_max_acceleration(AxisLimits { z: Some(50.0), ..Default::default() }));
+    }
+
+    #[test]
+    fn test_set_max_feed_rate() {
+        let limits = AxisLimits { x: Some(500.0), y: Some(500.0), z: Some(12.5), e: None };
+        assert_eq!("M203 X500 Y500 Z12.5\n", set_max_feed_rate(limits));
+    }
+
+    #[test]
+    fn test_set_acceleration() {
+        let accel = Acceleration { print: Some(1500.0), retract: Some(3000.0), travel: Some(2000.0) };
+        assert_eq!("M204 P1500 R3000 T2000\n", set_acceleration(accel));
+        assert_eq!("M204 T5000\n", set_acceleration(Acceleration { travel: Some(5000.0), ..Default::default() }));
+    }
+
+    #[test]
+    fn test_set_jerk() {
+        assert_eq!("M205 J0.013\n", set_jerk(Jerk::JunctionDeviation(0.013)));
+        let jerk = AxisLimits { x: Some(10.0), y: Some(10.0), z: Some(0.4), e: Some(5.0) };
+        assert_eq!("M205 X10 Y10 Z0.4 E5\n", set_jerk(Jerk::Classic(jerk)));
+    }
+
+    #[test]
+    fn test_overrides() {
+        assert_eq!("M220 S150\n", set_speed_factor(150));
+        assert_eq!("M221 S95\n", set_flow_factor(95, None));
+        assert_eq!("M221 S105 T1\n", set_flow_factor(105, Some(1)));
+    }
+
+    #[test]
+    fn test_set_pressure_advance() {
+        assert_eq!("M900 K0.05\n", set_pressure_advance(Flavor::Marlin, 0.05, None));
+        assert_eq!("M900 K0.05 T1\n", set_pressure_advance(Flavor::Marlin, 0.05, Some(1)));
+        assert_eq!("M572 D0 S0.05\n", set_pressure_advance(Flavor::RepRapFirmware, 0.05, None));
+        assert_eq!("M572 D1 S0.05\n", set_pressure_advance(Flavor::RepRapFirmware, 0.05, Some(1)));
+        assert_eq!("SET_PRESSURE_ADVANCE ADVANCE=0.05\n", set_pressure_advance(Flavor::Klipper, 0.05, None));
+        assert_eq!("SET_PRESSURE_ADVANCE EXTRUDER=extruder ADVANCE=0.05\n", set_pressure_advance(Flavor::Klipper, 0.05, Some(0)));
+        assert_eq!("SET_PRESSURE_ADVANCE EXTRUDER=extruder2 ADVANCE=0.05\n", set_pressure_advance(Flavor::Klipper, 0.05, Some(2)));
+        assert_eq!("", set_pressure_advance(Flavor::Grbl, 0.05, None));
+    }
+
+    #[test]
+    fn test_parses_back() {
+        let cmd = parse_line(&set_acceleration(Acceleration { print: Some(1250.5), ..Default::default() })).command.unwrap();
+        assert_eq!("M204", cmd.name);
+        assert_eq!(Some(1250.5), cmd.get("P"));
+        assert_eq!(None, cmd.get("T"));
+    }
+}
+
+use crate::klipper;
+use crate::Flavor;
+
+/// A value for each axis, axes left to None are not changed
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct AxisLimits {
+    pub x: Option<f32>,
+    pub y: Option<f32>,
+    pub z: Option<f32>,
+    pub e: Option<f32>,
+}
+
+impl AxisLimits {
+    fn words(self) -> String {
+        let axes = [('X', self.x), ('Y', self.y), ('Z', self.z), ('E', self.e)];
+        axes.iter().filter_map(|(letter, v)| v.map(|v| format!(" {}{}", letter, v))).collect()
+    }
+}
+
+/// Accelerations set by M204, in mm/s², the ones left to None are not changed
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Acceleration {
+    /// Acceleration of moves that extrude
+    pub print: Option<f32>,
+    /// Acceleration of moves of the extruder alone, such as retractions
+    pub retract: Option<f32>,
+    /// Acceleration of moves that don't extrude
+    pub travel: Option<f32>,
+}
+
+/// How fast the nozzle may change direction without slowing to a stop
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Jerk {
+    /// Marlin's junction deviation, in mm
+    JunctionDeviation(f32),
+    /// The speed change each axis can make instantly, in mm/s
+    Classic(AxisLimits),
+}
+
+/// Returns a M201 command setting the maximum acceleration of each axis, in mm/s², as a String
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::motion::{set_max_acceleration, AxisLimits};
+///
+/// let gcode = set_max_acceleration(AxisLimits { x: Some(2000.0), y: Some(2000.0), ..Default::default() });
+/// assert_eq!("M201 X2000 Y2000\n", gcode);
+/// ```
+pub fn set_max_acceleration(limits: AxisLimits) -> String {
+    format!("M201{}\n", limits.words())
+}
+
+/// Returns a M203 command setting the maximum feed rate of each axis, in mm/s, as a String
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::motion::{set_max_feed_rate, AxisLimits};
+///
+/// let gcode = set_max_feed_rate(AxisLimits { z: Some(10.0), ..Default::default() });
+/// assert_eq!("M203 Z10\n", gcode);
+/// ```
+pub fn set_max_feed_rate(limits: AxisLimits) -> String {
+    format!("M203{}\n", limits.words())
+}
+
+/// Returns a M204 command setting the print, retract and travel accelerations as a String
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::motion::{set_acceleration, Acceleration};
+///
+/// let gcode = set_acceleration(Acceleration { print: Some(1000.0), travel: Some(3000.0), ..Default::default() });
+/// assert_eq!("M204 P1000 T3000\n", gcode);
+/// ```
+pub fn set_acceleration(accel: Acceleration) -> String {
+    let params = [('P', accel.print), ('R', accel.retract), ('T', accel.travel)];
+    let words: String = params.iter().filter_map(|(letter, v)| v.map(|v| format!(" {}{}", letter, v))).collect();
+    format!("M204{}\n", words)
+}
+
+/// Returns a M205 command setting the jerk or junction deviation as a String
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::motion::{set_jerk, Jerk};
+///
+/// let gcode = set_jerk(Jerk::JunctionDeviation(0.02));
+/// assert_eq!("M205 J0.02\n", gcode);
+/// ```
+pub fn set_jerk(jerk: Jerk) -> String {
+    match jerk {
+        Jerk::JunctionDeviation(deviation) => format!("M205 J{}\n", deviation),
+        Jerk::Classic(limits) => format!("M205{}\n", limits.words()),
+    }
+}
+
+/// Returns a M220 command scaling the speed of every move, in percent, as a String
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::motion::set_speed_factor;
+///
+/// let gcode = set_speed_factor(80);
+/// assert_eq!("M220 S80\n", gcode);
+/// ```
+pub fn set_speed_factor(percent: u16) -> String {
+    format!("M220 S{}\n", percent)
+}
+
+/// Returns a M221 command scaling the filament extruded, in percent, as a String, for the active
+/// extruder unless a tool is given
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::motion::set_flow_factor;
+///
+/// let gcode = set_flow_factor(95, None);
+/// assert_eq!("M221 S95\n", gcode);
+/// ```
+pub fn set_flow_factor(percent: u16, tool: Option<u8>) -> String {
+    match tool {
+        Some(tool) => format!("M221 S{} T{}\n", percent, tool),
+        None => format!("M221 S{}\n", percent),
+    }
+}
+
+/// Returns the command setting the pressure advance (Marlin's linear advance K factor) as a String,
+/// for the active extruder unless a tool is given
+///
+/// Marlin uses M900, RepRapFirmware M572 and Klipper SET_PRESSURE_ADVANCE, naming the extruder as
+/// in its configuration (`extruder`, `extruder1`...). Grbl has no extruder, nothing is returned.
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::Flavor;
+/// use gen_gcode::motion::set_pressure_advance;
+///
+/// let gcode = set_pressure_advance(Flavor::Marlin, 0.06, None);
+/// assert_eq!("M900 K0.06\n", gcode);
+/// assert_eq!("M572 D0 S0.06\n", set_pressure_advance(Flavor::RepRapFirmware, 0.06, None));
+/// ```
+pub fn set_pressure_advance(flavor: Flavor, advance: f32, tool: Option<u8>) -> String {
+    match (flavor, tool) {
+        (Flavor::Marlin, None) => format!("M900 K{}\n", advance),
+        (Flavor::Marlin, Some(tool)) => format!("M900 K{} T{}\n", advance, tool),
+        (Flavor::RepRapFirmware, tool) => format!("M572 D{} S{}\n", tool.unwrap_or(0), advance),
+        (Flavor::Klipper, None) => klipper::set_pressure_advance(advance, None, None),
+        (Flavor::Klipper, Some(0)) => klipper::set_pressure_advance(advance, None, Some("extruder")),
+        (Flavor::Klipper, Some(tool)) => klipper::set_pressure_advance(advance, None, Some(&format!("extruder{}", tool))),
+        (Flavor::Grbl, _) => String::new(),
+    }
+}
@@ -0,0 +1,340 @@
+//! Client for OctoPrint's REST API, to upload generated programs and run them
+//!
+//! Enabled with the `octoprint` cargo feature, which brings in rustls so `https://` servers can be
+//! reached.
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::http::tests::HttpStub;
+    use crate::*;
+    use std::sync::Mutex;
+
+    fn octoprint_stub() -> HttpStub {
+        let polls = Mutex::new(0);
+        HttpStub::start(move |req| {
+            if req.header("X-Api-Key") != Some("secret") {
+                return (403, r#"{"error":"Invalid API key"}"#.to_string());
+            }
+            match (req.method.as_str(), req.path.as_str()) {
+                ("POST", "/api/files/local") => (201, r#"{"done":true,"files":{"local":{"name":"box.gcode","path":"parts/box.gcode","origin":"local"}}}"#.to_string()),
+                ("POST", "/api/files/local/parts/box.gcode") => (204, String::new()),
+                ("POST", "/api/job") | ("POST", "/api/printer/command") => (204, String::new()),
+                ("GET", "/api/job") => {
+                    let mut polls = polls.lock().unwrap();
+                    *polls += 1;
+                    let (state, completion) = match *polls {
+                        1 => ("Operational", "null"),
+                        2 => ("Printing", "10.5"),
+                        3 => ("Printing", "60.0"),
+                        _ => ("Operational", "100.0"),
+                    };
+                    let body = format!(r#"{{"job":{{"file":{{"name":"box.gcode","path":"parts/box.gcode"}},"estimatedPrintTime":1200.5}},"progress":{{"completion":{},"printTime":120,"printTimeLeft":null}},"state":"{}"}}"#, completion, state);
+                    (200, body)
+                }
+                _ => (404, r#"{"error":"Not found"}"#.to_string()),
+            }
+        })
+    }
+
+    #[test]
+    fn test_upload() {
+        let stub = octoprint_stub();
+        let client = OctoPrint::new(&stub.url, "secret");
+        let program = format!("{}{}", auto_home(), move_z(1.0));
+        let path = client.upload("parts/box.gcode", &program).unwrap();
+        assert_eq!("parts/box.gcode", path);
+        let req = &stub.requests()[0];
+        assert_eq!("POST", req.method);
+        assert!(req.header("Content-Type").unwrap().starts_with("multipart/form-data; boundary="));
+        assert!(req.body.contains("Content-Disposition: form-data; name=\"file\"; filename=\"box.gcode\""));
+        assert!(req.body.contains("Content-Disposition: form-data; name=\"path\"\r\n\r\nparts\r\n"));
+        assert!(req.body.contains("G28\nG0 Z1\n"));
+    }
+
+    #[test]
+    fn test_select_and_start() {
+        let stub = octoprint_stub();
+        let client = OctoPrint::new(&format!("{}/", stub.url), "secret");
+        client.select("parts/box.gcode", true).unwrap();
+        let req = &stub.requests()[0];
+        assert_eq!("/api/files/local/parts/box.gcode", req.path);
+        assert_eq!(serde_json::json!({"command": "select", "print": true}), serde_json::from_str::<serde_json::Value>(&req.body).unwrap());
+    }
+
+    #[test]
+    fn test_job_control() {
+        let stub = octoprint_stub();
+        let client = OctoPrint::new(&stub.url, "secret");
+        client.start().unwrap();
+        client.pause().unwrap();
+        client.resume().unwrap();
+        client.cancel().unwrap();
+        let bodies: Vec<serde_json::Value> = stub.requests().iter().map(|r| serde_json::from_str(&r.body).unwrap()).collect();
+        assert_eq!(
+            vec![
+                serde_json::json!({"command": "start"}),
+                serde_json::json!({"command": "pause", "action": "pause"}),
+                serde_json::json!({"command": "pause", "action": "resume"}),
+                serde_json::json!({"command": "cancel"}),
+            ],
+            bodies
+        );
+    }
+
+    #[test]
+    fn test_send_commands() {
+        let stub = octoprint_stub();
+        let client = OctoPrint::new(&stub.url, "secret");
+        let gcode = format!("{}; heat up\n\n(fan on)\n{}", set_hotend_temp(210, None), set_fan_speed(255, None));
+        client.send_commands(&gcode).unwrap();
+        let req = &stub.requests()[0];
+        assert_eq!("/api/printer/command", req.path);
+        assert_eq!(serde_json::json!({"commands": ["M104 S210", "M106 S255"]}), serde_json::from_str::<serde_json::Value>(&req.body).unwrap());
+    }
+
+    #[test]
+    fn test_select_encodes_path() {
+        let stub = octoprint_stub();
+        let client = OctoPrint::new(&stub.url, "secret");
+        assert!(client.select("my parts/box #1?.gcode", false).is_err());
+        assert_eq!("/api/files/local/my%20parts/box%20%231%3F.gcode", stub.requests()[0].path);
+    }
+
+    #[test]
+    fn test_job_status() {
+        let stub = octoprint_stub();
+        let client = OctoPrint::new(&stub.url, "secret");
+        client.job().unwrap();
+        let job = client.job().unwrap();
+        assert_eq!("Printing", job.state);
+        assert_eq!(Some("parts/box.gcode".to_string()), job.file);
+        assert_eq!(Some(10.5), job.completion);
+        assert_eq!(Some(120.0), job.print_time);
+        assert_eq!(None, job.print_time_left);
+        assert_eq!(Some(1200.5), job.estimated_print_time);
+        assert!(job.is_active());
+    }
+
+    #[test]
+    fn test_wait_for_job() {
+        let stub = octoprint_stub();
+        let client = OctoPrint::new(&stub.url, "secret");
+        let mut seen = Vec::new();
+        let done = client.wait_for_job(Duration::from_millis(1), Duration::from_secs(10), |job| seen.push(job.completion)).unwrap();
+        assert_eq!(vec![None, Some(10.5), Some(60.0), Some(100.0)], seen);
+        assert_eq!("Operational", done.state);
+        assert!(!done.is_active());
+    }
+
+    #[test]
+    fn test_wait_for_job_timeout() {
+        let stub = octoprint_stub();
+        let client = OctoPrint::new(&stub.url, "secret");
+        match client.wait_for_job(Duration::from_millis(1), Duration::ZERO, |_| ()) {
+            Err(OctoPrintError::Timeout(job)) => assert_eq!("Operational", job.state),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_http_error() {
+        let stub = octoprint_stub();
+        let client = OctoPrint::new(&stub.url, "wrong");
+        match client.job() {
+            Err(OctoPrintError::Http { status, message }) => {
+                assert_eq!(403, status);
+                assert!(message.contains("Invalid API key"));
+            }
+            other => panic!("expected an http error, got {:?}", other),
+        }
+    }
+}
+
+use crate::framing::strip_comments;
+use crate::http;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Reasons a request to OctoPrint failed
+#[derive(Debug)]
+pub enum OctoPrintError {
+    /// OctoPrint answered with an error status
+    Http { status: u16, message: String },
+    /// OctoPrint could not be reached
+    Transport(String),
+    /// OctoPrint answered with something that is not what its API documents
+    InvalidResponse(String),
+    /// The job was still running, or had not started, when the wait timed out. Holds the last
+    /// status seen.
+    Timeout(JobStatus),
+}
+
+impl fmt::Display for OctoPrintError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OctoPrintError::Http { status, message } => write!(f, "OctoPrint answered {}: {}", status, message),
+            OctoPrintError::Transport(e) => write!(f, "could not reach OctoPrint: {}", e),
+            OctoPrintError::InvalidResponse(e) => write!(f, "unexpected response from OctoPrint: {}", e),
+            OctoPrintError::Timeout(job) => write!(f, "timed out waiting for the job, printer is {}", job.state),
+        }
+    }
+}
+
+impl Error for OctoPrintError {}
+
+impl From<ureq::Error> for OctoPrintError {
+    fn from(e: ureq::Error) -> Self {
+        match e {
+            ureq::Error::Status(status, response) => OctoPrintError::Http { status, message: response.into_string().unwrap_or_default() },
+            ureq::Error::Transport(t) => OctoPrintError::Transport(t.to_string()),
+        }
+    }
+}
+
+/// State and progress of the current job, from `/api/job`
+#[derive(Debug, Clone, PartialEq)]
+pub struct JobStatus {
+    /// Printer state, such as `Operational`, `Printing` or `Paused`
+    pub state: String,
+    /// Path of the selected file
+    pub file: Option<String>,
+    /// Percentage of the file printed
+    pub completion: Option<f32>,
+    /// Seconds spent printing so far
+    pub print_time: Option<f32>,
+    /// Seconds OctoPrint expects the print still needs
+    pub print_time_left: Option<f32>,
+    /// Seconds OctoPrint expects the whole print to take
+    pub estimated_print_time: Option<f32>,
+}
+
+impl JobStatus {
+    /// Returns true while a job is running, paused or being started/stopped
+    pub fn is_active(&self) -> bool {
+        ["Printing", "Paused", "Pausing", "Resuming", "Cancelling", "Starting", "Finishing"].iter().any(|s| self.state.starts_with(s))
+    }
+
+    fn from_json(v: &Value) -> Result<JobStatus, OctoPrintError> {
+        let number = |v: &Value| v.as_f64().map(|n| n as f32);
+        let state = v["state"].as_str().ok_or_else(|| OctoPrintError::InvalidResponse("job without a state".to_string()))?;
+        Ok(JobStatus {
+            state: state.to_string(),
+            file: v["job"]["file"]["path"].as_str().or_else(|| v["job"]["file"]["name"].as_str()).map(String::from),
+            completion: number(&v["progress"]["completion"]),
+            print_time: number(&v["progress"]["printTime"]),
+            print_time_left: number(&v["progress"]["printTimeLeft"]),
+            estimated_print_time: number(&v["job"]["estimatedPrintTime"]),
+        })
+    }
+}
+
+/// Client for an OctoPrint server
+///
+/// # Examples
+/// ```no_run
+/// extern crate gen_gcode;
+/// use gen_gcode::{auto_home, move_z};
+/// use gen_gcode::octoprint::OctoPrint;
+/// use std::time::Duration;
+///
+/// let client = OctoPrint::new("http://octopi.local", "API_KEY");
+/// let program = format!("{}{}", auto_home(), move_z(10.0));
+/// let path = client.upload("gen_gcode/test.gcode", &program).unwrap();
+/// client.select(&path, true).unwrap();
+/// client.wait_for_job(Duration::from_secs(10), Duration::from_secs(24 * 3600), |job| println!("{:?}% done", job.completion)).unwrap();
+/// ```
+pub struct OctoPrint {
+    base_url: String,
+    api_key: String,
+    agent: ureq::Agent,
+}
+
+impl OctoPrint {
+    /// Creates a client for the server at `base_url`, authenticating with an application or user
+    /// API key
+    pub fn new(base_url: &str, api_key: &str) -> Self {
+        OctoPrint { base_url: base_url.trim_end_matches('/').to_string(), api_key: api_key.to_string(), agent: ureq::Agent::new() }
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        self.agent.request(method, &format!("{}{}", self.base_url, path)).set("X-Api-Key", &self.api_key)
+    }
+
+    fn post_json(&self, path: &str, body: Value) -> Result<(), OctoPrintError> {
+        self.request("POST", path).send_json(body)?;
+        Ok(())
+    }
+
+    /// Uploads a program to OctoPrint's local storage, `path` may include folders. Returns the path
+    /// OctoPrint stored the file at.
+    pub fn upload(&self, path: &str, program: &str) -> Result<String, OctoPrintError> {
+        let (folder, filename) = http::split_path(path);
+        let fields = if folder.is_empty() { vec![] } else { vec![("path", folder)] };
+        let (content_type, body) = http::multipart("file", filename, program, &fields);
+        let response = self.request("POST", "/api/files/local").set("Content-Type", &content_type).send_string(&body)?;
+        let v: Value = response.into_json().map_err(|e| OctoPrintError::InvalidResponse(e.to_string()))?;
+        v["files"]["local"]["path"].as_str().map(String::from).ok_or_else(|| OctoPrintError::InvalidResponse("upload did not return a path".to_string()))
+    }
+
+    /// Selects an uploaded file, optionally starting to print it straight away
+    pub fn select(&self, path: &str, print: bool) -> Result<(), OctoPrintError> {
+        self.post_json(&format!("/api/files/local/{}", http::encode_path(path)), json!({"command": "select", "print": print}))
+    }
+
+    /// Starts printing the selected file
+    pub fn start(&self) -> Result<(), OctoPrintError> {
+        self.post_json("/api/job", json!({"command": "start"}))
+    }
+
+    pub fn pause(&self) -> Result<(), OctoPrintError> {
+        self.post_json("/api/job", json!({"command": "pause", "action": "pause"}))
+    }
+
+    pub fn resume(&self) -> Result<(), OctoPrintError> {
+        self.post_json("/api/job", json!({"command": "pause", "action": "resume"}))
+    }
+
+    pub fn cancel(&self) -> Result<(), OctoPrintError> {
+        self.post_json("/api/job", json!({"command": "cancel"}))
+    }
+
+    /// Sends commands straight to the printer, `gcode` may hold several lines as returned by the
+    /// functions of this crate. Comments and blank lines are dropped.
+    pub fn send_commands(&self, gcode: &str) -> Result<(), OctoPrintError> {
+        let commands: Vec<String> = gcode.lines().map(strip_comments).filter(|l| !l.is_empty()).collect();
+        self.post_json("/api/printer/command", json!({ "commands": commands }))
+    }
+
+    /// Returns the state and progress of the current job
+    pub fn job(&self) -> Result<JobStatus, OctoPrintError> {
+        let v: Value = self.request("GET", "/api/job").call()?.into_json().map_err(|e| OctoPrintError::InvalidResponse(e.to_string()))?;
+        JobStatus::from_json(&v)
+    }
+
+    /// Polls the job every `interval` until it has started and is no longer active, calling
+    /// `on_progress` with every status, returns the last status
+    ///
+    /// OctoPrint can take a moment to start a print after [OctoPrint::select], so statuses of an
+    /// idle printer are not taken as the job being over until the job was seen running or
+    /// completed. Gives up with [OctoPrintError::Timeout] once `timeout` has passed.
+    pub fn wait_for_job<F: FnMut(&JobStatus)>(&self, interval: Duration, timeout: Duration, mut on_progress: F) -> Result<JobStatus, OctoPrintError> {
+        let deadline = Instant::now() + timeout;
+        let mut started = false;
+        loop {
+            let job = self.job()?;
+            on_progress(&job);
+            started |= job.is_active() || job.completion == Some(100.0);
+            if started && !job.is_active() {
+                return Ok(job);
+            }
+            if Instant::now() >= deadline {
+                return Err(OctoPrintError::Timeout(job));
+            }
+            thread::sleep(interval);
+        }
+    }
+}
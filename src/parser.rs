@@ -0,0 +1,227 @@
+//! Parse lines of G-Code (such as the ones produced by this crate) back into commands
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_move() {
+        let line = parse_line("G1 X10 Y5 E5 F400");
+        let cmd = line.command.unwrap();
+        assert_eq!("G1", cmd.name);
+        assert_eq!(Some(10.0), cmd.get("X"));
+        assert_eq!(Some(5.0), cmd.get("Y"));
+        assert_eq!(Some(5.0), cmd.get("E"));
+        assert_eq!(Some(400.0), cmd.get("F"));
+        assert_eq!(None, cmd.get("Z"));
+        assert_eq!(None, line.comment);
+    }
+
+    #[test]
+    fn test_parse_dotted_code() {
+        let cmd = parse_line("G92.1").command.unwrap();
+        assert_eq!("G92.1", cmd.name);
+        assert!(cmd.params.is_empty());
+    }
+
+    #[test]
+    fn test_parse_lowercase() {
+        let cmd = parse_line("g0 x1.5").command.unwrap();
+        assert_eq!("G0", cmd.name);
+        assert_eq!(Some(1.5), cmd.get("X"));
+    }
+
+    #[test]
+    fn test_parse_flag_param() {
+        let cmd = parse_line("G28 X Y").command.unwrap();
+        assert!(cmd.has("X"));
+        assert!(cmd.has("Y"));
+        assert!(!cmd.has("Z"));
+        assert_eq!(None, cmd.get("X"));
+    }
+
+    #[test]
+    fn test_parse_semicolon_comment() {
+        let line = parse_line("G28 ; home all axes");
+        assert_eq!("G28", line.command.unwrap().name);
+        assert_eq!(Some("home all axes".to_string()), line.comment);
+    }
+
+    #[test]
+    fn test_parse_paren_comment() {
+        let line = parse_line("(start of job)");
+        assert_eq!(None, line.command);
+        assert_eq!(Some("start of job".to_string()), line.comment);
+    }
+
+    #[test]
+    fn test_parse_marker() {
+        assert_eq!(Some(("LAYER", "3")), parse_line(";LAYER:3").marker());
+        assert_eq!(Some(("TYPE", "WALL-OUTER")), parse_line("(TYPE:WALL-OUTER)").marker());
+        assert_eq!(None, parse_line("; home: all axes").marker());
+        assert_eq!(None, parse_line("G28").marker());
+    }
+
+    #[test]
+    fn test_parse_blank() {
+        assert_eq!(Line::default(), parse_line("   "));
+    }
+
+    #[test]
+    fn test_parse_extended_command() {
+        let cmd = parse_line("SET_FAN_SPEED FAN=part SPEED=0.5").command.unwrap();
+        assert_eq!("SET_FAN_SPEED", cmd.name);
+        assert_eq!(Some("part"), cmd.get_str("FAN"));
+        assert_eq!(Some(0.5), cmd.get("SPEED"));
+    }
+
+    #[test]
+    fn test_parse_program() {
+        let lines = parse("G28\n\nG0 X1\n");
+        assert_eq!(3, lines.len());
+        assert_eq!(None, lines[1].command);
+    }
+}
+
+/// A single command on a line of G-Code, with its parameters
+///
+/// Classic commands (`G1`, `M104`, `T0`, ...) have single letter parameter keys, extended commands
+/// (such as Klipper's `SET_FAN_SPEED`) use `KEY=VALUE` parameters. Parameters given without a value
+/// (`G28 X`) are stored with an empty value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Command {
+    pub name: String,
+    pub params: Vec<(String, String)>,
+}
+
+impl Command {
+    /// Returns the numeric value of a parameter, if it is present and has a numeric value
+    ///
+    /// # Examples
+    /// ```
+    /// extern crate gen_gcode;
+    /// use gen_gcode::parser::parse_line;
+    ///
+    /// let cmd = parse_line("M104 S210 T2").command.unwrap();
+    /// assert_eq!(Some(210.0), cmd.get("S"));
+    /// assert_eq!(Some(2.0), cmd.get("T"));
+    /// ```
+    pub fn get(&self, key: &str) -> Option<f32> {
+        self.get_str(key).and_then(|v| v.parse().ok())
+    }
+
+    /// Returns the raw value of a parameter, if it is present
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.params.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    /// Returns true if the parameter is present, with or without a value
+    pub fn has(&self, key: &str) -> bool {
+        self.params.iter().any(|(k, _)| k == key)
+    }
+}
+
+/// A parsed line of G-Code, which may hold a command, a comment, both or neither
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Line {
+    pub command: Option<Command>,
+    pub comment: Option<String>,
+}
+
+impl Line {
+    /// Returns the key and value of a section marker comment, such as `;LAYER:3` or
+    /// `;TYPE:WALL-OUTER`, where the key is made of upper case letters, digits and underscores
+    ///
+    /// # Examples
+    /// ```
+    /// extern crate gen_gcode;
+    /// use gen_gcode::section_marker;
+    /// use gen_gcode::parser::parse_line;
+    ///
+    /// let line = parse_line(&section_marker("LAYER", 3));
+    /// assert_eq!(Some(("LAYER", "3")), line.marker());
+    /// ```
+    pub fn marker(&self) -> Option<(&str, &str)> {
+        let comment = self.comment.as_deref()?;
+        let colon = comment.find(':')?;
+        let (key, value) = (&comment[..colon], &comment[colon + 1..]);
+        let is_key = !key.is_empty() && key.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_');
+        if is_key {
+            Some((key, value.trim()))
+        } else {
+            None
+        }
+    }
+}
+
+/// Parses a single line of G-Code
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::{Point2d, move_xy};
+/// use gen_gcode::parser::parse_line;
+///
+/// let gcode = move_xy(Point2d { x: 10.0, y: 5.0 }, Some(400), None);
+/// let cmd = parse_line(&gcode).command.unwrap();
+/// assert_eq!("G0", cmd.name);
+/// assert_eq!(Some(10.0), cmd.get("X"));
+/// ```
+pub fn parse_line(line: &str) -> Line {
+    let mut code = String::new();
+    let mut comment: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ';' => {
+                comment = Some(chars.as_str().trim().to_string());
+                break;
+            }
+            '(' => {
+                let rest = chars.as_str();
+                let end = rest.find(')').unwrap_or(rest.len());
+                comment = Some(rest[..end].trim().to_string());
+                chars = rest[(end + 1).min(rest.len())..].chars();
+            }
+            _ => code.push(c),
+        }
+    }
+
+    let mut words = code.split_whitespace();
+    let command = words.next().map(|name| {
+        let name = name.to_uppercase();
+        let extended = !is_classic_code(&name);
+        let params = words
+            .map(|word| {
+                if extended {
+                    let mut kv = word.splitn(2, '=');
+                    let key = kv.next().unwrap_or("").to_uppercase();
+                    (key, kv.next().unwrap_or("").to_string())
+                } else {
+                    let mut cs = word.chars();
+                    let key = cs.next().map(|c| c.to_uppercase().to_string()).unwrap_or_default();
+                    (key, cs.as_str().to_string())
+                }
+            })
+            .collect();
+        Command { name, params }
+    });
+
+    Line { command, comment }
+}
+
+/// Parses every line of a G-Code program
+pub fn parse(program: &str) -> Vec<Line> {
+    program.lines().map(parse_line).collect()
+}
+
+fn is_classic_code(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() => {
+            let rest = chars.as_str();
+            !rest.is_empty() && rest.chars().all(|c| c.is_ascii_digit() || c == '.')
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,1867 @@
+//! Build a whole program out of motion and machine commands, then emit it as G-Code
+//!
+//! Unlike the functions at the root of the crate, which format a single command exactly as given,
+//! a [Program] keeps the geometry it is given so it can be emitted in different forms, such as in
+//! inches while the caller works in millimeters.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::leveling::{Leveling, ProbeGrid};
+    use crate::machine::{MachineProfile, Tool, ToolChange};
+    use crate::sequence::{EndSequence, StartSequence};
+    use crate::simulator::{simulate, Timeline, Warning};
+    use crate::wipe_tower::WipeTower;
+    use crate::*;
+
+    // emitting in inches rounds to 0.0001in, ie. 0.00254mm
+    fn approx(a: f32, b: f32) -> bool {
+        (a - b).abs() < 0.003
+    }
+
+    #[test]
+    fn test_empty_program() {
+        assert_eq!("G21\n", Program::new().render());
+    }
+
+    #[test]
+    fn test_matches_free_functions() {
+        let mut p = Program::new();
+        p.move_xy(Point2d { x: 10.0, y: 5.0 }, Some(400.0), None)
+            .move_xyz(Point3d { x: 10.0, y: 5.0, z: 0.17 }, None, Some(5.0))
+            .move_z(1.8)
+            .move_xy_arc_ij(Some(Point2d { x: 125.0, y: 0.0 }), Some(62.5), None, None, false)
+            .set_pos_2d(Point2d { x: 125.0, y: 125.0 }, Some(90.0))
+            .set_pos_3d(Point3d { x: 125.0, y: 125.0, z: 25.0 }, None)
+            .reset_extruder(0.0);
+        let expected = [
+            use_millimeters(),
+            move_xy(Point2d { x: 10.0, y: 5.0 }, Some(400), None),
+            move_xyz(Point3d { x: 10.0, y: 5.0, z: 0.17 }, None, Some(5.0)),
+            move_z(1.8),
+            move_xy_arc_ij(Some(Point2d { x: 125.0, y: 0.0 }), Some(62.5), None, None, false),
+            set_pos_2d(Point2d { x: 125.0, y: 125.0 }, Some(90.0)),
+            set_pos_3d(Point3d { x: 125.0, y: 125.0, z: 25.0 }, None),
+            reset_extruder(0.0),
+        ]
+        .concat();
+        assert_eq!(expected, p.render());
+    }
+
+    #[test]
+    fn test_push_raw_commands() {
+        let mut p = Program::new();
+        p.push(auto_home()).push(set_hotend_temp(210, None));
+        assert_eq!("G21\nG28\nM104 S210\n", p.render());
+    }
+
+    #[test]
+    fn test_emit_inches_from_millimeters() {
+        let mut p = Program::new();
+        p.emit_units(Units::Inches);
+        p.move_xyz(Point3d { x: 25.4, y: 50.8, z: 0.254 }, Some(254.0), Some(2.54));
+        assert_eq!("G20\nG1 X1 Y2 Z0.01 E0.1 F10\n", p.render());
+    }
+
+    #[test]
+    fn test_emit_millimeters_from_inches() {
+        let mut p = Program::with_units(Units::Inches);
+        p.move_xy(Point2d { x: 1.0, y: 0.5 }, Some(10.0), None)
+            .move_xy_arc_ij(Some(Point2d { x: 2.0, y: 0.5 }), Some(0.5), None, Some(0.1), true);
+        assert_eq!("G21\nG0 X25.4 Y12.7 F254\nG3 X50.8 Y12.7 I12.7 E2.54\n", p.render());
+    }
+
+    #[test]
+    fn test_inches_simulate_same_positions() {
+        let mut p = Program::new();
+        p.move_xy(Point2d { x: 12.5, y: 33.3 }, Some(3000.0), None)
+            .move_xyz(Point3d { x: 100.0, y: 80.0, z: 0.3 }, Some(1200.0), Some(4.2))
+            .move_xy_arc_ij(Some(Point2d { x: 120.0, y: 80.0 }), Some(10.0), None, Some(5.0), false);
+        let mm = simulate(&p.render()).final_state().clone();
+        p.emit_units(Units::Inches);
+        let inches = simulate(&p.render()).final_state().clone();
+        assert!(approx(mm.position.x, inches.position.x));
+        assert!(approx(mm.position.y, inches.position.y));
+        assert!(approx(mm.position.z, inches.position.z));
+        assert!(approx(mm.e, inches.e));
+        assert!((mm.feed_rate - inches.feed_rate).abs() < 0.2);
+    }
+
+    fn sample_program() -> Program {
+        let mut p = Program::new();
+        p.push(auto_home())
+            .move_xyz(Point3d { x: 10.0, y: 10.0, z: 0.2 }, Some(3000.0), None)
+            .move_xy(Point2d { x: 30.1, y: 10.0 }, Some(1200.0), Some(0.7))
+            .move_xy(Point2d { x: 30.1, y: 30.3 }, None, Some(1.4))
+            .move_xy_arc_ij(Some(Point2d { x: 10.1, y: 30.3 }), Some(-10.0), None, Some(2.1), true)
+            .reset_extruder(0.0)
+            .move_z(0.4)
+            .move_xy(Point2d { x: 20.0, y: 20.0 }, None, Some(0.33))
+            .set_pos_2d(Point2d { x: 0.0, y: 0.0 }, None)
+            .move_xy(Point2d { x: 5.0, y: -5.0 }, None, Some(0.5))
+            .move_xy_arc_ij(None, Some(2.5), Some(2.5), Some(1.0), false);
+        p
+    }
+
+    #[test]
+    fn test_emit_relative() {
+        let mut p = Program::new();
+        p.emit_positioning(Positioning::Relative)
+            .move_xyz(Point3d { x: 10.0, y: 10.0, z: 0.2 }, None, None)
+            .move_xy(Point2d { x: 30.0, y: 10.0 }, None, Some(0.7))
+            .move_xy_arc_ij(Some(Point2d { x: 10.0, y: 10.0 }), Some(-10.0), None, Some(1.4), true)
+            .reset_extruder(0.0)
+            .move_xy(Point2d { x: 0.0, y: 0.0 }, None, Some(0.5));
+        let expected = "G21\nG91\nG0 X10 Y10 Z0.2\nG1 X20 Y0 E0.7\nG3 X-20 Y0 I-10 E0.7\nG92 E0\nG1 X-10 Y-10 E0.5\n";
+        assert_eq!(expected, p.render());
+    }
+
+    #[test]
+    fn test_relative_after_home() {
+        let mut p = Program::new();
+        p.emit_positioning(Positioning::Relative)
+            .move_xy(Point2d { x: 10.0, y: 10.0 }, None, None)
+            .push(auto_home())
+            .move_xy(Point2d { x: 10.0, y: 10.0 }, None, None);
+        assert_eq!("G21\nG91\nG0 X10 Y10\nG28\nG0 X10 Y10\n", p.render());
+    }
+
+    #[test]
+    fn test_relative_after_homing_some_axes() {
+        let mut p = Program::new();
+        p.emit_positioning(Positioning::Relative)
+            .move_xyz(Point3d { x: 10.0, y: 10.0, z: 5.0 }, None, None)
+            .home(Axes { x: true, ..Axes::default() })
+            .push(crate::homing::home(Flavor::Grbl, Axes { z: true, ..Axes::default() }))
+            .move_xyz(Point3d { x: 20.0, y: 20.0, z: 1.0 }, None, None);
+        assert_eq!("G21\nG91\nG0 X10 Y10 Z5\nG28 X\n$HZ\nG0 X20 Y10 Z1\n", p.render());
+    }
+
+    #[test]
+    fn test_relative_reaches_same_positions() {
+        for units in [Units::Millimeters, Units::Inches].iter() {
+            let mut p = sample_program();
+            p.emit_units(*units);
+            let absolute = simulate(&p.render());
+            p.emit_positioning(Positioning::Relative);
+            let relative = simulate(&p.render());
+            // relative programs have one extra line for G91
+            assert_eq!(absolute.states.len() + 1, relative.states.len());
+            for (a, r) in absolute.states.iter().skip(1).zip(relative.states.iter().skip(2)) {
+                assert!(approx(a.position.x, r.position.x), "{:?} {:?}", a, r);
+                assert!(approx(a.position.y, r.position.y), "{:?} {:?}", a, r);
+                assert!(approx(a.position.z, r.position.z), "{:?} {:?}", a, r);
+                assert!(approx(a.e, r.e), "{:?} {:?}", a, r);
+                assert!(approx(a.filament, r.filament), "{:?} {:?}", a, r);
+            }
+        }
+    }
+
+    #[test]
+    fn test_relative_positioning_with_relative_extrusion() {
+        let mut p = Program::new();
+        p.emit_positioning(Positioning::Relative).push(relative_extrution());
+        for x in [10.0, 20.0, 30.0].iter() {
+            p.move_xy(Point2d { x: *x, y: 0.0 }, None, Some(1.0));
+        }
+        let gcode = p.render();
+        assert_eq!("G21\nG91\nM83\nG1 X10 Y0 E1\nG1 X10 Y0 E1\nG1 X10 Y0 E1\n", gcode);
+        let s = simulate(&gcode).final_state().clone();
+        assert_eq!(30.0, s.position.x);
+        assert!(approx(3.0, s.filament), "{}", s.filament);
+    }
+
+    #[test]
+    fn test_emit_line_numbers() {
+        let mut p = sample_program();
+        p.emit_line_numbers(true).emit_positioning(Positioning::Relative);
+        let framed = p.render();
+        assert!(framed.starts_with("N0 M110 N0*125\nN1 G21*"));
+        assert_eq!(Ok(framed.lines().count()), crate::framing::verify(&framed));
+    }
+
+    /// Whether the simulator modelled every command, moves before homing are fine in these tests
+    fn all_supported(timeline: &Timeline) -> bool {
+        !timeline.warnings.iter().any(|w| matches!(w, Warning::UnsupportedCommand { .. }))
+    }
+
+    fn two_tools() -> MachineProfile {
+        let second = Tool { offset: Point3d { x: 25.0, y: -0.5, z: 0.0 }, standby_temperature: Some(170), ..Tool::new(240) };
+        MachineProfile { tools: vec![Tool::new(210), second], ..MachineProfile::default() }
+    }
+
+    #[test]
+    fn test_tool_change() {
+        let mut p = Program::new();
+        p.set_machine(two_tools())
+            .select_tool(0)
+            .move_xyz(Point3d { x: 10.0, y: 10.0, z: 0.2 }, None, Some(1.0))
+            .select_tool(0)
+            .select_tool(1)
+            .move_xy(Point2d { x: 20.0, y: 10.0 }, None, Some(2.0))
+            .select_tool(0);
+        let rendered = p.render();
+        let expected = "G21\nM218 T1 X25 Y-0.5 Z0\nT0\nG1 X10 Y10 Z0.2 E1\nG92 E0\nG1 E-2 F2100\nG0 Z0.6\nT1\nM109 S240 T1\nG92 E0\nG1 E2 F300\nG0 Z0.2\nG92 E1\nG1 X20 Y10 E2\n";
+        assert!(rendered.starts_with(expected), "{}", rendered);
+        // the second tool waits at its standby temperature once the first one is back
+        assert!(rendered.ends_with("G0 Z0.6\nM104 S170 T1\nT0\nM109 S210 T0\nG92 E0\nG1 E2 F300\nG0 Z0.2\nG92 E2\n"), "{}", rendered);
+        let timeline = simulate(&rendered);
+        assert!(all_supported(&timeline));
+        let s = timeline.final_state();
+        assert_eq!((0, 2.0, 170.0), (s.tool, s.e, s.hotend_temp(1)));
+        assert!(approx(0.2, s.position.z));
+    }
+
+    #[test]
+    fn test_tool_change_relative() {
+        let mut machine = two_tools();
+        machine.tool_change.park = Some(Point2d { x: 0.0, y: 200.0 });
+        let mut p = Program::new();
+        p.set_machine(machine)
+            .emit_flavor(Flavor::RepRapFirmware)
+            .move_xyz(Point3d { x: 10.0, y: 10.0, z: 0.2 }, None, Some(1.0))
+            .select_tool(0)
+            .select_tool(1)
+            .move_xy(Point2d { x: 20.0, y: 10.0 }, None, Some(2.0));
+        let absolute = simulate(&p.render());
+        assert!(p.render().starts_with("G21\nG10 P1 X25 Y-0.5 Z0\n"));
+        p.emit_positioning(Positioning::Relative);
+        let relative = simulate(&p.render());
+        let (a, r) = (absolute.final_state(), relative.final_state());
+        assert!(approx(a.position.x, r.position.x) && approx(a.position.y, r.position.y) && approx(a.position.z, r.position.z));
+        assert!(approx(a.filament, r.filament), "{} {}", a.filament, r.filament);
+        assert_eq!(Point3d { x: 20.0, y: 10.0, z: 0.2 }, a.position);
+    }
+
+    #[test]
+    fn test_tracks_added_commands() {
+        let mut p = Program::with_units(Units::Inches);
+        p.move_xyz(Point3d { x: 1.0, y: 2.0, z: 0.5 }, None, Some(0.1)).push("M83\nG28 X".to_string());
+        assert!(p.relative_extrusion());
+        assert_eq!(Point3d { x: 0.0, y: 50.8, z: 12.7 }, p.position().0);
+        assert!(approx(2.54, p.position().1));
+        p.move_xy_arc_ij(Some(Point2d { x: 1.0, y: 1.0 }), Some(0.5), None, Some(0.2), false).push(crate::absolute_extrution());
+        assert!(!p.relative_extrusion());
+        assert_eq!(Point3d { x: 25.4, y: 25.4, z: 12.7 }, p.position().0);
+        assert!(approx(5.08, p.position().1));
+    }
+
+    #[test]
+    fn test_home_if_needed_keeps_homed_position() {
+        let mut p = Program::new();
+        p.set_retraction(Retraction { length: 0.0, z_hop: 0.5, ..Retraction::default() })
+            .home(Axes::all())
+            .move_xyz(Point3d { x: 10.0, y: 10.0, z: 5.0 }, None, None)
+            .home_if_needed(Axes::all());
+        assert_eq!(Point3d { x: 10.0, y: 10.0, z: 5.0 }, p.position().0);
+        p.retract();
+        assert!(p.render().ends_with("G28 O\nG0 Z5.5\n"));
+        // once the motors are off, homing is needed again
+        p.unretract().push(crate::disable_motors()).push("G28 O".to_string());
+        assert_eq!(Point3d { x: 0.0, y: 0.0, z: 0.0 }, p.position().0);
+        // the simulator agrees
+        p.move_z(1.0).home_if_needed(Axes::all());
+        let s = simulate(&p.render()).final_state().clone();
+        assert_eq!((p.position().0, Axes::all()), (s.position, s.homed));
+        assert_eq!(1.0, s.position.z);
+    }
+
+    #[test]
+    fn test_retract() {
+        let mut p = Program::with_units(Units::Inches);
+        p.set_retraction(Retraction { length: 2.0, z_hop: 0.5, ..Retraction::default() })
+            .push(crate::relative_extrution())
+            .move_xyz(Point3d { x: 1.0, y: 1.0, z: 0.01 }, None, Some(0.05))
+            .unretract()
+            .retract()
+            .retract()
+            .move_xy(Point2d { x: 3.0, y: 1.0 }, None, None)
+            .unretract()
+            .move_xy(Point2d { x: 4.0, y: 1.0 }, None, Some(0.05));
+        let rendered = p.render();
+        assert_eq!(1, rendered.matches("E-2 ").count(), "{}", rendered);
+        let timeline = simulate(&rendered);
+        assert!(approx(2.0, timeline.max_retraction()));
+        let s = timeline.final_state();
+        assert!(approx(0.254, s.position.z) && approx(2.54, s.filament), "{:?}", s);
+        assert!(timeline.states.iter().any(|s| approx(0.754, s.position.z)));
+    }
+
+    /// Returns the filament extruded on the wipe tower on each 0.2mm layer, and the lines printed
+    /// there as (layer, line) pairs
+    fn tower_extrusion(timeline: &Timeline, tower: WipeTower) -> (Vec<f32>, Vec<(usize, i32)>) {
+        let (mut extruded, mut lines) = (vec![0.0; 8], Vec::new());
+        for pair in timeline.states.windows(2) {
+            let (from, to) = (&pair[0], &pair[1]);
+            let inside = |s: &crate::simulator::State| tower.contains(Point2d { x: s.position.x, y: s.position.y });
+            if to.filament > from.filament && inside(from) && inside(to) {
+                let layer = (to.position.z / 0.2).round() as usize;
+                extruded[layer] += to.filament - from.filament;
+                if from.position.y == to.position.y && from.position.x != to.position.x {
+                    lines.push((layer, (to.position.y * 100.0).round() as i32));
+                }
+            }
+        }
+        (extruded, lines)
+    }
+
+    #[test]
+    fn test_wipe_tower() {
+        let tower = WipeTower::new(Point2d { x: 150.0, y: 150.0 }, 20.0, 20.0);
+        let mut p = Program::new();
+        p.set_machine(two_tools()).set_wipe_tower(tower).select_tool(0);
+        for layer in 1..6 {
+            p.begin_layer(0.2 * layer as f32);
+            if layer == 2 || layer == 4 {
+                p.select_tool((layer / 2 % 2) as u8);
+            }
+            p.move_xy(Point2d { x: 10.0, y: 10.0 }, None, None).move_xy(Point2d { x: 50.0, y: 10.0 }, None, Some(layer as f32 * 2.0));
+        }
+        // the tower is printed up to the last layer started
+        p.begin_layer(1.2);
+        let timeline = simulate(&p.render());
+        assert!(all_supported(&timeline));
+        let (extruded, _) = tower_extrusion(&timeline, tower);
+        let sparse = tower.filament_per_mm(0.2) * (tower.width - tower.line_width) * tower.line_count(tower.sparse_spacing) as f32;
+        let purge = tower.purge_volume / (std::f32::consts::PI * 0.875 * 0.875) + ToolChange::default().prime;
+        for (n, &e) in extruded.iter().enumerate().skip(1).take(5) {
+            let expected = if n == 2 || n == 4 { purge } else { sparse };
+            assert!(e >= expected * 0.99 && e < expected * 1.2, "layer {}: {} {}", n, e, expected);
+        }
+        assert_eq!(0.0, extruded[6]);
+        let s = timeline.final_state();
+        assert_eq!((1.2, 10.0), (s.position.z, s.e));
+    }
+
+    #[test]
+    fn test_wipe_tower_carries_purge_over() {
+        // 33 lines fit across the tower, the purge needs 40
+        let tower = WipeTower::new(Point2d { x: 150.0, y: 150.0 }, 20.0, 15.0);
+        let mut p = Program::new();
+        p.set_machine(two_tools()).set_wipe_tower(tower).select_tool(0).begin_layer(0.2).select_tool(1).select_tool(0);
+        p.move_xy(Point2d { x: 10.0, y: 10.0 }, None, Some(1.0)).begin_layer(0.4).begin_layer(0.6).begin_layer(0.8).begin_layer(1.0);
+        let timeline = simulate(&p.render());
+        assert!(all_supported(&timeline));
+        let (extruded, lines) = tower_extrusion(&timeline, tower);
+        // no line is printed twice on a layer
+        let mut unique = lines.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        assert_eq!(lines.len(), unique.len());
+        let count = |layer: usize| lines.iter().filter(|l| l.0 == layer).count();
+        // the purge of both tool changes spreads over three layers, the fourth gets sparse lines
+        let sparse = tower.line_count(tower.sparse_spacing);
+        assert_eq!((33, 33, 14, sparse), (count(1), count(2), count(3), count(4)));
+        // 14 lines joined in a zig-zag
+        let length = 14.0 * (tower.width - tower.line_width) + 13.0 * tower.line_width;
+        assert!((extruded[3] - length * tower.filament_per_mm(0.2)).abs() < 0.01, "{}", extruded[3]);
+        assert_eq!(1.0, timeline.final_state().e);
+    }
+
+    #[test]
+    fn test_tool_change_before_first_layer() {
+        let tower = WipeTower::new(Point2d { x: 150.0, y: 150.0 }, 20.0, 20.0);
+        let mut p = Program::new();
+        p.set_machine(two_tools()).set_wipe_tower(tower).select_tool(0).select_tool(1);
+        // nothing is printed on the tower until there is a layer to print on
+        assert!(!p.render().contains("X150.225"));
+        p.begin_layer(0.2).begin_layer(0.4);
+        let timeline = simulate(&p.render());
+        let (extruded, lines) = tower_extrusion(&timeline, tower);
+        assert_eq!(0.0, extruded[0]);
+        assert_eq!(tower.purge_lines(tower.purge_volume, 0.2), lines.iter().filter(|l| l.0 == 1).count());
+        assert!(!lines.iter().any(|l| l.0 == 2));
+    }
+
+    #[test]
+    fn test_begin_layer_in_inches() {
+        let mut p = Program::with_units(Units::Inches);
+        p.move_z(0.01).begin_layer(0.02);
+        assert_eq!("G21\nG0 Z0.254\nG0 Z0.508\n", p.render());
+    }
+
+    #[test]
+    fn test_start_end_sequence() {
+        let mut p = Program::new();
+        p.start_sequence(&StartSequence::new(60, 210))
+            .move_xy(Point2d { x: 50.0, y: 50.0 }, Some(3000.0), None)
+            .move_xy(Point2d { x: 60.0, y: 50.0 }, None, Some(1.0))
+            .end_sequence(&EndSequence::default());
+        let gcode = p.render();
+        assert!(gcode.starts_with("G21\nM140 S60\nM104 S210\nM190 S60\nM109 S210\nM82\nG28\n"));
+        let timeline = simulate(&gcode);
+        let s = timeline.final_state();
+        assert!(s.homed.is_empty());
+        assert_eq!((0.0, 0.0), (s.hotend_temp(0), s.bed_temp));
+        assert_eq!(Point3d { x: 0.0, y: 0.0, z: 10.3 }, s.position);
+        assert!(approx(14.0, s.filament), "{}", s.filament);
+        p.emit_positioning(Positioning::Relative);
+        let relative = simulate(&p.render());
+        assert_eq!(s.position, relative.final_state().position);
+        assert!(approx(s.filament, relative.final_state().filament));
+    }
+
+    #[test]
+    fn test_start_sequence_with_relative_positioning() {
+        let mut p = Program::new();
+        p.start_sequence(&StartSequence::new(60, 200));
+        let (start, _) = p.position();
+        for (n, x) in [10.0, 20.0, 30.0].iter().enumerate() {
+            p.move_xy(Point2d { x: start.x + x, y: start.y }, None, Some(n as f32 + 1.0));
+        }
+        let absolute = simulate(&p.render()).final_state().clone();
+        p.emit_positioning(Positioning::Relative);
+        let gcode = p.render();
+        assert!(!gcode.contains("M82"));
+        assert!(gcode.ends_with("G1 X10 Y0 E1\nG1 X10 Y0 E1\nG1 X10 Y0 E1\n"), "{}", gcode);
+        let relative = simulate(&gcode).final_state().clone();
+        assert_eq!(3.0, relative.e);
+        assert!(approx(absolute.filament, relative.filament), "{} {}", absolute.filament, relative.filament);
+    }
+
+    #[test]
+    fn test_leveling_follows_flavor_and_units() {
+        let grid = ProbeGrid { min: Point2d { x: 25.4, y: 25.4 }, max: Point2d { x: 254.0, y: 127.0 }, points_x: 3, points_y: 3 };
+        let mut p = Program::new();
+        p.start_sequence(&StartSequence { probe_z_offset: Some(-1.27), leveling: Leveling::Probe(Some(grid)), purge_line: None, ..StartSequence::new(60, 210) });
+        assert!(p.render().ends_with("G28\nM851 Z-1.27\nG29 L25.4 R254 F25.4 B127 X3 Y3\n"));
+        p.emit_flavor(Flavor::RepRapFirmware).emit_units(Units::Inches);
+        assert!(p.render().ends_with("G28\nG31 Z-0.05\nM557 X1:10 Y1:5 P3:3\nG29 S0\n"));
+        p.emit_flavor(Flavor::Klipper);
+        assert!(p.render().ends_with("G28\nBED_MESH_CALIBRATE MESH_MIN=1,1 MESH_MAX=10,5 PROBE_COUNT=3,3\n"));
+    }
+
+    #[test]
+    fn test_end_sequence_turns_off_every_hotend() {
+        let mut p = Program::new();
+        p.set_machine(two_tools()).end_sequence(&EndSequence { retract: 0.0, z_lift: 0.0, park: None, motors_off: false, ..EndSequence::default() });
+        assert_eq!("G21\nM218 T1 X25 Y-0.5 Z0\nM104 S0 T0\nM104 S0 T1\nM140 S0\nM107\n", p.render());
+    }
+
+    fn two_objects() -> Program {
+        let mut p = Program::new();
+        p.push(auto_home()).push(relative_extrution());
+        for layer in 1..3 {
+            let z = 0.2 * layer as f32;
+            p.begin_object("cube").move_xyz(Point3d { x: 0.0, y: 0.0, z }, None, None);
+            for &(x, y) in [(10.0, 0.0), (10.0, 10.0), (0.0, 10.0), (0.0, 0.0)].iter() {
+                p.move_xy(Point2d { x, y }, None, Some(layer as f32));
+            }
+            p.begin_object("cylinder")
+                .move_xy(Point2d { x: 30.0, y: 5.0 }, None, None)
+                .move_xy_arc_ij(None, Some(-5.0), None, Some(layer as f32), true)
+                .end_object();
+        }
+        p
+    }
+
+    #[test]
+    fn test_marlin_object_labels() {
+        let rendered = two_objects().render();
+        let labels: Vec<&str> = rendered.lines().filter(|l| l.starts_with("M486")).collect();
+        assert_eq!(vec!["M486 T2", "M486 S0 Acube", "M486 S-1", "M486 S1 Acylinder", "M486 S-1", "M486 S0 Acube", "M486 S-1", "M486 S1 Acylinder", "M486 S-1"], labels);
+        assert!(rendered.starts_with("G21\nM486 T2\nG28\nM83\nM486 S0 Acube\n"));
+    }
+
+    #[test]
+    fn test_klipper_object_labels() {
+        let mut p = two_objects();
+        p.emit_flavor(Flavor::Klipper);
+        let rendered = p.render();
+        let mut lines = rendered.lines().skip(1);
+        assert_eq!(Some("EXCLUDE_OBJECT_DEFINE NAME=cube CENTER=5,5 POLYGON=[[0,0],[10,0],[10,10],[0,10]]"), lines.next());
+        let cylinder = lines.next().unwrap();
+        assert!(cylinder.starts_with("EXCLUDE_OBJECT_DEFINE NAME=cylinder CENTER=25,5 POLYGON=[[20,5],"), "{}", cylinder);
+        assert_eq!(Some("G28"), lines.next());
+        assert_eq!(Some("M83"), lines.next());
+        assert_eq!(Some("EXCLUDE_OBJECT_START NAME=cube"), lines.next());
+        let ends = rendered.lines().filter(|l| l.starts_with("EXCLUDE_OBJECT_END")).count();
+        assert_eq!(4, ends);
+        assert!(simulate(&rendered).warnings.is_empty());
+    }
+
+    #[test]
+    fn test_object_closed_at_end() {
+        let mut p = Program::new();
+        p.begin_object("part").move_xy(Point2d { x: 1.0, y: 1.0 }, None, Some(1.0)).begin_object("part");
+        assert_eq!("G21\nM486 T1\nM486 S0 Apart\nG1 X1 Y1 E1\nM486 S-1\n", p.render());
+        p.emit_flavor(Flavor::Grbl);
+        assert_eq!("G21\nG1 X1 Y1 E1\n", p.render());
+    }
+
+    #[test]
+    fn test_comments() {
+        let mut p = Program::new();
+        p.with_comment("nothing to attach to")
+            .push(auto_home())
+            .with_comment("home")
+            .with_comment("home all axes")
+            .marker("LAYER", 0)
+            .with_comment("(not) attached to markers")
+            .begin_object("part")
+            .with_comment("part")
+            .move_z(0.2);
+        let rendered = p.render();
+        assert_eq!("G21\nM486 T1\n; nothing to attach to\nG28 ; home all axes\n;LAYER:0\n; (not) attached to markers\nM486 S0 Apart ; part\nG0 Z0.2\nM486 S-1\n", rendered);
+        // grbl skips object labels but keeps their comments
+        p.emit_flavor(Flavor::Grbl);
+        assert_eq!("G21\n(nothing to attach to)\nG28 (home all axes)\n(LAYER:0)\n([not] attached to markers)\n(part)\nG0 Z0.2\n", p.render());
+    }
+
+    #[test]
+    fn test_parser_preserves_comments() {
+        let mut p = Program::new();
+        p.comment("start").move_z(0.2).with_comment("first layer").marker("TYPE", "WALL-OUTER");
+        for flavor in [Flavor::Marlin, Flavor::Grbl].iter() {
+            p.emit_flavor(*flavor);
+            let lines = crate::parser::parse(&p.render());
+            assert_eq!(Some("start".to_string()), lines[1].comment);
+            assert_eq!(Some("first layer".to_string()), lines[2].comment);
+            assert_eq!(Some(0.2), lines[2].command.as_ref().unwrap().get("Z"));
+            assert_eq!(Some(("TYPE", "WALL-OUTER")), lines[3].marker());
+        }
+        assert!(all_supported(&simulate(&p.render())));
+    }
+
+    #[test]
+    fn test_emit_metadata_and_thumbnails() {
+        let mut p = two_objects();
+        p.emit_metadata(true).emit_thumbnails(&[(16, 16), (32, 24)]);
+        let rendered = p.render();
+        assert!(rendered.starts_with("; thumbnail begin 16x16 "));
+        assert_eq!(1, rendered.matches("; thumbnail begin 32x24 ").count());
+        let header = rendered.split("; thumbnail end\n;\n").last().unwrap();
+        assert!(header.starts_with("; generated by gen_gcode"));
+        for line in [";FLAVOR:Marlin", ";LAYER_COUNT:2", ";MINX:0", ";MAXX:30", ";MAXY:10", ";MINZ:0.2", ";MAXZ:0.4", ";LAYER:0", ";LAYER:1"].iter() {
+            assert!(rendered.lines().any(|l| l == *line), "{} missing in {}", line, header);
+        }
+        assert!(simulate(&rendered).warnings.is_empty());
+        p.emit_line_numbers(true);
+        assert!(!p.render().contains(';'));
+        p.emit_line_numbers(false).emit_flavor(Flavor::Grbl);
+        let grbl = p.render();
+        assert!(!grbl.contains(';'), "{}", grbl);
+        assert!(grbl.contains("\n(FLAVOR:Grbl)\n") && grbl.contains("\n(LAYER:1)\n") && grbl.starts_with("(thumbnail begin 16x16 "));
+        p.move_xyz(Point3d { x: 5.0, y: 5.0, z: f32::NAN }, None, Some(1.0));
+        assert!(p.render().starts_with("(thumbnail begin 16x16 "));
+    }
+
+    #[test]
+    fn test_convex_hull() {
+        let points = [(0.0, 0.0), (2.0, 0.0), (1.0, 1.0), (2.0, 2.0), (0.0, 2.0), (1.0, 0.0)];
+        let hull = convex_hull(points.iter().map(|&(x, y)| Point2d { x, y }).collect());
+        let expected: Vec<Point2d> = [(0.0, 0.0), (2.0, 0.0), (2.0, 2.0), (0.0, 2.0)].iter().map(|&(x, y)| Point2d { x, y }).collect();
+        assert_eq!(expected, hull);
+    }
+
+    #[test]
+    fn test_convex_hull_with_nan() {
+        let points = [(0.0, 0.0), (f32::NAN, 1.0), (2.0, 0.0), (1.0, 2.0)];
+        let hull = convex_hull(points.iter().map(|&(x, y)| Point2d { x, y }).collect());
+        let expected: Vec<Point2d> = [(0.0, 0.0), (2.0, 0.0), (1.0, 2.0)].iter().map(|&(x, y)| Point2d { x, y }).collect();
+        assert_eq!(expected, hull);
+    }
+
+    #[test]
+    fn test_object_names_sanitized() {
+        let mut p = Program::new();
+        p.emit_line_numbers(true).begin_object("part;1 (copy)*2").move_xy(Point2d { x: 10.0, y: 0.0 }, None, Some(0.5));
+        let rendered = p.render();
+        assert!(rendered.contains(" M486 S0 Apart_1__copy__2*"), "{}", rendered);
+        assert!(crate::framing::verify(&rendered).is_ok());
+    }
+
+    #[test]
+    fn test_format_value() {
+        assert_eq!("0", format_value(-0.00001, 3));
+        assert_eq!("0.17", format_value(0.17, 3));
+        assert_eq!("-1.5", format_value(-1.5, 3));
+        assert_eq!("0.3937", format_value(0.39370078, 4));
+        assert_eq!("120", format_value(120.0, 1));
+    }
+}
+
+use crate::framing::frame;
+use crate::klipper;
+use crate::machine::{MachineProfile, Retraction};
+use crate::metadata::{add_layer_comments, Metadata};
+use crate::parser::{parse_line, Command};
+use crate::homing::{self, Axes};
+use crate::leveling::{self, Leveling, ProbeGrid};
+use crate::sequence::{EndSequence, StartSequence};
+use crate::thumbnail::{render_preview, thumbnail_block, Image};
+use crate::wipe_tower::WipeTower;
+use crate::{Flavor, Point2d, Point3d, Positioning, Units};
+use std::f32::consts::PI;
+use std::fmt::Display;
+
+/// Decimal places used when emitting lengths, extrusion and feed rates in each unit system
+fn precision(units: Units) -> (usize, usize, usize) {
+    match units {
+        Units::Millimeters => (3, 5, 1),
+        Units::Inches => (4, 6, 2),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Op {
+    Move { x: Option<f32>, y: Option<f32>, z: Option<f32>, e: Option<f32>, f: Option<f32> },
+    Arc { dest: Option<Point2d>, i: Option<f32>, j: Option<f32>, e: Option<f32>, ccw: bool },
+    SetPosition { x: Option<f32>, y: Option<f32>, z: Option<f32>, e: Option<f32> },
+    Raw(String),
+    ObjectStart(usize),
+    ObjectEnd(usize),
+    Comment(String),
+    Marker(String, String),
+    Leveling(Leveling),
+    ProbeZOffset(f32),
+    Home { axes: Axes, only_if_needed: bool },
+    /// M82 or M83, left out of relative programs when absolute since G91 already makes E relative
+    ExtrusionMode { relative: bool },
+}
+
+/// A G-Code program, built up command by command
+///
+/// Geometry is given in the program's working units and stored in millimeters; it is converted to
+/// the emitted units (G21 millimeters by default) when the program is rendered. Coordinates, arc
+/// offsets, extruder positions and feed rates (units per minute) are all converted.
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::{Point2d, Units, auto_home};
+/// use gen_gcode::program::Program;
+///
+/// let mut program = Program::new();
+/// program.push(auto_home())
+///     .move_xy(Point2d { x: 25.4, y: 12.7 }, Some(1270.0), None);
+/// assert_eq!("G21\nG28\nG0 X25.4 Y12.7 F1270\n", program.render());
+///
+/// // same geometry, emitted in inches
+/// program.emit_units(Units::Inches);
+/// assert_eq!("G20\nG28\nG0 X1 Y0.5 F50\n", program.render());
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    units: Units,
+    emit_units: Units,
+    positioning: Positioning,
+    line_numbers: bool,
+    metadata: bool,
+    thumbnails: Vec<(u32, u32)>,
+    flavor: Flavor,
+    ops: Vec<(Op, Option<String>)>,
+    objects: Vec<String>,
+    current_object: Option<usize>,
+    machine: MachineProfile,
+    current_tool: Option<u8>,
+    wipe_tower: Option<WipeTower>,
+    tower_layer: TowerLayer,
+    /// Volume of filament still to purge on the wipe tower, from tool changes whose purge didn't
+    /// fit on their layer
+    tower_purge: f32,
+    /// Extruder position and height before the last [Program::retract], until it is undone
+    retracted: Option<(f32, f32)>,
+    /// Where the commands added so far leave the machine, kept up to date by [Program::add]
+    tracked: Tracked,
+}
+
+/// The state the commands added to a program leave the machine in, positions in millimeters
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Tracked {
+    pos: Point3d,
+    extruder: f32,
+    /// Whether extruder moves are relative, set by M83 and cleared by M82
+    relative_extrusion: bool,
+    homed: Axes,
+}
+
+impl Default for Tracked {
+    fn default() -> Self {
+        Tracked { pos: Point3d { x: 0.0, y: 0.0, z: 0.0 }, extruder: 0.0, relative_extrusion: false, homed: Axes::default() }
+    }
+}
+
+impl Tracked {
+    /// Updates the state with a command being added, formatted commands are parsed once, here
+    fn apply(&mut self, op: &Op) {
+        let pos = self.pos;
+        match op {
+            Op::Move { x, y, z, e, .. } | Op::SetPosition { x, y, z, e } => {
+                self.pos = Point3d { x: x.unwrap_or(pos.x), y: y.unwrap_or(pos.y), z: z.unwrap_or(pos.z) };
+                self.extruder = e.unwrap_or(self.extruder);
+            }
+            Op::Arc { dest, e, .. } => {
+                if let Some(dest) = dest {
+                    self.pos = Point3d { x: dest.x, y: dest.y, z: pos.z };
+                }
+                self.extruder = e.unwrap_or(self.extruder);
+            }
+            Op::Raw(gcode) => {
+                for cmd in gcode.lines().filter_map(|line| parse_line(line).command) {
+                    match cmd.name.as_str() {
+                        "M82" => self.relative_extrusion = false,
+                        "M83" => self.relative_extrusion = true,
+                        _ => self.pos = home_position(self.pos, raw_homing(&mut self.homed, &cmd)),
+                    }
+                }
+            }
+            Op::Home { axes, only_if_needed } => self.pos = home_position(pos, home_axes(&mut self.homed, *axes, *only_if_needed)),
+            Op::ExtrusionMode { relative } => self.relative_extrusion = *relative,
+            _ => (),
+        }
+    }
+}
+
+/// The top layer printed on the wipe tower so far
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+struct TowerLayer {
+    /// Height of the layer below it, and its own height
+    below: f32,
+    z: f32,
+    /// Lines printed on it
+    lines: usize,
+}
+
+impl Default for Program {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Program {
+    /// Creates an empty program, working in and emitting millimeters
+    pub fn new() -> Self {
+        Self::with_units(Units::Millimeters)
+    }
+
+    /// Creates an empty program with the given working units, emitting millimeters
+    pub fn with_units(units: Units) -> Self {
+        Program {
+            units,
+            emit_units: Units::Millimeters,
+            positioning: Positioning::Absolute,
+            line_numbers: false,
+            metadata: false,
+            thumbnails: Vec::new(),
+            flavor: Flavor::Marlin,
+            ops: Vec::new(),
+            objects: Vec::new(),
+            current_object: None,
+            machine: MachineProfile::default(),
+            current_tool: None,
+            wipe_tower: None,
+            tower_layer: TowerLayer::default(),
+            tower_purge: 0.0,
+            retracted: None,
+            tracked: Tracked::default(),
+        }
+    }
+
+    /// Sets the units the program is emitted in (G20 or G21)
+    pub fn emit_units(&mut self, units: Units) -> &mut Self {
+        self.emit_units = units;
+        self
+    }
+
+    /// Sets whether moves are emitted as absolute positions (G90, the default) or as distances
+    /// from the previous position (G91)
+    ///
+    /// Geometry is always given as absolute positions. Relative moves are worked out assuming the
+    /// program starts at the origin; commands added with [Program::push] are emitted as is and are
+    /// not tracked, apart from homing which moves the homed axes back to the origin. Extrusion is
+    /// emitted relative as well, so pushing an M82 into a relative program breaks it.
+    ///
+    /// # Examples
+    /// ```
+    /// extern crate gen_gcode;
+    /// use gen_gcode::{Point2d, Positioning, auto_home};
+    /// use gen_gcode::program::Program;
+    ///
+    /// let mut program = Program::new();
+    /// program.emit_positioning(Positioning::Relative)
+    ///     .push(auto_home())
+    ///     .move_xy(Point2d { x: 10.0, y: 10.0 }, None, None)
+    ///     .move_xy(Point2d { x: 20.0, y: 10.0 }, None, Some(0.5))
+    ///     .move_xy(Point2d { x: 20.0, y: 20.0 }, None, Some(1.0));
+    /// assert_eq!("G21\nG91\nG28\nG0 X10 Y10\nG1 X10 Y0 E0.5\nG1 X0 Y10 E0.5\n", program.render());
+    /// ```
+    pub fn emit_positioning(&mut self, positioning: Positioning) -> &mut Self {
+        self.positioning = positioning;
+        self
+    }
+
+    /// Sets whether every emitted command is wrapped with a line number and checksum for streaming
+    /// over a serial connection, see [crate::framing::frame]
+    ///
+    /// # Examples
+    /// ```
+    /// extern crate gen_gcode;
+    /// use gen_gcode::auto_home;
+    /// use gen_gcode::program::Program;
+    ///
+    /// let mut program = Program::new();
+    /// program.emit_line_numbers(true).push(auto_home());
+    /// assert_eq!("N0 M110 N0*125\nN1 G21*27\nN2 G28*17\n", program.render());
+    /// ```
+    pub fn emit_line_numbers(&mut self, line_numbers: bool) -> &mut Self {
+        self.line_numbers = line_numbers;
+        self
+    }
+
+    /// Sets the firmware the program is emitted for (Marlin by default)
+    pub fn emit_flavor(&mut self, flavor: Flavor) -> &mut Self {
+        self.flavor = flavor;
+        self
+    }
+
+    /// Sets the machine the program runs on, its tool offsets are emitted at the start of the
+    /// program and its tool change routine is used by [Program::select_tool]
+    pub fn set_machine(&mut self, machine: MachineProfile) -> &mut Self {
+        self.machine = machine;
+        self
+    }
+
+    /// Sets the wipe tower tool changes purge on, see [crate::wipe_tower]. Layers must then be
+    /// started with [Program::begin_layer], so layers without a tool change get their part of
+    /// the tower too.
+    pub fn set_wipe_tower(&mut self, tower: WipeTower) -> &mut Self {
+        self.wipe_tower = Some(tower);
+        self
+    }
+
+    /// Sets whether the program starts with metadata comments read by printer interfaces, such as
+    /// the estimated print time, and marks each layer with a `;LAYER:` comment, see
+    /// [crate::metadata]. Comments are dropped when emitting line numbers.
+    ///
+    /// # Examples
+    /// ```
+    /// extern crate gen_gcode;
+    /// use gen_gcode::Point3d;
+    /// use gen_gcode::program::Program;
+    ///
+    /// let mut program = Program::new();
+    /// program.emit_metadata(true)
+    ///     .move_xyz(Point3d { x: 0.0, y: 0.0, z: 0.2 }, Some(1200.0), None)
+    ///     .move_xyz(Point3d { x: 20.0, y: 0.0, z: 0.2 }, None, Some(1.0));
+    /// let gcode = program.render();
+    /// assert!(gcode.contains(";TIME:1\n"));
+    /// assert!(gcode.contains(";LAYER_COUNT:1\n"));
+    /// assert!(gcode.ends_with("G21\n;LAYER:0\nG0 X0 Y0 Z0.2 F1200\nG1 X20 Y0 Z0.2 E1\n"));
+    /// ```
+    pub fn emit_metadata(&mut self, metadata: bool) -> &mut Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Sets the sizes, in pixels, of the PNG thumbnails embedded at the start of the program. They
+    /// show the extruding moves seen from above, see [crate::thumbnail].
+    pub fn emit_thumbnails(&mut self, sizes: &[(u32, u32)]) -> &mut Self {
+        self.thumbnails = sizes.to_vec();
+        self
+    }
+
+    fn mm(&self, v: Option<f32>) -> Option<f32> {
+        v.map(|v| self.units.to_mm(v))
+    }
+
+    fn add(&mut self, op: Op) -> &mut Self {
+        self.tracked.apply(&op);
+        self.ops.push((op, None));
+        self
+    }
+
+    /// Adds a line holding only a comment, written with `;` or `( )` depending on the flavor
+    ///
+    /// # Examples
+    /// ```
+    /// extern crate gen_gcode;
+    /// use gen_gcode::{Flavor, Point2d};
+    /// use gen_gcode::program::Program;
+    ///
+    /// let mut program = Program::new();
+    /// program.comment("skirt")
+    ///     .move_xy(Point2d { x: 10.0, y: 0.0 }, None, Some(0.5))
+    ///     .with_comment("first side")
+    ///     .marker("TYPE", "SKIRT");
+    /// assert_eq!("G21\n; skirt\nG1 X10 Y0 E0.5 ; first side\n;TYPE:SKIRT\n", program.render());
+    ///
+    /// program.emit_flavor(Flavor::Grbl);
+    /// assert_eq!("G21\n(skirt)\nG1 X10 Y0 E0.5 (first side)\n(TYPE:SKIRT)\n", program.render());
+    /// ```
+    pub fn comment(&mut self, text: &str) -> &mut Self {
+        self.add(Op::Comment(text.to_string()))
+    }
+
+    /// Attaches a trailing comment to the last command added, replacing any comment it had. Without
+    /// a command to attach to, the comment gets its own line.
+    pub fn with_comment(&mut self, text: &str) -> &mut Self {
+        match self.ops.last_mut() {
+            Some((op, comment)) if !matches!(op, Op::Comment(_) | Op::Marker(..)) => {
+                *comment = Some(text.to_string());
+                self
+            }
+            _ => self.comment(text),
+        }
+    }
+
+    /// Adds a section marker, such as `;LAYER:3` or `;TYPE:WALL-OUTER`, used by slicers to tell
+    /// printer interfaces and G-Code viewers what is being printed
+    pub fn marker<T: Display>(&mut self, key: &str, value: T) -> &mut Self {
+        self.add(Op::Marker(key.to_string(), value.to_string()))
+    }
+
+    /// Adds an already formatted command, such as one returned by [crate::set_hotend_temp], it is
+    /// emitted as is
+    pub fn push(&mut self, gcode: String) -> &mut Self {
+        self.add(Op::Raw(gcode.trim_end_matches('\n').to_string()))
+    }
+
+    /// Adds a move in the XY plane, like [crate::move_xy]
+    pub fn move_xy(&mut self, dest: Point2d, feed_rate: Option<f32>, flow_rate: Option<f32>) -> &mut Self {
+        let op = Op::Move { x: self.mm(Some(dest.x)), y: self.mm(Some(dest.y)), z: None, e: self.mm(flow_rate), f: self.mm(feed_rate) };
+        self.add(op)
+    }
+
+    /// Adds a move in 3 dimentions, like [crate::move_xyz]
+    pub fn move_xyz(&mut self, dest: Point3d, feed_rate: Option<f32>, flow_rate: Option<f32>) -> &mut Self {
+        let op = Op::Move { x: self.mm(Some(dest.x)), y: self.mm(Some(dest.y)), z: self.mm(Some(dest.z)), e: self.mm(flow_rate), f: self.mm(feed_rate) };
+        self.add(op)
+    }
+
+    /// Adds a move along the Z axis, like [crate::move_z]
+    pub fn move_z(&mut self, z: f32) -> &mut Self {
+        self.add(Op::Move { x: None, y: None, z: self.mm(Some(z)), e: None, f: None })
+    }
+
+    /// Adds an arc in the XY plane, like [crate::move_xy_arc_ij]
+    pub fn move_xy_arc_ij(&mut self, dest: Option<Point2d>, x_offset: Option<f32>, y_offset: Option<f32>, flow_rate: Option<f32>, ccw: bool) -> &mut Self {
+        let dest = dest.map(|d| Point2d { x: self.units.to_mm(d.x), y: self.units.to_mm(d.y) });
+        let op = Op::Arc { dest, i: self.mm(x_offset), j: self.mm(y_offset), e: self.mm(flow_rate), ccw };
+        self.add(op)
+    }
+
+    /// Sets the current position in the XY plane, like [crate::set_pos_2d]
+    pub fn set_pos_2d(&mut self, pos: Point2d, extrude_pos: Option<f32>) -> &mut Self {
+        let op = Op::SetPosition { x: self.mm(Some(pos.x)), y: self.mm(Some(pos.y)), z: None, e: self.mm(extrude_pos) };
+        self.add(op)
+    }
+
+    /// Sets the current position in 3 dimentions, like [crate::set_pos_3d]
+    pub fn set_pos_3d(&mut self, pos: Point3d, extrude_pos: Option<f32>) -> &mut Self {
+        let op = Op::SetPosition { x: self.mm(Some(pos.x)), y: self.mm(Some(pos.y)), z: self.mm(Some(pos.z)), e: self.mm(extrude_pos) };
+        self.add(op)
+    }
+
+    /// Sets the extruder position, like [crate::reset_extruder]
+    pub fn reset_extruder(&mut self, extrude_pos: f32) -> &mut Self {
+        self.add(Op::SetPosition { x: None, y: None, z: None, e: self.mm(Some(extrude_pos)) })
+    }
+
+    /// Homes the given axes, written for the flavor the program is emitted for, see
+    /// [crate::homing::home]. The homed axes move back to the origin.
+    ///
+    /// # Examples
+    /// ```
+    /// extern crate gen_gcode;
+    /// use gen_gcode::Flavor;
+    /// use gen_gcode::homing::Axes;
+    /// use gen_gcode::program::Program;
+    ///
+    /// let mut program = Program::new();
+    /// program.home(Axes { x: true, y: true, z: false });
+    /// assert_eq!("G21\nG28 X Y\n", program.render());
+    /// program.emit_flavor(Flavor::Grbl);
+    /// assert_eq!("G21\n$HX\n$HY\n", program.render());
+    /// ```
+    pub fn home(&mut self, axes: Axes) -> &mut Self {
+        self.add(Op::Home { axes, only_if_needed: false })
+    }
+
+    /// Homes the given axes unless the firmware already knows their position, see
+    /// [crate::homing::home_if_needed]. When the program homed them all earlier, the firmware
+    /// skips homing and the nozzle stays where it is; otherwise they are taken to be homed.
+    ///
+    /// # Examples
+    /// ```
+    /// extern crate gen_gcode;
+    /// use gen_gcode::{Point3d, Positioning};
+    /// use gen_gcode::homing::Axes;
+    /// use gen_gcode::program::Program;
+    ///
+    /// let mut program = Program::new();
+    /// program.emit_positioning(Positioning::Relative)
+    ///     .home(Axes::all())
+    ///     .move_xyz(Point3d { x: 10.0, y: 10.0, z: 5.0 }, None, None)
+    ///     .home_if_needed(Axes::all())
+    ///     .move_xyz(Point3d { x: 20.0, y: 10.0, z: 5.0 }, None, None);
+    /// assert_eq!("G21\nG91\nG28\nG0 X10 Y10 Z5\nG28 O\nG0 X10 Y0 Z0\n", program.render());
+    /// ```
+    pub fn home_if_needed(&mut self, axes: Axes) -> &mut Self {
+        self.add(Op::Home { axes, only_if_needed: true })
+    }
+
+    /// Adds the commands leveling the bed, written for the flavor the program is emitted for, see
+    /// [crate::leveling]
+    ///
+    /// # Examples
+    /// ```
+    /// extern crate gen_gcode;
+    /// use gen_gcode::Flavor;
+    /// use gen_gcode::leveling::Leveling;
+    /// use gen_gcode::program::Program;
+    ///
+    /// let mut program = Program::new();
+    /// program.level_bed(Leveling::LoadSaved(None));
+    /// assert_eq!("G21\nM420 S1\n", program.render());
+    /// program.emit_flavor(Flavor::Klipper);
+    /// assert_eq!("G21\nBED_MESH_PROFILE LOAD=default\n", program.render());
+    /// ```
+    pub fn level_bed(&mut self, leveling: Leveling) -> &mut Self {
+        let units = self.units;
+        self.add(Op::Leveling(convert_grid(leveling, |v| units.to_mm(v))))
+    }
+
+    /// Sets the Z offset between the probe's trigger point and the nozzle, in millimeters, see
+    /// [crate::leveling::set_probe_z_offset]
+    pub fn set_probe_z_offset(&mut self, offset: f32) -> &mut Self {
+        self.add(Op::ProbeZOffset(self.units.to_mm(offset)))
+    }
+
+    /// Adds the commands that get the printer ready: heating, homing, leveling and a purge line,
+    /// see [StartSequence]. The extruder position is reset once the nozzle is primed. Programs
+    /// emitted with relative positioning leave out the M82, G91 already makes extrusion relative.
+    ///
+    /// # Examples
+    /// ```
+    /// extern crate gen_gcode;
+    /// use gen_gcode::program::Program;
+    /// use gen_gcode::leveling::Leveling;
+    /// use gen_gcode::sequence::StartSequence;
+    ///
+    /// let mut program = Program::new();
+    /// program.start_sequence(&StartSequence { leveling: Leveling::Probe(None), purge_line: None, ..StartSequence::new(60, 215) });
+    /// assert_eq!("G21\nM140 S60\nM104 S215\nM190 S60\nM109 S215\nM82\nG28\nG29\n", program.render());
+    /// ```
+    pub fn start_sequence(&mut self, sequence: &StartSequence) -> &mut Self {
+        let (bed, hotend) = (sequence.bed_temperature, sequence.hotend_temperature);
+        if sequence.parallel_heating {
+            self.push(crate::set_bed_temp(bed)).push(crate::set_hotend_temp(hotend, None));
+        }
+        self.push(crate::wait_bed_temp(bed)).push(crate::wait_hotend_temp(hotend, None));
+        self.add(Op::ExtrusionMode { relative: sequence.relative_extrusion });
+        if sequence.home {
+            self.home(Axes::all());
+        }
+        if let Some(offset) = sequence.probe_z_offset {
+            self.set_probe_z_offset(offset);
+        }
+        self.level_bed(sequence.leveling);
+        if let Some(line) = sequence.purge_line {
+            let (pos, _) = self.position();
+            self.add(Op::Move { x: None, y: None, z: Some(pos.z.max(line.z) + 2.0), e: None, f: None });
+            self.add(Op::Move { x: Some(line.start.x), y: Some(line.start.y), z: None, e: None, f: Some(line.travel_speed) });
+            self.add(Op::Move { x: None, y: None, z: Some(line.z), e: None, f: None });
+            self.add(Op::SetPosition { x: None, y: None, z: None, e: Some(0.0) });
+            self.add(Op::Move { x: Some(line.end.x), y: Some(line.end.y), z: None, e: Some(line.extrusion), f: Some(line.speed) });
+            self.add(Op::SetPosition { x: None, y: None, z: None, e: Some(0.0) });
+        }
+        self
+    }
+
+    /// Adds the commands that leave the printer safe once printing is done: retracting, lifting
+    /// and parking the nozzle, then turning off heaters, fans and motors, see [EndSequence].
+    /// Every hotend of the machine is turned off, see [Program::set_machine].
+    ///
+    /// # Examples
+    /// ```
+    /// extern crate gen_gcode;
+    /// use gen_gcode::Point3d;
+    /// use gen_gcode::program::Program;
+    /// use gen_gcode::sequence::EndSequence;
+    ///
+    /// let mut program = Program::new();
+    /// program.move_xyz(Point3d { x: 50.0, y: 50.0, z: 20.0 }, None, Some(100.0))
+    ///     .end_sequence(&EndSequence::default());
+    /// let expected = "G92 E0\nG1 E-2 F2100\nG0 Z30\nG0 X0 Y0 F6000\nM104 S0\nM140 S0\nM107\nM84\n";
+    /// assert!(program.render().ends_with(expected));
+    /// ```
+    pub fn end_sequence(&mut self, sequence: &EndSequence) -> &mut Self {
+        let (pos, _) = self.position();
+        if sequence.retract > 0.0 {
+            self.add(Op::SetPosition { x: None, y: None, z: None, e: Some(0.0) });
+            self.add(Op::Move { x: None, y: None, z: None, e: Some(-sequence.retract), f: Some(sequence.retract_speed) });
+        }
+        if sequence.z_lift > 0.0 {
+            self.add(Op::Move { x: None, y: None, z: Some(pos.z + sequence.z_lift), e: None, f: None });
+        }
+        if let Some(park) = sequence.park {
+            self.add(Op::Move { x: Some(park.x), y: Some(park.y), z: None, e: None, f: Some(sequence.travel_speed) });
+        }
+        match self.machine.tools.len() {
+            0 | 1 => {
+                self.push(crate::set_hotend_temp(0, None));
+            }
+            tools => {
+                for tool in 0..tools {
+                    self.push(crate::set_hotend_temp(0, Some(tool as u8)));
+                }
+            }
+        }
+        self.push(crate::set_bed_temp(0)).push(crate::fan_off(None));
+        if sequence.motors_off {
+            self.push(crate::disable_motors());
+        }
+        self
+    }
+
+    /// Sets how the machine retracts over travels, see [Program::retract]
+    pub fn set_retraction(&mut self, retraction: Retraction) -> &mut Self {
+        self.machine.retraction = retraction;
+        self
+    }
+
+    /// Pulls filament back and lifts the nozzle before a travel, by the machine's retraction, see
+    /// [crate::machine::Retraction]. Retracting again before [Program::unretract] does nothing.
+    ///
+    /// Like tool changes, retracting resets the extruder position, and [Program::unretract] sets
+    /// it back with G92, so it works with both absolute and relative extrusion.
+    ///
+    /// # Examples
+    /// ```
+    /// extern crate gen_gcode;
+    /// use gen_gcode::Point2d;
+    /// use gen_gcode::machine::Retraction;
+    /// use gen_gcode::program::Program;
+    ///
+    /// let mut program = Program::new();
+    /// program.set_retraction(Retraction { length: 1.5, z_hop: 0.2, ..Retraction::default() })
+    ///     .move_z(0.3)
+    ///     .move_xy(Point2d { x: 10.0, y: 0.0 }, None, Some(0.5))
+    ///     .retract()
+    ///     .move_xy(Point2d { x: 50.0, y: 0.0 }, None, None)
+    ///     .unretract();
+    /// let expected = "G21\nG0 Z0.3\nG1 X10 Y0 E0.5\nG92 E0\nG1 E-1.5 F2100\nG0 Z0.5\nG0 X50 Y0\nG0 Z0.3\nG92 E0\nG1 E1.5 F2100\nG92 E0.5\n";
+    /// assert_eq!(expected, program.render());
+    /// ```
+    pub fn retract(&mut self) -> &mut Self {
+        if self.retracted.is_some() {
+            return self
+        }
+        let retraction = self.machine.retraction;
+        let (pos, e) = self.position();
+        self.retracted = Some((e, pos.z));
+        if retraction.length > 0.0 {
+            self.add(Op::SetPosition { x: None, y: None, z: None, e: Some(0.0) });
+            self.add(Op::Move { x: None, y: None, z: None, e: Some(-retraction.length), f: Some(retraction.speed) });
+        }
+        if retraction.z_hop > 0.0 {
+            self.add(Op::Move { x: None, y: None, z: Some(pos.z + retraction.z_hop), e: None, f: None });
+        }
+        self
+    }
+
+    /// Lowers the nozzle back and pushes the filament pulled back by [Program::retract] again,
+    /// doing nothing when not retracted
+    pub fn unretract(&mut self) -> &mut Self {
+        let (e, z) = match self.retracted.take() {
+            Some(retracted) => retracted,
+            None => return self,
+        };
+        let retraction = self.machine.retraction;
+        if retraction.z_hop > 0.0 {
+            self.add(Op::Move { x: None, y: None, z: Some(z), e: None, f: None });
+        }
+        if retraction.length > 0.0 {
+            self.add(Op::SetPosition { x: None, y: None, z: None, e: Some(0.0) });
+            self.add(Op::Move { x: None, y: None, z: None, e: Some(retraction.length), f: Some(retraction.prime_speed) });
+            self.add(Op::SetPosition { x: None, y: None, z: None, e: Some(e) });
+        }
+        self
+    }
+
+    /// Selects the tool used by the following moves. The first tool is selected with a plain `T`
+    /// command; switching tools later runs the machine's tool change routine, see
+    /// [crate::machine::ToolChange]. Selecting the current tool again does nothing.
+    ///
+    /// The routine resets the extruder position around its own retraction and priming, then
+    /// sets it back with G92, so following moves carry on with the same E values.
+    ///
+    /// # Examples
+    /// ```
+    /// extern crate gen_gcode;
+    /// use gen_gcode::Point2d;
+    /// use gen_gcode::machine::{MachineProfile, Tool, ToolChange};
+    /// use gen_gcode::program::Program;
+    ///
+    /// let tool_change = ToolChange { z_hop: 0.0, park: Some(Point2d { x: 0.0, y: 200.0 }), ..ToolChange::default() };
+    /// let mut program = Program::new();
+    /// program.set_machine(MachineProfile { tools: vec![Tool::new(210), Tool::new(240)], tool_change, ..MachineProfile::default() })
+    ///     .select_tool(0)
+    ///     .move_xy(Point2d { x: 10.0, y: 10.0 }, None, Some(1.5))
+    ///     .select_tool(1);
+    /// let expected = "G21\nT0\nG1 X10 Y10 E1.5\nG92 E0\nG1 E-2 F2100\nG0 X0 Y200 F6000\nT1\nM109 S240 T1\nG92 E0\nG1 E2 F300\nG0 X10 Y10 F6000\nG92 E1.5\n";
+    /// assert_eq!(expected, program.render());
+    /// ```
+    pub fn select_tool(&mut self, tool: u8) -> &mut Self {
+        let previous = match self.current_tool.replace(tool) {
+            Some(previous) if previous == tool => return self,
+            Some(previous) => previous,
+            None => return self.push(crate::select_tool(tool)),
+        };
+        let change = self.machine.tool_change;
+        let (pos, e) = self.position();
+        let extrude = |e: f32, f: f32| Op::Move { x: None, y: None, z: None, e: Some(e), f: Some(f) };
+        let set_e = |e: f32| Op::SetPosition { x: None, y: None, z: None, e: Some(e) };
+        if change.retract > 0.0 {
+            self.add(set_e(0.0)).add(extrude(-change.retract, change.retract_speed));
+        }
+        if change.z_hop > 0.0 {
+            self.add(Op::Move { x: None, y: None, z: Some(pos.z + change.z_hop), e: None, f: None });
+        }
+        if let Some(park) = change.park {
+            self.add(Op::Move { x: Some(park.x), y: Some(park.y), z: None, e: None, f: Some(change.travel_speed) });
+        }
+        if let Some(standby) = self.machine.tool(previous).and_then(|t| t.standby_temperature) {
+            self.push(crate::set_hotend_temp(standby, Some(previous)));
+        }
+        self.push(crate::select_tool(tool));
+        if let Some(temperature) = self.machine.tool(tool).map(|t| t.temperature) {
+            match change.wait_for_temperature {
+                true => self.push(crate::wait_hotend_temp(temperature, Some(tool))),
+                false => self.push(crate::set_hotend_temp(temperature, Some(tool))),
+            };
+        }
+        if let Some(tower) = self.wipe_tower {
+            self.tower_purge += tower.purge_volume;
+        }
+        let purge = self.wipe_tower.and_then(|tower| self.tower_purge_lines(tower, pos.z).map(|lines| (tower, lines)));
+        if let Some((tower, (first, _, _))) = purge {
+            let start = tower.path(first, 1, tower.line_width)[0];
+            self.add(Op::Move { x: Some(start.x), y: Some(start.y), z: None, e: None, f: Some(change.travel_speed) });
+            self.add(Op::Move { x: None, y: None, z: Some(pos.z), e: None, f: None });
+        }
+        if change.prime > 0.0 {
+            self.add(set_e(0.0)).add(extrude(change.prime, change.prime_speed));
+        }
+        if let Some((tower, lines)) = purge {
+            self.purge_on_tower(tower, lines);
+            if change.z_hop > 0.0 {
+                self.add(Op::Move { x: None, y: None, z: Some(pos.z + change.z_hop), e: None, f: None });
+            }
+        }
+        if change.park.is_some() || purge.is_some() {
+            self.add(Op::Move { x: Some(pos.x), y: Some(pos.y), z: None, e: None, f: Some(change.travel_speed) });
+        }
+        if change.z_hop > 0.0 {
+            self.add(Op::Move { x: None, y: None, z: Some(pos.z), e: None, f: None });
+        }
+        self.add(set_e(e))
+    }
+
+    /// Moves up to a new layer at height `z`. With a wipe tower, the tower is first printed up to
+    /// the layer being left, with sparse lines when no tool change purged on it, and what is left
+    /// of earlier purges is printed on the new layer.
+    ///
+    /// # Examples
+    /// ```
+    /// extern crate gen_gcode;
+    /// use gen_gcode::{Point2d, relative_extrution};
+    /// use gen_gcode::program::Program;
+    /// use gen_gcode::wipe_tower::WipeTower;
+    ///
+    /// let mut program = Program::new();
+    /// program.set_wipe_tower(WipeTower::new(Point2d { x: 150.0, y: 150.0 }, 20.0, 4.0))
+    ///     .push(relative_extrution())
+    ///     .begin_layer(0.2)
+    ///     .move_xy(Point2d { x: 10.0, y: 10.0 }, None, Some(1.0))
+    ///     .begin_layer(0.4);
+    /// let rendered = program.render();
+    /// assert!(rendered.contains("G0 X150.225 Y150.225 F6000\nG1 X169.775 Y150.225 E0.73151 F2400\n"));
+    /// assert!(rendered.ends_with("G92 E1\nG0 Z0.4\n"));
+    /// ```
+    pub fn begin_layer(&mut self, z: f32) -> &mut Self {
+        let (pos, e) = self.position();
+        if let Some(tower) = self.wipe_tower {
+            let empty = pos.z > 0.0 && self.tower_lines(pos.z) == 0;
+            let layer_height = pos.z - self.tower_layer.below;
+            if empty && layer_height > 0.0 {
+                let count = tower.line_count(tower.sparse_spacing);
+                self.extrude_path(tower.path(0, count, tower.sparse_spacing), tower.filament_per_mm(layer_height), tower.speed);
+                self.tower_layer.lines = count;
+                self.add(Op::SetPosition { x: None, y: None, z: None, e: Some(e) });
+            }
+        }
+        self.move_z(z);
+        if let Some(tower) = self.wipe_tower {
+            let z = self.units.to_mm(z);
+            if let Some(lines) = self.tower_purge_lines(tower, z) {
+                self.purge_on_tower(tower, lines);
+                self.add(Op::SetPosition { x: None, y: None, z: None, e: Some(e) });
+            }
+        }
+        self
+    }
+
+    /// Returns the number of lines already printed on the wipe tower at height `z`, starting a
+    /// new tower layer if `z` is above the last one
+    fn tower_lines(&mut self, z: f32) -> usize {
+        if z > self.tower_layer.z + 1e-4 {
+            self.tower_layer = TowerLayer { below: self.tower_layer.z, z, lines: 0 };
+        }
+        self.tower_layer.lines
+    }
+
+    /// Returns the first line, number of lines and layer height of the purge left to print on the
+    /// wipe tower layer at height `z`, as much of it as fits, `None` when there is nothing to
+    /// print or no room left
+    fn tower_purge_lines(&mut self, tower: WipeTower, z: f32) -> Option<(usize, usize, f32)> {
+        let first = self.tower_lines(z);
+        let layer_height = z - self.tower_layer.below;
+        let room = tower.line_count(tower.line_width).saturating_sub(first);
+        let count = tower.purge_lines(self.tower_purge, layer_height).min(room);
+        if count == 0 {
+            return None
+        }
+        Some((first, count, layer_height))
+    }
+
+    /// Prints purge lines found by [Program::tower_purge_lines], taking them off the purge left
+    fn purge_on_tower(&mut self, tower: WipeTower, (first, count, layer_height): (usize, usize, f32)) {
+        self.extrude_path(tower.path(first, count, tower.line_width), tower.filament_per_mm(layer_height), tower.speed);
+        self.tower_layer.lines += count;
+        self.tower_purge = (self.tower_purge - count as f32 * tower.line_volume(layer_height)).max(0.0);
+    }
+
+    /// Adds extruding moves along a path at the current height, travelling to its start. The
+    /// extruder position is reset first and left where the last move ends.
+    fn extrude_path(&mut self, path: Vec<Point2d>, filament_per_mm: f32, speed: f32) {
+        let start = match path.first() {
+            Some(&start) => start,
+            None => return,
+        };
+        let relative = self.relative_extrusion();
+        let travel = self.machine.tool_change.travel_speed;
+        let mut e = 0.0;
+        self.add(Op::SetPosition { x: None, y: None, z: None, e: Some(0.0) });
+        let (pos, _) = self.position();
+        if (pos.x, pos.y) != (start.x, start.y) {
+            self.add(Op::Move { x: Some(start.x), y: Some(start.y), z: None, e: None, f: Some(travel) });
+        }
+        for (n, pair) in path.windows(2).enumerate() {
+            let amount = (pair[1].x - pair[0].x).hypot(pair[1].y - pair[0].y) * filament_per_mm;
+            e = if relative { amount } else { e + amount };
+            let f = if n == 0 { Some(speed) } else { None };
+            self.add(Op::Move { x: Some(pair[1].x), y: Some(pair[1].y), z: None, e: Some(e), f });
+        }
+    }
+
+    /// Whether extruder moves added so far are relative, set by M83 and cleared by M82
+    pub(crate) fn relative_extrusion(&self) -> bool {
+        self.tracked.relative_extrusion
+    }
+
+    /// Returns the position the commands added so far leave the nozzle at, and the extruder
+    /// position, in millimeters
+    fn position(&self) -> (Point3d, f32) {
+        (self.tracked.pos, self.tracked.extruder)
+    }
+
+    /// Returns the commands setting the offsets of the machine's tools, for firmware that takes
+    /// them from G-Code
+    fn tool_offsets(&self) -> String {
+        let (len, _, _) = precision(self.emit_units);
+        let value = |v: f32| format_value(round_to(self.emit_units.from_mm(v) as f64, len), len);
+        let mut out = String::new();
+        for (n, tool) in self.machine.tools.iter().enumerate() {
+            if tool.offset == (Point3d { x: 0.0, y: 0.0, z: 0.0 }) {
+                continue;
+            }
+            let (x, y, z) = (value(tool.offset.x), value(tool.offset.y), value(tool.offset.z));
+            out += &match self.flavor {
+                Flavor::Marlin => format!("M218 T{} X{} Y{} Z{}\n", n, x, y, z),
+                Flavor::RepRapFirmware => format!("G10 P{} X{} Y{} Z{}\n", n, x, y, z),
+                // Klipper takes offsets from its config, Grbl has a single tool
+                Flavor::Klipper | Flavor::Grbl => String::new(),
+            };
+        }
+        out
+    }
+
+    /// Starts labelling the following commands as part of a named object, so the firmware can
+    /// cancel that object mid-print. Starting an object ends the previous one; starting an object
+    /// again, such as on the next layer, continues it.
+    ///
+    /// Objects are emitted as `M486` labels for Marlin and RepRapFirmware, or as `EXCLUDE_OBJECT_*`
+    /// blocks for Klipper, defined with the convex outline of their extruding moves. Grbl has no
+    /// objects, labels are left out. Characters in names other than ASCII letters, digits, `_` and
+    /// `-` are replaced by underscores, so they can't be taken for comments or checksums.
+    ///
+    /// # Examples
+    /// ```
+    /// extern crate gen_gcode;
+    /// use gen_gcode::{Flavor, Point2d};
+    /// use gen_gcode::program::Program;
+    ///
+    /// let mut program = Program::new();
+    /// program.begin_object("left part")
+    ///     .move_xy(Point2d { x: 0.0, y: 0.0 }, None, None)
+    ///     .move_xy(Point2d { x: 10.0, y: 0.0 }, None, Some(0.5))
+    ///     .begin_object("right part")
+    ///     .move_xy(Point2d { x: 20.0, y: 0.0 }, None, None)
+    ///     .move_xy(Point2d { x: 30.0, y: 0.0 }, None, Some(1.0))
+    ///     .end_object();
+    /// let marlin = "G21\nM486 T2\nM486 S0 Aleft_part\nG0 X0 Y0\nG1 X10 Y0 E0.5\nM486 S-1\nM486 S1 Aright_part\nG0 X20 Y0\nG1 X30 Y0 E1\nM486 S-1\n";
+    /// assert_eq!(marlin, program.render());
+    ///
+    /// program.emit_flavor(Flavor::Klipper);
+    /// assert!(program.render().starts_with("G21\nEXCLUDE_OBJECT_DEFINE NAME=left_part CENTER=5,0 POLYGON=[[0,0],[10,0]]\n"));
+    /// ```
+    pub fn begin_object(&mut self, name: &str) -> &mut Self {
+        let name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect();
+        let id = match self.objects.iter().position(|o| *o == name) {
+            Some(id) => id,
+            None => {
+                self.objects.push(name);
+                self.objects.len() - 1
+            }
+        };
+        if self.current_object == Some(id) {
+            return self;
+        }
+        self.end_object();
+        self.add(Op::ObjectStart(id));
+        self.current_object = Some(id);
+        self
+    }
+
+    /// Ends the current object, following commands are not part of any object
+    pub fn end_object(&mut self) -> &mut Self {
+        if let Some(id) = self.current_object.take() {
+            self.add(Op::ObjectEnd(id));
+        }
+        self
+    }
+
+    /// Returns every run of consecutive extruding moves as a path in millimeters, along with the
+    /// object it belongs to. Arcs are split into short segments.
+    fn extrusion_paths(&self) -> Vec<(Option<usize>, Vec<Point3d>)> {
+        let mut paths = Vec::new();
+        let mut path: Vec<Point3d> = Vec::new();
+        let mut pos = Point3d { x: 0.0, y: 0.0, z: 0.0 };
+        let mut current: Option<usize> = None;
+        for (op, _) in &self.ops {
+            let start = pos;
+            let extruded = match op {
+                Op::Move { x, y, z, e, .. } => {
+                    pos = Point3d { x: x.unwrap_or(pos.x), y: y.unwrap_or(pos.y), z: z.unwrap_or(pos.z) };
+                    if e.is_some() { vec![pos] } else { Vec::new() }
+                }
+                Op::Arc { dest, i, j, e, ccw } => {
+                    let from = Point2d { x: start.x, y: start.y };
+                    let center = Point2d { x: start.x + i.unwrap_or(0.0), y: start.y + j.unwrap_or(0.0) };
+                    let to = dest.unwrap_or(from);
+                    pos = Point3d { x: to.x, y: to.y, z: pos.z };
+                    match e {
+                        Some(_) => arc_points(from, to, center, *ccw).iter().skip(1).map(|p| Point3d { x: p.x, y: p.y, z: pos.z }).collect(),
+                        None => Vec::new(),
+                    }
+                }
+                Op::SetPosition { x, y, z, .. } => {
+                    pos = Point3d { x: x.unwrap_or(pos.x), y: y.unwrap_or(pos.y), z: z.unwrap_or(pos.z) };
+                    Vec::new()
+                }
+                Op::ObjectStart(_) | Op::ObjectEnd(_) | Op::Raw(_) | Op::Leveling(_) | Op::ProbeZOffset(_) | Op::Home { .. } | Op::ExtrusionMode { .. } => Vec::new(),
+                Op::Comment(_) | Op::Marker(..) => continue,
+            };
+            if extruded.is_empty() {
+                if path.len() > 1 {
+                    paths.push((current, std::mem::take(&mut path)));
+                }
+                path.clear();
+            } else {
+                if path.is_empty() {
+                    path.push(start);
+                }
+                path.extend(extruded);
+            }
+            match op {
+                Op::ObjectStart(id) => current = Some(*id),
+                Op::ObjectEnd(_) => current = None,
+                _ => (),
+            }
+        }
+        if path.len() > 1 {
+            paths.push((current, path));
+        }
+        paths
+    }
+
+    /// Returns the convex outline of the extruding moves of every object, in millimeters
+    fn object_outlines(&self) -> Vec<Vec<Point2d>> {
+        let mut points: Vec<Vec<Point2d>> = vec![Vec::new(); self.objects.len()];
+        for (object, path) in self.extrusion_paths() {
+            if let Some(id) = object {
+                points[id].extend(path.iter().map(|p| Point2d { x: p.x, y: p.y }));
+            }
+        }
+        points.into_iter().map(convex_hull).collect()
+    }
+
+    /// Returns the commands declaring the program's objects, emitted before any other command
+    fn object_definitions(&self) -> String {
+        if self.objects.is_empty() {
+            return String::new();
+        }
+        match self.flavor {
+            Flavor::Marlin | Flavor::RepRapFirmware => format!("M486 T{}\n", self.objects.len()),
+            Flavor::Klipper => {
+                let (len, _, _) = precision(self.emit_units);
+                let emitted = |p: &Point2d| Point2d {
+                    x: round_to(self.emit_units.from_mm(p.x) as f64, len) as f32,
+                    y: round_to(self.emit_units.from_mm(p.y) as f64, len) as f32,
+                };
+                let mut out = String::new();
+                for (name, outline) in self.objects.iter().zip(self.object_outlines()) {
+                    let outline: Vec<Point2d> = outline.iter().map(emitted).collect();
+                    let center = bounding_center(&outline).map(|c| emitted(&c));
+                    out += &klipper::exclude_object_define(name, center, &outline);
+                }
+                out
+            }
+            Flavor::Grbl => String::new(),
+        }
+    }
+
+    /// Returns the program as G-Code, starting with the G20/G21 command for the emitted units, and
+    /// G91 when emitting relative moves. Thumbnails and metadata come before them when enabled.
+    pub fn render(&self) -> String {
+        let mut out = self.render_body();
+        if self.metadata {
+            out = Metadata::from_program(&out, self.flavor).header() + &out;
+        }
+        let style = self.flavor.comment_style();
+        let thumbnails: String = self.render_thumbnails().iter().map(|image| thumbnail_block(image, style)).collect();
+        out = thumbnails + &out;
+        if self.line_numbers {
+            return frame(&out);
+        }
+        out
+    }
+
+    /// Whether metadata is emitted, see [Program::emit_metadata]
+    pub(crate) fn has_metadata(&self) -> bool {
+        self.metadata
+    }
+
+    pub(crate) fn flavor(&self) -> Flavor {
+        self.flavor
+    }
+
+    /// The units geometry is given in, see [Program::with_units]
+    pub(crate) fn working_units(&self) -> Units {
+        self.units
+    }
+
+    /// Returns how the machine retracts over travels, see [Program::set_retraction]
+    pub(crate) fn retraction(&self) -> Retraction {
+        self.machine.retraction
+    }
+
+    /// Renders the thumbnails emitted with [Program::emit_thumbnails]
+    pub(crate) fn render_thumbnails(&self) -> Vec<Image> {
+        if self.thumbnails.is_empty() {
+            return Vec::new();
+        }
+        let paths: Vec<Vec<Point3d>> = self.extrusion_paths().into_iter().map(|(_, path)| path).collect();
+        self.thumbnails.iter().map(|&(w, h)| render_preview(&paths, w, h)).collect()
+    }
+
+    /// Returns the commands of the program, with layer comments when emitting metadata, but
+    /// without the metadata header, thumbnails or line numbers
+    pub(crate) fn render_body(&self) -> String {
+        let mut out = match self.emit_units {
+            Units::Millimeters => crate::use_millimeters(),
+            Units::Inches => crate::use_inches(),
+        };
+        if self.positioning == Positioning::Relative {
+            out += &crate::relative_positioning();
+        }
+        out += &self.object_definitions();
+        out += &self.tool_offsets();
+        let mut renderer = Renderer { units: self.emit_units, positioning: self.positioning, flavor: self.flavor, objects: &self.objects, pos: [0.0; 4], homed: Axes::default(), relative_extrusion: false };
+        let style = self.flavor.comment_style();
+        for (op, comment) in &self.ops {
+            let line = renderer.render_op(op);
+            let line = match comment {
+                Some(text) if line.is_empty() => style.format(text),
+                Some(text) => format!("{} {}", line, style.format(text)),
+                None => line,
+            };
+            if !line.is_empty() {
+                out += &line;
+                out.push('\n');
+            }
+        }
+        if let Some(id) = self.current_object {
+            let line = renderer.render_op(&Op::ObjectEnd(id));
+            if !line.is_empty() {
+                out += &line;
+                out.push('\n');
+            }
+        }
+        if self.metadata {
+            out = add_layer_comments(&out, style);
+        }
+        out
+    }
+}
+
+/// Tracks where the emitted program has moved to, in emitted units rounded to emitted precision,
+/// so relative moves add up to exactly the positions an absolute program would emit
+struct Renderer<'a> {
+    units: Units,
+    positioning: Positioning,
+    flavor: Flavor,
+    objects: &'a [String],
+    pos: [f64; 4],
+    homed: Axes,
+    /// Whether the firmware takes E as a distance, set by M83 and cleared by M82
+    relative_extrusion: bool,
+}
+
+impl<'a> Renderer<'a> {
+    /// Returns the word for an axis moving to a position in millimeters, updating the tracked position
+    fn axis(&mut self, letter: char, axis: usize, v: Option<f32>, decimals: usize) -> String {
+        let v = match v {
+            Some(v) => round_to(self.units.from_mm(v) as f64, decimals),
+            None => return String::new(),
+        };
+        let out = match self.positioning {
+            // with M83 the program already gives each move's extrusion, keep the sum the firmware
+            // will have reached in case it goes back to M82
+            Positioning::Relative if axis == 3 && self.relative_extrusion => {
+                self.pos[axis] = round_to(self.pos[axis] + v, decimals);
+                return format!(" {}{}", letter, format_value(v, decimals));
+            }
+            Positioning::Absolute => v,
+            Positioning::Relative => v - self.pos[axis],
+        };
+        self.pos[axis] = v;
+        format!(" {}{}", letter, format_value(out, decimals))
+    }
+
+    /// Moves the axes a homing command homes back to the origin, the axes already homed stay
+    /// where they are when only homing if needed
+    fn home(&mut self, axes: Axes, only_if_needed: bool) {
+        let moved = home_axes(&mut self.homed, axes, only_if_needed);
+        for (axis, _) in moved.letters() {
+            self.pos[axis] = 0.0;
+        }
+    }
+
+    /// Returns the word for a value that is not a position, such as an arc offset or feed rate
+    fn word(&self, letter: char, v: Option<f32>, decimals: usize) -> String {
+        match v {
+            Some(v) => format!(" {}{}", letter, format_value(self.units.from_mm(v) as f64, decimals)),
+            None => String::new(),
+        }
+    }
+
+    fn render_op(&mut self, op: &Op) -> String {
+        let (len, ext, feed) = precision(self.units);
+        match op {
+            Op::Move { x, y, z, e, f } => {
+                let code = if e.is_some() { "G1" } else { "G0" };
+                let (x, y, z, e) = (self.axis('X', 0, *x, len), self.axis('Y', 1, *y, len), self.axis('Z', 2, *z, len), self.axis('E', 3, *e, ext));
+                format!("{}{}{}{}{}{}", code, x, y, z, e, self.word('F', *f, feed))
+            }
+            Op::Arc { dest, i, j, e, ccw } => {
+                let code = if *ccw { "G3" } else { "G2" };
+                let (x, y) = (self.axis('X', 0, dest.map(|d| d.x), len), self.axis('Y', 1, dest.map(|d| d.y), len));
+                let e = self.axis('E', 3, *e, ext);
+                format!("{}{}{}{}{}{}", code, x, y, self.word('I', *i, len), self.word('J', *j, len), e)
+            }
+            Op::SetPosition { x, y, z, e } => {
+                // G92 always takes positions, even with relative positioning
+                let positioning = self.positioning;
+                self.positioning = Positioning::Absolute;
+                let (x, y, z, e) = (self.axis('X', 0, *x, len), self.axis('Y', 1, *y, len), self.axis('Z', 2, *z, len), self.axis('E', 3, *e, ext));
+                self.positioning = positioning;
+                format!("G92{}{}{}{}", x, y, z, e)
+            }
+            Op::Raw(gcode) => {
+                for cmd in gcode.lines().filter_map(|line| parse_line(line).command) {
+                    match cmd.name.as_str() {
+                        "M82" => self.relative_extrusion = false,
+                        "M83" => self.relative_extrusion = true,
+                        _ => {
+                            let moved = raw_homing(&mut self.homed, &cmd);
+                            self.home(moved, false);
+                        }
+                    }
+                }
+                gcode.clone()
+            }
+            Op::Home { axes, only_if_needed } => {
+                self.home(*axes, *only_if_needed);
+                match only_if_needed {
+                    true => homing::home_if_needed(self.flavor, *axes),
+                    false => homing::home(self.flavor, *axes),
+                }
+                .trim_end()
+                .to_string()
+            }
+            Op::ObjectStart(id) => match self.flavor {
+                Flavor::Marlin | Flavor::RepRapFirmware => format!("M486 S{} A{}", id, self.objects[*id]),
+                Flavor::Klipper => klipper::exclude_object_start(&self.objects[*id]).trim_end().to_string(),
+                Flavor::Grbl => String::new(),
+            },
+            Op::Comment(text) => self.flavor.comment_style().format(text),
+            Op::Marker(key, value) => self.flavor.comment_style().marker(key, value),
+            Op::Leveling(leveling) => {
+                let units = self.units;
+                convert_grid(*leveling, |v| round_to(units.from_mm(v) as f64, len) as f32).render(self.flavor).trim_end().to_string()
+            }
+            Op::ProbeZOffset(offset) => {
+                let offset = round_to(self.units.from_mm(*offset) as f64, len) as f32;
+                leveling::set_probe_z_offset(self.flavor, offset).trim_end().to_string()
+            }
+            Op::ExtrusionMode { relative } => {
+                self.relative_extrusion = *relative;
+                match (relative, self.positioning) {
+                    (true, _) => "M83".to_string(),
+                    (false, Positioning::Absolute) => "M82".to_string(),
+                    (false, Positioning::Relative) => String::new(),
+                }
+            }
+            Op::ObjectEnd(id) => match self.flavor {
+                Flavor::Marlin | Flavor::RepRapFirmware => "M486 S-1".to_string(),
+                Flavor::Klipper => klipper::exclude_object_end(Some(&self.objects[*id])).trim_end().to_string(),
+                Flavor::Grbl => String::new(),
+            },
+        }
+    }
+}
+
+/// Returns points along an arc from `start` to `end` around `center`, every 1/32 of a turn, the
+/// arc is a full circle when `start` and `end` are the same
+pub(crate) fn arc_points(start: Point2d, end: Point2d, center: Point2d, ccw: bool) -> Vec<Point2d> {
+    let radius = (start.x - center.x).hypot(start.y - center.y);
+    let a0 = (start.y - center.y).atan2(start.x - center.x);
+    let a1 = (end.y - center.y).atan2(end.x - center.x);
+    let mut sweep = if ccw { a1 - a0 } else { a0 - a1 };
+    if sweep <= 1e-6 {
+        sweep += 2.0 * PI;
+    }
+    let steps = (sweep / (PI / 16.0)).ceil() as usize;
+    let direction = if ccw { 1.0 } else { -1.0 };
+    let mut points: Vec<Point2d> = (0..steps)
+        .map(|n| {
+            let a = a0 + direction * sweep * n as f32 / steps as f32;
+            Point2d { x: center.x + radius * a.cos(), y: center.y + radius * a.sin() }
+        })
+        .collect();
+    points.push(end);
+    points
+}
+
+/// Returns the convex hull of a set of points, counter-clockwise, using Andrew's monotone chain.
+/// Points that are not finite are left out.
+fn convex_hull(mut points: Vec<Point2d>) -> Vec<Point2d> {
+    points.retain(|p| p.x.is_finite() && p.y.is_finite());
+    points.sort_by(|a, b| a.x.total_cmp(&b.x).then(a.y.total_cmp(&b.y)));
+    points.dedup();
+    if points.len() < 3 {
+        return points;
+    }
+    let mut lower = half_hull(points.iter());
+    let mut upper = half_hull(points.iter().rev());
+    // the last point of each half is the first point of the other
+    lower.pop();
+    upper.pop();
+    lower.extend(upper);
+    lower
+}
+
+/// Returns the points of a sorted set that turn left, ie. the lower half of the hull when sorted
+/// left to right
+fn half_hull<'a, I: Iterator<Item = &'a Point2d>>(points: I) -> Vec<Point2d> {
+    let cross = |o: Point2d, a: Point2d, b: Point2d| (a.x - o.x) * (b.y - o.y) - (a.y - o.y) * (b.x - o.x);
+    let mut hull: Vec<Point2d> = Vec::new();
+    for &p in points {
+        while hull.len() >= 2 && cross(hull[hull.len() - 2], hull[hull.len() - 1], p) <= 0.0 {
+            hull.pop();
+        }
+        hull.push(p);
+    }
+    hull
+}
+
+/// Returns the center of the bounding box of a set of points
+fn bounding_center(points: &[Point2d]) -> Option<Point2d> {
+    let first = points.first()?;
+    let (mut min, mut max) = (*first, *first);
+    for p in points {
+        min = Point2d { x: min.x.min(p.x), y: min.y.min(p.y) };
+        max = Point2d { x: max.x.max(p.x), y: max.y.max(p.y) };
+    }
+    Some(Point2d { x: (min.x + max.x) / 2.0, y: (min.y + max.y) / 2.0 })
+}
+
+/// Marks axes as homed, returning the ones moved back to the origin. Homing only if needed
+/// (`G28 O`) moves nothing when every axis was already homed, the firmware skips it.
+fn home_axes(homed: &mut Axes, axes: Axes, only_if_needed: bool) -> Axes {
+    if only_if_needed && homed.contains(axes) {
+        return Axes::default()
+    }
+    *homed = homed.union(axes);
+    axes
+}
+
+/// Applies a parsed command to the homed axes, returning the axes it moves back to the origin
+fn raw_homing(homed: &mut Axes, cmd: &Command) -> Axes {
+    match cmd.name.as_str() {
+        // with the motors off the axes can be moved by hand, so they need homing again
+        "M18" | "M84" => {
+            *homed = Axes::default();
+            Axes::default()
+        }
+        _ => match homing::homed_axes(cmd) {
+            Some(axes) => home_axes(homed, axes, cmd.name == "G28" && cmd.has("O")),
+            None => Axes::default(),
+        },
+    }
+}
+
+/// Returns where homing leaves the nozzle, the homed axes move back to the origin
+fn home_position(pos: Point3d, axes: Axes) -> Point3d {
+    Point3d { x: if axes.x { 0.0 } else { pos.x }, y: if axes.y { 0.0 } else { pos.y }, z: if axes.z { 0.0 } else { pos.z } }
+}
+
+/// Applies a unit conversion to the probed area of a [Leveling]
+fn convert_grid(leveling: Leveling, convert: impl Fn(f32) -> f32) -> Leveling {
+    match leveling {
+        Leveling::Probe(Some(grid)) => {
+            let min = Point2d { x: convert(grid.min.x), y: convert(grid.min.y) };
+            let max = Point2d { x: convert(grid.max.x), y: convert(grid.max.y) };
+            Leveling::Probe(Some(ProbeGrid { min, max, ..grid }))
+        }
+        other => other,
+    }
+}
+
+fn round_to(v: f64, decimals: usize) -> f64 {
+    let scale = 10_f64.powi(decimals as i32);
+    (v * scale).round() / scale
+}
+
+/// Formats a value rounded to the given decimal places, without trailing zeros
+fn format_value(v: f64, decimals: usize) -> String {
+    let s = format!("{:.*}", decimals, v);
+    let s = if s.contains('.') { s.trim_end_matches('0').trim_end_matches('.') } else { &s };
+    if s == "-0" {
+        return "0".to_string();
+    }
+    s.to_string()
+}
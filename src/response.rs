@@ -0,0 +1,364 @@
+//! Parse the lines firmware sends back, such as temperature and position reports
+//!
+//! Understands the replies of Marlin, RepRapFirmware, Grbl and Klipper (through its Marlin
+//! compatible serial interface).
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_ok() {
+        assert_eq!(Response::Ok { line: None, temperatures: None }, parse_response("ok"));
+        assert_eq!(Response::Ok { line: Some(12), temperatures: None }, parse_response("ok N12"));
+    }
+
+    #[test]
+    fn test_ok_with_temperatures() {
+        let expected = TemperatureReport {
+            hotend: Some(Reading { current: 210.0, target: Some(210.0) }),
+            bed: Some(Reading { current: 60.0, target: Some(60.0) }),
+            ..TemperatureReport::default()
+        };
+        assert_eq!(Response::Ok { line: None, temperatures: Some(expected) }, parse_response("ok T:210.0 /210.0 B:60.0 /60.0"));
+        let r = parse_temperatures("ok T1:200.0 /200.0 B:60.0 /60.0").unwrap();
+        assert_eq!(vec![None, Some(Reading { current: 200.0, target: Some(200.0) })], r.tools);
+    }
+
+    #[test]
+    fn test_temperature_autoreport() {
+        let r = match parse_response(" T:201.37 /210.00 B:59.80 /60.00 T0:201.37 /210.00 T1:25.00 /0.00 C:35.1 /40.0 @:127 B@:0") {
+            Response::Temperatures(r) => r,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(Some(Reading { current: 201.37, target: Some(210.0) }), r.hotend);
+        assert_eq!(vec![Some(Reading { current: 201.37, target: Some(210.0) }), Some(Reading { current: 25.0, target: Some(0.0) })], r.tools);
+        assert_eq!(Some(Reading { current: 35.1, target: Some(40.0) }), r.chamber);
+        assert_eq!(Some(59.8), r.bed.map(|b| b.current));
+    }
+
+    #[test]
+    fn test_temperature_without_target() {
+        let r = parse_temperatures("T:24.5 B:23.9").unwrap();
+        assert_eq!(Some(Reading { current: 24.5, target: None }), r.hotend);
+        assert_eq!(Some(Reading { current: 23.9, target: None }), r.bed);
+    }
+
+    #[test]
+    fn test_temperature_unreasonable_tool() {
+        let r = parse_temperatures("T:24.5 T4000000000:25.0 /0.0 T1:30.0").unwrap();
+        assert_eq!(vec![None, Some(Reading { current: 30.0, target: None })], r.tools);
+        assert_eq!(None, parse_temperatures("T4000000000:25.0 /0.0"));
+    }
+
+    #[test]
+    fn test_position() {
+        let expected = PositionReport { x: 10.0, y: 5.0, z: 0.2, e: 1.5 };
+        assert_eq!(Response::Position(expected), parse_response("X:10.00 Y:5.00 Z:0.20 E:1.50 Count X:800 Y:400 Z:80"));
+        assert_eq!(Response::Position(expected), parse_response("X:10.000 Y:5.000 Z:0.200 E:1.500"));
+    }
+
+    #[test]
+    fn test_firmware_info() {
+        let line = "FIRMWARE_NAME:Marlin bugfix-2.1.x (Jan  1 2024 12:00:00) SOURCE_CODE_URL:github.com/MarlinFirmware/Marlin PROTOCOL_VERSION:1.0 MACHINE_TYPE:Ender-3 V2 EXTRUDER_COUNT:1 UUID:cede2a2f-41a2-4748-9b12-c55c62f367ff";
+        let info = match parse_response(line) {
+            Response::FirmwareInfo(info) => info,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(Some("Marlin bugfix-2.1.x (Jan  1 2024 12:00:00)"), info.name());
+        assert_eq!(Some("Ender-3 V2"), info.get("MACHINE_TYPE"));
+        assert_eq!(Some(1), info.extruder_count());
+        assert_eq!(Some("github.com/MarlinFirmware/Marlin"), info.get("SOURCE_CODE_URL"));
+    }
+
+    #[test]
+    fn test_firmware_info_klipper() {
+        let info = match parse_response("FIRMWARE_NAME:Klipper FIRMWARE_VERSION:v0.12.0-85-gd785b396") {
+            Response::FirmwareInfo(info) => info,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(Some("Klipper"), info.name());
+        assert_eq!(Some("v0.12.0-85-gd785b396"), info.get("FIRMWARE_VERSION"));
+    }
+
+    #[test]
+    fn test_capability() {
+        assert_eq!(Response::Capability { name: "AUTOREPORT_TEMP".to_string(), enabled: true }, parse_response("Cap:AUTOREPORT_TEMP:1"));
+        assert_eq!(Response::Capability { name: "EEPROM".to_string(), enabled: false }, parse_response("Cap:EEPROM:0"));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(Response::Error("Printer halted. kill() called!".to_string()), parse_response("Error:Printer halted. kill() called!"));
+        assert_eq!(Response::KlipperError("Move out of range: 250.000 0.000 0.200 [0.000]".to_string()), parse_response("!! Move out of range: 250.000 0.000 0.200 [0.000]"));
+        assert_eq!(Response::Comment("Klipper state: Ready".to_string()), parse_response("// Klipper state: Ready"));
+    }
+
+    #[test]
+    fn test_grbl() {
+        assert_eq!(Response::GrblError(20), parse_response("error:20"));
+        assert_eq!(Response::Alarm(1), parse_response("ALARM:1"));
+        assert_eq!(Response::Message("Caution: Unlocked".to_string()), parse_response("[MSG:Caution: Unlocked]"));
+        assert_eq!(Response::Error("Bad command".to_string()), parse_response("error:Bad command"));
+    }
+
+    #[test]
+    fn test_resend_busy_echo() {
+        assert_eq!(Response::Resend(6), parse_response("Resend: 6"));
+        assert_eq!(Response::Resend(6), parse_response("rs 6"));
+        assert_eq!(Response::Busy("processing".to_string()), parse_response("busy: processing"));
+        assert_eq!(Response::Busy("processing".to_string()), parse_response("echo:busy: processing"));
+        assert_eq!(Response::Echo("Unknown command: \"M999\"".to_string()), parse_response("echo:Unknown command: \"M999\""));
+        assert_eq!(Response::Start, parse_response("start"));
+        assert_eq!(Response::Other("wait".to_string()), parse_response("wait"));
+    }
+}
+
+use crate::machine::MAX_HOTENDS;
+
+/// Current and target temperature of a heater, in °C
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Reading {
+    pub current: f32,
+    /// Not every firmware reports the target
+    pub target: Option<f32>,
+}
+
+/// Temperatures reported in response to M105 or by temperature auto-reporting
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TemperatureReport {
+    /// Active hotend (`T:`)
+    pub hotend: Option<Reading>,
+    /// Each hotend by tool number (`T0:`, `T1:`, ...), only reported by multi-extruder machines.
+    /// Tools missing from the report are `None`.
+    pub tools: Vec<Option<Reading>>,
+    pub bed: Option<Reading>,
+    pub chamber: Option<Reading>,
+}
+
+/// Logical position reported in response to M114, in the machine's current units
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PositionReport {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub e: f32,
+}
+
+/// `KEY:VALUE` fields reported in response to M115
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FirmwareInfo {
+    pub fields: Vec<(String, String)>,
+}
+
+impl FirmwareInfo {
+    /// Returns the value of a field such as `MACHINE_TYPE`
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.fields.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    /// Returns the `FIRMWARE_NAME` field
+    pub fn name(&self) -> Option<&str> {
+        self.get("FIRMWARE_NAME")
+    }
+
+    /// Returns the `EXTRUDER_COUNT` field
+    pub fn extruder_count(&self) -> Option<u8> {
+        self.get("EXTRUDER_COUNT").and_then(|c| c.parse().ok())
+    }
+}
+
+/// A line sent by the firmware
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    /// The command was processed, some firmwares include the line number or temperatures
+    Ok { line: Option<u32>, temperatures: Option<TemperatureReport> },
+    /// The firmware asks for the lines starting from this one to be sent again
+    Resend(u32),
+    /// The firmware is still working on the last command
+    Busy(String),
+    Echo(String),
+    /// A Marlin or RepRapFirmware `Error:` message
+    Error(String),
+    /// A Klipper `!!` message
+    KlipperError(String),
+    /// A Grbl `error:N` code, see [crate::grbl::error_description]
+    GrblError(u8),
+    /// A Grbl `ALARM:N` code, see [crate::grbl::alarm_description]
+    Alarm(u8),
+    /// A Grbl `[MSG:...]` feedback message
+    Message(String),
+    /// A Klipper `//` informational message
+    Comment(String),
+    Temperatures(TemperatureReport),
+    Position(PositionReport),
+    FirmwareInfo(FirmwareInfo),
+    /// A `Cap:` line reported after the M115 firmware info
+    Capability { name: String, enabled: bool },
+    /// The firmware has (re)started
+    Start,
+    Other(String),
+}
+
+/// Parses a line sent by the firmware
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::response::{parse_response, Reading, Response};
+///
+/// match parse_response("ok T:210.0 /210.0 B:60.0 /60.0") {
+///     Response::Ok { temperatures: Some(t), .. } => {
+///         assert_eq!(Some(Reading { current: 210.0, target: Some(210.0) }), t.hotend);
+///         assert_eq!(Some(Reading { current: 60.0, target: Some(60.0) }), t.bed);
+///     }
+///     other => panic!("unexpected response {:?}", other),
+/// }
+/// ```
+pub fn parse_response(line: &str) -> Response {
+    let line = line.trim();
+    if let Some(rest) = line.strip_prefix("ok") {
+        let rest = rest.trim();
+        let (line_number, rest) = match rest.strip_prefix('N') {
+            Some(n) => {
+                let end = n.find(' ').unwrap_or(n.len());
+                (n[..end].parse().ok(), n[end..].trim())
+            }
+            None => (None, rest),
+        };
+        return Response::Ok { line: line_number, temperatures: parse_temperatures(rest) };
+    }
+    if let Some(n) = line.strip_prefix("Resend:").or_else(|| line.strip_prefix("rs ")) {
+        if let Ok(n) = n.trim().parse() {
+            return Response::Resend(n);
+        }
+    }
+    if let Some(msg) = line.strip_prefix("echo:busy:").or_else(|| line.strip_prefix("busy:")) {
+        return Response::Busy(msg.trim().to_string());
+    }
+    if let Some(msg) = line.strip_prefix("echo:") {
+        return Response::Echo(msg.trim().to_string());
+    }
+    if let Some(code) = line.strip_prefix("error:").and_then(|c| c.trim().parse().ok()) {
+        return Response::GrblError(code);
+    }
+    if let Some(code) = line.strip_prefix("ALARM:").and_then(|c| c.trim().parse().ok()) {
+        return Response::Alarm(code);
+    }
+    if let Some(msg) = line.strip_prefix("[MSG:").and_then(|m| m.strip_suffix(']')) {
+        return Response::Message(msg.to_string());
+    }
+    if let Some(msg) = line.strip_prefix("Error:").or_else(|| line.strip_prefix("error:")) {
+        return Response::Error(msg.trim().to_string());
+    }
+    if let Some(msg) = line.strip_prefix("!!") {
+        return Response::KlipperError(msg.trim().to_string());
+    }
+    if let Some(msg) = line.strip_prefix("//") {
+        return Response::Comment(msg.trim().to_string());
+    }
+    if let Some(cap) = line.strip_prefix("Cap:") {
+        let mut parts = cap.rsplitn(2, ':');
+        let enabled = parts.next().map(|v| v.trim() == "1").unwrap_or(false);
+        let name = parts.next().unwrap_or("").to_string();
+        return Response::Capability { name, enabled };
+    }
+    if line.starts_with("FIRMWARE_NAME:") {
+        return Response::FirmwareInfo(FirmwareInfo { fields: parse_fields(line) });
+    }
+    if line == "start" {
+        return Response::Start;
+    }
+    if let Some(t) = parse_temperatures(line) {
+        return Response::Temperatures(t);
+    }
+    if let Some(p) = parse_position(line) {
+        return Response::Position(p);
+    }
+    Response::Other(line.to_string())
+}
+
+/// Parses a temperature report such as `T:210.0 /210.0 B:60.0 /60.0`, returns None if there are no
+/// temperatures in the line
+pub fn parse_temperatures(line: &str) -> Option<TemperatureReport> {
+    let mut report = TemperatureReport::default();
+    let mut found = false;
+    let mut words = line.split_whitespace().peekable();
+    while let Some(word) = words.next() {
+        let (key, value) = match word.find(':') {
+            Some(i) => (&word[..i], &word[i + 1..]),
+            None => continue,
+        };
+        let current = match value.parse::<f32>() {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        let target = match words.peek().and_then(|w| w.strip_prefix('/')) {
+            Some(t) => {
+                let t = t.parse().ok();
+                words.next();
+                t
+            }
+            None => None,
+        };
+        let reading = Some(Reading { current, target });
+        match key {
+            "T" => report.hotend = reading,
+            "B" => report.bed = reading,
+            "C" => report.chamber = reading,
+            // readings for tool numbers no real machine has are garbled lines, skip them
+            _ => match key.strip_prefix('T').and_then(|n| n.parse::<usize>().ok()).filter(|&t| t < MAX_HOTENDS) {
+                Some(tool) => {
+                    if report.tools.len() <= tool {
+                        report.tools.resize(tool + 1, None);
+                    }
+                    report.tools[tool] = Some(Reading { current, target });
+                }
+                None => continue,
+            },
+        }
+        found = true;
+    }
+    if found {
+        Some(report)
+    } else {
+        None
+    }
+}
+
+/// Parses a M114 position report such as `X:10.00 Y:5.00 Z:0.20 E:1.00 Count X:800 Y:400 Z:80`
+pub fn parse_position(line: &str) -> Option<PositionReport> {
+    let logical = line.split("Count").next()?;
+    let mut axes = [None; 4];
+    for word in logical.split_whitespace() {
+        let mut kv = word.splitn(2, ':');
+        let index = match kv.next()? {
+            "X" => 0,
+            "Y" => 1,
+            "Z" => 2,
+            "E" => 3,
+            _ => continue,
+        };
+        axes[index] = kv.next()?.parse().ok();
+    }
+    Some(PositionReport { x: axes[0]?, y: axes[1]?, z: axes[2]?, e: axes[3]? })
+}
+
+/// Splits a M115 line into `KEY:VALUE` fields, keys are upper case words, values run until the
+/// next key
+fn parse_fields(line: &str) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    for word in line.split(' ') {
+        let key_end = word.find(':').filter(|&i| i > 0 && word[..i].chars().all(|c| c.is_ascii_uppercase() || c == '_'));
+        match key_end {
+            Some(i) => fields.push((word[..i].to_string(), word[i + 1..].to_string())),
+            None => {
+                if let Some((_, value)) = fields.last_mut() {
+                    value.push(' ');
+                    value.push_str(word);
+                }
+            }
+        }
+    }
+    fields
+}
@@ -0,0 +1,548 @@
+//! Stream G-Code to a printer over a serial connection, one command at a time
+//!
+//! Every command is sent with a line number and checksum (see [crate::framing]) and the next one is
+//! only sent once the firmware has answered `ok`, resending lines the firmware asks for again.
+//! Lines can be packed with [crate::meatpack] to fit more commands through a slow connection.
+
+#[cfg(all(test, unix))]
+pub(crate) mod tests {
+    use super::*;
+    use crate::framing::{checksum, split_frame};
+    use crate::*;
+    use std::collections::HashSet;
+    use std::fs::File;
+    use crate::meatpack::Decoder;
+    use std::io::BufReader;
+    use std::os::unix::io::FromRawFd;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// Opens a pseudo-terminal in raw mode, returns the (firmware, host) ends
+    pub(crate) fn pty_pair() -> (File, File) {
+        unsafe {
+            let (mut master, mut slave) = (0, 0);
+            let res = libc::openpty(&mut master, &mut slave, std::ptr::null_mut(), std::ptr::null(), std::ptr::null());
+            assert_eq!(0, res, "openpty failed");
+            for fd in [master, slave].iter() {
+                let mut t: libc::termios = std::mem::zeroed();
+                libc::tcgetattr(*fd, &mut t);
+                libc::cfmakeraw(&mut t);
+                libc::tcsetattr(*fd, libc::TCSANOW, &t);
+            }
+            (File::from_raw_fd(master), File::from_raw_fd(slave))
+        }
+    }
+
+    /// Behaves like Marlin's serial handling, returns the commands it executed once the host hangs up
+    #[derive(Default)]
+    struct FakeFirmware {
+        /// Line numbers that arrive corrupted the first time they are sent
+        corrupt: HashSet<u32>,
+        /// Line numbers that keep the firmware busy for a while before answering
+        busy: HashSet<u32>,
+    }
+
+    impl FakeFirmware {
+        fn spawn(mut self, port: File) -> thread::JoinHandle<Vec<String>> {
+            thread::spawn(move || {
+                let mut out = port.try_clone().unwrap();
+                let mut reader = BufReader::new(port);
+                let mut executed = Vec::new();
+                let mut expected = 0;
+                // Marlin unpacks MeatPack before reading lines, and passes plain text through
+                let mut decoder = Decoder::new();
+                let mut text = String::new();
+                let mut chunk = [0u8; 256];
+                while let Ok(n) = reader.read(&mut chunk) {
+                    if n == 0 {
+                        break;
+                    }
+                    text += &decoder.feed(&chunk[..n]);
+                    while let Some(end) = text.find('\n') {
+                        let raw: String = text.drain(..=end).collect();
+                        let raw = raw.trim().to_string();
+                        if raw.is_empty() {
+                            continue;
+                        }
+                        let star = raw.rfind('*').unwrap();
+                        let (number, command) = split_frame(&raw).unwrap();
+                        let valid = checksum(&raw[..star]).to_string() == raw[star + 1..] && number == expected;
+                        if !valid || self.corrupt.remove(&number) {
+                            let reply = format!("Error:checksum mismatch, Last Line: {}\nResend: {}\nok\n", expected as i64 - 1, expected);
+                            out.write_all(reply.as_bytes()).unwrap();
+                            continue;
+                        }
+                        expected = number + 1;
+                        if command.starts_with("M110") {
+                            expected = command[6..].parse::<u32>().unwrap() + 1;
+                        } else {
+                            executed.push(command.to_string());
+                        }
+                        if self.busy.contains(&number) {
+                            out.write_all(b"echo:busy: processing\n").unwrap();
+                            out.write_all(b"busy: processing\n").unwrap();
+                        }
+                        if command.starts_with("M117") {
+                            out.write_all(format!("echo:{}\n", &command[5..]).as_bytes()).unwrap();
+                        }
+                        out.write_all(b"ok\n").unwrap();
+                    }
+                }
+                executed
+            })
+        }
+    }
+
+    fn sample_program() -> String {
+        let mut program = auto_home();
+        program += "; a comment to skip\n\n(another one)\n";
+        program += &move_xy(Point2d { x: 10.0, y: 5.0 }, Some(3000), None);
+        program += &move_xy(Point2d { x: 20.0, y: 5.0 }, None, Some(1.0));
+        program += "M117 Hello\n";
+        program += &move_xy(Point2d { x: 20.0, y: 15.0 }, None, Some(2.0));
+        program
+    }
+
+    const SAMPLE_COMMANDS: [&str; 5] = ["G28", "G0 X10 Y5 F3000", "G1 X20 Y5 E1", "M117 Hello", "G1 X20 Y15 E2"];
+
+    #[test]
+    fn test_send_program() {
+        let (firmware, host) = pty_pair();
+        let handle = FakeFirmware::default().spawn(firmware);
+        let mut sender = Sender::new(host);
+        let report = sender.send_program(&sample_program()).unwrap();
+        drop(sender);
+        assert_eq!(SAMPLE_COMMANDS.to_vec(), handle.join().unwrap());
+        assert_eq!(5, report.sent);
+        assert_eq!(0, report.resends);
+        assert_eq!(vec!["Hello".to_string()], report.echoes);
+    }
+
+    #[test]
+    fn test_meatpack() {
+        for &no_spaces in [false, true].iter() {
+            let (firmware, host) = pty_pair();
+            let corrupt = [3].iter().copied().collect();
+            let handle = FakeFirmware { corrupt, ..FakeFirmware::default() }.spawn(firmware);
+            let mut sender = Sender::new(host);
+            sender.enable_meatpack(no_spaces).unwrap();
+            let report = sender.send_program(&sample_program()).unwrap();
+            sender.disable_meatpack().unwrap();
+            sender.send_command("M84").unwrap();
+            drop(sender);
+            let encoder = Encoder::new(no_spaces);
+            let mut expected: Vec<String> = SAMPLE_COMMANDS.iter().map(|c| encoder.command(c)).collect();
+            expected.push("M84".to_string());
+            assert_eq!(expected, handle.join().unwrap());
+            assert_eq!(1, report.resends);
+            assert_eq!(vec!["Hello".to_string()], report.echoes);
+        }
+    }
+
+    #[test]
+    fn test_resend() {
+        let (firmware, host) = pty_pair();
+        let corrupt = [2, 4].iter().copied().collect();
+        let handle = FakeFirmware { corrupt, ..FakeFirmware::default() }.spawn(firmware);
+        let mut sender = Sender::new(host);
+        let report = sender.send_program(&sample_program()).unwrap();
+        drop(sender);
+        assert_eq!(SAMPLE_COMMANDS.to_vec(), handle.join().unwrap());
+        assert_eq!(2, report.resends);
+        assert_eq!(2, report.errors.len());
+    }
+
+    #[test]
+    fn test_busy() {
+        let (firmware, host) = pty_pair();
+        let busy = [1].iter().copied().collect();
+        let handle = FakeFirmware { busy, ..FakeFirmware::default() }.spawn(firmware);
+        let mut sender = Sender::new(host);
+        let report = sender.send_program(&sample_program()).unwrap();
+        drop(sender);
+        assert_eq!(5, handle.join().unwrap().len());
+        assert_eq!(5, report.sent);
+        assert_eq!(vec!["busy: processing".to_string(), "Hello".to_string()], report.echoes);
+    }
+
+    #[test]
+    fn test_send_command_returns_responses() {
+        let (firmware, host) = pty_pair();
+        let handle = FakeFirmware::default().spawn(firmware);
+        let mut sender = Sender::new(host);
+        assert_eq!(vec!["ok".to_string()], sender.send_command(&auto_home()).unwrap());
+        assert_eq!(vec!["echo:hi".to_string(), "ok".to_string()], sender.send_command("M117 hi").unwrap());
+        drop(sender);
+        assert_eq!(vec!["G28".to_string(), "M117 hi".to_string()], handle.join().unwrap());
+    }
+
+    #[test]
+    fn test_cancel() {
+        let (firmware, host) = pty_pair();
+        let handle = FakeFirmware::default().spawn(firmware);
+        let mut sender = Sender::new(host);
+        sender.control().cancel();
+        match sender.send_program(&sample_program()) {
+            Err(SendError::Cancelled { sent }) => assert_eq!(0, sent),
+            other => panic!("expected cancel, got {:?}", other),
+        }
+        drop(sender);
+        assert!(handle.join().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_cancel_while_paused() {
+        let (firmware, host) = pty_pair();
+        let handle = FakeFirmware::default().spawn(firmware);
+        let mut sender = Sender::new(host);
+        let control = sender.control();
+        control.pause();
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            control.cancel();
+        });
+        assert!(matches!(sender.send_program(&sample_program()), Err(SendError::Cancelled { sent: 0 })));
+        canceller.join().unwrap();
+        drop(sender);
+        assert!(handle.join().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_pause_resume() {
+        let (firmware, host) = pty_pair();
+        let handle = FakeFirmware::default().spawn(firmware);
+        let mut sender = Sender::new(host);
+        let control = sender.control();
+        control.pause();
+        assert!(control.is_paused());
+        let resumer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            control.resume();
+        });
+        let start = Instant::now();
+        let report = sender.send_program(&sample_program()).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        resumer.join().unwrap();
+        drop(sender);
+        assert_eq!(5, report.sent);
+        assert_eq!(5, handle.join().unwrap().len());
+    }
+
+    #[test]
+    fn test_connection_closed() {
+        let (firmware, host) = pty_pair();
+        drop(firmware);
+        let mut sender = Sender::new(host);
+        assert!(matches!(sender.send_program(&sample_program()), Err(SendError::Io(_))));
+    }
+}
+
+use crate::framing::{frame_line, reset_line_number, strip_comments};
+use crate::meatpack::Encoder;
+use crate::response::{parse_response, Response};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Number of sent lines kept around in case the firmware asks for them again
+const HISTORY_LEN: usize = 256;
+
+const RUNNING: u8 = 0;
+const PAUSED: u8 = 1;
+const CANCELLED: u8 = 2;
+
+/// Handle to pause, resume or cancel a [Sender], usable from another thread
+#[derive(Debug, Clone, Default)]
+pub struct Control(Arc<AtomicU8>);
+
+impl Control {
+    /// Stops sending new commands after the one in flight has been acknowledged
+    pub fn pause(&self) {
+        let _ = self.0.compare_exchange(RUNNING, PAUSED, Ordering::SeqCst, Ordering::SeqCst);
+    }
+
+    /// Carries on sending after [Control::pause]
+    pub fn resume(&self) {
+        let _ = self.0.compare_exchange(PAUSED, RUNNING, Ordering::SeqCst, Ordering::SeqCst);
+    }
+
+    /// Stops sending for good, the sender returns [SendError::Cancelled]
+    pub fn cancel(&self) {
+        self.0.store(CANCELLED, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.0.load(Ordering::SeqCst) == PAUSED
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst) == CANCELLED
+    }
+}
+
+/// Reasons streaming stopped early
+#[derive(Debug)]
+pub enum SendError {
+    Io(io::Error),
+    /// Cancelled through [Control::cancel] after `sent` commands were acknowledged
+    Cancelled { sent: usize },
+    /// The firmware asked for a line that is no longer in the history
+    ResendUnavailable { line: u32 },
+    /// The firmware reported an error it cannot recover from, such as being halted
+    Firmware(String),
+    /// A Grbl machine went into an alarm state, see [crate::grbl::alarm_description]
+    Alarm(u8),
+    /// A command is too long to ever fit in the firmware's receive buffer
+    LineTooLong(String),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SendError::Io(e) => write!(f, "i/o error: {}", e),
+            SendError::Cancelled { sent } => write!(f, "cancelled after {} commands", sent),
+            SendError::ResendUnavailable { line } => write!(f, "firmware asked to resend line {} which is no longer available", line),
+            SendError::Firmware(msg) => write!(f, "firmware error: {}", msg),
+            SendError::Alarm(code) => write!(f, "alarm {}: {}", code, crate::grbl::alarm_description(*code)),
+            SendError::LineTooLong(line) => write!(f, "line too long to send: {}", line),
+        }
+    }
+}
+
+impl Error for SendError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SendError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SendError {
+    fn from(e: io::Error) -> Self {
+        SendError::Io(e)
+    }
+}
+
+/// What happened while streaming a program
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Report {
+    /// Commands acknowledged by the firmware
+    pub sent: usize,
+    /// Lines sent again because the firmware asked for them
+    pub resends: usize,
+    /// `echo:` messages, without the `echo:` prefix
+    pub echoes: Vec<String>,
+    /// `Error:` messages the firmware recovered from
+    pub errors: Vec<String>,
+}
+
+/// How the firmware answered a line
+enum Ack {
+    Ok,
+    Resend(u32),
+}
+
+/// Streams commands to firmware over any transport, such as a serial port opened as a file
+///
+/// # Examples
+/// ```no_run
+/// extern crate gen_gcode;
+/// use gen_gcode::{Point2d, auto_home, move_xy};
+/// use gen_gcode::sender::Sender;
+/// use std::fs::OpenOptions;
+///
+/// let port = OpenOptions::new().read(true).write(true).open("/dev/ttyUSB0").unwrap();
+/// let mut sender = Sender::new(port);
+/// let program = format!("{}{}", auto_home(), move_xy(Point2d { x: 10.0, y: 5.0 }, None, None));
+/// let report = sender.send_program(&program).unwrap();
+/// assert_eq!(2, report.sent);
+/// ```
+pub struct Sender<T: Read + Write> {
+    conn: Connection<T>,
+    control: Control,
+    next_line: u32,
+    started: bool,
+    history: VecDeque<(u32, String)>,
+    report: Report,
+    meatpack: Option<Encoder>,
+}
+
+impl<T: Read + Write> Sender<T> {
+    pub fn new(transport: T) -> Self {
+        Sender { conn: Connection::new(transport), control: Control::default(), next_line: 1, started: false, history: VecDeque::new(), report: Report::default(), meatpack: None }
+    }
+
+    /// Returns a handle to pause, resume or cancel streaming
+    pub fn control(&self) -> Control {
+        self.control.clone()
+    }
+
+    /// Packs every line sent from now on with MeatPack, after sending the signals that switch it on,
+    /// see [crate::meatpack]. The firmware must support MeatPack, such as Marlin built with it.
+    pub fn enable_meatpack(&mut self, no_spaces: bool) -> Result<(), SendError> {
+        let encoder = Encoder::new(no_spaces);
+        self.conn.write(&encoder.enable())?;
+        self.meatpack = Some(encoder);
+        Ok(())
+    }
+
+    /// Switches MeatPack off, lines are sent as plain text from now on
+    pub fn disable_meatpack(&mut self) -> Result<(), SendError> {
+        if let Some(encoder) = self.meatpack.take() {
+            self.conn.write(&encoder.disable())?;
+        }
+        Ok(())
+    }
+
+    /// Sends every command of a program, skipping comments and blank lines
+    pub fn send_program(&mut self, program: &str) -> Result<Report, SendError> {
+        self.report = Report::default();
+        for line in program.lines() {
+            let command = strip_comments(line);
+            if command.is_empty() {
+                continue;
+            }
+            self.wait_while_paused()?;
+            self.send(&command)?;
+        }
+        Ok(std::mem::take(&mut self.report))
+    }
+
+    /// Sends a single command and returns every line the firmware answered with, up to and
+    /// including the `ok`
+    pub fn send_command(&mut self, command: &str) -> Result<Vec<String>, SendError> {
+        self.report = Report::default();
+        self.send(command.trim())
+    }
+
+    fn wait_while_paused(&self) -> Result<(), SendError> {
+        loop {
+            if self.control.is_cancelled() {
+                return Err(SendError::Cancelled { sent: self.report.sent });
+            }
+            if !self.control.is_paused() {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn send(&mut self, command: &str) -> Result<Vec<String>, SendError> {
+        if !self.started {
+            self.started = true;
+            self.history.push_back((0, frame_line(0, &reset_line_number(0))));
+            self.transmit_from(0, 0)?;
+        }
+        let n = self.next_line;
+        self.next_line += 1;
+        // the checksum covers the command as the firmware unpacks it
+        let command = match &self.meatpack {
+            Some(encoder) => encoder.command(command),
+            None => command.to_string(),
+        };
+        self.history.push_back((n, frame_line(n, &command)));
+        if self.history.len() > HISTORY_LEN {
+            self.history.pop_front();
+        }
+        let responses = self.transmit_from(n, n)?;
+        self.report.sent += 1;
+        Ok(responses)
+    }
+
+    /// Sends lines `first..=last` from the history, going back whenever the firmware asks for a
+    /// resend, returns the responses to the last line
+    fn transmit_from(&mut self, first: u32, last: u32) -> Result<Vec<String>, SendError> {
+        let mut n = first;
+        loop {
+            let framed = match self.history.iter().find(|(l, _)| *l == n) {
+                Some((_, framed)) => framed.clone(),
+                None => return Err(SendError::ResendUnavailable { line: n }),
+            };
+            match &self.meatpack {
+                Some(encoder) => self.conn.write(&encoder.pack(&framed))?,
+                None => self.conn.write(framed.as_bytes())?,
+            }
+            let mut responses = Vec::new();
+            match self.wait_ack(&mut responses)? {
+                Ack::Ok if n == last => return Ok(responses),
+                Ack::Ok => n += 1,
+                Ack::Resend(line) => {
+                    self.report.resends += 1;
+                    n = line;
+                }
+            }
+        }
+    }
+
+    fn wait_ack(&mut self, responses: &mut Vec<String>) -> Result<Ack, SendError> {
+        let mut resend = None;
+        loop {
+            let line = self.conn.read_line(&self.control, self.report.sent)?;
+            responses.push(line.clone());
+            match parse_response(&line) {
+                Response::Ok { .. } => {
+                    return Ok(match resend {
+                        Some(n) => Ack::Resend(n),
+                        None => Ack::Ok,
+                    })
+                }
+                Response::Resend(n) => resend = Some(n),
+                // the firmware is still working on a long command, keep waiting
+                Response::Busy(msg) if line.starts_with("echo:") => self.report.echoes.push(format!("busy: {}", msg)),
+                Response::Echo(msg) => self.report.echoes.push(msg),
+                Response::Error(msg) if msg.contains("halted") || msg.contains("kill") => return Err(SendError::Firmware(msg)),
+                Response::Error(msg) | Response::KlipperError(msg) => self.report.errors.push(msg),
+                _ => (),
+            }
+        }
+    }
+}
+
+/// A transport and the bytes read from it that do not make a whole line yet
+pub(crate) struct Connection<T: Read + Write> {
+    transport: T,
+    buf: Vec<u8>,
+}
+
+impl<T: Read + Write> Connection<T> {
+    pub(crate) fn new(transport: T) -> Self {
+        Connection { transport, buf: Vec::new() }
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) -> Result<(), SendError> {
+        self.transport.write_all(bytes)?;
+        self.transport.flush()?;
+        Ok(())
+    }
+
+    /// Returns the next non-empty line, read timeouts are retried unless streaming was cancelled
+    pub(crate) fn read_line(&mut self, control: &Control, sent: usize) -> Result<String, SendError> {
+        loop {
+            if let Some(pos) = self.buf.iter().position(|&b| b == b'\n') {
+                let bytes: Vec<u8> = self.buf.drain(..=pos).collect();
+                let line = String::from_utf8_lossy(&bytes).trim().to_string();
+                if line.is_empty() {
+                    continue;
+                }
+                return Ok(line);
+            }
+            let mut chunk = [0u8; 256];
+            match self.transport.read(&mut chunk) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed").into()),
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(e) if matches!(e.kind(), io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    if control.is_cancelled() {
+                        return Err(SendError::Cancelled { sent });
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
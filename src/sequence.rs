@@ -0,0 +1,75 @@
+//! Start and end sequences, the commands that get a printer ready before a print and leave it
+//! safe afterwards
+//!
+//! Add them to a program with [crate::program::Program::start_sequence] and
+//! [crate::program::Program::end_sequence].
+
+use crate::leveling::Leveling;
+use crate::Point2d;
+
+/// A line of filament laid down at the edge of the bed to prime the nozzle, lengths are in
+/// millimeters and speeds in millimeters per minute
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PurgeLine {
+    pub start: Point2d,
+    pub end: Point2d,
+    /// Height of the nozzle above the bed
+    pub z: f32,
+    /// Length of filament pushed out along the line
+    pub extrusion: f32,
+    pub speed: f32,
+    pub travel_speed: f32,
+}
+
+impl Default for PurgeLine {
+    /// A 100mm line along the left edge of the bed
+    fn default() -> Self {
+        PurgeLine { start: Point2d { x: 2.0, y: 20.0 }, end: Point2d { x: 2.0, y: 120.0 }, z: 0.3, extrusion: 15.0, speed: 1500.0, travel_speed: 6000.0 }
+    }
+}
+
+/// Heats, homes and levels the printer, then primes the nozzle
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StartSequence {
+    pub bed_temperature: u8,
+    pub hotend_temperature: u16,
+    /// Whether to heat the bed and hotend at the same time (M140, M104, then M190, M109), rather
+    /// than the hotend only once the bed is hot
+    pub parallel_heating: bool,
+    pub home: bool,
+    /// Z offset of the probe set before leveling, see [crate::leveling::set_probe_z_offset]
+    pub probe_z_offset: Option<f32>,
+    pub leveling: Leveling,
+    pub purge_line: Option<PurgeLine>,
+    /// Whether the print uses relative extrusion (M83) rather than absolute (M82)
+    pub relative_extrusion: bool,
+}
+
+impl StartSequence {
+    /// Creates a sequence heating in parallel, homing and drawing the default purge line, with
+    /// absolute extrusion and no leveling
+    pub fn new(bed_temperature: u8, hotend_temperature: u16) -> Self {
+        StartSequence { bed_temperature, hotend_temperature, parallel_heating: true, home: true, probe_z_offset: None, leveling: Leveling::None, purge_line: Some(PurgeLine::default()), relative_extrusion: false }
+    }
+}
+
+/// Retracts, lifts the nozzle away from the print and parks it, then turns off heaters, fans and
+/// motors, lengths are in millimeters and speeds in millimeters per minute
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct EndSequence {
+    pub retract: f32,
+    pub retract_speed: f32,
+    /// Height the nozzle is raised by
+    pub z_lift: f32,
+    /// Where the nozzle is moved once lifted, `None` to leave it above the print
+    pub park: Option<Point2d>,
+    pub travel_speed: f32,
+    /// Whether to turn the motors off, letting the axes move freely
+    pub motors_off: bool,
+}
+
+impl Default for EndSequence {
+    fn default() -> Self {
+        EndSequence { retract: 2.0, retract_speed: 2100.0, z_lift: 10.0, park: Some(Point2d { x: 0.0, y: 0.0 }), travel_speed: 6000.0, motors_off: true }
+    }
+}
@@ -0,0 +1,1027 @@
+//! A virtual machine that executes G-Code and records the state of the machine after every command
+//!
+//! Useful for testing generators without a printer: run the generated program and assert on the
+//! resulting [Timeline] of positions, extruder values, temperatures and fan speeds.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thermal::HeaterModel;
+    use crate::*;
+
+    fn approx(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn test_initial_state() {
+        let sim = Simulator::new();
+        let s = sim.state();
+        assert_eq!(Point3d { x: 0.0, y: 0.0, z: 0.0 }, s.position);
+        assert_eq!(Units::Millimeters, s.units);
+        assert!(!s.relative_positioning);
+        assert!(s.homed.is_empty());
+    }
+
+    #[test]
+    fn test_absolute_moves() {
+        let program = format!("{}{}", move_xy(Point2d { x: 10.0, y: 5.0 }, Some(600), None), move_xyz(Point3d { x: 20.0, y: 5.0, z: 0.2 }, None, Some(2.0)));
+        let timeline = simulate(&program);
+        let s = timeline.final_state();
+        assert_eq!(Point3d { x: 20.0, y: 5.0, z: 0.2 }, s.position);
+        assert_eq!(2.0, s.e);
+        assert_eq!(600.0, s.feed_rate);
+        assert_eq!(3, timeline.states.len());
+    }
+
+    #[test]
+    fn test_relative_positioning() {
+        let program = format!("{}{}{}", relative_positioning(), move_xy(Point2d { x: 10.0, y: 5.0 }, None, Some(1.0)), move_xy(Point2d { x: 10.0, y: 5.0 }, None, Some(1.0)));
+        let s = simulate(&program).final_state().clone();
+        assert_eq!(Point3d { x: 20.0, y: 10.0, z: 0.0 }, s.position);
+        // G91 makes the extruder relative as well
+        assert_eq!(2.0, s.e);
+    }
+
+    #[test]
+    fn test_relative_extrusion_only() {
+        let program = format!("{}{}{}", relative_extrution(), move_xy(Point2d { x: 10.0, y: 0.0 }, None, Some(1.0)), move_xy(Point2d { x: 20.0, y: 0.0 }, None, Some(1.0)));
+        let s = simulate(&program).final_state().clone();
+        assert_eq!(20.0, s.position.x);
+        assert_eq!(2.0, s.e);
+        assert_eq!(2.0, s.filament);
+    }
+
+    #[test]
+    fn test_absolute_positioning_resets_relative_extrusion() {
+        let program = format!("{}{}{}", relative_positioning(), absolute_positioning(), move_xy(Point2d { x: 5.0, y: 0.0 }, None, Some(3.0)));
+        let s = simulate(&program).final_state().clone();
+        assert!(!s.relative_extrusion);
+        assert_eq!(3.0, s.e);
+    }
+
+    #[test]
+    fn test_set_pos_and_reset_pos() {
+        let mut program = move_xy(Point2d { x: 100.0, y: 100.0 }, None, None);
+        program += &set_pos_2d(Point2d { x: 0.0, y: 0.0 }, None);
+        program += &move_xy(Point2d { x: 10.0, y: 0.0 }, None, None);
+        let timeline = simulate(&program);
+        let s = timeline.final_state();
+        assert_eq!(10.0, s.position.x);
+        assert_eq!(110.0, s.machine_position.x);
+
+        program += &reset_pos();
+        let s = simulate(&program).final_state().clone();
+        assert_eq!(110.0, s.position.x);
+    }
+
+    #[test]
+    fn test_reset_extruder_keeps_filament() {
+        let mut program = move_xy(Point2d { x: 10.0, y: 0.0 }, None, Some(5.0));
+        program += &reset_extruder(0.0);
+        program += &move_xy(Point2d { x: 20.0, y: 0.0 }, None, Some(5.0));
+        let s = simulate(&program).final_state().clone();
+        assert_eq!(5.0, s.e);
+        assert_eq!(10.0, s.filament);
+    }
+
+    #[test]
+    fn test_inches() {
+        let program = format!("{}{}", use_inches(), move_xy(Point2d { x: 1.0, y: 2.0 }, Some(10), None));
+        let s = simulate(&program).final_state().clone();
+        assert_eq!(Units::Inches, s.units);
+        assert!(approx(25.4, s.position.x));
+        assert!(approx(50.8, s.position.y));
+        assert!(approx(254.0, s.feed_rate));
+    }
+
+    #[test]
+    fn test_auto_home() {
+        let mut program = move_xyz(Point3d { x: 10.0, y: 10.0, z: 10.0 }, None, None);
+        program += &set_pos_2d(Point2d { x: 0.0, y: 0.0 }, None);
+        program += &auto_home();
+        let s = simulate(&program).final_state().clone();
+        assert!(s.homed.is_all());
+        assert_eq!(Point3d { x: 0.0, y: 0.0, z: 0.0 }, s.position);
+        assert_eq!(Point3d { x: 0.0, y: 0.0, z: 0.0 }, s.machine_position);
+    }
+
+    #[test]
+    fn test_home_selected_axes() {
+        let timeline = simulate("G28 X Y\nG0 X10 Y20 Z5\nG92 X0 Y0 Z0\nG28 Z\n");
+        let s = timeline.final_state();
+        assert_eq!(Axes::all(), s.homed);
+        assert_eq!(Point3d { x: 10.0, y: 20.0, z: 0.0 }, s.machine_position);
+        assert_eq!(Point3d { x: 0.0, y: 0.0, z: 0.0 }, s.position);
+        assert_eq!(Axes { x: true, y: true, z: false }, timeline.states[1].homed);
+    }
+
+    #[test]
+    fn test_home_only_if_needed() {
+        let s = simulate("G28\nG0 X10 Y10 Z10\nG28 O\n").final_state().clone();
+        assert_eq!(Point3d { x: 10.0, y: 10.0, z: 10.0 }, s.machine_position);
+        let s = simulate("G28 X Y\nG0 X10 Y10\nG28 O\n").final_state().clone();
+        assert_eq!(Point3d { x: 0.0, y: 0.0, z: 0.0 }, s.machine_position);
+        assert!(s.homed.is_all());
+    }
+
+    #[test]
+    fn test_grbl_homing_cycle() {
+        let s = simulate("G0 X10 Y10 Z10\n$HZ\n").final_state().clone();
+        assert_eq!(Point3d { x: 10.0, y: 10.0, z: 0.0 }, s.machine_position);
+        assert_eq!(Axes { z: true, ..Axes::default() }, s.homed);
+        assert!(simulate("$H\n").final_state().homed.is_all());
+    }
+
+    #[test]
+    fn test_move_before_homing_warning() {
+        let timeline = simulate("G0 X10\nG0 X20 E1\nG28 X\nG0 X5 Y5\nG1 E2\nM84\nG0 X1\n");
+        let expected = vec![
+            Warning::MoveBeforeHoming { line: 1, axis: 'X' },
+            Warning::MoveBeforeHoming { line: 4, axis: 'Y' },
+            Warning::MoveBeforeHoming { line: 7, axis: 'X' },
+        ];
+        assert_eq!(expected, timeline.warnings);
+    }
+
+    #[test]
+    fn test_motion_limits() {
+        let s = simulate("M201 X3000 Y3000\nM201 Z100\nM203 X300\nM204 S1000\nM204 R2000\nM205 X8 Y8\nM205 E5\nM220 S90\n").final_state().clone();
+        assert_eq!(AxisLimits { x: Some(3000.0), y: Some(3000.0), z: Some(100.0), e: None }, s.max_acceleration);
+        assert_eq!(Some(300.0), s.max_feed_rate.x);
+        assert_eq!(Acceleration { print: Some(1000.0), retract: Some(2000.0), travel: Some(1000.0) }, s.acceleration);
+        assert_eq!(Some(Jerk::Classic(AxisLimits { x: Some(8.0), y: Some(8.0), z: None, e: Some(5.0) })), s.jerk);
+        assert_eq!(90.0, s.speed_factor);
+        assert_eq!(Some(Jerk::JunctionDeviation(0.02)), simulate("M205 J0.02\n").final_state().jerk);
+    }
+
+    #[test]
+    fn test_flow_factor() {
+        let timeline = simulate("M221 S50\nG1 X10 E2\nM221 S100 T1\nT1\nG1 X20 E4\n");
+        let s = timeline.final_state();
+        assert_eq!((4.0, 3.0), (s.e, s.filament));
+        assert_eq!(50.0, s.flow_factor(0));
+        assert_eq!(100.0, s.flow_factor(1));
+    }
+
+    #[test]
+    fn test_temperatures_and_fans() {
+        let mut program = set_hotend_temp(210, None);
+        program += &wait_hotend_temp(200, Some(1));
+        program += &set_bed_temp(60);
+        program += &set_chamber_temp(40);
+        program += &set_fan_speed(128, None);
+        program += &set_fan_speed(255, Some(2));
+        let s = simulate(&program).final_state().clone();
+        assert_eq!(210.0, s.hotend_temp(0));
+        assert_eq!(200.0, s.hotend_temp(1));
+        assert_eq!(60.0, s.bed_temp);
+        assert_eq!(40.0, s.chamber_temp);
+        assert_eq!(128, s.fan_speed(0));
+        assert_eq!(255, s.fan_speed(2));
+
+        program += &fan_off(Some(2));
+        let s = simulate(&program).final_state().clone();
+        assert_eq!(0, s.fan_speed(2));
+        assert_eq!(128, s.fan_speed(0));
+    }
+
+    #[test]
+    fn test_arc_full_circle() {
+        let mut program = move_xy(Point2d { x: 20.0, y: 10.0 }, Some(600), None);
+        program += &move_xy_arc_ij(None, Some(-10.0), Some(0.0), Some(5.0), true);
+        let timeline = simulate(&program);
+        let s = timeline.final_state();
+        assert_eq!(Point3d { x: 20.0, y: 10.0, z: 0.0 }, s.position);
+        // one full circle of radius 10 at 10mm/s
+        let arc_time = s.time - timeline.states[1].time;
+        assert!(approx(std::f32::consts::PI * 2.0, arc_time));
+    }
+
+    #[test]
+    fn test_arc_half_circle_clockwise() {
+        let program = move_xy_arc_ij(Some(Point2d { x: 20.0, y: 0.0 }), Some(10.0), None, None, false);
+        let s = simulate(&program).final_state().clone();
+        assert_eq!(20.0, s.position.x);
+    }
+
+    #[test]
+    fn test_move_time() {
+        let program = move_xy(Point2d { x: 30.0, y: 40.0 }, Some(3000), None);
+        let timeline = simulate(&program);
+        assert!(approx(1.0, timeline.duration()));
+    }
+
+    #[test]
+    fn test_max_retraction() {
+        let mut program = move_xy(Point2d { x: 10.0, y: 0.0 }, None, Some(5.0));
+        program += &reset_extruder(0.0);
+        program += "G1 E-0.8 F2400\n";
+        program += "G1 E0 F2400\n";
+        program += &move_xy(Point2d { x: 20.0, y: 0.0 }, None, Some(1.0));
+        program += "G1 E0.5\n";
+        let timeline = simulate(&program);
+        assert!(approx(0.8, timeline.max_retraction()));
+    }
+
+    #[test]
+    fn test_layers() {
+        let mut program = String::new();
+        for (n, z) in [0.2_f32, 0.4, 0.6].iter().enumerate() {
+            program += &move_z(*z);
+            program += &move_xy(Point2d { x: 10.0, y: 0.0 }, None, Some(n as f32 * 2.0 + 1.0));
+            program += &move_xy(Point2d { x: 0.0, y: 0.0 }, None, Some(n as f32 * 2.0 + 2.0));
+        }
+        let timeline = simulate(&program);
+        let layers = timeline.layers();
+        assert_eq!(3, layers.len());
+        assert_eq!(0.4, layers[1].z);
+        let end = &timeline.states[layers[2].end];
+        assert_eq!(6.0, end.e);
+        assert_eq!(0.6, end.position.z);
+    }
+
+    #[test]
+    fn test_wait_hotend_temp_time() {
+        let timeline = simulate(&wait_hotend_temp(205, None));
+        let s = timeline.final_state();
+        // 25°C to 205°C at 2°C/s
+        assert_eq!(90.0, s.time);
+        assert_eq!(90.0, s.waiting_time);
+        assert_eq!(Some(205.0), s.hotend_current(0));
+    }
+
+    #[test]
+    fn test_unreachable_temperature_warning() {
+        let model = ThermalModel { bed: HeaterModel { heating_rate: 0.0, cooling_rate: 0.1 }, ..ThermalModel::default() };
+        let timeline = Simulator::with_thermal_model(model).run(&wait_bed_temp(60));
+        assert_eq!(vec![Warning::UnreachableTemperature { line: 1, command: "M190".to_string() }], timeline.warnings);
+        assert_eq!(0.0, timeline.duration());
+    }
+
+    #[test]
+    fn test_parallel_heating() {
+        // the bed heats while the hotend is waited on, so the bed wait is shorter
+        let program = format!("{}{}{}", set_bed_temp(60), wait_hotend_temp(205, None), wait_bed_temp(60));
+        let s = simulate(&program).final_state().clone();
+        assert_eq!(60.0, s.bed_current);
+        assert!(approx(90.0, s.time));
+
+        let program = format!("{}{}", wait_hotend_temp(205, None), wait_bed_temp(60));
+        let s = simulate(&program).final_state().clone();
+        assert!(approx(160.0, s.time));
+    }
+
+    #[test]
+    fn test_wait_does_not_block_when_cooling() {
+        let program = format!("{}{}", wait_hotend_temp(210, None), wait_hotend_temp(200, None));
+        let timeline = simulate(&program);
+        assert_eq!(timeline.states[1].time, timeline.final_state().time);
+        assert_eq!(200.0, timeline.final_state().hotend_temp(0));
+    }
+
+    #[test]
+    fn test_wait_r_blocks_when_cooling() {
+        let program = format!("{}M109 R200\n", wait_hotend_temp(210, None));
+        let timeline = simulate(&program);
+        assert!(approx(10.0, timeline.final_state().time - timeline.states[1].time));
+    }
+
+    #[test]
+    fn test_heaters_follow_moves() {
+        let program = format!("{}{}", set_hotend_temp(210, None), move_xy(Point2d { x: 600.0, y: 0.0 }, Some(6000), None));
+        let s = simulate(&program).final_state().clone();
+        assert_eq!(Some(37.0), s.hotend_current(0));
+        assert_eq!(0.0, s.waiting_time);
+    }
+
+    #[test]
+    fn test_chamber_wait_with_model() {
+        let model = ThermalModel { ambient: 20.0, chamber: HeaterModel { heating_rate: 0.1, cooling_rate: 0.1 }, ..ThermalModel::default() };
+        let timeline = Simulator::with_thermal_model(model).run(&wait_chamber_temp(50));
+        assert!(approx(300.0, timeline.duration()));
+    }
+
+    #[test]
+    fn test_select_tool() {
+        let program = format!("{}{}{}{}", select_tool(2), set_hotend_temp(230, None), select_tool(0), set_hotend_temp(200, None));
+        let timeline = simulate(&program);
+        assert!(timeline.warnings.is_empty());
+        let s = timeline.final_state();
+        assert_eq!(0, s.tool);
+        assert_eq!(230.0, s.hotend_temp(2));
+        assert_eq!(200.0, s.hotend_temp(0));
+        assert_eq!(2, timeline.states[1].tool);
+    }
+
+    #[test]
+    fn test_unsupported_command_warning() {
+        let timeline = simulate("G28\nM999\n");
+        assert_eq!(vec![Warning::UnsupportedCommand { line: 2, command: "M999".to_string() }], timeline.warnings);
+    }
+
+    #[test]
+    fn test_invalid_index_warning() {
+        let timeline = simulate("T4000000000\nM104 T99 S200\nM221 T-1 S90\nM106 P4000000000 S255\nM104 S210\n");
+        let invalid = |line: usize, command: &str| Warning::InvalidIndex { line, command: command.to_string() };
+        assert_eq!(vec![invalid(1, "T4000000000"), invalid(2, "M104"), invalid(3, "M221"), invalid(4, "M106")], timeline.warnings);
+        let s = timeline.final_state();
+        assert_eq!(0, s.tool);
+        assert_eq!(1, s.hotend_temps.len());
+        assert_eq!(210.0, s.hotend_temp(0));
+        assert_eq!(0, s.fan_speed(0));
+    }
+
+    #[test]
+    fn test_leveling_commands() {
+        let timeline = simulate("G28\nM851 Z-1.2\nG29\nM420 S1\nBED_MESH_PROFILE LOAD=default\nG1 X10\nM84\n");
+        assert!(timeline.warnings.is_empty());
+        assert!(timeline.final_state().homed.is_empty());
+        assert!(timeline.states[6].homed.is_all());
+    }
+
+    #[test]
+    fn test_comments_are_skipped() {
+        let timeline = simulate("; just a comment\nG28 ; home\n");
+        assert_eq!(2, timeline.states.len());
+        assert_eq!(2, timeline.final_state().line);
+    }
+}
+
+use crate::homing::{homed_axes, Axes};
+use crate::machine::{MAX_FANS, MAX_HOTENDS};
+use crate::motion::{Acceleration, AxisLimits, Jerk};
+use crate::parser::{parse_line, Command};
+use crate::thermal::ThermalModel;
+use crate::{Point3d, Units};
+
+/// Snapshot of the simulated machine's state
+///
+/// All lengths are in millimeters and feed rates in millimeters per minute, regardless of the
+/// units the program was written in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct State {
+    /// Line of the program that produced this state, 0 for the initial state
+    pub line: usize,
+    /// Position of the nozzle/tool in the coordinate system set by G92
+    pub position: Point3d,
+    /// Position of the nozzle/tool relative to the machine's home
+    pub machine_position: Point3d,
+    /// Position of the E axis in the coordinate system set by G92
+    pub e: f32,
+    /// Total length of filament fed through the extruder, unaffected by G92
+    pub filament: f32,
+    pub units: Units,
+    pub relative_positioning: bool,
+    pub relative_extrusion: bool,
+    pub feed_rate: f32,
+    /// Tool selected with the last T command, temperature commands without a T apply to it
+    pub tool: usize,
+    /// Target temperature of each hotend, indexed by tool number
+    pub hotend_temps: Vec<f32>,
+    /// Modelled temperature of each hotend, indexed by tool number
+    pub hotend_currents: Vec<f32>,
+    pub bed_temp: f32,
+    pub bed_current: f32,
+    pub chamber_temp: f32,
+    pub chamber_current: f32,
+    /// Speed of each fan (0-255), indexed by fan number
+    pub fan_speeds: Vec<u8>,
+    /// Axes whose position is known, set by homing and cleared by turning the motors off
+    pub homed: Axes,
+    /// Maximum acceleration of each axis set by M201, in mm/s²
+    pub max_acceleration: AxisLimits,
+    /// Maximum feed rate of each axis set by M203, in mm/s
+    pub max_feed_rate: AxisLimits,
+    /// Accelerations set by M204, moves are instant when neither these nor
+    /// [State::max_acceleration] are set
+    pub acceleration: Acceleration,
+    /// Jerk or junction deviation set by M205
+    pub jerk: Option<Jerk>,
+    /// Percentage set by M220 that every feed rate is scaled by
+    pub speed_factor: f32,
+    /// Percentage set by M221 that extrusion is scaled by, indexed by tool number
+    pub flow_factors: Vec<f32>,
+    /// Seconds elapsed since the start of the program
+    pub time: f32,
+    /// Seconds of [State::time] spent waiting for heaters
+    pub waiting_time: f32,
+}
+
+impl State {
+    /// Returns the target temperature of a hotend, 0 if it was never set
+    pub fn hotend_temp(&self, tool: usize) -> f32 {
+        self.hotend_temps.get(tool).copied().unwrap_or(0.0)
+    }
+
+    /// Returns the modelled temperature of a hotend, if the machine has that hotend
+    pub fn hotend_current(&self, tool: usize) -> Option<f32> {
+        self.hotend_currents.get(tool).copied()
+    }
+
+    /// Returns the flow percentage of an extruder, 100 if it was never set
+    pub fn flow_factor(&self, tool: usize) -> f32 {
+        self.flow_factors.get(tool).copied().unwrap_or(100.0)
+    }
+
+    /// Returns the speed of a fan, 0 if it was never set
+    pub fn fan_speed(&self, fan: usize) -> u8 {
+        self.fan_speeds.get(fan).copied().unwrap_or(0)
+    }
+}
+
+/// Something questionable the simulator noticed while executing a program
+#[derive(Debug, Clone, PartialEq)]
+pub enum Warning {
+    /// A command the simulator does not model, it was skipped
+    UnsupportedCommand { line: usize, command: String },
+    /// A move along an axis that was not homed yet, flagged once per axis until it is homed
+    MoveBeforeHoming { line: usize, axis: char },
+    /// A tool or fan number past [MAX_HOTENDS] or [MAX_FANS], the command was skipped
+    InvalidIndex { line: usize, command: String },
+    /// A temperature wait the thermal model never finishes, because the heater does not heat or
+    /// cool, the wait was skipped
+    UnreachableTemperature { line: usize, command: String },
+}
+
+/// A run of extruding moves at the same Z height
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layer {
+    pub z: f32,
+    /// Index into [Timeline::states] of the first extruding move of the layer
+    pub start: usize,
+    /// Index into [Timeline::states] of the last extruding move of the layer
+    pub end: usize,
+}
+
+/// The states a machine went through while executing a program
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timeline {
+    /// The initial state, followed by the state after each executed command
+    pub states: Vec<State>,
+    pub warnings: Vec<Warning>,
+}
+
+impl Timeline {
+    /// Returns the state of the machine after the last command
+    pub fn final_state(&self) -> &State {
+        self.states.last().expect("a timeline always holds the initial state")
+    }
+
+    /// Returns the simulated run time of the program in seconds
+    pub fn duration(&self) -> f32 {
+        self.final_state().time
+    }
+
+    /// Returns the largest length of filament pulled back from the furthest point the extruder had
+    /// reached, ie. the deepest retraction in the program
+    pub fn max_retraction(&self) -> f32 {
+        let mut peak = f32::MIN;
+        let mut max = 0.0_f32;
+        for s in &self.states {
+            peak = peak.max(s.filament);
+            max = max.max(peak - s.filament);
+        }
+        max
+    }
+
+    /// Splits the program into layers, a new layer starts whenever an extruding move happens at a
+    /// different Z height than the previous one
+    ///
+    /// # Examples
+    /// ```
+    /// extern crate gen_gcode;
+    /// use gen_gcode::{Point2d, move_xy, move_z};
+    /// use gen_gcode::simulator::simulate;
+    ///
+    /// let mut program = String::new();
+    /// for (n, z) in [0.17_f32, 0.37, 0.57].iter().enumerate() {
+    ///     program += &move_z(*z);
+    ///     program += &move_xy(Point2d { x: 10.0 * n as f32, y: 10.0 }, None, Some(n as f32 + 1.0));
+    /// }
+    /// let timeline = simulate(&program);
+    /// let layer_3 = &timeline.layers()[2];
+    /// assert_eq!(0.57, timeline.states[layer_3.end].position.z);
+    /// ```
+    pub fn layers(&self) -> Vec<Layer> {
+        let mut layers: Vec<Layer> = Vec::new();
+        for (i, pair) in self.states.windows(2).enumerate() {
+            let (prev, cur) = (&pair[0], &pair[1]);
+            if cur.filament <= prev.filament || cur.machine_position == prev.machine_position {
+                continue;
+            }
+            let z = cur.position.z;
+            match layers.last_mut() {
+                Some(layer) if layer.z == z => layer.end = i + 1,
+                _ => layers.push(Layer { z, start: i + 1, end: i + 1 }),
+            }
+        }
+        layers
+    }
+}
+
+/// Executes G-Code one command at a time, tracking the state of the machine
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::{Point3d, auto_home, move_xyz};
+/// use gen_gcode::simulator::Simulator;
+///
+/// let mut sim = Simulator::new();
+/// sim.execute_line(1, &auto_home());
+/// sim.execute_line(2, &move_xyz(Point3d { x: 10.0, y: 5.0, z: 0.2 }, None, Some(1.0)));
+/// assert_eq!(Point3d { x: 10.0, y: 5.0, z: 0.2 }, sim.state().position);
+/// assert_eq!(1.0, sim.state().e);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Simulator {
+    state: State,
+    thermal: ThermalModel,
+    offset: Point3d,
+    e_offset: f32,
+    timeline: Vec<State>,
+    warnings: Vec<Warning>,
+    /// Unhomed axes a move was already flagged for
+    warned: Axes,
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Simulator {
+    /// Creates a simulator for an unhomed machine sitting at (0,0,0), in millimeters and absolute
+    /// positioning, with all heaters and fans off
+    pub fn new() -> Self {
+        Self::with_thermal_model(ThermalModel::default())
+    }
+
+    /// Creates a simulator like [Simulator::new], using the given model to work out how long
+    /// M109/M190/M191 wait for their heaters
+    ///
+    /// # Examples
+    /// ```
+    /// extern crate gen_gcode;
+    /// use gen_gcode::wait_bed_temp;
+    /// use gen_gcode::simulator::Simulator;
+    /// use gen_gcode::thermal::{HeaterModel, ThermalModel};
+    ///
+    /// let model = ThermalModel { ambient: 20.0, bed: HeaterModel { heating_rate: 0.5, cooling_rate: 0.1 }, ..ThermalModel::default() };
+    /// let timeline = Simulator::with_thermal_model(model).run(&wait_bed_temp(60));
+    /// assert_eq!(80.0, timeline.duration());
+    /// ```
+    pub fn with_thermal_model(thermal: ThermalModel) -> Self {
+        let origin = Point3d { x: 0.0, y: 0.0, z: 0.0 };
+        let state = State {
+            line: 0,
+            position: origin,
+            machine_position: origin,
+            e: 0.0,
+            filament: 0.0,
+            units: Units::Millimeters,
+            relative_positioning: false,
+            relative_extrusion: false,
+            feed_rate: 1500.0,
+            tool: 0,
+            hotend_temps: vec![0.0],
+            hotend_currents: vec![thermal.ambient],
+            bed_temp: 0.0,
+            bed_current: thermal.ambient,
+            chamber_temp: 0.0,
+            chamber_current: thermal.ambient,
+            fan_speeds: Vec::new(),
+            homed: Axes::default(),
+            max_acceleration: AxisLimits::default(),
+            max_feed_rate: AxisLimits::default(),
+            acceleration: Acceleration::default(),
+            jerk: None,
+            speed_factor: 100.0,
+            flow_factors: vec![100.0],
+            time: 0.0,
+            waiting_time: 0.0,
+        };
+        Simulator { timeline: vec![state.clone()], state, thermal, offset: origin, e_offset: 0.0, warnings: Vec::new(), warned: Axes::default() }
+    }
+
+    /// Returns the current state of the machine
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// Parses and executes a single line of G-Code, lines holding only a comment are skipped
+    pub fn execute_line(&mut self, line: usize, gcode: &str) {
+        if let Some(cmd) = parse_line(gcode).command {
+            self.execute(line, &cmd);
+        }
+    }
+
+    /// Executes a single parsed command and records the resulting state
+    pub fn execute(&mut self, line: usize, cmd: &Command) {
+        self.state.line = line;
+        match cmd.name.as_str() {
+            "G0" | "G1" => self.linear_move(cmd),
+            "G2" => self.arc_move(cmd, false),
+            "G3" => self.arc_move(cmd, true),
+            "G20" => self.state.units = Units::Inches,
+            "G21" => self.state.units = Units::Millimeters,
+            "G28" | "$H" | "$HX" | "$HY" | "$HZ" => self.home(cmd),
+            "G90" => {
+                self.state.relative_positioning = false;
+                self.state.relative_extrusion = false;
+            }
+            "G91" => {
+                self.state.relative_positioning = true;
+                self.state.relative_extrusion = true;
+            }
+            "G92" => self.set_position(cmd),
+            "G92.1" => {
+                self.offset = Point3d { x: 0.0, y: 0.0, z: 0.0 };
+                self.update_logical();
+            }
+            "M82" => self.state.relative_extrusion = false,
+            "M83" => self.state.relative_extrusion = true,
+            "M104" => {
+                if let Some(tool) = self.hotend(line, cmd) {
+                    self.state.hotend_temps[tool] = cmd.get("S").unwrap_or(0.0);
+                }
+            }
+            "M109" => {
+                if let Some(tool) = self.hotend(line, cmd) {
+                    let (target, both_ways) = wait_target(cmd);
+                    self.state.hotend_temps[tool] = target;
+                    let current = self.state.hotend_currents[tool];
+                    self.wait_for(line, cmd, current, target, both_ways, self.thermal.hotend.time_to_reach(current, target));
+                }
+            }
+            "M140" => self.state.bed_temp = cmd.get("S").unwrap_or(0.0),
+            "M190" => {
+                let (target, both_ways) = wait_target(cmd);
+                self.state.bed_temp = target;
+                let current = self.state.bed_current;
+                self.wait_for(line, cmd, current, target, both_ways, self.thermal.bed.time_to_reach(current, target));
+            }
+            "M141" => self.state.chamber_temp = cmd.get("S").unwrap_or(0.0),
+            "M191" => {
+                let (target, both_ways) = wait_target(cmd);
+                self.state.chamber_temp = target;
+                let current = self.state.chamber_current;
+                self.wait_for(line, cmd, current, target, both_ways, self.thermal.chamber.time_to_reach(current, target));
+            }
+            name if name.starts_with('T') && name[1..].parse::<usize>().is_ok() => {
+                let tool = name[1..].parse().unwrap();
+                if tool < MAX_HOTENDS {
+                    self.state.tool = tool;
+                    self.add_hotend(tool);
+                } else {
+                    self.warnings.push(Warning::InvalidIndex { line, command: cmd.name.clone() });
+                }
+            }
+            // tool offsets are applied by the firmware, positions stay in the program's coordinates
+            "M218" => (),
+            "M201" => self.state.max_acceleration = self.axis_limits(cmd, self.state.max_acceleration),
+            "M203" => self.state.max_feed_rate = self.axis_limits(cmd, self.state.max_feed_rate),
+            "M204" => {
+                let a = &mut self.state.acceleration;
+                // S is the older way of setting both the print and travel accelerations
+                let s = cmd.get("S");
+                a.print = cmd.get("P").or(s).or(a.print);
+                a.travel = cmd.get("T").or(s).or(a.travel);
+                a.retract = cmd.get("R").or(a.retract);
+            }
+            "M205" => {
+                if let Some(j) = cmd.get("J") {
+                    self.state.jerk = Some(Jerk::JunctionDeviation(j));
+                }
+                if ["X", "Y", "Z", "E"].iter().any(|k| cmd.has(k)) {
+                    let current = match self.state.jerk {
+                        Some(Jerk::Classic(limits)) => limits,
+                        _ => AxisLimits::default(),
+                    };
+                    self.state.jerk = Some(Jerk::Classic(self.axis_limits(cmd, current)));
+                }
+            }
+            "M220" => self.state.speed_factor = cmd.get("S").unwrap_or(100.0),
+            "M221" => {
+                if let Some(tool) = self.hotend(line, cmd) {
+                    self.state.flow_factors[tool] = cmd.get("S").unwrap_or(100.0);
+                }
+            }
+            "M106" => self.set_fan(line, cmd, cmd.get("S").unwrap_or(255.0) as u8),
+            "M107" => self.set_fan(line, cmd, 0),
+            // object labels only matter to the firmware when cancelling an object
+            "M486" | "EXCLUDE_OBJECT_DEFINE" | "EXCLUDE_OBJECT_START" | "EXCLUDE_OBJECT_END" => (),
+            // leveling and probing only change how the firmware maps heights to the bed
+            "G29" | "G30" | "G31" | "M420" | "M557" | "M851" | "BED_MESH_CALIBRATE" | "BED_MESH_PROFILE" | "PROBE" => (),
+            // pressure advance only changes how the firmware drives the extruder along a move
+            "M900" | "M572" | "SET_PRESSURE_ADVANCE" => (),
+            // with the motors off the axes can be moved by hand, so they need homing again
+            "M18" | "M84" => {
+                self.state.homed = Axes::default();
+                self.warned = Axes::default();
+            }
+            _ => self.warnings.push(Warning::UnsupportedCommand { line, command: cmd.name.clone() }),
+        }
+        self.timeline.push(self.state.clone());
+    }
+
+    /// Executes every line of a program and returns the resulting timeline
+    pub fn run(mut self, program: &str) -> Timeline {
+        for (n, line) in program.lines().enumerate() {
+            self.execute_line(n + 1, line);
+        }
+        Timeline { states: self.timeline, warnings: self.warnings }
+    }
+
+    fn to_mm(&self, value: f32) -> f32 {
+        self.state.units.to_mm(value)
+    }
+
+    /// Returns the machine position a command's axis parameter moves to
+    fn target(&self, cmd: &Command, key: &str, machine: f32, offset: f32) -> f32 {
+        match cmd.get(key) {
+            Some(v) if self.state.relative_positioning => machine + self.to_mm(v),
+            Some(v) => self.to_mm(v) + offset,
+            None => machine,
+        }
+    }
+
+    fn target_position(&self, cmd: &Command) -> Point3d {
+        let m = self.state.machine_position;
+        Point3d {
+            x: self.target(cmd, "X", m.x, self.offset.x),
+            y: self.target(cmd, "Y", m.y, self.offset.y),
+            z: self.target(cmd, "Z", m.z, self.offset.z),
+        }
+    }
+
+    fn target_filament(&self, cmd: &Command) -> f32 {
+        match cmd.get("E") {
+            Some(v) if self.state.relative_extrusion => self.state.filament + self.to_mm(v),
+            Some(v) => self.to_mm(v) + self.e_offset,
+            None => self.state.filament,
+        }
+    }
+
+    fn update_feed_rate(&mut self, cmd: &Command) {
+        if let Some(f) = cmd.get("F") {
+            self.state.feed_rate = self.to_mm(f);
+        }
+    }
+
+    /// Returns the limits with the axes given by a command replaced
+    fn axis_limits(&self, cmd: &Command, current: AxisLimits) -> AxisLimits {
+        let value = |key: &str, v: Option<f32>| cmd.get(key).map(|v| self.to_mm(v)).or(v);
+        AxisLimits { x: value("X", current.x), y: value("Y", current.y), z: value("Z", current.z), e: value("E", current.e) }
+    }
+
+    /// Moves to the given machine position, advancing the clock by the time the move takes at the
+    /// current feed rate, see [Simulator::move_time]. Extrusion is scaled by the flow factor.
+    fn move_to(&mut self, dest: Point3d, filament: f32, length: f32) {
+        self.check_homed(dest);
+        let flow = self.state.flow_factor(self.state.tool) / 100.0;
+        let fed = self.state.filament + (filament - self.state.filament) * flow;
+        let seconds = self.move_time(dest, fed - self.state.filament, length);
+        self.advance(seconds);
+        self.e_offset += fed - filament;
+        self.state.machine_position = dest;
+        self.state.filament = fed;
+        self.update_logical();
+    }
+
+    /// Returns how long a move takes, at the current feed rate capped by the axes' maximum feed
+    /// rates. When an acceleration is set the move speeds up and slows down following a trapezoid,
+    /// starting and ending at the jerk speed (at rest with junction deviation), there is no
+    /// look-ahead between moves.
+    fn move_time(&self, dest: Point3d, extruded: f32, length: f32) -> f32 {
+        let s = &self.state;
+        let m = s.machine_position;
+        let deltas = [dest.x - m.x, dest.y - m.y, dest.z - m.z, extruded];
+        let straight = distance(m, dest);
+        let along_axes = length > 0.0;
+        let (length, reference) = if length > 0.0 { (length, straight) } else { (extruded.abs(), extruded.abs()) };
+        let feed = s.feed_rate / 60.0 * s.speed_factor / 100.0;
+        if length <= 0.0 || feed <= 0.0 {
+            return 0.0
+        }
+        // the share of the move's speed taken by each axis, an axis limit caps the whole move
+        let shares: Vec<f32> = deltas.iter().map(|d| if reference > 0.0 { d.abs() / reference } else { 0.0 }).collect();
+        let cap = |limits: AxisLimits, v: Option<f32>| {
+            let limits = [limits.x, limits.y, limits.z, limits.e];
+            shares.iter().zip(limits.iter()).fold(v, |v, (share, limit)| match limit {
+                Some(limit) if *share > 0.0 => Some(v.map_or(limit / share, |v| v.min(limit / share))),
+                _ => v,
+            })
+        };
+        let speed = cap(s.max_feed_rate, Some(feed)).unwrap_or(feed);
+        let accel = match (along_axes, extruded != 0.0) {
+            (false, _) => s.acceleration.retract,
+            (true, true) => s.acceleration.print,
+            (true, false) => s.acceleration.travel,
+        };
+        let accel = match cap(s.max_acceleration, accel) {
+            Some(accel) if accel > 0.0 => accel,
+            _ => return length / speed,
+        };
+        let boundary = match s.jerk {
+            Some(Jerk::Classic(jerk)) => cap(jerk, Some(speed)).unwrap_or(0.0),
+            _ => 0.0,
+        };
+        trapezoid_time(length, speed, boundary, accel)
+    }
+
+    fn linear_move(&mut self, cmd: &Command) {
+        self.update_feed_rate(cmd);
+        let dest = self.target_position(cmd);
+        let filament = self.target_filament(cmd);
+        let length = distance(self.state.machine_position, dest);
+        self.move_to(dest, filament, length);
+    }
+
+    fn arc_move(&mut self, cmd: &Command, ccw: bool) {
+        self.update_feed_rate(cmd);
+        let start = self.state.machine_position;
+        // I and J are always relative to the start point
+        let dest = self.target_position(cmd);
+        let cx = start.x + self.to_mm(cmd.get("I").unwrap_or(0.0));
+        let cy = start.y + self.to_mm(cmd.get("J").unwrap_or(0.0));
+        let radius = ((start.x - cx).powi(2) + (start.y - cy).powi(2)).sqrt();
+        let a0 = (start.y - cy).atan2(start.x - cx);
+        let a1 = (dest.y - cy).atan2(dest.x - cx);
+        let tau = std::f32::consts::PI * 2.0;
+        let mut sweep = if ccw { a1 - a0 } else { a0 - a1 };
+        while sweep <= 1e-6 {
+            sweep += tau;
+        }
+        let filament = self.target_filament(cmd);
+        let planar = radius * sweep;
+        let length = (planar.powi(2) + (dest.z - start.z).powi(2)).sqrt();
+        self.move_to(dest, filament, length);
+    }
+
+    /// Homes the axes named by a homing command, G28 O skips homing when they are already homed
+    fn home(&mut self, cmd: &Command) {
+        let axes = homed_axes(cmd).unwrap_or_else(Axes::all);
+        if cmd.has("O") && self.state.homed.contains(axes) {
+            return
+        }
+        let (m, o) = (&mut self.state.machine_position, &mut self.offset);
+        for (axis, _) in axes.letters() {
+            match axis {
+                0 => (m.x, o.x) = (0.0, 0.0),
+                1 => (m.y, o.y) = (0.0, 0.0),
+                _ => (m.z, o.z) = (0.0, 0.0),
+            }
+        }
+        self.state.homed = self.state.homed.union(axes);
+        self.update_logical();
+    }
+
+    /// Flags the first move along each axis that was not homed
+    fn check_homed(&mut self, dest: Point3d) {
+        let m = self.state.machine_position;
+        let moved = Axes { x: dest.x != m.x, y: dest.y != m.y, z: dest.z != m.z };
+        let known = self.state.homed.union(self.warned);
+        let flagged = Axes { x: moved.x && !known.x, y: moved.y && !known.y, z: moved.z && !known.z };
+        for (_, axis) in flagged.letters() {
+            self.warnings.push(Warning::MoveBeforeHoming { line: self.state.line, axis });
+        }
+        self.warned = self.warned.union(flagged);
+    }
+
+    fn set_position(&mut self, cmd: &Command) {
+        let m = self.state.machine_position;
+        if let Some(x) = cmd.get("X") {
+            self.offset.x = m.x - self.to_mm(x);
+        }
+        if let Some(y) = cmd.get("Y") {
+            self.offset.y = m.y - self.to_mm(y);
+        }
+        if let Some(z) = cmd.get("Z") {
+            self.offset.z = m.z - self.to_mm(z);
+        }
+        if let Some(e) = cmd.get("E") {
+            self.e_offset = self.state.filament - self.to_mm(e);
+        }
+        self.update_logical();
+    }
+
+    /// Returns the hotend a temperature command applies to, adding it if the machine did not have
+    /// it yet, or `None` with a warning if the tool number is out of range
+    fn hotend(&mut self, line: usize, cmd: &Command) -> Option<usize> {
+        let tool = match cmd.get("T") {
+            Some(t) => index(t, MAX_HOTENDS),
+            None => Some(self.state.tool),
+        };
+        match tool {
+            Some(tool) => self.add_hotend(tool),
+            None => self.warnings.push(Warning::InvalidIndex { line, command: cmd.name.clone() }),
+        }
+        tool
+    }
+
+    fn add_hotend(&mut self, tool: usize) {
+        if self.state.hotend_temps.len() <= tool {
+            self.state.hotend_temps.resize(tool + 1, 0.0);
+            self.state.hotend_currents.resize(tool + 1, self.thermal.ambient);
+            self.state.flow_factors.resize(tool + 1, 100.0);
+        }
+    }
+
+    /// Waits `seconds` for a heater to reach its target. Like Marlin, waits given with `S` only
+    /// block while heating, waits given with `R` block while cooling as well. A wait the thermal
+    /// model says never ends is skipped with a warning.
+    fn wait_for(&mut self, line: usize, cmd: &Command, current: f32, target: f32, both_ways: bool, seconds: Option<f32>) {
+        if target > current || (both_ways && target < current) {
+            match seconds {
+                Some(seconds) => {
+                    self.advance(seconds);
+                    self.state.waiting_time += seconds;
+                }
+                None => self.warnings.push(Warning::UnreachableTemperature { line, command: cmd.name.clone() }),
+            }
+        }
+    }
+
+    /// Lets time pass, moving every heater towards its target
+    fn advance(&mut self, seconds: f32) {
+        let t = self.thermal;
+        self.state.time += seconds;
+        for (current, target) in self.state.hotend_currents.iter_mut().zip(&self.state.hotend_temps) {
+            *current = t.hotend.step(*current, *target, seconds, t.ambient);
+        }
+        self.state.bed_current = t.bed.step(self.state.bed_current, self.state.bed_temp, seconds, t.ambient);
+        self.state.chamber_current = t.chamber.step(self.state.chamber_current, self.state.chamber_temp, seconds, t.ambient);
+    }
+
+    fn set_fan(&mut self, line: usize, cmd: &Command, speed: u8) {
+        match index(cmd.get("P").unwrap_or(0.0), MAX_FANS) {
+            Some(fan) => {
+                if self.state.fan_speeds.len() <= fan {
+                    self.state.fan_speeds.resize(fan + 1, 0);
+                }
+                self.state.fan_speeds[fan] = speed;
+            }
+            None => self.warnings.push(Warning::InvalidIndex { line, command: cmd.name.clone() }),
+        }
+    }
+
+    fn update_logical(&mut self) {
+        let m = self.state.machine_position;
+        self.state.position = Point3d { x: m.x - self.offset.x, y: m.y - self.offset.y, z: m.z - self.offset.z };
+        self.state.e = self.state.filament - self.e_offset;
+    }
+}
+
+/// Returns how long covering a distance takes when speeding up from `boundary` towards `speed` at
+/// `accel`, then slowing back down to `boundary`
+fn trapezoid_time(length: f32, speed: f32, boundary: f32, accel: f32) -> f32 {
+    let ramp = (speed * speed - boundary * boundary) / (2.0 * accel);
+    if 2.0 * ramp <= length {
+        2.0 * (speed - boundary) / accel + (length - 2.0 * ramp) / speed
+    } else {
+        // never reaches full speed
+        let peak = (accel * length + boundary * boundary).sqrt();
+        2.0 * (peak - boundary) / accel
+    }
+}
+
+/// Runs a program on a fresh [Simulator] and returns its timeline
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::{Point2d, auto_home, move_xy, set_hotend_temp};
+/// use gen_gcode::simulator::simulate;
+///
+/// let program = format!("{}{}{}", auto_home(), set_hotend_temp(210, None), move_xy(Point2d { x: 10.0, y: 5.0 }, None, Some(1.0)));
+/// let timeline = simulate(&program);
+/// assert_eq!(10.0, timeline.final_state().position.x);
+/// assert_eq!(210.0, timeline.final_state().hotend_temp(0));
+/// ```
+pub fn simulate(program: &str) -> Timeline {
+    Simulator::new().run(program)
+}
+
+/// Returns the target of a wait command and whether it waits for cooling as well as heating
+fn wait_target(cmd: &Command) -> (f32, bool) {
+    match cmd.get("R") {
+        Some(r) => (r, true),
+        None => (cmd.get("S").unwrap_or(0.0), false),
+    }
+}
+
+/// Returns a tool or fan number as an index, `None` if it is not a whole number below `max`
+fn index(value: f32, max: usize) -> Option<usize> {
+    if value >= 0.0 && value.fract() == 0.0 && value < max as f32 {
+        Some(value as usize)
+    } else {
+        None
+    }
+}
+
+fn distance(a: Point3d, b: Point3d) -> f32 {
+    ((b.x - a.x).powi(2) + (b.y - a.y).powi(2) + (b.z - a.z).powi(2)).sqrt()
+}
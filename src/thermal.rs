@@ -0,0 +1,126 @@
+//! A simple thermal model of the machine's heaters, used to work out how long temperature waits take
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_time_to_reach_heating() {
+        let h = HeaterModel { heating_rate: 2.0, cooling_rate: 1.0 };
+        assert_eq!(Some(90.0), h.time_to_reach(25.0, 205.0));
+    }
+
+    #[test]
+    fn test_time_to_reach_cooling() {
+        let h = HeaterModel { heating_rate: 2.0, cooling_rate: 0.5 };
+        assert_eq!(Some(20.0), h.time_to_reach(60.0, 50.0));
+    }
+
+    #[test]
+    fn test_time_to_reach_unreachable() {
+        let h = HeaterModel { heating_rate: 0.0, cooling_rate: f32::NAN };
+        assert_eq!(None, h.time_to_reach(25.0, 205.0));
+        assert_eq!(None, h.time_to_reach(60.0, 50.0));
+        assert_eq!(Some(0.0), h.time_to_reach(60.0, 60.0));
+        assert_eq!(None, HeaterModel { heating_rate: -1.0, cooling_rate: 1.0 }.time_to_reach(25.0, 205.0));
+    }
+
+    #[test]
+    fn test_step_heats_to_target() {
+        let h = HeaterModel { heating_rate: 2.0, cooling_rate: 1.0 };
+        assert_eq!(45.0, h.step(25.0, 210.0, 10.0, 25.0));
+        assert_eq!(210.0, h.step(205.0, 210.0, 10.0, 25.0));
+    }
+
+    #[test]
+    fn test_step_cools_to_ambient_when_off() {
+        let h = HeaterModel { heating_rate: 2.0, cooling_rate: 1.0 };
+        assert_eq!(190.0, h.step(200.0, 0.0, 10.0, 25.0));
+        assert_eq!(25.0, h.step(30.0, 0.0, 10.0, 25.0));
+    }
+
+    #[test]
+    fn test_default_model() {
+        let m = ThermalModel::default();
+        assert_eq!(25.0, m.ambient);
+        assert!(m.hotend.heating_rate > m.bed.heating_rate);
+    }
+}
+
+/// How quickly a heater heats up and cools down, in °C per second
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct HeaterModel {
+    pub heating_rate: f32,
+    pub cooling_rate: f32,
+}
+
+impl HeaterModel {
+    /// Returns the seconds needed to go from the current temperature to the target, `None` if the
+    /// rate it would change at is not a positive number, so the target is never reached
+    ///
+    /// # Examples
+    /// ```
+    /// extern crate gen_gcode;
+    /// use gen_gcode::thermal::HeaterModel;
+    ///
+    /// let hotend = HeaterModel { heating_rate: 2.0, cooling_rate: 1.0 };
+    /// assert_eq!(Some(90.0), hotend.time_to_reach(25.0, 205.0));
+    /// ```
+    pub fn time_to_reach(&self, current: f32, target: f32) -> Option<f32> {
+        let (change, rate) = if target > current { (target - current, self.heating_rate) } else { (current - target, self.cooling_rate) };
+        if change == 0.0 {
+            Some(0.0)
+        } else if rate > 0.0 && rate.is_finite() {
+            Some(change / rate)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the temperature after `seconds` have passed, heating towards the target or cooling
+    /// towards it (or towards ambient if the heater is off)
+    pub fn step(&self, current: f32, target: f32, seconds: f32, ambient: f32) -> f32 {
+        let goal = if target > 0.0 { target } else { ambient };
+        if goal > current {
+            (current + self.heating_rate * seconds).min(goal)
+        } else {
+            (current - self.cooling_rate * seconds).max(goal)
+        }
+    }
+}
+
+/// Heating behavior of a machine's hotends, bed and chamber
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::thermal::{HeaterModel, ThermalModel};
+///
+/// // a printer with a slow bed in a warm room
+/// let model = ThermalModel {
+///     ambient: 30.0,
+///     bed: HeaterModel { heating_rate: 0.25, cooling_rate: 0.05 },
+///     ..ThermalModel::default()
+/// };
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ThermalModel {
+    /// Temperature heaters start at and cool down to when off
+    pub ambient: f32,
+    /// Model used for every hotend
+    pub hotend: HeaterModel,
+    pub bed: HeaterModel,
+    pub chamber: HeaterModel,
+}
+
+impl Default for ThermalModel {
+    /// Rough figures for a typical desktop FDM printer
+    fn default() -> Self {
+        ThermalModel {
+            ambient: 25.0,
+            hotend: HeaterModel { heating_rate: 2.0, cooling_rate: 1.0 },
+            bed: HeaterModel { heating_rate: 0.5, cooling_rate: 0.1 },
+            chamber: HeaterModel { heating_rate: 0.05, cooling_rate: 0.02 },
+        }
+    }
+}
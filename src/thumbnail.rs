@@ -0,0 +1,381 @@
+//! Preview images of a program, embedded in G-Code as base64 PNG thumbnails
+//!
+//! Printer screens and web interfaces such as OctoPrint and Mainsail show the thumbnails written
+//! between `; thumbnail begin` and `; thumbnail end` comments, in the format used by PrusaSlicer
+//! and Cura.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(png: &[u8]) -> (png::OutputInfo, Vec<u8>) {
+        let decoder = png::Decoder::new(png);
+        let mut reader = decoder.read_info().unwrap();
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf).unwrap();
+        (info, buf)
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(0xCBF4_3926, crc32(b"123456789"));
+    }
+
+    #[test]
+    fn test_adler32() {
+        assert_eq!(0x11E6_0398, adler32(b"Wikipedia"));
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!("", base64(b""));
+        assert_eq!("Zg==", base64(b"f"));
+        assert_eq!("Zm8=", base64(b"fo"));
+        assert_eq!("Zm9v", base64(b"foo"));
+        assert_eq!("Zm9vYmFy", base64(b"foobar"));
+    }
+
+    #[test]
+    fn test_png_round_trip() {
+        let mut image = Image::new(33, 7);
+        for x in 0..33 {
+            image.set_pixel(x, 3, [x as u8 * 7, 20, 30, 255]);
+        }
+        image.set_pixel(0, 0, [1, 2, 3, 4]);
+        let (info, pixels) = decode(&image.encode_png());
+        assert_eq!((33, 7), (info.width, info.height));
+        assert_eq!(png::ColorType::Rgba, info.color_type);
+        assert_eq!(image.pixels, pixels);
+    }
+
+    #[test]
+    fn test_png_compresses_runs() {
+        let image = Image::new(300, 300);
+        let png = image.encode_png();
+        assert!(png.len() < 10_000, "{}", png.len());
+        assert_eq!(image.pixels, decode(&png).1);
+    }
+
+    #[test]
+    fn test_render_preview() {
+        let square = vec![
+            Point3d { x: 10.0, y: 10.0, z: 0.2 },
+            Point3d { x: 30.0, y: 10.0, z: 0.2 },
+            Point3d { x: 30.0, y: 30.0, z: 0.2 },
+            Point3d { x: 10.0, y: 30.0, z: 0.2 },
+            Point3d { x: 10.0, y: 10.0, z: 0.2 },
+        ];
+        let image = render_preview(&[square], 16, 16);
+        // the square fills the image, less a 1 pixel margin
+        assert_eq!(255, image.pixel(1, 1)[3]);
+        assert_eq!(255, image.pixel(14, 14)[3]);
+        assert_eq!(255, image.pixel(8, 1)[3]);
+        assert_eq!(0, image.pixel(8, 8)[3]);
+        assert_eq!(0, image.pixel(0, 0)[3]);
+        // y goes up on the bed but down in the image
+        let top = render_preview(&[vec![Point3d { x: 0.0, y: 0.0, z: 0.2 }, Point3d { x: 10.0, y: 10.0, z: 0.2 }]], 12, 12);
+        assert_eq!(255, top.pixel(10, 1)[3]);
+        assert_eq!(0, top.pixel(1, 1)[3]);
+    }
+
+    #[test]
+    fn test_thumbnail_block() {
+        let image = Image::new(2, 2);
+        let block = thumbnail_block(&image, CommentStyle::Semicolon);
+        let encoded = base64(&image.encode_png());
+        let mut lines = block.lines();
+        assert_eq!(Some(format!("; thumbnail begin 2x2 {}", encoded.len()).as_str()), lines.next());
+        let body: String = lines.clone().take_while(|l| *l != "; thumbnail end").map(|l| l.trim_start_matches("; ")).collect();
+        assert_eq!(encoded, body);
+        assert!(lines.all(|l| l.len() <= 80));
+        assert!(block.ends_with("; thumbnail end\n;\n"));
+        let block = thumbnail_block(&image, CommentStyle::Parentheses);
+        assert!(block.starts_with(&format!("(thumbnail begin 2x2 {})\n(", encoded.len())));
+        assert!(block.ends_with(")\n(thumbnail end)\n"));
+    }
+
+    #[test]
+    fn test_render_preview_with_nan() {
+        let path = vec![Point3d { x: 0.0, y: 0.0, z: 0.2 }, Point3d { x: 10.0, y: 10.0, z: f32::NAN }, Point3d { x: 10.0, y: 0.0, z: 0.2 }];
+        let image = render_preview(&[path], 12, 12);
+        assert_eq!(255, image.pixel(10, 10)[3]);
+    }
+}
+
+use crate::{CommentStyle, Point3d};
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Returns the standard base64 encoding of some data, with padding
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::thumbnail::base64;
+///
+/// assert_eq!("RzI4", base64(b"G28"));
+/// ```
+pub fn base64(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Returns the CRC-32 (ISO-HDLC, as used by PNG and zlib) of some data
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { crc >> 1 ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+/// Writes bits least significant first, as deflate streams are packed
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u32,
+    len: u32,
+}
+
+impl BitWriter {
+    fn bits(&mut self, value: u32, count: u32) {
+        self.acc |= value << self.len;
+        self.len += count;
+        while self.len >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.len -= 8;
+        }
+    }
+
+    /// Huffman codes are packed most significant bit first
+    fn code(&mut self, code: u32, count: u32) {
+        let reversed = code.reverse_bits() >> (32 - count);
+        self.bits(reversed, count);
+    }
+
+    fn symbol(&mut self, symbol: u32) {
+        match symbol {
+            0..=143 => self.code(0x30 + symbol, 8),
+            144..=255 => self.code(0x190 + symbol - 144, 9),
+            256..=279 => self.code(symbol - 256, 7),
+            _ => self.code(0xC0 + symbol - 280, 8),
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.len > 0 {
+            self.out.push(self.acc as u8);
+        }
+        self.out
+    }
+}
+
+const LENGTH_BASE: [u32; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u32; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u32; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DISTANCE_EXTRA: [u32; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+/// Compresses data as a zlib stream, using fixed Huffman codes and only looking for matches at the
+/// given distances, which is enough for the repeated pixels and rows of an image
+fn zlib(data: &[u8], distances: &[usize]) -> Vec<u8> {
+    let mut w = BitWriter { out: vec![0x78, 0x01], acc: 0, len: 0 };
+    // a single final block with fixed codes
+    w.bits(1, 1);
+    w.bits(1, 2);
+    let mut i = 0;
+    while i < data.len() {
+        let mut best = (0, 0);
+        for &d in distances.iter().filter(|&&d| d <= i && d <= 32768) {
+            let mut len = 0;
+            while len < 258 && i + len < data.len() && data[i + len] == data[i + len - d] {
+                len += 1;
+            }
+            if len > best.0 {
+                best = (len, d);
+            }
+        }
+        let (len, dist) = best;
+        if len < 3 {
+            w.symbol(data[i] as u32);
+            i += 1;
+            continue;
+        }
+        let l = LENGTH_BASE.iter().rposition(|&b| b <= len as u32).unwrap();
+        w.symbol(257 + l as u32);
+        w.bits(len as u32 - LENGTH_BASE[l], LENGTH_EXTRA[l]);
+        let d = DISTANCE_BASE.iter().rposition(|&b| b <= dist as u32).unwrap();
+        w.code(d as u32, 5);
+        w.bits(dist as u32 - DISTANCE_BASE[d], DISTANCE_EXTRA[d]);
+        i += len;
+    }
+    w.symbol(256);
+    let mut out = w.finish();
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+/// An RGBA image
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    /// Rows of RGBA pixels, top to bottom
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    /// Creates a transparent image
+    pub fn new(width: u32, height: u32) -> Self {
+        Image { width, height, pixels: vec![0; (width * height * 4) as usize] }
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let i = ((y * self.width + x) * 4) as usize;
+        [self.pixels[i], self.pixels[i + 1], self.pixels[i + 2], self.pixels[i + 3]]
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, rgba: [u8; 4]) {
+        if x < self.width && y < self.height {
+            let i = ((y * self.width + x) * 4) as usize;
+            self.pixels[i..i + 4].copy_from_slice(&rgba);
+        }
+    }
+
+    /// Draws a line between two pixels, with Bresenham's algorithm
+    fn line(&mut self, from: (i64, i64), to: (i64, i64), rgba: [u8; 4]) {
+        let (mut x, mut y) = from;
+        let (dx, dy) = ((to.0 - x).abs(), -(to.1 - y).abs());
+        let (sx, sy) = ((to.0 - x).signum(), (to.1 - y).signum());
+        let mut err = dx + dy;
+        loop {
+            if x >= 0 && y >= 0 {
+                self.set_pixel(x as u32, y as u32, rgba);
+            }
+            if (x, y) == to {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
+    }
+
+    /// Returns the image encoded as a PNG file
+    pub fn encode_png(&self) -> Vec<u8> {
+        let stride = (self.width * 4) as usize;
+        let mut raw = Vec::with_capacity((stride + 1) * self.height as usize);
+        for row in self.pixels.chunks(stride.max(1)).take(self.height as usize) {
+            // filter type 0, the rows are stored as is
+            raw.push(0);
+            raw.extend_from_slice(row);
+        }
+        let mut out = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+        let mut header = Vec::new();
+        header.extend_from_slice(&self.width.to_be_bytes());
+        header.extend_from_slice(&self.height.to_be_bytes());
+        // 8 bits per channel, RGBA, default compression, filtering and no interlacing
+        header.extend_from_slice(&[8, 6, 0, 0, 0]);
+        png_chunk(&mut out, b"IHDR", &header);
+        png_chunk(&mut out, b"IDAT", &zlib(&raw, &[4, stride + 1]));
+        png_chunk(&mut out, b"IEND", &[]);
+        out
+    }
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Draws extrusion paths seen from above, scaled to fit the image with a 1 pixel margin. Paths
+/// are shaded from dark at the bottom to light at the top, on a transparent background.
+pub fn render_preview(paths: &[Vec<Point3d>], width: u32, height: u32) -> Image {
+    let mut image = Image::new(width, height);
+    let points = paths.iter().flatten();
+    let (mut min, mut max) = (Point3d { x: f32::MAX, y: f32::MAX, z: f32::MAX }, Point3d { x: f32::MIN, y: f32::MIN, z: f32::MIN });
+    for p in points {
+        min = Point3d { x: min.x.min(p.x), y: min.y.min(p.y), z: min.z.min(p.z) };
+        max = Point3d { x: max.x.max(p.x), y: max.y.max(p.y), z: max.z.max(p.z) };
+    }
+    if min.x > max.x || width < 3 || height < 3 {
+        return image;
+    }
+    let (inner_w, inner_h) = ((width - 3) as f32, (height - 3) as f32);
+    let scale = (inner_w / (max.x - min.x).max(1e-3)).min(inner_h / (max.y - min.y).max(1e-3));
+    let offset = ((inner_w - (max.x - min.x) * scale) / 2.0 + 1.0, (inner_h - (max.y - min.y) * scale) / 2.0 + 1.0);
+    let to_pixel = |p: &Point3d| {
+        let x = offset.0 + (p.x - min.x) * scale;
+        let y = offset.1 + (max.y - p.y) * scale;
+        (x.round() as i64, y.round() as i64)
+    };
+    let mut segments: Vec<(&Point3d, &Point3d)> = paths.iter().flat_map(|path| path.iter().zip(path.iter().skip(1))).collect();
+    segments.sort_by(|a, b| a.1.z.total_cmp(&b.1.z));
+    for (from, to) in segments {
+        let height = if max.z > min.z { (to.z - min.z) / (max.z - min.z) } else { 1.0 };
+        let shade = |dark: f32, light: f32| (dark + (light - dark) * height) as u8;
+        let rgba = [shade(150.0, 255.0), shade(60.0, 170.0), shade(0.0, 60.0), 255];
+        image.line(to_pixel(from), to_pixel(to), rgba);
+    }
+    image
+}
+
+/// Returns an image as a thumbnail block of G-Code comments in the given style, in the format read
+/// by PrusaSlicer, Cura, OctoPrint and Moonraker
+///
+/// # Examples
+/// ```
+/// extern crate gen_gcode;
+/// use gen_gcode::CommentStyle;
+/// use gen_gcode::thumbnail::{thumbnail_block, Image};
+///
+/// let block = thumbnail_block(&Image::new(16, 16), CommentStyle::Semicolon);
+/// assert!(block.starts_with("; thumbnail begin 16x16 "));
+/// assert!(block.ends_with("; thumbnail end\n;\n"));
+/// ```
+pub fn thumbnail_block(image: &Image, style: CommentStyle) -> String {
+    let encoded = base64(&image.encode_png());
+    let mut out = style.format(&format!("thumbnail begin {}x{} {}", image.width, image.height, encoded.len())) + "\n";
+    for chunk in encoded.as_bytes().chunks(78) {
+        out += &style.format(&String::from_utf8_lossy(chunk));
+        out.push('\n');
+    }
+    out += &style.format("thumbnail end");
+    out += match style {
+        CommentStyle::Semicolon => "\n;\n",
+        CommentStyle::Parentheses => "\n",
+    };
+    out
+}
@@ -0,0 +1,138 @@
+//! Wipe towers, the block printed beside multi-material prints to purge the nozzle after a tool
+//! change
+//!
+//! The tower takes up a rectangle on the bed and grows with the print: each tool change purges
+//! on it with tightly packed lines, and layers without a tool change get sparse lines so the
+//! tower stays as tall as the print. A purge that doesn't fit on its layer is finished on the
+//! next ones. See [crate::program::Program::set_wipe_tower].
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tower() -> WipeTower {
+        WipeTower { line_width: 0.5, ..WipeTower::new(Point2d { x: 100.0, y: 50.0 }, 20.0, 10.0) }
+    }
+
+    #[test]
+    fn test_contains() {
+        let tower = tower();
+        assert!(tower.contains(Point2d { x: 110.0, y: 55.0 }));
+        assert!(tower.contains(Point2d { x: 100.0, y: 60.0 }));
+        assert!(!tower.contains(Point2d { x: 99.9, y: 55.0 }));
+        assert!(!tower.contains(Point2d { x: 110.0, y: 60.1 }));
+    }
+
+    #[test]
+    fn test_line_count() {
+        let tower = tower();
+        assert_eq!(20, tower.line_count(0.5));
+        assert_eq!(5, tower.line_count(2.0));
+    }
+
+    #[test]
+    fn test_purge_lines() {
+        let tower = tower();
+        // 70mm³ at 0.5mm wide and 0.2mm high is 700mm of line, lines are 19.5mm long
+        assert_eq!(36, tower.purge_lines(tower.purge_volume, 0.2));
+        assert_eq!(18, tower.purge_lines(tower.purge_volume, 0.4));
+        assert_eq!(1, tower.purge_lines(1.0, 0.2));
+        assert_eq!(0, tower.purge_lines(tower.purge_volume, 0.0));
+        assert!((tower.line_volume(0.2) - 1.95).abs() < 1e-6);
+        let area = std::f32::consts::PI * 0.875 * 0.875;
+        assert!((tower.filament_per_mm(0.2) - 0.1 / area).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_path_zig_zag() {
+        let tower = tower();
+        let expected = [(100.25, 50.25), (119.75, 50.25), (119.75, 50.75), (100.25, 50.75), (100.25, 51.25), (119.75, 51.25)];
+        let expected: Vec<Point2d> = expected.iter().map(|&(x, y)| Point2d { x, y }).collect();
+        assert_eq!(expected, tower.path(0, 3, 0.5));
+    }
+
+    #[test]
+    fn test_path_stops_at_back() {
+        let tower = tower();
+        let path = tower.path(19, 3, 0.5);
+        assert_eq!(vec![Point2d { x: 119.75, y: 59.75 }, Point2d { x: 100.25, y: 59.75 }], path);
+        assert!(tower.path(20, 3, 0.5).is_empty());
+    }
+}
+
+use crate::Point2d;
+use std::f32::consts::PI;
+
+/// A wipe tower's place on the bed and how it is printed, lengths are in millimeters and speeds in
+/// millimeters per minute
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct WipeTower {
+    /// Front left corner of the tower
+    pub position: Point2d,
+    /// Size along the X axis, the direction lines are printed in
+    pub width: f32,
+    /// Size along the Y axis
+    pub depth: f32,
+    pub line_width: f32,
+    /// Volume of filament purged after each tool change, in cubic millimeters
+    pub purge_volume: f32,
+    pub filament_diameter: f32,
+    pub speed: f32,
+    /// Distance between lines on layers without a tool change
+    pub sparse_spacing: f32,
+}
+
+impl WipeTower {
+    /// Creates a tower purging 70mm³ of 1.75mm filament per tool change with 0.45mm lines
+    pub fn new(position: Point2d, width: f32, depth: f32) -> Self {
+        WipeTower { position, width, depth, line_width: 0.45, purge_volume: 70.0, filament_diameter: 1.75, speed: 2400.0, sparse_spacing: 2.0 }
+    }
+
+    /// Whether a point lies in the area taken by the tower
+    pub fn contains(&self, p: Point2d) -> bool {
+        p.x >= self.position.x && p.x <= self.position.x + self.width && p.y >= self.position.y && p.y <= self.position.y + self.depth
+    }
+
+    /// Returns the length of filament extruded per millimeter of line at a layer height
+    pub fn filament_per_mm(&self, layer_height: f32) -> f32 {
+        let radius = self.filament_diameter / 2.0;
+        self.line_width * layer_height / (PI * radius * radius)
+    }
+
+    /// Returns the number of lines that fit across the tower with the given distance between them
+    pub fn line_count(&self, spacing: f32) -> usize {
+        ((self.depth - self.line_width) / spacing).floor() as usize + 1
+    }
+
+    /// Returns the volume of filament in one line across the tower at a layer height, in cubic
+    /// millimeters
+    pub fn line_volume(&self, layer_height: f32) -> f32 {
+        (self.width - self.line_width) * self.line_width * layer_height
+    }
+
+    /// Returns the number of lines needed to purge a volume at a layer height, none when the lines
+    /// would hold nothing, such as at a layer height of 0
+    pub fn purge_lines(&self, volume: f32, layer_height: f32) -> usize {
+        let line = self.line_volume(layer_height);
+        if line <= 0.0 || volume <= 0.0 {
+            return 0
+        }
+        (volume / line).ceil() as usize
+    }
+
+    /// Returns up to `count` lines starting from line `first`, joined into a zig-zag path to
+    /// extrude through. Lines past the back of the tower are left out, the layer is full.
+    pub fn path(&self, first: usize, count: usize, spacing: f32) -> Vec<Point2d> {
+        let last = self.line_count(spacing).min(first.saturating_add(count));
+        let half = self.line_width / 2.0;
+        let (left, right) = (self.position.x + half, self.position.x + self.width - half);
+        let mut path = Vec::new();
+        for line in first..last {
+            let y = self.position.y + half + line as f32 * spacing;
+            let (from, to) = if line % 2 == 0 { (left, right) } else { (right, left) };
+            path.push(Point2d { x: from, y });
+            path.push(Point2d { x: to, y });
+        }
+        path
+    }
+}